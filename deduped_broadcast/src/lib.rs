@@ -68,6 +68,22 @@ where
     pub fn subscribe(&self) -> broadcast::Receiver<T> {
         self.broadcast_filtered_tx.subscribe()
     }
+
+    pub fn total_unfiltered(&self) -> usize {
+        self.total_unfiltered.load(Ordering::SeqCst)
+    }
+
+    pub fn total_filtered(&self) -> usize {
+        self.total_filtered.load(Ordering::SeqCst)
+    }
+
+    pub fn total_broadcasts(&self) -> usize {
+        self.total_broadcasts.load(Ordering::SeqCst)
+    }
+
+    pub fn subscriptions(&self) -> usize {
+        self.broadcast_filtered_tx.receiver_count()
+    }
 }
 
 impl<T> Debug for DedupedBroadcaster<T>
@@ -0,0 +1,441 @@
+//! One json line per request. Answers "what happened to request X" after the fact.
+//!
+//! Records are sent to a background task so that a slow disk never slows down a request.
+//! If the task falls behind, records are dropped (and counted) instead of queued forever.
+use crate::app::Web3ProxyJoinHandle;
+use crate::config::{AccessLogParams, AppConfig};
+use crate::jsonrpc::{ValidatedRequest, ValidatedResponse};
+use alloy::primitives::U64;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sonic_rs::{json, Value};
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicU64};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, Stdout};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+
+/// records waiting to be written. more than this are dropped
+/// TODO: config for this?
+const BUFFER: usize = 10_000;
+
+/// `access_log = "stdout"` writes here instead of to a file
+const STDOUT: &str = "stdout";
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogRecord<'a> {
+    pub at: DateTime<Utc>,
+    /// from x-amzn-trace-id or generated
+    pub request_id: Option<&'a str>,
+    pub method: &'a str,
+    /// depends on `access_log_params`. "redacted" if the method's params are not logged
+    pub params: Option<Cow<'a, Value>>,
+    pub proxy_mode: &'static str,
+    /// the api key's name. never the secret
+    pub api_key: Option<&'a str>,
+    pub client_ip: Option<IpAddr>,
+    pub head_block: Option<U64>,
+    pub from_block: Option<U64>,
+    pub to_block: Option<U64>,
+    pub archive_request: bool,
+    /// every rpc that was tried. in order
+    pub backend_rpcs: Vec<String>,
+    /// answered from the response cache
+    pub cache_hit: bool,
+    /// how many times the request waited because no servers were synced
+    pub no_servers: u64,
+    pub response_bytes: u64,
+    pub response_millis: u64,
+    /// see `error_class`
+    pub error: Option<&'static str>,
+}
+
+impl<'a> AccessLogRecord<'a> {
+    /// call this after `set_response`
+    pub fn new(web3_request: &'a ValidatedRequest, config: &AppConfig) -> Self {
+        let method = web3_request.inner.method();
+
+        let params =
+            web3_request
+                .inner
+                .jsonrpc_request()
+                .and_then(|x| match config.access_log_params {
+                    AccessLogParams::Omit => None,
+                    AccessLogParams::Redact if config.redacts_params(method) => {
+                        Some(Cow::Owned(json!("redacted")))
+                    }
+                    AccessLogParams::Redact | AccessLogParams::Full => {
+                        Some(Cow::Borrowed(&x.params))
+                    }
+                });
+
+        let response_lock = web3_request.response.lock();
+
+        let error = error_class(&response_lock);
+
+        Self {
+            at: Utc::now(),
+            request_id: web3_request.request_id.as_deref(),
+            method,
+            params,
+            proxy_mode: web3_request.proxy_mode().name(),
+            api_key: web3_request.api_key.as_ref().map(|x| &*x.name),
+            client_ip: web3_request.client_ip,
+            head_block: web3_request.head_block.as_ref().map(|x| x.number()),
+            from_block: web3_request.request_blocks.from_block().map(|x| x.num()),
+            to_block: web3_request.request_blocks.to_block().map(|x| x.num()),
+            archive_request: response_lock.archive_request,
+            backend_rpcs: response_lock
+                .backend_rpcs
+                .iter()
+                .map(|x| x.name.clone())
+                .collect(),
+            cache_hit: response_lock.cache_hit,
+            no_servers: response_lock.no_servers,
+            response_bytes: response_lock.response_bytes,
+            response_millis: response_lock.response_millis,
+            error,
+        }
+    }
+}
+
+pub struct AccessLog(LineWriter);
+
+impl AccessLog {
+    /// None if `access_log` is not set
+    pub async fn spawn(
+        config: &AppConfig,
+        shutdown_receiver: broadcast::Receiver<()>,
+    ) -> anyhow::Result<Option<(Self, Web3ProxyJoinHandle<()>)>> {
+        let Some(target) = config.access_log.as_deref() else {
+            return Ok(None);
+        };
+
+        let (writer, handle) = LineWriter::spawn(
+            "access log",
+            target,
+            config.access_log_max_bytes,
+            config.access_log_max_files,
+            shutdown_receiver,
+        )
+        .await?;
+
+        Ok(Some((Self(writer), handle)))
+    }
+
+    pub fn record(&self, web3_request: &ValidatedRequest, config: &AppConfig) {
+        self.0.send(&AccessLogRecord::new(web3_request, config));
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped()
+    }
+}
+
+/// None for success. "app" for our errors, "no_servers" if that was why, and "user" for jsonrpc errors from the rpcs
+pub fn error_class(response: &ValidatedResponse) -> Option<&'static str> {
+    if response.error_response {
+        if response.no_servers > 0 {
+            Some("no_servers")
+        } else {
+            Some("app")
+        }
+    } else if response.user_error_response {
+        Some("user")
+    } else {
+        None
+    }
+}
+
+/// json lines written by a background task. used by the access log and the traffic capture
+pub struct LineWriter {
+    /// for logs
+    name: &'static str,
+    sender: mpsc::Sender<Vec<u8>>,
+    /// records that were dropped because the writer fell behind
+    dropped: AtomicU64,
+}
+
+impl LineWriter {
+    /// `target` is "stdout" or a file path. errors if the file can't be opened so that a bad path stops startup
+    pub async fn spawn(
+        name: &'static str,
+        target: &str,
+        max_bytes: u64,
+        max_files: usize,
+        shutdown_receiver: broadcast::Receiver<()>,
+    ) -> anyhow::Result<(Self, Web3ProxyJoinHandle<()>)> {
+        let (sender, receiver) = mpsc::channel(BUFFER);
+
+        let handle = if target == STDOUT {
+            info!("{} writing to stdout", name);
+
+            tokio::spawn(write_lines(
+                name,
+                receiver,
+                tokio::io::stdout(),
+                shutdown_receiver,
+            ))
+        } else {
+            let file = RotatingFile::open(target.into(), max_bytes, max_files)
+                .await
+                .with_context(|| format!("starting the {}", name))?;
+
+            info!(path=%target, "{} writing to a file", name);
+
+            tokio::spawn(write_lines(name, receiver, file, shutdown_receiver))
+        };
+
+        let x = Self {
+            name,
+            sender,
+            dropped: AtomicU64::new(0),
+        };
+
+        Ok((x, handle))
+    }
+
+    /// queue one line. this never waits
+    pub fn send<T: Serialize>(&self, record: &T) {
+        let mut line = match sonic_rs::to_vec(record) {
+            Ok(x) => x,
+            Err(err) => {
+                error!(?err, "unable to serialize {} record", self.name);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if self.sender.try_send(line).is_err() {
+            let dropped = self.dropped.fetch_add(1, atomic::Ordering::Relaxed);
+
+            // don't make the problem worse by logging every drop
+            if dropped.is_multiple_of(1_000) {
+                warn!(
+                    dropped = dropped + 1,
+                    "{} is behind! records dropped", self.name
+                );
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// where the lines go
+trait Sink {
+    async fn write_line(&mut self, line: &[u8]) -> anyhow::Result<()>;
+
+    async fn flush(&mut self) -> anyhow::Result<()>;
+}
+
+impl Sink for Stdout {
+    async fn write_line(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        self.write_all(line).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        AsyncWriteExt::flush(self).await?;
+        Ok(())
+    }
+}
+
+/// a file that is renamed to "{path}.1" once it reaches `max_bytes`. older files move up a number
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    async fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        let written = file.metadata().await?.len();
+
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut x = self.path.clone().into_os_string();
+        x.push(format!(".{}", n));
+        x.into()
+    }
+
+    async fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush().await?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).await?;
+        } else {
+            // the oldest file is overwritten by the rename
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+
+                if fs::try_exists(&from).await? {
+                    fs::rename(&from, self.rotated_path(n + 1)).await?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated_path(1)).await?;
+        }
+
+        *self = Self::open(self.path.clone(), self.max_bytes, self.max_files).await?;
+
+        Ok(())
+    }
+}
+
+impl Sink for RotatingFile {
+    async fn write_line(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        if self.max_bytes > 0
+            && self.written > 0
+            && self.written + line.len() as u64 > self.max_bytes
+        {
+            self.rotate().await?;
+        }
+
+        self.file.write_all(line).await?;
+        self.written += line.len() as u64;
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        // tokio's file writes happen in the background until they are flushed
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+/// write lines until shutdown. everything already queued is written before exiting
+async fn write_lines<S: Sink>(
+    name: &'static str,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    mut sink: S,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> crate::errors::Web3ProxyResult<()> {
+    let mut batch = Vec::with_capacity(100);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_receiver.recv() => {
+                // stop taking new records, but write the ones that are already queued
+                receiver.close();
+            }
+            x = receiver.recv_many(&mut batch, 100) => {
+                if x == 0 {
+                    break;
+                }
+
+                for line in batch.drain(..) {
+                    if let Err(err) = sink.write_line(&line).await {
+                        error!(?err, "unable to write to the {}", name);
+                    }
+                }
+
+                if let Err(err) = sink.flush().await {
+                    error!(?err, "unable to flush the {}", name);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessLogRecord, RotatingFile, Sink};
+    use crate::config::{AccessLogParams, AppConfig};
+    use crate::jsonrpc::ValidatedRequest;
+    use sonic_rs::json;
+    use std::sync::Arc;
+
+    async fn request(method: &'static str) -> Arc<ValidatedRequest> {
+        ValidatedRequest::new_internal(method.into(), &json!(["0xdeadbeef"]), None, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_access_log_params() {
+        let mut config = AppConfig::default();
+
+        let raw_tx = request("eth_sendRawTransaction").await;
+        let balance = request("eth_getBalance").await;
+
+        // the default redacts transactions
+        let x = sonic_rs::to_string(&AccessLogRecord::new(&raw_tx, &config)).unwrap();
+        assert!(x.contains("\"params\":\"redacted\""), "{}", x);
+        assert!(x.contains("\"method\":\"eth_sendRawTransaction\""));
+        assert!(x.contains("\"proxy_mode\":\"best\""));
+
+        let x = sonic_rs::to_string(&AccessLogRecord::new(&balance, &config)).unwrap();
+        assert!(x.contains("\"params\":[\"0xdeadbeef\"]"), "{}", x);
+        assert!(x.contains("\"error\":null"));
+
+        config.access_log_params = AccessLogParams::Omit;
+        let x = sonic_rs::to_string(&AccessLogRecord::new(&balance, &config)).unwrap();
+        assert!(x.contains("\"params\":null"), "{}", x);
+
+        config.access_log_params = AccessLogParams::Full;
+        let x = sonic_rs::to_string(&AccessLogRecord::new(&raw_tx, &config)).unwrap();
+        assert!(x.contains("\"params\":[\"0xdeadbeef\"]"), "{}", x);
+
+        balance.response.lock().error_response = true;
+        let x = sonic_rs::to_string(&AccessLogRecord::new(&balance, &config)).unwrap();
+        assert!(x.contains("\"error\":\"app\""), "{}", x);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_rotation() {
+        let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.jsonl");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).await.unwrap();
+
+        for i in 0..4 {
+            file.write_line(format!("line {}\n", i).as_bytes())
+                .await
+                .unwrap();
+        }
+        file.flush().await.unwrap();
+
+        // every line is 7 bytes. each one gets its own file and only 2 old files are kept
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 3\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.jsonl.1")).unwrap(),
+            "line 2\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.jsonl.2")).unwrap(),
+            "line 1\n"
+        );
+        assert!(!dir.join("access.jsonl.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+//! Keys for authenticated access. Each key has its own limits and method rules.
+use crate::config::{ApiKeyConfig, TopConfig};
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::rpc_proxy_ws::ProxyMode;
+use crate::rate_limit::TokenBucket;
+use arc_swap::ArcSwap;
+use hashbrown::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+pub struct ApiKey {
+    /// the name from the config. this is safe to log. the secret is not
+    pub name: Arc<str>,
+    config: ApiKeyConfig,
+    concurrency: Option<Arc<Semaphore>>,
+    rate_limit: Option<TokenBucket>,
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApiKey {
+    pub fn new(name: Arc<str>, config: ApiKeyConfig) -> Self {
+        let concurrency = config
+            .max_concurrent_requests
+            .map(|x| Arc::new(Semaphore::new(x as usize)));
+
+        // TODO: config for the burst size
+        let rate_limit = config.requests_per_second.map(|x| TokenBucket::new(x, x));
+
+        Self {
+            name,
+            config,
+            concurrency,
+            rate_limit,
+        }
+    }
+
+    /// this key can use the admin api
+    pub fn is_admin(&self) -> bool {
+        self.config.admin
+    }
+
+    pub fn check_proxy_mode(&self, proxy_mode: ProxyMode) -> Web3ProxyResult<()> {
+        let allowed = &self.config.allowed_proxy_modes;
+
+        if allowed.is_empty() || allowed.iter().any(|x| x == proxy_mode.name()) {
+            Ok(())
+        } else {
+            Err(Web3ProxyError::AccessDenied(
+                format!(
+                    "proxy mode {} is not allowed for this key",
+                    proxy_mode.name()
+                )
+                .into(),
+            ))
+        }
+    }
+
+    pub fn check_method(&self, method: &str) -> Web3ProxyResult<()> {
+        let allowed = &self.config.allowed_methods;
+
+        if self.config.denied_methods.iter().any(|x| x == method)
+            || !(allowed.is_empty() || allowed.iter().any(|x| x == method))
+        {
+            Err(Web3ProxyError::AccessDenied(
+                format!("method {} is not allowed for this key", method).into(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// check the methods and take one request per method from the rate limit.
+    /// a batch with more calls than `requests_per_second` is always refused.
+    /// the permit must be held until the response is sent
+    pub fn authorize<'a>(
+        &self,
+        methods: impl IntoIterator<Item = &'a str>,
+    ) -> Web3ProxyResult<Option<OwnedSemaphorePermit>> {
+        let mut num_requests = 0;
+        for method in methods {
+            self.check_method(method)?;
+            num_requests += 1;
+        }
+
+        let permit = match self.concurrency.as_ref() {
+            None => None,
+            Some(x) => match x.clone().try_acquire_owned() {
+                Ok(x) => Some(x),
+                // TODO: wait a little while for a permit instead?
+                Err(_) => return Err(Web3ProxyError::RateLimited(Some(Duration::from_secs(1)))),
+            },
+        };
+
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            rate_limit
+                .try_acquire(num_requests)
+                .map_err(Web3ProxyError::RateLimited)?;
+        }
+
+        Ok(permit)
+    }
+}
+
+/// All of the api keys. They can be replaced while running.
+#[derive(Default)]
+pub struct ApiKeys {
+    by_secret: ArcSwap<HashMap<String, Arc<ApiKey>>>,
+    /// sent after the keys change. open websockets check that their key is still valid
+    changed: watch::Sender<()>,
+}
+
+impl ApiKeys {
+    /// a key for proxy requests. admin keys only work on the admin api
+    pub fn get(&self, secret: &str) -> Option<Arc<ApiKey>> {
+        self.by_secret
+            .load()
+            .get(secret)
+            .filter(|x| !x.is_admin())
+            .cloned()
+    }
+
+    /// a key for the admin api. proxy keys do not work there
+    pub fn get_admin(&self, secret: &str) -> Option<Arc<ApiKey>> {
+        self.by_secret
+            .load()
+            .get(secret)
+            .filter(|x| x.is_admin())
+            .cloned()
+    }
+
+    /// the latest version of a key. None if it was removed or its secret changed
+    pub fn current(&self, key: &ApiKey) -> Option<Arc<ApiKey>> {
+        self.get(&key.config.key)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_secret.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_secret.load().is_empty()
+    }
+
+    /// replace all the keys. keys with an unchanged name and config keep their rate limits
+    pub fn apply(&self, configs: HashMap<String, ApiKeyConfig>) {
+        let old = self.by_secret.load();
+
+        let mut new = HashMap::with_capacity(configs.len());
+
+        for (name, config) in configs {
+            if config.disabled {
+                continue;
+            }
+
+            if config.key.is_empty() {
+                warn!(%name, "api key has no secret! skipping");
+                continue;
+            }
+
+            if !config.extra.is_empty() {
+                warn!(%name, extra=?config.extra.keys(), "unknown ApiKeyConfig fields!");
+            }
+
+            let key = match old.get(&config.key) {
+                Some(x) if *x.name == *name && x.config == config => x.clone(),
+                _ => Arc::new(ApiKey::new(name.into(), config)),
+            };
+
+            if let Some(duplicate) = new.insert(key.config.key.clone(), key) {
+                warn!(name=%duplicate.name, "api key secret is used more than once! only one name will be used");
+            }
+        }
+
+        let changed = new.len() != old.len()
+            || new
+                .iter()
+                .any(|(k, v)| !old.get(k).is_some_and(|x| Arc::ptr_eq(x, v)));
+
+        self.by_secret.store(Arc::new(new));
+
+        if changed {
+            info!(num_keys = self.len(), "api keys changed");
+            self.changed.send_replace(());
+        }
+    }
+
+    /// the keys from the config section and the optional api_keys_path file. the file wins if a name is in both
+    pub async fn load(top_config: &TopConfig) -> anyhow::Result<HashMap<String, ApiKeyConfig>> {
+        let mut configs = top_config.api_keys.clone();
+
+        if let Some(path) = top_config.app.api_keys_path.as_ref() {
+            configs.extend(load_api_keys_file(path).await?);
+        }
+
+        Ok(configs)
+    }
+}
+
+async fn load_api_keys_file(path: &Path) -> anyhow::Result<HashMap<String, ApiKeyConfig>> {
+    let x = tokio::fs::read_to_string(path).await?;
+
+    let x = shellexpand::env(&x)?;
+
+    Ok(toml::from_str(&x)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiKeys;
+    use crate::config::ApiKeyConfig;
+    use crate::errors::Web3ProxyError;
+    use crate::frontend::rpc_proxy_ws::ProxyMode;
+    use hashbrown::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::advance;
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_api_key_rules() {
+        let keys = ApiKeys::default();
+
+        let config = ApiKeyConfig {
+            allowed_proxy_modes: vec!["best".into()],
+            denied_methods: vec!["eth_sendRawTransaction".into()],
+            key: "secret".into(),
+            max_concurrent_requests: Some(1),
+            requests_per_second: Some(3),
+            ..Default::default()
+        };
+
+        keys.apply(HashMap::from([
+            ("a".to_string(), config.clone()),
+            (
+                "disabled".to_string(),
+                ApiKeyConfig {
+                    disabled: true,
+                    key: "other".into(),
+                    ..Default::default()
+                },
+            ),
+        ]));
+
+        assert_eq!(keys.len(), 1);
+        assert!(keys.get("other").is_none());
+
+        let key = keys.get("secret").unwrap();
+        assert_eq!(&*key.name, "a");
+
+        key.check_proxy_mode(ProxyMode::Best).unwrap();
+        assert!(key.check_proxy_mode(ProxyMode::Versus).is_err());
+
+        assert!(matches!(
+            key.authorize(["eth_chainId", "eth_sendRawTransaction"]),
+            Err(Web3ProxyError::AccessDenied(_))
+        ));
+
+        let permit = key.authorize(["eth_chainId", "eth_blockNumber"]).unwrap();
+
+        // only one request at a time
+        assert!(matches!(
+            key.authorize(["eth_chainId"]),
+            Err(Web3ProxyError::RateLimited(Some(_)))
+        ));
+
+        drop(permit);
+
+        // 2 of 3 tokens were used
+        let permit = key.authorize(["eth_chainId"]).unwrap();
+        drop(permit);
+        assert!(matches!(
+            key.authorize(["eth_chainId"]),
+            Err(Web3ProxyError::RateLimited(Some(_)))
+        ));
+
+        // a batch bigger than the bucket is refused, even with a full bucket
+        advance(Duration::from_secs(10)).await;
+        assert!(matches!(
+            key.authorize(["eth_chainId"; 4]),
+            Err(Web3ProxyError::RateLimited(None))
+        ));
+        key.authorize(["eth_chainId"; 3]).unwrap();
+
+        let mut changed = keys.subscribe();
+        changed.mark_unchanged();
+
+        // unchanged keys keep their state
+        keys.apply(HashMap::from([("a".to_string(), config)]));
+        assert!(Arc::ptr_eq(&key, &keys.get("secret").unwrap()));
+        assert!(!changed.has_changed().unwrap());
+
+        // removed keys are gone for anyone holding on to them
+        keys.apply(HashMap::new());
+        assert!(keys.current(&key).is_none());
+        assert!(changed.has_changed().unwrap());
+    }
+
+    #[test]
+    fn test_admin_keys_are_separate() {
+        let keys = ApiKeys::default();
+
+        keys.apply(HashMap::from([
+            (
+                "ops".to_string(),
+                ApiKeyConfig {
+                    admin: true,
+                    key: "admin-secret".into(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "a".to_string(),
+                ApiKeyConfig {
+                    key: "secret".into(),
+                    ..Default::default()
+                },
+            ),
+        ]));
+
+        assert!(keys.get("admin-secret").is_none());
+        assert!(keys.get_admin("admin-secret").is_some());
+
+        assert!(keys.get("secret").is_some());
+        assert!(keys.get_admin("secret").is_none());
+    }
+}
@@ -0,0 +1,655 @@
+//! Server-side filters for clients that poll for changes (eth_newFilter, eth_getFilterChanges, etc.)
+//!
+//! The filters live in the proxy instead of on a backend so that they keep working when a backend goes away.
+
+use super::App;
+use crate::errors::Web3ProxyResult;
+use crate::jsonrpc::{
+    self, JsonRpcErrorData, LooseId, ResponseData, SingleRequest, ValidatedRequest,
+};
+use crate::rpcs::blockchain::{BlockCursor, BlockHeader};
+use alloy::primitives::{TxHash, B256, U128};
+use alloy::rpc::types::{Filter, FilterSet, Log};
+use futures::future::join_all;
+use hashbrown::HashMap;
+use nanorand::Rng;
+use parking_lot::Mutex;
+use sonic_rs::{json, JsonValueTrait, OwnedLazyValue};
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, Instant};
+use tracing::{trace, warn};
+
+/// filters that are not polled often enough drop their oldest changes
+/// TODO: config for this
+const MAX_FILTER_CHANGES: usize = 10_000;
+
+/// how many recent blocks to remember. a reorg deeper than this uninstalls the block and log filters
+const MAX_REORG_DEPTH: usize = 64;
+
+pub type FilterId = U128;
+
+#[derive(Debug)]
+enum FilterChanges {
+    Blocks(VecDeque<B256>),
+    Logs(Box<Filter>, VecDeque<Log>),
+    PendingTransactions(VecDeque<TxHash>),
+}
+
+impl FilterChanges {
+    fn take(&mut self) -> sonic_rs::Value {
+        match self {
+            Self::Blocks(x) => json!(x.drain(..).collect::<Vec<_>>()),
+            Self::Logs(_, x) => json!(x.drain(..).collect::<Vec<_>>()),
+            Self::PendingTransactions(x) => json!(x.drain(..).collect::<Vec<_>>()),
+        }
+    }
+}
+
+fn push_capped<T>(changes: &mut VecDeque<T>, item: T) {
+    if changes.len() >= MAX_FILTER_CHANGES {
+        changes.pop_front();
+    }
+    changes.push_back(item);
+}
+
+/// an empty set matches everything. so does a union with one
+fn union<T: Clone + Eq + Hash>(a: &FilterSet<T>, b: &FilterSet<T>) -> FilterSet<T> {
+    if a.is_empty() || b.is_empty() {
+        FilterSet::default()
+    } else {
+        a.iter().chain(b.iter()).cloned().collect()
+    }
+}
+
+#[derive(Debug)]
+struct ServerFilter {
+    changes: FilterChanges,
+    client_ip: Option<IpAddr>,
+    last_poll: Instant,
+}
+
+/// All of the filters installed by clients
+#[derive(Debug)]
+pub struct Filters {
+    by_id: Mutex<HashMap<FilterId, ServerFilter>>,
+    /// each ip can only have this many filters
+    max_per_client: usize,
+    /// filters that haven't been polled in this long are uninstalled
+    timeout: Duration,
+}
+
+impl Filters {
+    pub fn new(max_per_client: usize, timeout: Duration) -> Self {
+        Self {
+            by_id: Default::default(),
+            max_per_client,
+            timeout,
+        }
+    }
+
+    fn install(
+        &self,
+        changes: FilterChanges,
+        client_ip: Option<IpAddr>,
+    ) -> Result<FilterId, JsonRpcErrorData> {
+        let mut by_id = self.by_id.lock();
+
+        // internal requests don't have an ip. they don't count against anyone
+        if client_ip.is_some() {
+            let count = by_id.values().filter(|x| x.client_ip == client_ip).count();
+
+            if count >= self.max_per_client {
+                return Err(JsonRpcErrorData {
+                    message: format!(
+                        "too many filters ({}). uninstall some or let them expire",
+                        count
+                    )
+                    .into(),
+                    code: -32005,
+                    data: None,
+                });
+            }
+        }
+
+        // random ids so that clients can't guess each other's filters
+        let id = loop {
+            let id = FilterId::from(nanorand::tls_rng().generate::<u128>());
+
+            if !by_id.contains_key(&id) {
+                break id;
+            }
+        };
+
+        by_id.insert(
+            id,
+            ServerFilter {
+                changes,
+                client_ip,
+                last_poll: Instant::now(),
+            },
+        );
+
+        Ok(id)
+    }
+
+    pub fn uninstall(&self, id: &FilterId) -> bool {
+        self.by_id.lock().remove(id).is_some()
+    }
+
+    /// changes since the last poll
+    fn changes(&self, id: &FilterId) -> Option<sonic_rs::Value> {
+        let mut by_id = self.by_id.lock();
+
+        let x = by_id.get_mut(id)?;
+
+        x.last_poll = Instant::now();
+
+        Some(x.changes.take())
+    }
+
+    /// the filter's params. only for log filters
+    fn log_filter(&self, id: &FilterId) -> Option<Filter> {
+        let mut by_id = self.by_id.lock();
+
+        let x = by_id.get_mut(id)?;
+
+        x.last_poll = Instant::now();
+
+        match &x.changes {
+            FilterChanges::Logs(filter, _) => Some(filter.as_ref().clone()),
+            _ => None,
+        }
+    }
+
+    /// one eth_getLogs query that covers the addresses and topics of every log filter. None if there aren't any log filters
+    fn logs_query(&self) -> Option<Filter> {
+        let mut query: Option<Filter> = None;
+
+        for x in self.by_id.lock().values() {
+            let FilterChanges::Logs(filter, _) = &x.changes else {
+                continue;
+            };
+
+            match query.as_mut() {
+                None => {
+                    let mut q = Filter::new();
+                    q.address = filter.address.clone();
+                    q.topics = filter.topics.clone();
+                    query = Some(q);
+                }
+                Some(q) => {
+                    q.address = union(&q.address, &filter.address);
+
+                    for (a, b) in q.topics.iter_mut().zip(filter.topics.iter()) {
+                        *a = union(a, b);
+                    }
+                }
+            }
+        }
+
+        query
+    }
+
+    /// filters that missed changes are uninstalled. the client gets "filter not found" and knows to start over
+    fn uninstall_if(&self, f: impl Fn(&FilterChanges) -> bool) {
+        self.by_id.lock().retain(|id, x| {
+            let keep = !f(&x.changes);
+
+            if !keep {
+                warn!(%id, "filter missed changes. uninstalling");
+            }
+
+            keep
+        });
+    }
+
+    fn push_block_hashes(&self, hashes: &[B256]) {
+        for x in self.by_id.lock().values_mut() {
+            if let FilterChanges::Blocks(changes) = &mut x.changes {
+                for hash in hashes {
+                    push_capped(changes, *hash);
+                }
+            }
+        }
+    }
+
+    fn push_logs(&self, logs: &[Log]) {
+        for x in self.by_id.lock().values_mut() {
+            if let FilterChanges::Logs(filter, changes) = &mut x.changes {
+                for log in logs.iter().filter(|log| filter.rpc_matches(log)) {
+                    push_capped(changes, log.clone());
+                }
+            }
+        }
+    }
+
+    fn push_pending_transaction(&self, txid: TxHash) {
+        for x in self.by_id.lock().values_mut() {
+            if let FilterChanges::PendingTransactions(changes) = &mut x.changes {
+                push_capped(changes, txid);
+            }
+        }
+    }
+
+    fn expire(&self) {
+        let timeout = self.timeout;
+
+        self.by_id.lock().retain(|id, x| {
+            let keep = x.last_poll.elapsed() < timeout;
+
+            if !keep {
+                trace!(%id, "filter expired");
+            }
+
+            keep
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.lock().is_empty()
+    }
+}
+
+impl App {
+    /// eth_newFilter, eth_getFilterChanges, and the rest of the filter methods
+    pub(super) async fn filter_request(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+    ) -> Web3ProxyResult<jsonrpc::SingleResponse> {
+        let params = web3_request.inner.params();
+        let client_ip = web3_request.client_ip;
+
+        let filter_id = || {
+            params
+                .get(0)
+                .and_then(|x| sonic_rs::from_value::<FilterId>(x).ok())
+                .ok_or_else(|| JsonRpcErrorData::from("invalid filter id"))
+        };
+
+        let response_data: ResponseData<Arc<OwnedLazyValue>> = match web3_request.inner.method() {
+            "eth_newBlockFilter" => self
+                .filters
+                .install(FilterChanges::Blocks(Default::default()), client_ip)
+                .map_or_else(Into::into, |id| json!(id).into()),
+            "eth_newPendingTransactionFilter" => self
+                .filters
+                .install(
+                    FilterChanges::PendingTransactions(Default::default()),
+                    client_ip,
+                )
+                .map_or_else(Into::into, |id| json!(id).into()),
+            "eth_newFilter" => {
+                match params
+                    .get(0)
+                    .and_then(|x| sonic_rs::from_value::<Filter>(x).ok())
+                {
+                    None => JsonRpcErrorData::from("invalid filter params").into(),
+                    Some(filter) => self
+                        .filters
+                        .install(
+                            FilterChanges::Logs(Box::new(filter), Default::default()),
+                            client_ip,
+                        )
+                        .map_or_else(Into::into, |id| json!(id).into()),
+                }
+            }
+            "eth_getFilterChanges" => match filter_id() {
+                Err(err) => err.into(),
+                Ok(id) => match self.filters.changes(&id) {
+                    None => JsonRpcErrorData::from("filter not found").into(),
+                    Some(changes) => changes.into(),
+                },
+            },
+            "eth_getFilterLogs" => match filter_id() {
+                Err(err) => err.into(),
+                Ok(id) => match self.filters.log_filter(&id) {
+                    None => JsonRpcErrorData::from("filter not found").into(),
+                    Some(filter) => {
+                        // the backends do the work here. send it through like any other eth_getLogs
+                        let request = SingleRequest::new(
+                            LooseId::Number(1),
+                            "eth_getLogs".into(),
+                            json!([filter]),
+                        )?;
+
+                        let logs_request = ValidatedRequest::new_with_app(
+                            self,
+                            web3_request.proxy_mode(),
+                            None,
+                            request.into(),
+                            web3_request.head_block.clone(),
+                            web3_request.request_id.clone(),
+                            client_ip,
+                            web3_request.api_key.clone(),
+                        )
+                        .await?;
+
+                        let mut response = self
+                            .balanced_rpcs
+                            .try_proxy_connection::<Arc<OwnedLazyValue>>(&logs_request)
+                            .await?;
+
+                        response.set_id(web3_request.id());
+
+                        return Ok(response);
+                    }
+                },
+            },
+            "eth_uninstallFilter" => match filter_id() {
+                Err(err) => err.into(),
+                Ok(id) => json!(self.filters.uninstall(&id)).into(),
+            },
+            method => {
+                return Err(crate::errors::Web3ProxyError::MethodNotFound(
+                    method.to_owned().into(),
+                ));
+            }
+        };
+
+        Ok(jsonrpc::ParsedResponse::from_response_data(response_data, web3_request.id()).into())
+    }
+
+    /// feed new blocks, logs, and pending transactions into the filters
+    pub(super) async fn update_filters(
+        self: Arc<Self>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) -> Web3ProxyResult<()> {
+        let mut head_block_receiver = self.watch_consensus_head_receiver.clone();
+        let mut pending_txid_receiver = self.pending_txid_firehose.subscribe();
+
+        let mut expire_interval = interval((self.filters.timeout / 2).max(Duration::from_secs(1)));
+
+        // the watch channel can skip blocks. the cursor fills them in and notices reorgs
+        let mut cursor = BlockCursor::new(MAX_REORG_DEPTH);
+
+        // the logs that were pushed for recent blocks. if a block is reorged out, its logs are pushed again with removed=true
+        let mut pushed: VecDeque<(B256, Vec<Log>)> = VecDeque::with_capacity(MAX_REORG_DEPTH);
+
+        let head = head_block_receiver.borrow_and_update().clone();
+        if let Some(head) = head {
+            let _ = cursor.advance(&self.balanced_rpcs, head).await;
+        }
+
+        loop {
+            select! {
+                _ = shutdown_receiver.recv() => {
+                    break;
+                }
+                x = head_block_receiver.changed() => {
+                    if x.is_err() {
+                        break;
+                    }
+
+                    let new_head = head_block_receiver.borrow_and_update().clone();
+
+                    if let Some(new_head) = new_head {
+                        self.update_filters_for_head(&mut cursor, &mut pushed, new_head).await;
+                    }
+                }
+                x = pending_txid_receiver.recv() => {
+                    match x {
+                        Ok(txid) => self.filters.push_pending_transaction(txid),
+                        Err(RecvError::Lagged(x)) => {
+                            trace!(lagged=x, "pending transaction filters lagged");
+                        }
+                        Err(RecvError::Closed) => {
+                            break;
+                        }
+                    }
+                }
+                _ = expire_interval.tick() => {
+                    self.filters.expire();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update_filters_for_head(
+        &self,
+        cursor: &mut BlockCursor,
+        pushed: &mut VecDeque<(B256, Vec<Log>)>,
+        new_head: BlockHeader,
+    ) {
+        if self.filters.is_empty() {
+            // nobody is waiting on the blocks in between. start over at the new head
+            *cursor = BlockCursor::new(MAX_REORG_DEPTH);
+            pushed.clear();
+        }
+
+        let (removed_blocks, new_blocks) =
+            match cursor.advance(&self.balanced_rpcs, new_head.clone()).await {
+                Ok(x) => x,
+                Err(err) => {
+                    // skipping blocks would silently lose changes
+                    warn!(?err, "filters are unable to fill a gap");
+                    self.filters
+                        .uninstall_if(|x| !matches!(x, FilterChanges::PendingTransactions(_)));
+
+                    *cursor = BlockCursor::new(MAX_REORG_DEPTH);
+                    pushed.clear();
+                    let _ = cursor.advance(&self.balanced_rpcs, new_head).await;
+                    return;
+                }
+            };
+
+        if new_blocks.is_empty() {
+            return;
+        }
+
+        // block filters get the new branch again after a reorg (like geth)
+        let hashes: Vec<_> = new_blocks.iter().map(|x| *x.hash()).collect();
+
+        self.filters.push_block_hashes(&hashes);
+
+        for removed_block in removed_blocks {
+            let Some(i) = pushed.iter().position(|(x, _)| x == removed_block.hash()) else {
+                continue;
+            };
+
+            let (_, mut logs) = pushed.remove(i).expect("index was just found");
+
+            for log in logs.iter_mut() {
+                log.removed = true;
+            }
+
+            self.filters.push_logs(&logs);
+        }
+
+        let Some(query) = self.filters.logs_query() else {
+            pushed.clear();
+            return;
+        };
+
+        // query by hash so that we get logs for the blocks we just told the block filters about
+        let params: Vec<_> = hashes
+            .iter()
+            .map(|hash| [query.clone().at_block_hash(*hash)])
+            .collect();
+
+        let logs = join_all(params.iter().map(|params| {
+            self.balanced_rpcs.internal_request::<_, Vec<Log>>(
+                "eth_getLogs".into(),
+                params,
+                Some(Duration::from_secs(30)),
+            )
+        }))
+        .await;
+
+        let mut new_logs = Vec::with_capacity(hashes.len());
+
+        for (hash, logs) in hashes.into_iter().zip(logs) {
+            match logs {
+                Ok(logs) => new_logs.push((hash, logs)),
+                Err(err) => {
+                    // internal_request already tried the other rpcs. skipping the block would lose its logs
+                    warn!(?err, %hash, "unable to fetch logs for filters");
+                    self.filters
+                        .uninstall_if(|x| matches!(x, FilterChanges::Logs(..)));
+                    pushed.clear();
+                    return;
+                }
+            }
+        }
+
+        for (hash, logs) in new_logs {
+            self.filters.push_logs(&logs);
+
+            if pushed.len() >= MAX_REORG_DEPTH {
+                pushed.pop_front();
+            }
+
+            pushed.push_back((hash, logs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilterChanges, Filters};
+    use crate::test_utils::TestFrontend;
+    use alloy::primitives::{Address, B256};
+    use alloy::rpc::types::Filter;
+    use sonic_rs::{json, JsonValueTrait, Value};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn block_filters_and_caps() {
+        let filters = Filters::new(2, Duration::from_secs(60));
+
+        let client = Some(IpAddr::from([127, 0, 0, 1]));
+
+        let a = filters
+            .install(FilterChanges::Blocks(Default::default()), client)
+            .unwrap();
+        let b = filters
+            .install(
+                FilterChanges::PendingTransactions(Default::default()),
+                client,
+            )
+            .unwrap();
+        assert_ne!(a, b);
+
+        // per client cap
+        assert!(filters
+            .install(FilterChanges::Blocks(Default::default()), client)
+            .is_err());
+        assert!(filters
+            .install(
+                FilterChanges::Blocks(Default::default()),
+                Some(IpAddr::from([127, 0, 0, 2]))
+            )
+            .is_ok());
+
+        let hash = B256::with_last_byte(1);
+        filters.push_block_hashes(&[hash]);
+
+        assert_eq!(filters.changes(&a), Some(json!([hash])));
+        assert_eq!(filters.changes(&a), Some(json!([])));
+        assert_eq!(filters.changes(&b), Some(json!([])));
+
+        assert!(filters.uninstall(&a));
+        assert!(!filters.uninstall(&a));
+        assert_eq!(filters.changes(&a), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn filters_expire() {
+        let filters = Filters::new(10, Duration::from_secs(60));
+
+        let a = filters
+            .install(FilterChanges::Blocks(Default::default()), None)
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        filters.expire();
+        assert!(filters.changes(&a).is_some());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        filters.expire();
+        assert!(filters.changes(&a).is_none());
+    }
+
+    #[test]
+    fn one_logs_query_for_every_filter() {
+        let filters = Filters::new(10, Duration::from_secs(60));
+
+        assert!(filters.logs_query().is_none());
+
+        let a = Address::with_last_byte(1);
+        let b = Address::with_last_byte(2);
+        let topic = B256::with_last_byte(3);
+
+        let logs = |filter: Filter| FilterChanges::Logs(Box::new(filter), Default::default());
+
+        filters
+            .install(logs(Filter::new().address(a).event_signature(topic)), None)
+            .unwrap();
+        filters
+            .install(logs(Filter::new().address(b).event_signature(topic)), None)
+            .unwrap();
+
+        let query = filters.logs_query().unwrap();
+        assert_eq!(query.address.len(), 2);
+        assert!(query.address.contains(&a) && query.address.contains(&b));
+        assert!(query.topics[0].contains(&topic));
+
+        // a filter without topics needs every topic
+        filters
+            .install(logs(Filter::new().address(a)), None)
+            .unwrap();
+
+        let query = filters.logs_query().unwrap();
+        assert_eq!(query.address.len(), 2);
+        assert!(query.topics[0].is_empty());
+
+        filters.uninstall_if(|x| matches!(x, FilterChanges::Logs(..)));
+        assert!(filters.logs_query().is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn spoofed_forwarded_for_shares_the_cap() {
+        let x = TestFrontend::spawn(
+            r#"
+            [app]
+            chain_id = 31337
+            max_filters_per_client = 1
+
+            [balanced_rpcs]
+            "#,
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        let new_filter = |forwarded_for: &str| {
+            let request = client
+                .post(x.http_url("/"))
+                .header("x-forwarded-for", forwarded_for)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(r#"{"jsonrpc":"2.0","id":1,"method":"eth_newBlockFilter","params":[]}"#);
+
+            async move {
+                let body = request.send().await.unwrap().text().await.unwrap();
+                sonic_rs::from_str::<Value>(&body).unwrap()
+            }
+        };
+
+        assert!(new_filter("1.2.3.4").await["result"].is_str());
+
+        // we don't trust any proxies, so the header is ignored and this is the same client
+        assert!(new_filter("5.6.7.8").await["error"].is_object());
+    }
+}
@@ -0,0 +1,531 @@
+//! eth_getLogs ranges that are too wide for one rpc are split into chunks and sent to several rpcs at once
+//!
+//! Each rpc gets chunks that fit its own max range. The max range is configured or lowered for a while whenever an rpc rejects a range.
+
+use super::App;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{self, LooseId, SingleRequest, ValidatedRequest};
+use crate::rpcs::consensus::RpcsForRequest;
+use crate::rpcs::one::Web3Rpc;
+use alloy::primitives::U64;
+use alloy::rpc::types::Log;
+use futures::stream::{self, StreamExt};
+use sonic_rs::{json, JsonValueMutTrait, JsonValueTrait};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::trace;
+
+/// how many chunks each rpc works on at once
+/// TODO: config for this?
+const CHUNKS_PER_RPC: usize = 2;
+
+/// the most eth_getLogs requests sent for one client request. retries count too
+/// TODO: config for this?
+const MAX_CHUNKS_PER_REQUEST: usize = 1_000;
+
+/// errors that rpcs use for a range that is too wide. lowercase.
+/// rate limits and pruned history also mention ranges and limits, so only these phrases count
+const RANGE_ERRORS: &[&str] = &[
+    "block range greater than",
+    "block range is too wide",
+    "block range limit exceeded",
+    "block range too large",
+    "eth_getlogs is limited to a",
+    "exceed maximum block range",
+    // alchemy
+    "log response size exceeded",
+    "query exceeds max block range",
+    // infura. their rate limits have the same code (-32005), so the message is what matters
+    "query returned more than",
+];
+
+/// blocks `from..=to` of the client's range
+#[derive(Debug)]
+struct Chunk {
+    from: u64,
+    to: u64,
+    /// rpcs that already errored on these blocks
+    failed: Vec<Arc<Web3Rpc>>,
+}
+
+impl Chunk {
+    fn num_blocks(&self) -> u64 {
+        self.to - self.from + 1
+    }
+}
+
+impl App {
+    /// eth_getLogs with `get_logs_split` on. None if the range fits every rpc and the request should be proxied like normal
+    pub(super) async fn try_split_get_logs(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+    ) -> Web3ProxyResult<Option<jsonrpc::SingleResponse>> {
+        if !self.config.load().get_logs_split || web3_request.inner.method() != "eth_getLogs" {
+            return Ok(None);
+        }
+
+        // blockHash requests don't have a range
+        let (Some(from), Some(to)) = (
+            web3_request.request_blocks.from_block(),
+            web3_request.request_blocks.to_block(),
+        ) else {
+            return Ok(None);
+        };
+
+        let mut pending = vec![Chunk {
+            from: from.num().to(),
+            to: to.num().to(),
+            failed: vec![],
+        }];
+
+        let rpcs = self
+            .balanced_rpcs
+            .try_rpcs_for_request(web3_request)
+            .await?;
+
+        if rpcs
+            .inner()
+            .iter()
+            .all(|rpc| self.chunk_size(rpc) >= pending[0].num_blocks())
+        {
+            return Ok(None);
+        }
+
+        // the params were already cleaned up by ValidatedRequest. only the range changes
+        let filter = web3_request
+            .inner
+            .params()
+            .get(0)
+            .cloned()
+            .ok_or_else(|| Web3ProxyError::BadRequest("invalid format. no params".into()))?;
+        let filter = &filter;
+
+        let max_parallel = (rpcs.inner().len() + rpcs.outer().len()).max(1) * CHUNKS_PER_RPC;
+
+        let mut logs = BTreeMap::new();
+        let mut next_rpc = 0;
+        let mut last_error = None;
+        let mut num_chunks = 0;
+
+        while !pending.is_empty() {
+            // cut the chunks to fit whichever rpc gets them
+            let mut requests = vec![];
+            for mut chunk in pending.drain(..) {
+                loop {
+                    let Some(rpc) = pick_rpc(&rpcs, &chunk.failed, &mut next_rpc) else {
+                        // every rpc failed on these blocks
+                        return Err(last_error.unwrap_or(Web3ProxyError::NoServersSynced));
+                    };
+
+                    let to = chunk
+                        .to
+                        .min(chunk.from.saturating_add(self.chunk_size(&rpc) - 1));
+
+                    let piece = Chunk {
+                        from: chunk.from,
+                        to,
+                        failed: chunk.failed.clone(),
+                    };
+
+                    requests.push((rpc, piece));
+
+                    if to == chunk.to {
+                        break;
+                    }
+                    chunk.from = to + 1;
+                }
+            }
+
+            trace!(num_chunks=%requests.len(), "splitting eth_getLogs");
+
+            num_chunks += requests.len();
+
+            if num_chunks > MAX_CHUNKS_PER_REQUEST {
+                return Err(Web3ProxyError::BadRequest(
+                    format!(
+                        "eth_getLogs range needs more than {} requests. use a smaller range",
+                        MAX_CHUNKS_PER_REQUEST
+                    )
+                    .into(),
+                ));
+            }
+
+            let mut results = stream::iter(requests)
+                .map(|(rpc, chunk)| async move {
+                    let result = self
+                        .get_logs_chunk(web3_request, filter, &rpc, &chunk)
+                        .await;
+                    (rpc, chunk, result)
+                })
+                .buffer_unordered(max_parallel);
+
+            while let Some((rpc, mut chunk, result)) = results.next().await {
+                match result {
+                    Ok(x) => {
+                        logs.insert(chunk.from, x);
+                    }
+                    Err(err) => {
+                        if let Some(range) = rejected_range(&err, chunk.num_blocks()) {
+                            // the same rpc can try again with smaller chunks
+                            rpc.lower_max_get_logs_range(range);
+                        } else {
+                            trace!(%rpc, ?err, from=chunk.from, to=chunk.to, "eth_getLogs chunk failed");
+                            chunk.failed.push(rpc);
+                        }
+
+                        last_error = Some(err);
+                        pending.push(chunk);
+                    }
+                }
+            }
+        }
+
+        // logs inside each chunk are already in order
+        let logs: Vec<Log> = logs.into_values().flatten().collect();
+
+        Ok(Some(
+            jsonrpc::ParsedResponse::from_value(json!(logs), web3_request.id()).into(),
+        ))
+    }
+
+    /// the number of blocks to send the rpc at once
+    fn chunk_size(&self, rpc: &Web3Rpc) -> u64 {
+        rpc.max_get_logs_range()
+            .unwrap_or(self.config.load().get_logs_chunk_size)
+            .max(1)
+    }
+
+    async fn get_logs_chunk(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+        filter: &sonic_rs::Value,
+        rpc: &Arc<Web3Rpc>,
+        chunk: &Chunk,
+    ) -> Web3ProxyResult<Vec<Log>> {
+        let mut filter = filter.clone();
+
+        if let Some(x) = filter.as_object_mut() {
+            x.insert("fromBlock", json!(U64::from(chunk.from)));
+            x.insert("toBlock", json!(U64::from(chunk.to)));
+        }
+
+        let request =
+            SingleRequest::new(LooseId::Number(1), "eth_getLogs".into(), json!([filter]))?;
+
+        let chunk_request = ValidatedRequest::new_with_app(
+            self,
+            web3_request.proxy_mode(),
+            None,
+            request.into(),
+            web3_request.head_block.clone(),
+            web3_request.request_id.clone(),
+            web3_request.client_ip,
+            web3_request.api_key.clone(),
+        )
+        .await?;
+
+        rpc.authorized_request(&chunk_request, None, false).await
+    }
+}
+
+/// round robin over the synced rpcs. the other rpcs are only used once all the synced rpcs have failed
+fn pick_rpc(
+    rpcs: &RpcsForRequest,
+    failed: &[Arc<Web3Rpc>],
+    next_rpc: &mut usize,
+) -> Option<Arc<Web3Rpc>> {
+    for tier in [rpcs.inner(), rpcs.outer()] {
+        let available: Vec<_> = tier.iter().filter(|x| !failed.contains(x)).collect();
+
+        if !available.is_empty() {
+            let rpc = available[*next_rpc % available.len()];
+            *next_rpc += 1;
+            return Some(rpc.clone());
+        }
+    }
+
+    None
+}
+
+/// rpcs say a range is too wide in lots of different ways. returns a smaller range to use, or None for any other error
+fn rejected_range(err: &Web3ProxyError, num_blocks: u64) -> Option<u64> {
+    let Web3ProxyError::JsonRpcErrorData(err) = err else {
+        return None;
+    };
+
+    if num_blocks <= 1 {
+        return None;
+    }
+
+    let message = err.message.to_ascii_lowercase();
+
+    if !RANGE_ERRORS.iter().any(|x| message.contains(x)) {
+        return None;
+    }
+
+    // "more than 10000 results" is a count of logs, not blocks
+    let stated = if message.contains("result") {
+        None
+    } else {
+        first_number(&message)
+    };
+
+    let range = stated
+        .filter(|x| *x < num_blocks)
+        .unwrap_or(num_blocks / 2)
+        .max(1);
+
+    Some(range)
+}
+
+/// the first decimal number in an error message. "10,000" and "2k" work. hex is skipped
+fn first_number(message: &str) -> Option<u64> {
+    let message = message.replace([',', '_'], "");
+
+    message
+        .split(|x: char| !x.is_ascii_alphanumeric())
+        .find_map(|word| {
+            if let Some(x) = word.strip_suffix('k') {
+                x.parse::<u64>().ok().map(|x| x * 1_000)
+            } else {
+                word.parse().ok()
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_number, rejected_range};
+    use crate::errors::Web3ProxyError;
+    use crate::frontend::rpc_proxy_ws::ProxyMode;
+    use crate::jsonrpc::{JsonRpcErrorData, LooseId, SingleRequest, ValidatedRequest};
+    use crate::rpcs::blockchain::BlockHeader;
+    use crate::rpcs::consensus::RankedRpcs;
+    use crate::rpcs::one::Web3Rpc;
+    use crate::test_utils::stub_rpc::{stub_error, StubRpc};
+    use crate::test_utils::TestFrontend;
+    use alloy::rpc::types::{Block, Log};
+    use parking_lot::Mutex;
+    use sonic_rs::{json, JsonValueTrait, Value};
+    use std::sync::Arc;
+    use tokio::time::Duration;
+
+    fn rpc_error(message: &str) -> Web3ProxyError {
+        Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData::from(message.to_string()))
+    }
+
+    #[test]
+    fn test_first_number() {
+        assert_eq!(first_number("max block range 5000"), Some(5_000));
+        assert_eq!(first_number("limited to a 10,000 range"), Some(10_000));
+        assert_eq!(first_number("up to a 2k block range"), Some(2_000));
+        assert_eq!(first_number("try [0x1, 0x2]"), None);
+    }
+
+    #[test]
+    fn test_rejected_range() {
+        assert_eq!(
+            rejected_range(&rpc_error("exceed maximum block range: 5000"), 10_000),
+            Some(5_000)
+        );
+        assert_eq!(
+            rejected_range(
+                &rpc_error("Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range"),
+                10_000
+            ),
+            Some(2_000)
+        );
+
+        // a count of logs. halve the blocks instead
+        assert_eq!(
+            rejected_range(
+                &rpc_error(
+                    "query returned more than 10000 results. Try with this block range [0x1, 0x2]"
+                ),
+                10_000
+            ),
+            Some(5_000)
+        );
+
+        // a stated range that is no help
+        assert_eq!(
+            rejected_range(&rpc_error("block range too large, max 20000"), 10_000),
+            Some(5_000)
+        );
+
+        // one block can't be split
+        assert_eq!(rejected_range(&rpc_error("block range too large"), 1), None);
+
+        // other errors are retried on another rpc
+        assert_eq!(
+            rejected_range(&rpc_error("execution reverted"), 10_000),
+            None
+        );
+        assert_eq!(
+            rejected_range(&Web3ProxyError::NoServersSynced, 10_000),
+            None
+        );
+
+        // rate limits and pruned history are not about the range
+        for x in [
+            "too many requests",
+            "daily request count exceeded, request rate limited",
+            "rate limit exceeded",
+            "your plan's compute units limit exceeded",
+            "missing trie node. the block range is older than the pruning limit",
+            "history is not available for this range",
+        ] {
+            assert_eq!(rejected_range(&rpc_error(x), 10_000), None, "{}", x);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lower_max_get_logs_range() {
+        let rpc = Web3Rpc::default();
+
+        assert_eq!(rpc.max_get_logs_range(), None);
+
+        rpc.lower_max_get_logs_range(5_000);
+        assert_eq!(rpc.max_get_logs_range(), Some(5_000));
+
+        // never raised
+        rpc.lower_max_get_logs_range(10_000);
+        assert_eq!(rpc.max_get_logs_range(), Some(5_000));
+
+        rpc.lower_max_get_logs_range(0);
+        assert_eq!(rpc.max_get_logs_range(), Some(1));
+
+        // learned ranges expire
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        assert_eq!(rpc.max_get_logs_range(), None);
+    }
+
+    /// the `fromBlock` and `toBlock` of an eth_getLogs request
+    fn log_range(params: &Value) -> (u64, u64) {
+        let num = |key: &str| {
+            let x = params[0][key].as_str().unwrap();
+            u64::from_str_radix(x.trim_start_matches("0x"), 16).unwrap()
+        };
+
+        (num("fromBlock"), num("toBlock"))
+    }
+
+    /// one log for every block in the range
+    fn logs_for_range(from: u64, to: u64) -> Value {
+        let logs: Vec<_> = (from..=to)
+            .map(|x| {
+                json!({
+                    "address": "0x0000000000000000000000000000000000000000",
+                    "topics": [],
+                    "data": "0x",
+                    "blockHash": null,
+                    "blockNumber": format!("{:#x}", x),
+                    "transactionHash": null,
+                    "transactionIndex": null,
+                    "logIndex": null,
+                    "removed": false,
+                })
+            })
+            .collect();
+
+        json!(logs)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_split_get_logs() {
+        let x = TestFrontend::spawn(
+            r#"
+            [app]
+            chain_id = 31337
+            get_logs_split = true
+            get_logs_chunk_size = 10
+
+            [balanced_rpcs]
+            "#,
+        )
+        .await;
+
+        // the later chunks are answered first, so the merge has to put them back in order
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let good = {
+            let ranges = ranges.clone();
+
+            StubRpc::spawn(move |_, params| {
+                let (from, to) = log_range(params);
+
+                ranges.lock().push((from, to));
+
+                let delay = Duration::from_millis(10 * (30 - from));
+
+                (delay, Ok(logs_for_range(from, to)))
+            })
+            .await
+        };
+
+        let broken = StubRpc::constant(Duration::ZERO, Err(stub_error("header not found"))).await;
+
+        let rpcs = vec![
+            Web3Rpc::for_tests("good", &good.url).await,
+            Web3Rpc::for_tests("broken", &broken.url).await,
+        ];
+
+        let mut head_block: Block = Block::default();
+        head_block.header.inner.number = 100;
+        let head_block = BlockHeader::new(Arc::new(head_block));
+
+        x.app
+            .balanced_rpcs
+            .watch_ranked_rpcs
+            .send_replace(Some(Arc::new(RankedRpcs::from_rpcs(
+                rpcs,
+                Some(head_block.clone()),
+                false,
+            ))));
+
+        let request = SingleRequest::new(
+            LooseId::Number(1),
+            "eth_getLogs".into(),
+            json!([{"fromBlock": "0x0", "toBlock": "0x1d"}]),
+        )
+        .unwrap();
+
+        let web3_request = ValidatedRequest::new_with_app(
+            &x.app,
+            ProxyMode::Best,
+            Some(Duration::from_secs(10)),
+            request.into(),
+            Some(head_block),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let response = x
+            .app
+            .try_split_get_logs(&web3_request)
+            .await
+            .unwrap()
+            .expect("the range is wider than the chunk size")
+            .parsed()
+            .await
+            .unwrap();
+
+        let logs: Vec<Log> =
+            sonic_rs::from_str(&sonic_rs::to_string(response.result().unwrap()).unwrap()).unwrap();
+
+        let block_numbers: Vec<_> = logs.iter().map(|x| x.block_number.unwrap()).collect();
+
+        let expected: Vec<_> = (0..30).collect();
+
+        assert_eq!(block_numbers, expected);
+
+        // round robin sends the broken rpc at least one chunk. every chunk is retried on the good rpc
+        assert!(broken.requests() >= 1);
+
+        let mut ranges = ranges.lock().clone();
+        ranges.sort();
+        assert_eq!(ranges, [(0, 9), (10, 19), (20, 29)]);
+    }
+}
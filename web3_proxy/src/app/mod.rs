@@ -1,6 +1,19 @@
+mod filters;
+mod logs;
+mod reload;
+mod routes;
 mod ws;
 
-use crate::config::{AppConfig, TopConfig};
+pub use filters::Filters;
+pub use reload::{ConfigReloadStatus, ReloadTrigger};
+pub use ws::SubscriptionGate;
+
+use crate::access_log::AccessLog;
+use crate::api_keys::{ApiKey, ApiKeys};
+use crate::audit::AuditLog;
+use crate::capture::Capture;
+use crate::config::{config_diff, AppConfig, RouteConfig, RpcGroup, TopConfig};
+use crate::connections::Connections;
 use crate::errors::{RequestForError, Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::globals::APP;
@@ -8,29 +21,36 @@ use crate::jsonrpc::{
     self, JsonRpcErrorData, JsonRpcRequestEnum, ResponseData, SingleRequest, SingleResponse,
     ValidatedRequest,
 };
+use crate::metrics::AppMetrics;
+use crate::rate_limit::IpRateLimits;
+use crate::response_cache::JsonRpcQueryCacheKey;
 use crate::rpcs::blockchain::BlockHeader;
 use crate::rpcs::consensus::RankedRpcs;
-use crate::rpcs::many::Web3Rpcs;
+use crate::rpcs::many::{PendingRpcs, VersusReport, Web3Rpcs};
 use crate::rpcs::one::Web3Rpc;
 use alloy::consensus::{Transaction as _, TxEnvelope};
 use alloy::eips::Decodable2718;
 use alloy::primitives::{keccak256, Address, Bytes, TxHash, B256, U256, U64};
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use axum::http::StatusCode;
 use deduped_broadcast::DedupedBroadcaster;
 use futures::future::join_all;
 use futures::stream::FuturesUnordered;
 use hashbrown::HashSet;
+use parking_lot::RwLock;
 use sonic_rs::{json, JsonContainerTrait, JsonValueTrait, OwnedLazyValue};
 use std::fmt;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::atomic::AtomicU16;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{broadcast, watch, Semaphore};
 use tokio::task::{yield_now, JoinHandle};
-use tokio::time::{sleep, sleep_until, timeout_at, Instant};
+use tokio::time::{interval, sleep, sleep_until, timeout_at, Instant, MissedTickBehavior};
 use tokio::{pin, select};
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
 // TODO: make this customizable?
 // TODO: include GIT_REF in here. i had trouble getting https://docs.rs/vergen/latest/vergen/ to work with a workspace. also .git is in .dockerignore
@@ -47,14 +67,31 @@ pub type Web3ProxyJoinHandle<T> = JoinHandle<Web3ProxyResult<T>>;
 /// The application
 // TODO: i'm sure this is more arcs than necessary, but spawning futures makes references hard
 pub struct App {
+    /// one json line per request. None if `access_log` is not set
+    pub access_log: Option<AccessLog>,
+    /// changes made with the admin api
+    pub admin_audit: AuditLog,
+    /// sampled requests for `web3_proxy_cli replay`. None if `capture_path` is not set
+    pub capture: Option<Capture>,
+    /// keys for authenticated access. these are replaced when the config changes
+    pub api_keys: ApiKeys,
     /// Send requests to the best server available
     pub balanced_rpcs: Arc<Web3Rpcs>,
     /// Send 4337 Abstraction Bundler requests to one of these servers
     pub bundler_4337_rpcs: Arc<Web3Rpcs>,
-    /// application config
-    /// TODO: this will need a large refactor to handle reloads while running. maybe use a watch::Receiver and a task_local?
-    pub config: AppConfig,
+    /// application config. replaced when the config changes. don't hold the guard across an await
+    pub config: ArcSwap<AppConfig>,
+    /// open websockets, subscriptions, and in-flight requests. drained on shutdown
+    pub connections: Arc<Connections>,
+    /// the result of the last config reload. shown at /status/reload
+    pub config_reload: RwLock<ConfigReloadStatus>,
+    /// eth_newFilter and friends. these are fed by watch_consensus_head_receiver and pending_txid_firehose
+    pub filters: Filters,
     pub http_client: Option<reqwest::Client>,
+    /// limits for clients without an api key
+    pub ip_rate_limits: IpRateLimits,
+    /// prometheus counters and histograms for the /metrics endpoint
+    pub metrics: AppMetrics,
     /// rpc clients that subscribe to newHeads use this channel
     /// don't drop this or the sender will stop working
     /// TODO: broadcast channel instead?
@@ -65,6 +102,8 @@ pub struct App {
     pub frontend_port: Arc<AtomicU16>,
     /// Send private requests (like eth_sendRawTransaction) to all these servers
     pub protected_rpcs: Arc<Web3Rpcs>,
+    /// methods that go to other rpcs. these are replaced when the config changes
+    pub routes: ArcSwap<Vec<Arc<RouteConfig>>>,
     /// when the app started
     pub start: Instant,
     /// limit the number of tx subscriptions
@@ -100,6 +139,7 @@ impl App {
         let mut background_shutdown_receiver = shutdown_sender.subscribe();
 
         top_config.clean();
+        top_config.app.validate()?;
 
         let (new_top_config_sender, mut new_top_config_receiver) =
             watch::channel(top_config.clone());
@@ -139,8 +179,10 @@ impl App {
             top_config.app.min_synced_rpcs,
             top_config.app.min_sum_soft_limit,
             "balanced rpcs".into(),
+            Some(RpcGroup::Balanced),
             Some(watch_consensus_head_sender),
             Some(deduped_txid_firehose.clone()),
+            top_config.app.response_cache_max_bytes,
         )
         .await
         .web3_context("spawning balanced rpcs")?;
@@ -155,12 +197,15 @@ impl App {
             0,
             0,
             "protected rpcs".into(),
+            Some(RpcGroup::Private),
             // subscribing to new heads here won't work well. if they are fast, they might be ahead of balanced_rpcs
             // they also often have low rate limits
             // however, they are well connected to miners/validators. so maybe using them as a safety check would be good
             // TODO: but maybe we could include privates in the "backup" tier
             None,
             None,
+            // private rpcs are only used for sending transactions
+            0,
         )
         .await
         .web3_context("spawning private_rpcs")?;
@@ -173,8 +218,10 @@ impl App {
             0,
             0,
             "eip4337 rpcs".into(),
+            Some(RpcGroup::Bundler4337),
             None,
             None,
+            0,
         )
         .await
         .web3_context("spawning bundler_4337_rpcs")?;
@@ -185,15 +232,52 @@ impl App {
 
         let tx_subscriptions = Semaphore::new(1);
 
+        let filters = Filters::new(
+            top_config.app.max_filters_per_client,
+            Duration::from_secs(top_config.app.filter_timeout_seconds),
+        );
+
+        let access_log = match AccessLog::spawn(&top_config.app, shutdown_sender.subscribe())
+            .await
+            .context("starting the access log")?
+        {
+            Some((access_log, access_log_handle)) => {
+                important_background_handles.push(access_log_handle);
+                Some(access_log)
+            }
+            None => None,
+        };
+
+        let capture = match Capture::spawn(&top_config.app, shutdown_sender.subscribe())
+            .await
+            .context("starting the traffic capture")?
+        {
+            Some((capture, capture_handle)) => {
+                important_background_handles.push(capture_handle);
+                Some(capture)
+            }
+            None => None,
+        };
+
         let app = Self {
+            access_log,
+            admin_audit: Default::default(),
+            api_keys: Default::default(),
             balanced_rpcs,
             bundler_4337_rpcs,
-            config: top_config.app.clone(),
+            capture,
+            config: ArcSwap::from_pointee(top_config.app.clone()),
+            config_reload: Default::default(),
+            connections: Default::default(),
+            filters,
             frontend_port: frontend_port.clone(),
             hostname,
             http_client,
+            ip_rate_limits: IpRateLimits::new(&top_config.app),
+            metrics: AppMetrics::new(),
             pending_txid_firehose: deduped_txid_firehose,
             protected_rpcs: private_rpcs,
+            routes: Default::default(),
             start: Instant::now(),
             watch_consensus_head_receiver,
             tx_subscriptions,
@@ -201,6 +285,9 @@ impl App {
 
         let app = Arc::new(app);
 
+        app.api_keys.apply(ApiKeys::load(&top_config).await?);
+        app.apply_routes(&top_config.routes);
+
         if let Err(app) = APP.set(app.clone()) {
             error!(?app, "global APP can only be set once!");
         };
@@ -215,9 +302,11 @@ impl App {
 
                     // TODO: compare new and old here? the sender should be doing that already but maybe its better here
 
-                    if let Err(err) = app.apply_top_config_rpcs(&new_top_config).await {
+                    if let Err(err) = app.apply_top_config(&new_top_config).await {
                         error!(?err, "unable to apply config! Retrying in 10 seconds (or if the config changes)");
 
+                        app.config_reload_failed(None, err.to_string());
+
                         select! {
                             _ = config_watcher_shutdown_receiver.recv() => {
                                 break;
@@ -226,6 +315,8 @@ impl App {
                             _ = new_top_config_receiver.changed() => {}
                         }
                     } else {
+                        app.config_reload_applied();
+
                         // configs applied successfully. wait for configs to change or for the app to exit
                         select! {
                             _ = config_watcher_shutdown_receiver.recv() => {
@@ -246,6 +337,51 @@ impl App {
             important_background_handles.push(config_handle);
         }
 
+        // the api keys section is part of the top config. the api keys file is checked for changes on an interval
+        {
+            let app = app.clone();
+            let mut new_top_config_receiver = new_top_config_sender.subscribe();
+            let mut shutdown_receiver = shutdown_sender.subscribe();
+
+            let api_keys_handle = tokio::spawn(async move {
+                let mut interval = interval(Duration::from_secs(30));
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                // the keys were loaded during spawn
+                interval.tick().await;
+                new_top_config_receiver.borrow_and_update();
+
+                loop {
+                    select! {
+                        _ = shutdown_receiver.recv() => {
+                            break;
+                        }
+                        _ = new_top_config_receiver.changed() => {}
+                        _ = interval.tick() => {}
+                    }
+
+                    let new_top_config = new_top_config_receiver.borrow_and_update().to_owned();
+
+                    match ApiKeys::load(&new_top_config).await {
+                        Ok(x) => app.api_keys.apply(x),
+                        Err(err) => error!(?err, "unable to load api keys! keeping the old keys"),
+                    }
+                }
+
+                Ok(())
+            });
+
+            important_background_handles.push(api_keys_handle);
+        }
+
+        // keep the server-side filters up to date
+        {
+            let filters_handle =
+                tokio::spawn(app.clone().update_filters(shutdown_sender.subscribe()));
+
+            important_background_handles.push(filters_handle);
+        }
+
         if important_background_handles.is_empty() {
             trace!("no important background handles");
 
@@ -269,68 +405,210 @@ impl App {
         })
     }
 
+    /// everything is checked and the new connections are spawned before anything is swapped in. an error leaves the old config running
     pub async fn apply_top_config(&self, new_top_config: &TopConfig) -> Web3ProxyResult<()> {
-        // TODO: update self.config from new_top_config.app (or move it entirely to a global)
-        self.apply_top_config_rpcs(new_top_config).await
-    }
+        let new_config = &new_top_config.app;
+
+        // an invalid app config rejects the whole reload
+        let changed = self.check_app_config(new_config)?;
+
+        let (balanced, protected, bundler_4337) =
+            self.prepare_top_config_rpcs(new_top_config).await?;
+
+        // nothing below here can fail
+        if changed {
+            self.config.store(Arc::new(new_config.clone()));
+        }
+
+        self.apply_routes(&new_top_config.routes);
 
-    async fn apply_top_config_rpcs(&self, new_top_config: &TopConfig) -> Web3ProxyResult<()> {
         info!("applying new config");
 
+        if let Some(x) = balanced {
+            self.balanced_rpcs.commit_server_configs(x).await;
+        }
+        if let Some(x) = protected {
+            self.protected_rpcs.commit_server_configs(x).await;
+        }
+        if let Some(x) = bundler_4337 {
+            self.bundler_4337_rpcs.commit_server_configs(x).await;
+        }
+
+        // after the commit so that the new rpcs get the new max head block age too
+        if changed {
+            self.balanced_rpcs.apply_limits(
+                new_config.max_head_block_lag,
+                new_config.min_synced_rpcs,
+                new_config.min_sum_soft_limit,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// validate the new app config and log what changed. returns false if nothing changed. some settings are only read at startup
+    fn check_app_config(&self, new_config: &AppConfig) -> Web3ProxyResult<bool> {
+        let old_config = self.config.load_full();
+
+        new_config.validate()?;
+
+        if new_config.chain_id != old_config.chain_id {
+            return Err(anyhow::anyhow!(
+                "chain_id cannot change from {} to {} without a restart",
+                old_config.chain_id,
+                new_config.chain_id
+            )
+            .into());
+        }
+
+        let changes = config_diff(&*old_config, new_config);
+
+        for change in changes.iter() {
+            if AppConfig::RESTART_REQUIRED.contains(&change.path.as_str()) {
+                warn!(path=%change.path, old=%change.old, new=%change.new, "app config changed. this needs a restart to take effect");
+            } else {
+                info!(path=%change.path, old=%change.old, new=%change.new, "app config changed");
+            }
+        }
+
+        Ok(!changes.is_empty())
+    }
+
+    /// spawn the connections for every group. if any group fails, all of the new connections are dropped
+    async fn prepare_top_config_rpcs(
+        &self,
+        new_top_config: &TopConfig,
+    ) -> Web3ProxyResult<(
+        Option<PendingRpcs>,
+        Option<PendingRpcs>,
+        Option<PendingRpcs>,
+    )> {
+        let new_config = &new_top_config.app;
+
         let balanced = self
             .balanced_rpcs
-            .apply_server_configs(self, &new_top_config.balanced_rpcs)
+            .prepare_server_configs(
+                self,
+                &new_top_config.balanced_rpcs,
+                new_config.min_synced_rpcs,
+                new_config.min_synced_rpcs,
+                new_config.min_sum_soft_limit,
+            )
             .await
             .web3_context("updating balanced rpcs");
 
         let protected = self
             .protected_rpcs
-            .apply_server_configs(self, &new_top_config.private_rpcs)
+            .prepare_server_configs(
+                self,
+                &new_top_config.private_rpcs,
+                new_config.min_synced_rpcs,
+                self.protected_rpcs.min_head_rpcs(),
+                self.protected_rpcs.min_sum_soft_limit(),
+            )
             .await
             .web3_context("updating private_rpcs");
 
         let bundler_4337 = self
             .bundler_4337_rpcs
-            .apply_server_configs(self, &new_top_config.bundler_4337_rpcs)
+            .prepare_server_configs(
+                self,
+                &new_top_config.bundler_4337_rpcs,
+                new_config.min_synced_rpcs,
+                self.bundler_4337_rpcs.min_head_rpcs(),
+                self.bundler_4337_rpcs.min_sum_soft_limit(),
+            )
             .await
             .web3_context("updating bundler_4337_rpcs");
 
-        // TODO: log all the errors if there are multiple
-        balanced?;
-        protected?;
-        bundler_4337?;
+        match (balanced, protected, bundler_4337) {
+            (Ok(balanced), Ok(protected), Ok(bundler_4337)) => {
+                Ok((balanced, protected, bundler_4337))
+            }
+            (balanced, protected, bundler_4337) => {
+                let mut first_err = None;
+
+                for x in [balanced, protected, bundler_4337] {
+                    match x {
+                        Ok(Some(pending)) => pending.abandon(),
+                        Ok(None) => {}
+                        Err(err) => {
+                            if first_err.is_none() {
+                                first_err = Some(err);
+                            } else {
+                                error!(?err, "also unable to apply the new config");
+                            }
+                        }
+                    }
+                }
 
-        Ok(())
+                Err(first_err.expect("one of the groups failed"))
+            }
+        }
     }
 
     pub fn head_block_receiver(&self) -> watch::Receiver<Option<BlockHeader>> {
         self.watch_consensus_head_receiver.clone()
     }
 
+    /// write the request to the access log and the traffic capture (if they are on). call this after `set_response`
+    pub fn record_access(&self, web3_request: &ValidatedRequest) {
+        if self.access_log.is_none() && self.capture.is_none() {
+            return;
+        }
+
+        let config = self.config.load();
+
+        if let Some(access_log) = self.access_log.as_ref() {
+            access_log.record(web3_request, &config);
+        }
+
+        if let Some(capture) = self.capture.as_ref() {
+            capture.record(web3_request, &config);
+        }
+    }
+
     /// send the request or batch of requests to the approriate RPCs
     pub async fn proxy_web3_rpc(
         self: &Arc<Self>,
         proxy_mode: ProxyMode,
         request: JsonRpcRequestEnum,
         request_id: Option<String>,
-    ) -> Web3ProxyResult<(StatusCode, jsonrpc::Response, Vec<Arc<Web3Rpc>>)> {
+        client_ip: Option<IpAddr>,
+        api_key: Option<Arc<ApiKey>>,
+    ) -> Web3ProxyResult<(
+        StatusCode,
+        jsonrpc::Response,
+        Vec<Arc<Web3Rpc>>,
+        Vec<VersusReport>,
+    )> {
         // trace!(?request, "proxy_web3_rpc");
 
         let response = match request {
             JsonRpcRequestEnum::Single(request) => {
-                let (status_code, response, rpcs) = self
-                    .proxy_request(request, proxy_mode, None, request_id)
+                let (status_code, response, rpcs, versus) = self
+                    .proxy_request(request, proxy_mode, None, request_id, client_ip, api_key)
                     .await;
 
-                (status_code, jsonrpc::Response::Single(response), rpcs)
+                (
+                    status_code,
+                    jsonrpc::Response::Single(response),
+                    rpcs,
+                    versus.into_iter().collect(),
+                )
             }
             JsonRpcRequestEnum::Batch(requests) => {
-                let (responses, rpcs) = self
-                    .proxy_web3_rpc_requests(proxy_mode, requests, request_id)
+                let (responses, rpcs, versus) = self
+                    .proxy_web3_rpc_requests(proxy_mode, requests, request_id, client_ip, api_key)
                     .await?;
 
                 // TODO: real status code. if an error happens, i don't think we are following the spec here
-                (StatusCode::OK, jsonrpc::Response::Batch(responses), rpcs)
+                (
+                    StatusCode::OK,
+                    jsonrpc::Response::Batch(responses),
+                    rpcs,
+                    versus,
+                )
             }
         };
 
@@ -344,11 +622,17 @@ impl App {
         proxy_mode: ProxyMode,
         requests: Vec<SingleRequest>,
         request_id: Option<String>,
-    ) -> Web3ProxyResult<(Vec<jsonrpc::ParsedResponse>, Vec<Arc<Web3Rpc>>)> {
+        client_ip: Option<IpAddr>,
+        api_key: Option<Arc<ApiKey>>,
+    ) -> Web3ProxyResult<(
+        Vec<jsonrpc::ParsedResponse>,
+        Vec<Arc<Web3Rpc>>,
+        Vec<VersusReport>,
+    )> {
         let num_requests = requests.len();
 
         if num_requests == 0 {
-            return Ok((vec![], vec![]));
+            return Ok((vec![], vec![], vec![]));
         }
 
         // get the head block now so that any requests that need it all use the same block
@@ -368,6 +652,8 @@ impl App {
                         proxy_mode,
                         Some(head_block.clone()),
                         request_id.clone(),
+                        client_ip,
+                        api_key.clone(),
                     )
                 })
                 .collect::<Vec<_>>(),
@@ -377,9 +663,12 @@ impl App {
         let mut collected: Vec<jsonrpc::ParsedResponse> = Vec::with_capacity(num_requests);
         let mut collected_rpc_names: HashSet<String> = HashSet::new();
         let mut collected_rpcs: Vec<Arc<Web3Rpc>> = vec![];
+        let mut collected_versus: Vec<VersusReport> = vec![];
         for response in responses {
             // TODO: any way to attach the tried rpcs to the error? it is likely helpful
-            let (_status_code, response, rpcs) = response;
+            let (_status_code, response, rpcs, versus) = response;
+
+            collected_versus.extend(versus);
 
             // TODO: individual error handling
             collected.push(response.parsed().await?);
@@ -395,7 +684,7 @@ impl App {
             // TODO: what should we do with the status code? check the jsonrpc spec
         }
 
-        Ok((collected, collected_rpcs))
+        Ok((collected, collected_rpcs, collected_versus))
     }
 
     /// try to send transactions to the best available rpcs with protected/private mempools
@@ -431,11 +720,12 @@ impl App {
         })?;
 
         if let Some(chain_id) = tx.chain_id() {
-            if self.config.chain_id != chain_id {
+            if self.config.load().chain_id != chain_id {
                 return Err(Web3ProxyError::BadRequest(
                     format!(
                         "unexpected chain_id. {} != {}",
-                        chain_id, self.config.chain_id
+                        chain_id,
+                        self.config.load().chain_id
                     )
                     .into(),
                 ));
@@ -508,13 +798,21 @@ impl App {
     }
 
     /// proxy request with up to 3 tries.
-    async fn proxy_request(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn proxy_request(
         self: &Arc<Self>,
         request: SingleRequest,
         proxy_mode: ProxyMode,
         head_block: Option<BlockHeader>,
         request_id: Option<String>,
-    ) -> (StatusCode, jsonrpc::SingleResponse, Vec<Arc<Web3Rpc>>) {
+        client_ip: Option<IpAddr>,
+        api_key: Option<Arc<ApiKey>>,
+    ) -> (
+        StatusCode,
+        jsonrpc::SingleResponse,
+        Vec<Arc<Web3Rpc>>,
+        Option<VersusReport>,
+    ) {
         // TODO: this clone is only for an error response. refactor to not need it
         let error_id = request.id.clone();
 
@@ -536,6 +834,8 @@ impl App {
             request.into(),
             head_block,
             request_id,
+            client_ip,
+            api_key,
         )
         .await
         {
@@ -546,43 +846,50 @@ impl App {
 
                 let rpcs = vec![];
 
-                return (a, b, rpcs);
+                return (a, b, rpcs, None);
             }
         };
 
         let mut last_success = None;
-        let mut last_error = None;
+        // denied methods are not retried
+        let mut last_error = self
+            .config
+            .load()
+            .check_method(web3_request.inner.method())
+            .err();
 
         let latest_start = sleep_until(Instant::now() + Duration::from_secs(3));
         pin!(latest_start);
 
-        // TODO: how many retries?
-        loop {
-            // TODO: refresh the request here?
+        if last_error.is_none() {
+            // TODO: how many retries?
+            loop {
+                // TODO: refresh the request here?
 
-            // turn some of the Web3ProxyErrors into Ok results
-            match self._proxy_request(&web3_request).await {
-                Ok(response_data) => {
-                    last_success = Some(response_data);
-                    break;
-                }
-                Err(err) => {
-                    last_error = Some(err);
+                // turn some of the Web3ProxyErrors into Ok results
+                match self._proxy_request(&web3_request).await {
+                    Ok(response_data) => {
+                        last_success = Some(response_data);
+                        break;
+                    }
+                    Err(err) => {
+                        last_error = Some(err);
+                    }
                 }
-            }
 
-            select! {
-                _ = ranked_rpcs_recv.changed() => {
-                    // TODO: pass these RankedRpcs to ValidatedRequest::new_with_app
-                    ranked_rpcs_recv.borrow_and_update();
-                }
-                _ = &mut latest_start => {
-                    // do not retry if we've already been trying for 3 seconds
-                    break;
+                select! {
+                    _ = ranked_rpcs_recv.changed() => {
+                        // TODO: pass these RankedRpcs to ValidatedRequest::new_with_app
+                        ranked_rpcs_recv.borrow_and_update();
+                    }
+                    _ = &mut latest_start => {
+                        // do not retry if we've already been trying for 3 seconds
+                        break;
+                    }
                 }
-            }
 
-            // TODO: refresh the request?
+                // TODO: refresh the request?
+            }
         }
 
         let last_response = if let Some(last_success) = last_success {
@@ -625,9 +932,15 @@ impl App {
 
         web3_request.set_response(&response);
 
+        self.metrics
+            .record_request(&web3_request, &self.config.load());
+        self.record_access(&web3_request);
+
         let rpcs = web3_request.backend_rpcs_used();
 
-        (code, response, rpcs)
+        let versus = web3_request.versus_report();
+
+        (code, response, rpcs, versus)
     }
 
     /// Main request logic in a dedicated function so the try operator is easy to use.
@@ -639,91 +952,16 @@ impl App {
         // TODO: serve net_version without querying the backend
         // TODO: don't force OwnedLazyValue
         let response: jsonrpc::SingleResponse = match web3_request.inner.method() {
-            // lots of commands are blocked
-            method @ ("db_getHex"
-            | "db_getString"
-            | "db_putHex"
-            | "db_putString"
-            | "debug_accountRange"
-            | "debug_backtraceAt"
-            | "debug_blockProfile"
-            | "debug_bundler_clearState"
-            | "debug_bundler_dumpMempool"
-            | "debug_bundler_sendBundleNow"
-            | "debug_chaindbCompact"
-            | "debug_chaindbProperty"
-            | "debug_cpuProfile"
-            | "debug_freeOSMemory"
-            | "debug_freezeClient"
-            | "debug_gcStats"
-            | "debug_goTrace"
-            | "debug_memStats"
-            | "debug_mutexProfile"
-            | "debug_setBlockProfileRate"
-            | "debug_setGCPercent"
-            | "debug_setHead"
-            | "debug_setMutexProfileFraction"
-            | "debug_standardTraceBadBlockToFile"
-            | "debug_standardTraceBlockToFile"
-            | "debug_startCPUProfile"
-            | "debug_startGoTrace"
-            | "debug_stopCPUProfile"
-            | "debug_stopGoTrace"
-            | "debug_writeBlockProfile"
-            | "debug_writeMemProfile"
-            | "debug_writeMutexProfile"
-            | "erigon_cacheCheck"
-            | "eth_compileLLL"
-            | "eth_compileSerpent"
-            | "eth_compileSolidity"
-            | "eth_getCompilers"
-            | "eth_sendTransaction"
-            | "eth_sign"
-            | "eth_signTransaction"
-            | "eth_submitHashrate"
-            | "eth_submitWork"
-            | "les_addBalance"
-            | "les_setClientParams"
-            | "les_setDefaultParams"
-            | "miner_setEtherbase"
-            | "miner_setExtra"
-            | "miner_setGasLimit"
-            | "miner_setGasPrice"
-            | "miner_start"
-            | "miner_stop"
-            | "personal_ecRecover"
-            | "personal_importRawKey"
-            | "personal_listAccounts"
-            | "personal_lockAccount"
-            | "personal_newAccount"
-            | "personal_sendTransaction"
-            | "personal_sign"
-            | "personal_unlockAccount"
-            | "shh_addToGroup"
-            | "shh_getFilterChanges"
-            | "shh_getMessages"
-            | "shh_hasIdentity"
-            | "shh_newFilter"
-            | "shh_newGroup"
-            | "shh_newIdentity"
-            | "shh_post"
-            | "shh_uninstallFilter"
-            | "shh_version"
-            | "wallet_getEthereumChains"
-            | "wallet_getSnaps"
-            | "wallet_requestSnaps") => {
+            // TODO: implement these commands
+            method @ "eth_pollSubscriptions" => {
                 return Err(Web3ProxyError::MethodNotFound(method.to_owned().into()));
             }
-            // TODO: implement these commands
-            method @ ("eth_getFilterChanges"
+            "eth_getFilterChanges"
             | "eth_getFilterLogs"
             | "eth_newBlockFilter"
             | "eth_newFilter"
             | "eth_newPendingTransactionFilter"
-            | "eth_pollSubscriptions"
-            | "eth_uninstallFilter") => {
-                return Err(Web3ProxyError::MethodNotFound(method.to_owned().into()));
-            }
+            | "eth_uninstallFilter" => self.filter_request(web3_request).await?,
             "eth_sendUserOperation"
             | "eth_estimateUserOperationGas"
             | "eth_getUserOperationByHash"
@@ -743,7 +981,7 @@ impl App {
                     }
                 }
             }
-            "eth_chainId" => jsonrpc::ParsedResponse::from_value(json!(U64::from(self.config.chain_id)), web3_request.id()).into(),
+            "eth_chainId" => jsonrpc::ParsedResponse::from_value(json!(U64::from(self.config.load().chain_id)), web3_request.id()).into(),
             // TODO: eth_callBundle (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_callbundle)
             // TODO: eth_cancelPrivateTransaction (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_cancelprivatetransaction, but maybe just reject)
             // TODO: eth_sendPrivateTransaction (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_sendprivatetransaction)
@@ -763,16 +1001,18 @@ impl App {
                     .await?
                     .into_result()?;
 
+                let config = self.config.load();
+
                 let gas_increase = if let Some(gas_increase_percent) =
-                    self.config.gas_increase_percent
+                    config.gas_increase_percent
                 {
                     let gas_increase = gas_estimate * gas_increase_percent / U256::from(100);
 
-                    let min_gas_increase = self.config.gas_increase_min.unwrap_or_default();
+                    let min_gas_increase = config.gas_increase_min.unwrap_or_default();
 
                     gas_increase.max(min_gas_increase)
                 } else {
-                    self.config.gas_increase_min.unwrap_or_default()
+                    config.gas_increase_min.unwrap_or_default()
                 };
 
                 gas_estimate += gas_increase;
@@ -939,23 +1179,51 @@ impl App {
                 data: None,
             }, web3_request.id()).into(),
             // Send all other methods to a backend RPC.
-            method => {
-                if method.starts_with("admin_") {
-                    // TODO: emit a stat? will probably just be noise
-                    return Err(Web3ProxyError::AccessDenied("admin methods are not allowed".into()));
+            _ => {
+                if let Some(route) = web3_request.route.as_ref() {
+                    return self.proxy_routed_request(web3_request, route).await;
                 }
-                if method.starts_with("alchemy_") {
-                    return Err(JsonRpcErrorData::from(format!(
-                        "the method {} does not exist/is not available",
-                        method
-                    )).into());
+                let cache_key = self
+                    .balanced_rpcs
+                    .response_cache
+                    .as_ref()
+                    .and_then(|_| JsonRpcQueryCacheKey::try_from_request(web3_request));
+
+                if let Some((response_cache, cache_key)) = self.balanced_rpcs.response_cache.as_ref().zip(cache_key.as_ref()) {
+                    if let Some(data) = response_cache.get(cache_key).await {
+                        web3_request.response.lock().cache_hit = true;
+
+                        return Ok(jsonrpc::ParsedResponse::from_response_data(data, web3_request.id()).into());
+                    }
+                }
+
+                // wide eth_getLogs ranges might be split across multiple rpcs
+                let split = timeout_at(web3_request.expire_at(), self.try_split_get_logs(web3_request)).await??;
+
+                let mut response = if let Some(response) = split {
+                    response
+                } else {
+                    timeout_at(
+                        web3_request.expire_at(),
+                        self.balanced_rpcs
+                            .try_proxy_connection::<Arc<OwnedLazyValue>>(web3_request),
+                    )
+                    .await??
+                };
+
+                if let Some((response_cache, cache_key)) = self.balanced_rpcs.response_cache.as_ref().zip(cache_key) {
+                    // streamed responses are too big to cache
+                    if let SingleResponse::Parsed(parsed) = &response {
+                        if let Some(result) = parsed.result() {
+                            // the head block might have changed. use the block that the rpcs actually had
+                            let backend_rpcs = web3_request.backend_rpcs_used();
+
+                            if let Some(cache_key) = cache_key.for_response(&self.balanced_rpcs, &backend_rpcs).await {
+                                response_cache.insert(cache_key, result.clone().into()).await;
+                            }
+                        }
+                    }
                 }
-                let mut response = timeout_at(
-                    web3_request.expire_at(),
-                    self.balanced_rpcs
-                        .try_proxy_connection::<Arc<OwnedLazyValue>>(web3_request),
-                )
-                .await??;
 
                 response.set_id(web3_request.id());
                 response
@@ -972,3 +1240,67 @@ impl fmt::Debug for App {
         f.debug_struct("Web3ProxyApp").finish_non_exhaustive()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TopConfig;
+    use crate::test_utils::stub_rpc::{stub_error, StubRpc};
+    use crate::test_utils::TestFrontend;
+    use sonic_rs::json;
+    use std::sync::Arc;
+    use tokio::time::{sleep, Duration, Instant};
+
+    #[test_log::test(tokio::test)]
+    async fn test_reload_keeps_unchanged_rpcs() {
+        let stub = StubRpc::spawn(|method, _| match method {
+            "eth_chainId" => (Duration::ZERO, Ok(json!("0x7a69"))),
+            _ => (Duration::ZERO, Err(stub_error("not supported"))),
+        })
+        .await;
+
+        let config = |app_extra: &str, soft_limit: u32| {
+            format!(
+                r#"
+                [app]
+                chain_id = 31337
+                min_sum_soft_limit = 1
+                {}
+
+                [balanced_rpcs.a]
+                http_url = "{}"
+                soft_limit = {}
+                "#,
+                app_extra, stub.url, soft_limit
+            )
+        };
+
+        let x = TestFrontend::spawn(&config("", 1)).await;
+
+        // the config loop applies the first config
+        let start = Instant::now();
+        let first = loop {
+            if let Some(rpc) = x.app.balanced_rpcs.get("a") {
+                break rpc;
+            }
+
+            assert!(start.elapsed() < Duration::from_secs(10), "rpc never added");
+            sleep(Duration::from_millis(10)).await;
+        };
+
+        // an app config edit doesn't touch the rpcs
+        let new_config = config(r#"redirect_public_url = "https://example.com""#, 1);
+        x.app
+            .apply_top_config(&TopConfig::from_toml_str(&new_config).unwrap())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &x.app.balanced_rpcs.get("a").unwrap()));
+
+        // a changed rpc is replaced
+        let new_config = config(r#"redirect_public_url = "https://example.com""#, 2);
+        x.app
+            .apply_top_config(&TopConfig::from_toml_str(&new_config).unwrap())
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &x.app.balanced_rpcs.get("a").unwrap()));
+    }
+}
@@ -0,0 +1,94 @@
+//! config reloads are started by proxyd and applied by the app. the results are shown at `/status/reload`
+
+use super::App;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+/// what started a config reload
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadTrigger {
+    /// the config file's modified time changed
+    FileChanged,
+    /// the config file is re-read on an interval in case file changes are missed
+    Poll,
+    /// `kill -HUP`
+    Sighup,
+}
+
+impl fmt::Display for ReloadTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileChanged => f.write_str("file_changed"),
+            Self::Poll => f.write_str("poll"),
+            Self::Sighup => f.write_str("sighup"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConfigReloadStatus {
+    /// the config file that is being watched. None if the config didn't come from a file
+    pub path: Option<PathBuf>,
+    /// the settings that changed in the last reload. urls and keys are never included
+    pub last_changes: Vec<String>,
+    /// None if the last reload worked
+    pub last_error: Option<String>,
+    pub last_reload_at: Option<DateTime<Utc>>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_trigger: Option<ReloadTrigger>,
+    pub num_failures: u64,
+    pub num_reloads: u64,
+}
+
+impl App {
+    pub fn config_reload_status(&self) -> ConfigReloadStatus {
+        self.config_reload.read().clone()
+    }
+
+    pub fn set_config_path(&self, path: PathBuf) {
+        self.config_reload.write().path = Some(path);
+    }
+
+    /// a new config was read and is about to be sent to the app
+    pub fn config_reload_started(&self, trigger: ReloadTrigger, changes: Vec<String>) {
+        let mut status = self.config_reload.write();
+
+        status.last_changes = changes;
+        status.last_error = None;
+        status.last_reload_at = Some(Utc::now());
+        status.last_trigger = Some(trigger);
+        status.num_reloads += 1;
+    }
+
+    /// the config couldn't be read, parsed, validated, or applied
+    pub fn config_reload_failed(&self, trigger: Option<ReloadTrigger>, err: String) {
+        let mut status = self.config_reload.write();
+
+        if let Some(trigger) = trigger {
+            // errors before a reload starts (like a bad file) are reloads too
+            status.last_changes.clear();
+            status.last_reload_at = Some(Utc::now());
+            status.last_trigger = Some(trigger);
+            status.num_reloads += 1;
+        }
+
+        status.last_error = Some(err);
+        status.num_failures += 1;
+    }
+
+    /// a good config was read but it is the same as the running config. clears errors from a bad file that was fixed
+    pub fn config_reload_unchanged(&self, trigger: ReloadTrigger) {
+        self.config_reload_started(trigger, vec![]);
+        self.config_reload_applied();
+    }
+
+    pub(super) fn config_reload_applied(&self) {
+        let mut status = self.config_reload.write();
+
+        status.last_error = None;
+        status.last_success_at = Some(Utc::now());
+    }
+}
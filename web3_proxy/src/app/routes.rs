@@ -0,0 +1,86 @@
+//! `[[routes]]` send some methods to a different group of rpcs, or only to rpcs with some tags
+
+use super::App;
+use crate::config::{RouteConfig, RpcGroup};
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{SingleResponse, ValidatedRequest};
+use crate::rpcs::many::Web3Rpcs;
+use sonic_rs::OwnedLazyValue;
+use std::sync::{atomic, Arc};
+use tokio::time::timeout_at;
+use tracing::{info, trace};
+
+impl App {
+    /// the first route that matches the method
+    pub fn route_for(&self, method: &str) -> Option<Arc<RouteConfig>> {
+        self.routes
+            .load()
+            .iter()
+            .find(|x| x.matches(method))
+            .cloned()
+    }
+
+    pub(super) fn apply_routes(&self, routes: &[RouteConfig]) {
+        let old = self.routes.load();
+
+        if old.len() != routes.len() || old.iter().zip(routes).any(|(a, b)| **a != *b) {
+            info!(num_routes = routes.len(), "routes changed");
+        }
+
+        self.routes
+            .store(Arc::new(routes.iter().cloned().map(Arc::new).collect()));
+    }
+
+    pub fn rpcs_for_group(&self, group: RpcGroup) -> &Arc<Web3Rpcs> {
+        match group {
+            RpcGroup::Balanced => &self.balanced_rpcs,
+            RpcGroup::Private => &self.protected_rpcs,
+            RpcGroup::Bundler4337 => &self.bundler_4337_rpcs,
+        }
+    }
+
+    /// send the request to the route's group. if that fails, try each of the fallback groups in order
+    /// TODO: cache responses? the response cache is only for balanced_rpcs
+    pub(super) async fn proxy_routed_request(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+        route: &RouteConfig,
+    ) -> Web3ProxyResult<SingleResponse> {
+        let mut last_error = None;
+
+        for (i, group) in std::iter::once(route.group)
+            .chain(route.fallback.iter().copied())
+            .enumerate()
+        {
+            if web3_request.expired() {
+                break;
+            }
+
+            if i > 0 {
+                web3_request
+                    .route_fallback
+                    .store(true, atomic::Ordering::Relaxed);
+            }
+
+            let rpcs = self.rpcs_for_group(group);
+
+            match timeout_at(
+                web3_request.expire_at(),
+                rpcs.try_proxy_connection::<Arc<OwnedLazyValue>>(web3_request),
+            )
+            .await?
+            {
+                Ok(mut response) => {
+                    response.set_id(web3_request.id());
+                    return Ok(response);
+                }
+                Err(err) => {
+                    trace!(?err, %rpcs, "routed request failed");
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(Web3ProxyError::Timeout(None)))
+    }
+}
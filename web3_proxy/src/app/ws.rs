@@ -1,21 +1,115 @@
 //! Websocket-specific functions for the Web3ProxyApp
 
 use super::App;
+use crate::api_keys::ApiKey;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::jsonrpc::ResponseData;
 use crate::jsonrpc::{self, RequestOrMethod, ValidatedRequest};
-use alloy::primitives::U64;
-use axum::extract::ws::Message;
+use crate::rpcs::blockchain::BlockCursor;
+use alloy::primitives::{B256, U64};
+use alloy::rpc::types::{Filter, Log};
+use axum::extract::ws::{close_code, CloseFrame, Message};
 use futures::future::AbortHandle;
 use futures::future::Abortable;
 use futures::stream::StreamExt;
 use sonic_rs::{json, JsonValueTrait};
+use std::collections::VecDeque;
+use std::net::IpAddr;
 use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, OwnedRwLockWriteGuard, RwLock as AsyncRwLock};
 use tokio_stream::wrappers::BroadcastStream;
 use tokio_stream::wrappers::WatchStream;
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
+
+/// how far back a subscription looks for the fork point after a reorg
+/// TODO: config for this
+const MAX_REORG_DEPTH: usize = 64;
+
+/// a subscription's notifications wait until the response with its id is queued.
+/// a batch's responses are sent all at once, so the gate is held until the whole batch is ready
+#[derive(Clone, Default)]
+pub struct SubscriptionGate(Arc<AsyncRwLock<()>>);
+
+impl SubscriptionGate {
+    /// subscriptions wait until this guard is dropped
+    pub fn hold(&self) -> OwnedRwLockWriteGuard<()> {
+        self.0
+            .clone()
+            .try_write_owned()
+            .expect("a gate is only held once")
+    }
+
+    async fn wait(&self) {
+        drop(self.0.read().await);
+    }
+}
+
+/// sent when a subscription can't fill a gap. clients should resubscribe and backfill with eth_getBlockByNumber or eth_getLogs
+fn blocks_skipped() -> CloseFrame {
+    CloseFrame {
+        code: close_code::ERROR,
+        reason: "unable to send every block. resubscribe".into(),
+    }
+}
+
+/// send one log to a subscriber. returns false if the subscriber is gone
+async fn send_log(
+    app: &Arc<App>,
+    proxy_mode: ProxyMode,
+    client_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
+    subscription_id: U64,
+    log: &Log,
+    response_sender: &mpsc::Sender<Message>,
+) -> bool {
+    let subscription_web3_request = match ValidatedRequest::new_with_app(
+        app,
+        proxy_mode,
+        None,
+        RequestOrMethod::Method("eth_subscribe(logs)".into(), 0),
+        None,
+        None,
+        client_ip,
+        api_key,
+    )
+    .await
+    {
+        Ok(x) => x,
+        Err(err) => {
+            error!(?err, "error creating subscription_web3_request");
+            return false;
+        }
+    };
+
+    let response_json = json!({
+        "jsonrpc": "2.0",
+        "method":"eth_subscription",
+        "params": {
+            "subscription": subscription_id,
+            "result": log,
+        },
+    });
+
+    let response_str =
+        sonic_rs::to_string(&response_json).expect("this should always be valid json");
+
+    let response_bytes = response_str.len() as u64;
+
+    if response_sender
+        .send(Message::Text(response_str.into()))
+        .await
+        .is_err()
+    {
+        return false;
+    }
+
+    subscription_web3_request.set_response(response_bytes);
+
+    true
+}
 
 impl App {
     pub async fn eth_subscribe<'a>(
@@ -24,6 +118,7 @@ impl App {
         subscription_count: &'a AtomicU64,
         // TODO: taking a sender for Message instead of the exact json we are planning to send feels wrong, but its easier for now
         response_sender: mpsc::Sender<Message>,
+        gate: SubscriptionGate,
     ) -> Web3ProxyResult<(AbortHandle, jsonrpc::ParsedResponse)> {
         let subscribe_to = web3_request
             .inner
@@ -41,18 +136,28 @@ impl App {
         let subscription_id = subscription_count.fetch_add(1, atomic::Ordering::SeqCst);
         let subscription_id = U64::from(subscription_id);
 
+        // counted until the subscription's task exits
+        let subscription_guard = self.connections.subscription();
+
         // TODO: calling `json!` on every request is probably not fast. but it works for now
         // TODO: i think we need a stricter EthSubscribeRequest type that JsonRpcRequest can turn into
         // TODO: DRY This up. lots of duplication between newHeads and newPendingTransactions
         match subscribe_to {
             "newHeads" => {
                 // we clone the watch before spawning so that theres less chance of missing anything
-                // TODO: watch receivers can miss a block. is that okay?
+                // watch receivers can miss a block. the cursor fills in any gaps
                 let head_block_receiver = self.watch_consensus_head_receiver.clone();
                 let app = self.clone();
                 let proxy_mode = web3_request.proxy_mode();
+                let client_ip = web3_request.client_ip;
+                let api_key = web3_request.api_key.clone();
 
                 tokio::spawn(async move {
+                    let _subscription_guard = subscription_guard;
+
+                    // the subscribe response goes first
+                    gate.wait().await;
+
                     trace!("newHeads subscription {:?}", subscription_id);
 
                     let mut head_block_receiver = Abortable::new(
@@ -60,74 +165,230 @@ impl App {
                         subscription_registration,
                     );
 
-                    while let Some(new_head) = head_block_receiver.next().await {
+                    let mut cursor = BlockCursor::new(MAX_REORG_DEPTH);
+
+                    let mut close_frame = None;
+
+                    'subscription: while let Some(new_head) = head_block_receiver.next().await {
                         let new_head = if let Some(new_head) = new_head {
                             new_head
                         } else {
                             continue;
                         };
 
-                        let subscription_web3_request = ValidatedRequest::new_with_app(
-                            &app,
-                            proxy_mode,
-                            None,
-                            RequestOrMethod::Method("eth_subscribe(newHeads)".into(), 0),
-                            Some(new_head),
-                            None,
-                        )
-                        .await;
-
-                        match subscription_web3_request {
-                            Err(err) => {
-                                error!(?err, "error creating subscription_web3_request");
-                                // TODO: send them an error message before closing
-                                break;
-                            }
-                            Ok(subscription_web3_request) => {
-                                // TODO: make a response struct for subscription notifications.
-                                let response_json = json!({
-                                    "jsonrpc": "2.0",
-                                    "method":"eth_subscription",
-                                    "params": {
-                                        "subscription": subscription_id,
-                                        // TODO: option to include full transaction objects instead of just the hashes?
-                                        "result": subscription_web3_request.head_block.as_ref().map(|x| &x.0),
-                                    },
-                                });
-
-                                let response_str = sonic_rs::to_string(&response_json)
-                                    .expect("this should always be valid json");
-
-                                let response_bytes = response_str.len() as u64;
-
-                                // TODO: do clients support binary messages?
-                                // TODO: can we check a content type header?
-                                let response_msg = Message::Text(response_str.into());
-
-                                if response_sender.send(response_msg).await.is_err() {
-                                    // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
-                                    // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
-                                    break;
-                                };
-
-                                subscription_web3_request.set_response(response_bytes);
+                        // after a reorg, geth sends the new branch from the fork point. removed blocks are not sent
+                        let (_, new_blocks) =
+                            match cursor.advance(&app.balanced_rpcs, new_head).await {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    warn!(?err, "newHeads subscription is unable to fill a gap");
+                                    close_frame = Some(blocks_skipped());
+                                    break 'subscription;
+                                }
+                            };
+
+                        for new_block in new_blocks {
+                            let subscription_web3_request = ValidatedRequest::new_with_app(
+                                &app,
+                                proxy_mode,
+                                None,
+                                RequestOrMethod::Method("eth_subscribe(newHeads)".into(), 0),
+                                Some(new_block),
+                                None,
+                                client_ip,
+                                api_key.clone(),
+                            )
+                            .await;
+
+                            match subscription_web3_request {
+                                Err(err) => {
+                                    error!(?err, "error creating subscription_web3_request");
+                                    // TODO: send them an error message before closing
+                                    break 'subscription;
+                                }
+                                Ok(subscription_web3_request) => {
+                                    // TODO: make a response struct for subscription notifications.
+                                    let response_json = json!({
+                                        "jsonrpc": "2.0",
+                                        "method":"eth_subscription",
+                                        "params": {
+                                            "subscription": subscription_id,
+                                            // TODO: option to include full transaction objects instead of just the hashes?
+                                            "result": subscription_web3_request.head_block.as_ref().map(|x| &x.0),
+                                        },
+                                    });
+
+                                    let response_str = sonic_rs::to_string(&response_json)
+                                        .expect("this should always be valid json");
+
+                                    let response_bytes = response_str.len() as u64;
+
+                                    // TODO: do clients support binary messages?
+                                    // TODO: can we check a content type header?
+                                    let response_msg = Message::Text(response_str.into());
+
+                                    if response_sender.send(response_msg).await.is_err() {
+                                        // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
+                                        // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
+                                        break 'subscription;
+                                    };
+
+                                    subscription_web3_request.set_response(response_bytes);
+                                }
                             }
                         }
                     }
 
-                    let _ = response_sender.send(Message::Close(None)).await;
+                    let _ = response_sender.send(Message::Close(close_frame)).await;
 
                     trace!("closed newHeads subscription {:?}", subscription_id);
                 });
             }
+            "logs" => {
+                // TODO: what should happen if they include a block range or hash? geth ignores it
+                let filter: Filter = match web3_request.inner.params().get(1) {
+                    None => Default::default(),
+                    Some(x) => sonic_rs::from_value(x).map_err(|err| {
+                        Web3ProxyError::BadRequest(format!("invalid logs filter: {}", err).into())
+                    })?,
+                };
+
+                // we clone the watch before spawning so that theres less chance of missing anything
+                let head_block_receiver = self.watch_consensus_head_receiver.clone();
+                let app = self.clone();
+                let proxy_mode = web3_request.proxy_mode();
+                let client_ip = web3_request.client_ip;
+                let api_key = web3_request.api_key.clone();
+
+                tokio::spawn(async move {
+                    let _subscription_guard = subscription_guard;
+
+                    // the subscribe response goes first
+                    gate.wait().await;
+
+                    trace!("logs subscription {:?}", subscription_id);
+
+                    let mut head_block_receiver = Abortable::new(
+                        WatchStream::new(head_block_receiver),
+                        subscription_registration,
+                    );
+
+                    let mut cursor = BlockCursor::new(MAX_REORG_DEPTH);
+
+                    let mut close_frame = None;
+
+                    // the logs that we have sent for recent blocks. if a block is reorged out, its logs are sent again with removed=true
+                    let mut sent: VecDeque<(B256, Vec<Log>)> =
+                        VecDeque::with_capacity(MAX_REORG_DEPTH);
+
+                    'subscription: while let Some(new_head) = head_block_receiver.next().await {
+                        let new_head = if let Some(new_head) = new_head {
+                            new_head
+                        } else {
+                            continue;
+                        };
+
+                        // the watch channel can skip blocks. the cursor follows the parent hashes back to a block we've already seen
+                        let (removed_blocks, new_blocks) =
+                            match cursor.advance(&app.balanced_rpcs, new_head).await {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    warn!(?err, "logs subscription is unable to fill a gap");
+                                    close_frame = Some(blocks_skipped());
+                                    break 'subscription;
+                                }
+                            };
+
+                        for removed_block in removed_blocks {
+                            let Some(i) = sent.iter().position(|(x, _)| x == removed_block.hash())
+                            else {
+                                continue;
+                            };
+
+                            let (_, logs) = sent.remove(i).expect("index was just found");
+
+                            for mut log in logs {
+                                log.removed = true;
+
+                                if !send_log(
+                                    &app,
+                                    proxy_mode,
+                                    client_ip,
+                                    api_key.clone(),
+                                    subscription_id,
+                                    &log,
+                                    &response_sender,
+                                )
+                                .await
+                                {
+                                    break 'subscription;
+                                }
+                            }
+                        }
+
+                        for block in new_blocks {
+                            let logs: Vec<Log> = match app
+                                .balanced_rpcs
+                                .internal_request(
+                                    "eth_getLogs".into(),
+                                    &[filter.clone().at_block_hash(*block.hash())],
+                                    Some(Duration::from_secs(30)),
+                                )
+                                .await
+                            {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    // internal_request already tried the other rpcs. skipping the block would lose its logs (and their removals after a reorg)
+                                    warn!(?err, %block, "unable to get logs for subscription");
+                                    close_frame = Some(blocks_skipped());
+                                    break 'subscription;
+                                }
+                            };
+
+                            for log in logs.iter() {
+                                if !send_log(
+                                    &app,
+                                    proxy_mode,
+                                    client_ip,
+                                    api_key.clone(),
+                                    subscription_id,
+                                    log,
+                                    &response_sender,
+                                )
+                                .await
+                                {
+                                    break 'subscription;
+                                }
+                            }
+
+                            if sent.len() >= MAX_REORG_DEPTH {
+                                sent.pop_front();
+                            }
+
+                            sent.push_back((*block.hash(), logs));
+                        }
+                    }
+
+                    let _ = response_sender.send(Message::Close(close_frame)).await;
+
+                    trace!("closed logs subscription {:?}", subscription_id);
+                });
+            }
             // TODO: bring back the other custom subscription types that had the full transaction object
             "newPendingTransactions" => {
                 // we subscribe before spawning so that theres less chance of missing anything
                 let pending_txid_firehose = self.pending_txid_firehose.subscribe();
                 let app = self.clone();
                 let proxy_mode = web3_request.proxy_mode();
+                let client_ip = web3_request.client_ip;
+                let api_key = web3_request.api_key.clone();
 
                 tokio::spawn(async move {
+                    let _subscription_guard = subscription_guard;
+
+                    // the subscribe response goes first
+                    gate.wait().await;
+
                     let mut pending_txid_firehose = Abortable::new(
                         BroadcastStream::new(pending_txid_firehose),
                         subscription_registration,
@@ -154,6 +415,8 @@ impl App {
                                     ),
                                     None,
                                     None,
+                                    client_ip,
+                                    api_key.clone(),
                                 )
                                 .await
                                 {
@@ -222,9 +485,49 @@ impl App {
         let response = jsonrpc::SingleResponse::Parsed(response);
         // TODO: this serializes twice
         web3_request.set_response(&response);
+        self.record_access(&web3_request);
         let response = response.parsed().await.expect("Response already parsed");
 
         // TODO: make a `SubscriptonHandle(AbortHandle, JoinHandle)` struct?
         Ok((subscription_abort_handle, response))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionGate;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, Duration};
+
+    #[tokio::test]
+    async fn test_subscription_gate() {
+        let gate = SubscriptionGate::default();
+        let held = gate.hold();
+
+        let (sender, mut receiver) = mpsc::channel(2);
+
+        let notifications = {
+            let gate = gate.clone();
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                gate.wait().await;
+                sender.send("notification").await.unwrap();
+            })
+        };
+
+        sleep(Duration::from_millis(10)).await;
+        assert!(receiver.try_recv().is_err());
+
+        sender.send("response").await.unwrap();
+        drop(held);
+
+        notifications.await.unwrap();
+
+        assert_eq!(receiver.recv().await, Some("response"));
+        assert_eq!(receiver.recv().await, Some("notification"));
+
+        // a gate that isn't held doesn't wait
+        SubscriptionGate::default().wait().await;
+    }
+}
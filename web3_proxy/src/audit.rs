@@ -0,0 +1,140 @@
+//! Changes made with the admin api. The most recent entries are kept in memory for the admin api's `/audit`.
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info, warn};
+
+/// older entries are only in the logs (and the audit file if one is configured)
+const MAX_ENTRIES: usize = 1_000;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// the name of the admin's api key. never the secret
+    pub key_name: Arc<str>,
+    pub client_ip: Option<IpAddr>,
+    /// "disable", "drain", "tier", etc.
+    pub action: &'static str,
+    /// "balanced_rpcs.llamanodes"
+    pub target: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    /// None if the action worked
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    /// log the entry, append it to the audit file, and keep it in memory
+    pub async fn record(&self, entry: AuditEntry, path: Option<&Path>) {
+        if let Some(err) = entry.error.as_ref() {
+            warn!(key=%entry.key_name, ip=?entry.client_ip, action=entry.action, target=%entry.target, %err, "admin action failed");
+        } else {
+            info!(key=%entry.key_name, ip=?entry.client_ip, action=entry.action, target=%entry.target, old=?entry.old, new=?entry.new, "admin action");
+        }
+
+        if let Some(path) = path {
+            if let Err(err) = append_json_line(path, &entry).await {
+                error!(?err, ?path, "unable to write to the admin audit file");
+            }
+        }
+
+        let mut entries = self.entries.lock();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+
+        entries.push_back(entry);
+    }
+
+    /// newest first
+    pub fn recent(&self, limit: usize) -> Vec<AuditEntry> {
+        self.entries
+            .lock()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+async fn append_json_line(path: &Path, entry: &AuditEntry) -> anyhow::Result<()> {
+    let mut line = sonic_rs::to_vec(entry)?;
+    line.push(b'\n');
+
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    f.write_all(&line).await?;
+
+    // tokio's file writes happen in the background until they are flushed
+    f.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditEntry, AuditLog, MAX_ENTRIES};
+    use chrono::Utc;
+
+    fn entry(target: String) -> AuditEntry {
+        AuditEntry {
+            at: Utc::now(),
+            key_name: "ops".into(),
+            client_ip: None,
+            action: "disable",
+            target,
+            old: Some("enabled".to_string()),
+            new: Some("disabled".to_string()),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let log = AuditLog::default();
+
+        log.record(entry("balanced_rpcs.a".to_string()), Some(&path))
+            .await;
+        log.record(entry("balanced_rpcs.b".to_string()), Some(&path))
+            .await;
+
+        let recent = log.recent(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].target, "balanced_rpcs.b");
+
+        let file = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(file.lines().count(), 2);
+        assert!(file.contains("\"target\":\"balanced_rpcs.a\""));
+
+        // only the newest entries are kept in memory
+        for i in 0..MAX_ENTRIES {
+            log.record(entry(i.to_string()), None).await;
+        }
+
+        assert_eq!(log.recent(usize::MAX).len(), MAX_ENTRIES);
+        assert_eq!(log.recent(1)[0].target, (MAX_ENTRIES - 1).to_string());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
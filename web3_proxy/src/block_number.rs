@@ -13,16 +13,65 @@ use serde::Serialize;
 use sonic_rs::{json, JsonContainerTrait, JsonValueMutTrait, JsonValueTrait, Value};
 use tracing::{error, trace, warn};
 
-pub fn block_number_to_u64(block_num: BlockNumberOrTag, latest_block: U64) -> (U64, bool) {
+/// The block numbers that tags like "latest" and "finalized" resolve to
+#[derive(Clone, Copy, Debug)]
+pub struct BlockTags {
+    pub latest: U64,
+    /// None if the rpcs don't support the tag. the backend will resolve it
+    pub safe: Option<U64>,
+    /// None if the rpcs don't support the tag. the backend will resolve it
+    pub finalized: Option<U64>,
+}
+
+impl BlockTags {
+    pub fn new(head_block: &BlockHeader, app: Option<&App>) -> Self {
+        let latest = head_block.number();
+
+        // the consensus finalized and safe blocks can be a little newer than the head block that was saved on the request
+        let (safe, finalized) = match app {
+            None => (None, None),
+            Some(app) => (
+                app.balanced_rpcs
+                    .safe_block()
+                    .map(|x| x.number().min(latest)),
+                app.balanced_rpcs
+                    .finalized_block()
+                    .map(|x| x.number().min(latest)),
+            ),
+        };
+
+        Self {
+            latest,
+            safe,
+            finalized,
+        }
+    }
+
+    /// only the tags. for tests and requests without an app
+    pub fn latest(latest: U64) -> Self {
+        Self {
+            latest,
+            safe: None,
+            finalized: None,
+        }
+    }
+}
+
+/// the bool is true if the request should be changed to use the number
+pub fn block_number_to_u64(block_num: BlockNumberOrTag, tags: &BlockTags) -> (U64, bool) {
     match block_num {
         BlockNumberOrTag::Earliest => (U64::ZERO, false),
-        BlockNumberOrTag::Finalized => {
-            warn!("finalized block requested! not yet implemented!");
-            (latest_block - U64::from(10), false)
-        }
+        BlockNumberOrTag::Finalized => match tags.finalized {
+            Some(x) => (x, true),
+            None => {
+                // leave "finalized" for the backend. any synced rpc can serve it
+                trace!("finalized block requested but unknown");
+                (tags.latest, false)
+            }
+        },
         BlockNumberOrTag::Latest => {
             // change "latest" to a number
-            (latest_block, true)
+            (tags.latest, true)
         }
         BlockNumberOrTag::Number(x) => {
             // we already have a number
@@ -31,12 +80,16 @@ pub fn block_number_to_u64(block_num: BlockNumberOrTag, latest_block: U64) -> (U
         BlockNumberOrTag::Pending => {
             // modified is false because we want the backend to see "pending"
             // TODO: think more about how to handle Pending
-            (latest_block, false)
-        }
-        BlockNumberOrTag::Safe => {
-            warn!("safe block requested! not yet implemented!");
-            (latest_block - U64::from(3), false)
+            (tags.latest, false)
         }
+        BlockNumberOrTag::Safe => match tags.safe {
+            Some(x) => (x, true),
+            None => {
+                // leave "safe" for the backend. any synced rpc can serve it
+                trace!("safe block requested but unknown");
+                (tags.latest, false)
+            }
+        },
     }
 }
 
@@ -130,7 +183,7 @@ pub async fn clean_block_number<'a>(
                     } else if let Ok(block_num) = sonic_rs::from_value::<U64>(x) {
                         (block_num, false)
                     } else if let Ok(block_number) = sonic_rs::from_value::<BlockNumberOrTag>(x) {
-                        block_number_to_u64(block_number, head_block.number())
+                        block_number_to_u64(block_number, &BlockTags::new(head_block, app))
                     } else if let Ok(block_hash) = sonic_rs::from_value::<B256>(x) {
                         if block_hash == *head_block.hash() {
                             (head_block.number(), false)
@@ -364,13 +417,14 @@ impl RequestBlocks {
                 if obj.contains_key(&"blockHash") {
                     Ok(Self::None)
                 } else {
+                    let tags = BlockTags::new(head_block, app);
+
                     let from_block = if let Some(x) = obj.get_mut(&"fromBlock") {
                         // TODO: use .take instead of clone
                         // what if its a hash?
                         let block_num: BlockNumberOrTag = sonic_rs::from_value(x)?;
 
-                        let (block_num, _change) =
-                            block_number_to_u64(block_num, head_block.number());
+                        let (block_num, _change) = block_number_to_u64(block_num, &tags);
 
                         // TODO: double check this. it scares me
                         // we always change because some clients send U64 with padding and erigon doesn't like that
@@ -389,9 +443,12 @@ impl RequestBlocks {
 
                         // sometimes people request `from_block=head+1, to_block="latest"`. latest becomes head and then theres a problem
                         // TODO: delay here until the app has this block?
-                        let latest_block = head_block.number().max(from_block.num());
+                        let tags = BlockTags {
+                            latest: head_block.number().max(from_block.num()),
+                            ..tags
+                        };
 
-                        let (block_num, _change) = block_number_to_u64(block_num, latest_block);
+                        let (block_num, _change) = block_number_to_u64(block_num, &tags);
 
                         // TODO: double check this. it scares me but i think we need it
                         trace!("changing toBlock in eth_getLogs. {} -> {}", x, block_num);
@@ -413,13 +470,15 @@ impl RequestBlocks {
                         BlockNumOrHash::And(head_block.into())
                     };
 
+                    let max_range = app.map_or(200_000, |x| x.config.load().get_logs_max_range);
+
                     if let Some(range) = to_block.num().checked_sub(from_block.num()) {
-                        if range.to::<u64>() > 200_000 {
+                        if range.to::<u64>() > max_range {
                             return Err(Web3ProxyError::range_too_large(
                                 from_block,
                                 to_block,
                                 range,
-                                U64::from(200_000u32),
+                                U64::from(max_range),
                             ));
                         }
                     } else {
@@ -497,14 +556,14 @@ impl RequestBlocks {
 
 #[cfg(test)]
 mod test {
-    use super::RequestBlocks;
+    use super::{block_number_to_u64, BlockTags, RequestBlocks};
     use crate::{
         errors::Web3ProxyError,
         jsonrpc::{LooseId, SingleRequest},
         rpcs::blockchain::BlockHeader,
     };
     use alloy::primitives::{B256, U64};
-    use alloy::rpc::types::Block;
+    use alloy::rpc::types::{Block, BlockNumberOrTag};
     use sonic_rs::{json, JsonValueTrait};
     use std::sync::Arc;
 
@@ -608,6 +667,36 @@ mod test {
         assert!(matches!(x, RequestBlocks::Point { .. }));
     }
 
+    #[test]
+    fn test_block_tags() {
+        let tags = BlockTags {
+            latest: U64::from(100),
+            safe: Some(U64::from(90)),
+            finalized: Some(U64::from(80)),
+        };
+
+        assert_eq!(
+            block_number_to_u64(BlockNumberOrTag::Finalized, &tags),
+            (U64::from(80), true)
+        );
+        assert_eq!(
+            block_number_to_u64(BlockNumberOrTag::Safe, &tags),
+            (U64::from(90), true)
+        );
+
+        // unknown tags are left for the backend
+        let tags = BlockTags::latest(U64::from(100));
+
+        assert_eq!(
+            block_number_to_u64(BlockNumberOrTag::Finalized, &tags),
+            (U64::from(100), false)
+        );
+        assert_eq!(
+            block_number_to_u64(BlockNumberOrTag::Safe, &tags),
+            (U64::from(100), false)
+        );
+    }
+
     #[test]
     fn test_serializing_padded_ints() {
         let x: U64 = "0x001234".parse().unwrap();
@@ -0,0 +1,163 @@
+//! Samples real requests into a json lines file. `web3_proxy_cli replay` sends them to other servers.
+//!
+//! Params are captured after "latest" and other block tags are replaced with block numbers. This keeps replays comparable.
+//! Params are stored as sent because replays need them. Methods that the access log redacts are never captured.
+use crate::access_log::{error_class, LineWriter};
+use crate::app::Web3ProxyJoinHandle;
+use crate::config::{glob_match, AppConfig};
+use crate::jsonrpc::ValidatedRequest;
+use alloy::primitives::{B256, U64};
+use chrono::{DateTime, Utc};
+use nanorand::Rng;
+use serde::{Deserialize, Serialize};
+use sonic_rs::Value;
+use tokio::sync::broadcast;
+
+/// one line of a capture file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CaptureRecord {
+    pub at: DateTime<Utc>,
+    pub method: String,
+    pub params: Value,
+    /// the head block when the request was received
+    pub head_block: Option<U64>,
+    pub head_block_hash: Option<B256>,
+    pub from_block: Option<U64>,
+    pub to_block: Option<U64>,
+    pub response_millis: u64,
+    /// see `error_class`
+    pub error: Option<String>,
+}
+
+impl CaptureRecord {
+    /// None if there is no jsonrpc request to replay
+    pub fn new(web3_request: &ValidatedRequest) -> Option<Self> {
+        let request = web3_request.inner.jsonrpc_request()?;
+
+        let response_lock = web3_request.response.lock();
+
+        Some(Self {
+            at: Utc::now(),
+            method: request.method.to_string(),
+            params: request.params.clone(),
+            head_block: web3_request.head_block.as_ref().map(|x| x.number()),
+            head_block_hash: web3_request.head_block.as_ref().map(|x| *x.hash()),
+            from_block: web3_request.request_blocks.from_block().map(|x| x.num()),
+            to_block: web3_request.request_blocks.to_block().map(|x| x.num()),
+            response_millis: response_lock.response_millis,
+            error: error_class(&response_lock).map(String::from),
+        })
+    }
+}
+
+/// false for skipped and redacted methods. otherwise true for `capture_sample_rate` of the calls
+pub fn should_capture(config: &AppConfig, method: &str) -> bool {
+    if config.redacts_params(method)
+        || config
+            .capture_skip_methods
+            .iter()
+            .any(|x| glob_match(x, method))
+    {
+        return false;
+    }
+
+    if config.capture_sample_rate >= 1.0 {
+        true
+    } else if config.capture_sample_rate <= 0.0 {
+        false
+    } else {
+        nanorand::tls_rng().generate::<f64>() < config.capture_sample_rate
+    }
+}
+
+pub struct Capture(LineWriter);
+
+impl Capture {
+    /// None if `capture_path` is not set
+    pub async fn spawn(
+        config: &AppConfig,
+        shutdown_receiver: broadcast::Receiver<()>,
+    ) -> anyhow::Result<Option<(Self, Web3ProxyJoinHandle<()>)>> {
+        let Some(path) = config.capture_path.as_ref() else {
+            return Ok(None);
+        };
+
+        // captures are rotated like the access log
+        let (writer, handle) = LineWriter::spawn(
+            "traffic capture",
+            &path.to_string_lossy(),
+            config.access_log_max_bytes,
+            config.access_log_max_files,
+            shutdown_receiver,
+        )
+        .await?;
+
+        Ok(Some((Self(writer), handle)))
+    }
+
+    pub fn record(&self, web3_request: &ValidatedRequest, config: &AppConfig) {
+        if !should_capture(config, web3_request.inner.method()) {
+            return;
+        }
+
+        if let Some(record) = CaptureRecord::new(web3_request) {
+            self.0.send(&record);
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{should_capture, CaptureRecord};
+    use crate::config::AppConfig;
+    use crate::jsonrpc::ValidatedRequest;
+    use sonic_rs::json;
+
+    #[test]
+    fn test_should_capture() {
+        let mut config = AppConfig {
+            capture_sample_rate: 1.0,
+            ..Default::default()
+        };
+
+        assert!(should_capture(&config, "eth_getBalance"));
+        assert!(!should_capture(&config, "eth_sendRawTransaction"));
+        assert!(!should_capture(&config, "eth_signTypedData_v4"));
+        assert!(!should_capture(&config, "eth_getFilterChanges"));
+        assert!(!should_capture(&config, "eth_newBlockFilter"));
+        assert!(!should_capture(&config, "eth_subscribe"));
+
+        // redacted params are never written to disk
+        config.access_log_redact_methods.push("eth_call".into());
+        assert!(!should_capture(&config, "eth_call"));
+
+        config.capture_sample_rate = 0.0;
+        assert!(!should_capture(&config, "eth_getBalance"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_record_round_trip() {
+        let web3_request = ValidatedRequest::new_internal(
+            "eth_getBalance".into(),
+            &json!(["0x0000000000000000000000000000000000000000", "0x1"]),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let record = CaptureRecord::new(&web3_request).unwrap();
+
+        let line = sonic_rs::to_string(&record).unwrap();
+
+        let x: CaptureRecord = sonic_rs::from_str(&line).unwrap();
+
+        assert_eq!(x.method, "eth_getBalance");
+        assert_eq!(x.params, record.params);
+        assert_eq!(x.error, None);
+    }
+}
@@ -1,14 +1,22 @@
 use crate::app::Web3ProxyJoinHandle;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::JsonRpcErrorData;
 use crate::rpcs::blockchain::{BlockHeader, BlocksByHashCache};
 use crate::rpcs::one::Web3Rpc;
 use alloy::primitives::{TxHash, U256, U64};
+use anyhow::Context;
 use deduped_broadcast::DedupedBroadcaster;
 use hashbrown::HashMap;
+use ipnet::IpNet;
 use sentry::types::Dsn;
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_inline_default::serde_inline_default;
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use std::collections::BTreeSet;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 use std::time::Duration;
@@ -16,14 +24,20 @@ use tokio::sync::mpsc;
 use tracing::warn;
 
 pub type BlockAndRpc = (Option<BlockHeader>, Arc<Web3Rpc>);
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct TopConfig {
     pub app: AppConfig,
+    /// keyed by a name for the key. the name is used in logs and stats. the secret is not
+    #[serde(default = "Default::default")]
+    pub api_keys: HashMap<String, ApiKeyConfig>,
     pub balanced_rpcs: HashMap<String, Web3RpcConfig>,
     #[serde(default = "Default::default")]
     pub private_rpcs: HashMap<String, Web3RpcConfig>,
     #[serde(default = "Default::default")]
     pub bundler_4337_rpcs: HashMap<String, Web3RpcConfig>,
+    /// `[[routes]]` send some methods to a different group of rpcs. the first matching route is used
+    #[serde(default = "Default::default")]
+    pub routes: Vec<RouteConfig>,
     /// unknown config options get put here
     #[serde(flatten, default = "HashMap::default")]
     pub extra: HashMap<String, toml::Value>,
@@ -35,6 +49,52 @@ impl TopConfig {
         Ok(toml::from_str(&expanded)?)
     }
 
+    /// read, parse, clean, and validate a config file. check_config and config reloads both use this
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let top_config = fs::read_to_string(path)
+            .with_context(|| format!("unable to read config @ {}", path.display()))?;
+
+        let mut top_config = Self::from_toml_str(&top_config)
+            .with_context(|| format!("unable to parse config @ {}", path.display()))?;
+
+        top_config.clean();
+
+        top_config.validate()?;
+
+        Ok(top_config)
+    }
+
+    /// settings that can't work. checked before a config is used
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.app.validate()?;
+
+        if self.balanced_rpcs.values().all(|x| x.disabled) {
+            return Err(anyhow::anyhow!(
+                "balanced_rpcs needs at least one enabled rpc"
+            ));
+        }
+
+        for (name, rpc) in self
+            .balanced_rpcs
+            .iter()
+            .chain(self.private_rpcs.iter())
+            .chain(self.bundler_4337_rpcs.iter())
+        {
+            if rpc.http_url.is_none() && rpc.ws_url.is_none() && rpc.ipc_path.is_none() {
+                return Err(anyhow::anyhow!(
+                    "rpc {} needs an http_url, ws_url, or ipc_path",
+                    name
+                ));
+            }
+        }
+
+        if let Some(x) = self.routes.iter().find(|x| x.methods.is_empty()) {
+            return Err(anyhow::anyhow!("route has no methods: {:?}", x));
+        }
+
+        Ok(())
+    }
+
     /// TODO: this should probably be part of Deserialize
     pub fn clean(&mut self) {
         if !self.extra.is_empty() {
@@ -45,32 +105,130 @@ impl TopConfig {
         }
 
         self.app.clean();
+
+        for route in self.routes.iter() {
+            if route.methods.is_empty() {
+                warn!(?route, "route has no methods and will never match!");
+            }
+
+            if !route.extra.is_empty() {
+                warn!(extra=?route.extra.keys(), "unknown RouteConfig fields!");
+            }
+        }
     }
 }
 
 /// shared configuration between Web3Rpcs
 // TODO: no String, only &str
 #[serde_inline_default]
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct AppConfig {
+    /// one json line per request. "stdout" or a file path. None turns it off
+    pub access_log: Option<String>,
+
+    /// the access log file is rotated when it gets this big. 0 never rotates
+    #[serde_inline_default(100_000_000u64)]
+    pub access_log_max_bytes: u64,
+
+    /// how many rotated access log files to keep. "access.jsonl.1" is the newest
+    #[serde_inline_default(5usize)]
+    pub access_log_max_files: usize,
+
+    /// which request params go in the access log
+    #[serde(default = "Default::default")]
+    pub access_log_params: AccessLogParams,
+
+    /// glob patterns. unless `access_log_params = "full"`, these methods never have their params logged or captured
+    #[serde_inline_default(default_access_log_redact_methods())]
+    pub access_log_redact_methods: Vec<String>,
+
+    /// the admin api listens here. None turns it off. keep it off the public internet. "127.0.0.1:8545"
+    pub admin_addr: Option<SocketAddr>,
+
+    /// admin api changes are always logged. they are also appended to this file as json lines
+    pub admin_audit_path: Option<PathBuf>,
+
+    /// optional toml file with more `[name]` tables of ApiKeyConfig. it is checked for changes every 30 seconds
+    pub api_keys_path: Option<PathBuf>,
+
     /// erigon defaults to pruning beyond 90,000 blocks
     #[serde_inline_default(90_000u64)]
     pub archive_depth: u64,
 
+    /// sampled requests are appended to this file as json lines. `web3_proxy_cli replay` sends them to other servers
+    pub capture_path: Option<PathBuf>,
+
+    /// the fraction of requests that are captured. 1.0 captures everything
+    #[serde_inline_default(0.01f64)]
+    pub capture_sample_rate: f64,
+
+    /// glob patterns. these are never captured because replaying them would send transactions or need server state
+    #[serde_inline_default(default_capture_skip_methods())]
+    pub capture_skip_methods: Vec<String>,
+
     /// EVM chain id. 1 for ETH
     /// TODO: better type for chain_id? max of `u64::MAX / 2 - 36` <https://github.com/ethereum/EIPs/issues/2294>
     #[serde_inline_default(1u64)]
     pub chain_id: u64,
 
+    /// headers with the client's ip. only used when the connection is from one of `trusted_proxies`.
+    /// checked in order. "x-forwarded-for", "forwarded", and single address headers like "cf-connecting-ip" work.
+    #[serde_inline_default(vec!["x-forwarded-for".to_string()])]
+    pub client_ip_headers: Vec<String>,
+
+    /// checked after `method_rules`. the default denies methods that are dangerous or make no sense for a public proxy.
+    /// set this to `[]` to allow everything that `method_rules` doesn't deny.
+    #[serde_inline_default(builtin_method_rules())]
+    pub default_method_rules: Vec<MethodRuleConfig>,
+
+    /// server-side filters (eth_newFilter, etc.) are uninstalled if they are not polled for this long.
+    #[serde_inline_default(300u64)]
+    pub filter_timeout_seconds: u64,
+
     /// minimum amount to increase eth_estimateGas results
     pub gas_increase_min: Option<U256>,
 
     /// percentage to increase eth_estimateGas results. 100 == 100%
     pub gas_increase_percent: Option<U256>,
 
+    /// eth_getLogs chunk size for rpcs without a configured or learned `max_get_logs_range`. only used with `get_logs_split`.
+    #[serde_inline_default(10_000u64)]
+    pub get_logs_chunk_size: u64,
+
+    /// eth_getLogs requests with a wider range are rejected.
+    #[serde_inline_default(200_000u64)]
+    pub get_logs_max_range: u64,
+
+    /// split eth_getLogs ranges into chunks that the rpcs can handle and query the chunks in parallel.
+    /// with this on, `get_logs_max_range` can be raised past what any one rpc allows.
+    #[serde_inline_default(false)]
+    pub get_logs_split: bool,
+
+    /// the max number of open subscriptions for one ip. requests with an api key are not limited by ip.
+    pub ip_max_subscriptions: Option<u32>,
+
+    /// jsonrpc calls per second for one ip over http. a batch counts each call. the burst is the same size, so bigger batches are refused.
+    pub ip_requests_per_second: Option<u32>,
+
+    /// jsonrpc calls per second for one ip over websockets. a batch counts each call. the burst is the same size, so bigger batches are refused.
+    pub ip_ws_messages_per_second: Option<u32>,
+
+    /// the max number of server-side filters that one ip can have installed.
+    #[serde_inline_default(100usize)]
+    pub max_filters_per_client: usize,
+
     /// do not serve any requests if the best known block is behind the best known block by more than this many blocks.
     pub max_head_block_lag: Option<U64>,
 
+    /// allow or deny methods before they are sent anywhere. the first rule with a matching method wins.
+    /// methods that no rule matches are allowed.
+    #[serde_inline_default(vec![])]
+    pub method_rules: Vec<MethodRuleConfig>,
+
+    /// glob patterns for methods that get their own metrics label. common eth, net, web3, debug, and trace methods always do. everything else is "other"
+    #[serde_inline_default(vec![])]
+    pub metrics_methods: Vec<String>,
+
     /// The soft limit prevents thundering herds as new blocks are seen.
     #[serde_inline_default(1u32)]
     pub min_sum_soft_limit: u32,
@@ -82,6 +240,14 @@ pub struct AppConfig {
     /// the stats page url for an anonymous user.
     pub redirect_public_url: Option<String>,
 
+    /// max size of the cache for responses that are fixed for a given block. 0 disables the cache.
+    #[serde_inline_default(100_000_000u64)]
+    pub response_cache_max_bytes: u64,
+
+    /// on shutdown, websockets and in-flight requests get this long to finish. then they are cut off
+    #[serde_inline_default(30u64)]
+    pub shutdown_drain_seconds: u64,
+
     /// optional script to run before shutting the frontend down.
     /// this is useful for keeping load balancers happy.
     pub shutdown_script: Option<String>,
@@ -101,6 +267,11 @@ pub struct AppConfig {
     /// Optionally send errors to <https://sentry.io>
     pub sentry_url: Option<Dsn>,
 
+    /// connections from these networks can set the client's ip with `client_ip_headers`.
+    /// the default is to trust nobody and use the connection's address.
+    #[serde_inline_default(vec![])]
+    pub trusted_proxies: Vec<IpNet>,
+
     /// unknown config options get put here
     #[serde(flatten, default = "HashMap::default")]
     pub extra: HashMap<String, toml::Value>,
@@ -121,6 +292,481 @@ impl AppConfig {
                 "unknown Web3ProxyAppConfig fields!",
             );
         }
+
+        // this is done here and not in main so that reloaded configs get it too
+        if self.chain_id == 137 {
+            // TODO: these numbers are arbitrary. i think the maticnetwork/erigon fork has a bug
+            if self.gas_increase_min.is_none() {
+                self.gas_increase_min = Some(U256::from(40_000));
+            }
+
+            if self.gas_increase_percent.is_none() {
+                self.gas_increase_percent = Some(U256::from(40));
+            }
+        }
+
+        for rule in self
+            .method_rules
+            .iter()
+            .chain(self.default_method_rules.iter())
+        {
+            if !rule.extra.is_empty() {
+                warn!(extra=?rule.extra.keys(), "unknown MethodRuleConfig fields!");
+            }
+        }
+    }
+
+    /// settings that are only read when the app starts
+    pub const RESTART_REQUIRED: &'static [&'static str] = &[
+        "access_log",
+        "access_log_max_bytes",
+        "access_log_max_files",
+        "admin_addr",
+        "capture_path",
+        "chain_id",
+        "filter_timeout_seconds",
+        "ip_max_subscriptions",
+        "ip_requests_per_second",
+        "ip_ws_messages_per_second",
+        "max_filters_per_client",
+        "response_cache_max_bytes",
+        "sentry_url",
+        "start_script",
+        "start_script_args",
+    ];
+
+    /// settings that can't work. checked before a config is used
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.min_synced_rpcs == 0 {
+            return Err(anyhow::anyhow!("min_synced_rpcs must be at least 1"));
+        }
+
+        if !(0.0..=1.0).contains(&self.capture_sample_rate) {
+            return Err(anyhow::anyhow!(
+                "capture_sample_rate must be between 0 and 1"
+            ));
+        }
+
+        if self.get_logs_chunk_size == 0 {
+            return Err(anyhow::anyhow!("get_logs_chunk_size must be at least 1"));
+        }
+
+        if self.get_logs_max_range == 0 {
+            return Err(anyhow::anyhow!("get_logs_max_range must be at least 1"));
+        }
+
+        if let Some(x) = self
+            .method_rules
+            .iter()
+            .chain(self.default_method_rules.iter())
+            .find(|x| x.methods.is_empty())
+        {
+            return Err(anyhow::anyhow!("method rule has no methods: {:?}", x));
+        }
+
+        Ok(())
+    }
+
+    /// true if this method's params must not be written to disk. the access log and traffic capture both check this
+    pub fn redacts_params(&self, method: &str) -> bool {
+        self.access_log_params != AccessLogParams::Full
+            && self
+                .access_log_redact_methods
+                .iter()
+                .any(|x| glob_match(x, method))
+    }
+
+    /// Err if the first rule that matches the method denies it
+    pub fn check_method(&self, method: &str) -> Web3ProxyResult<()> {
+        let rule = self
+            .method_rules
+            .iter()
+            .chain(self.default_method_rules.iter())
+            .find(|x| x.matches(method));
+
+        match rule {
+            Some(rule) if rule.action == MethodRuleAction::Deny => Err(rule.error(method)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// how much of each request's params go in the access log
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogParams {
+    /// no params
+    Omit,
+    /// params for everything except `access_log_redact_methods`
+    #[default]
+    Redact,
+    /// every method's params. transactions and signatures included!
+    Full,
+}
+
+/// methods with signed transactions or things to sign in their params
+fn default_access_log_redact_methods() -> Vec<String> {
+    [
+        "eth_sendBundle",
+        "eth_sendPrivateTransaction",
+        "eth_sendRawTransaction*",
+        "*_sign*",
+        "personal_*",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// sends, signatures, subscriptions, and filters
+fn default_capture_skip_methods() -> Vec<String> {
+    [
+        "*_send*",
+        "*_sign*",
+        "eth_*Filter*",
+        "eth_*subscribe",
+        "personal_*",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MethodRuleAction {
+    Allow,
+    Deny,
+}
+
+/// Allow or deny methods that match glob patterns
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MethodRuleConfig {
+    /// "allow" or "deny"
+    pub action: MethodRuleAction,
+    /// the jsonrpc error code for a denied method with a `message`
+    #[serde_inline_default(-32601i64)]
+    pub code: i64,
+    /// deny with an http 403 like other access denied errors instead of a jsonrpc error
+    #[serde(default = "Default::default")]
+    pub forbidden: bool,
+    /// the error for a denied method. "{method}" is replaced with the method. None sends "Method not found"
+    pub message: Option<String>,
+    /// glob patterns. `*` matches anything. "debug_*" or "*_sign*"
+    #[serde_inline_default(vec![])]
+    pub methods: Vec<String>,
+    /// unknown config options get put here
+    #[serde(flatten, default = "HashMap::default")]
+    pub extra: HashMap<String, toml::Value>,
+}
+
+impl MethodRuleConfig {
+    fn deny(methods: &[&str]) -> Self {
+        Self {
+            action: MethodRuleAction::Deny,
+            code: -32601,
+            forbidden: false,
+            message: None,
+            methods: methods.iter().map(|x| x.to_string()).collect(),
+            extra: Default::default(),
+        }
+    }
+
+    pub fn matches(&self, method: &str) -> bool {
+        self.methods.iter().any(|x| glob_match(x, method))
+    }
+
+    fn error(&self, method: &str) -> Web3ProxyError {
+        let Some(message) = self.message.as_ref() else {
+            return Web3ProxyError::MethodNotFound(method.to_string().into());
+        };
+
+        let message = message.replace("{method}", method);
+
+        if self.forbidden {
+            Web3ProxyError::AccessDenied(message.into())
+        } else {
+            JsonRpcErrorData {
+                message: message.into(),
+                code: self.code,
+                data: None,
+            }
+            .into()
+        }
+    }
+}
+
+/// the methods that used to be hardcoded in the app
+fn builtin_method_rules() -> Vec<MethodRuleConfig> {
+    vec![
+        MethodRuleConfig::deny(&[
+            "db_*",
+            "debug_accountRange",
+            "debug_backtraceAt",
+            "debug_blockProfile",
+            "debug_bundler_clearState",
+            "debug_bundler_dumpMempool",
+            "debug_bundler_sendBundleNow",
+            "debug_chaindbCompact",
+            "debug_chaindbProperty",
+            "debug_cpuProfile",
+            "debug_freeOSMemory",
+            "debug_freezeClient",
+            "debug_gcStats",
+            "debug_goTrace",
+            "debug_memStats",
+            "debug_mutexProfile",
+            "debug_setBlockProfileRate",
+            "debug_setGCPercent",
+            "debug_setHead",
+            "debug_setMutexProfileFraction",
+            "debug_standardTraceBadBlockToFile",
+            "debug_standardTraceBlockToFile",
+            "debug_startCPUProfile",
+            "debug_startGoTrace",
+            "debug_stopCPUProfile",
+            "debug_stopGoTrace",
+            "debug_writeBlockProfile",
+            "debug_writeMemProfile",
+            "debug_writeMutexProfile",
+            "erigon_cacheCheck",
+            "eth_compileLLL",
+            "eth_compileSerpent",
+            "eth_compileSolidity",
+            "eth_getCompilers",
+            "eth_sendTransaction",
+            "eth_sign",
+            "eth_signTransaction",
+            "eth_submitHashrate",
+            "eth_submitWork",
+            "les_addBalance",
+            "les_setClientParams",
+            "les_setDefaultParams",
+            "miner_*",
+            "personal_*",
+            "shh_*",
+            "wallet_getEthereumChains",
+            "wallet_getSnaps",
+            "wallet_requestSnaps",
+        ]),
+        MethodRuleConfig {
+            forbidden: true,
+            message: Some("admin methods are not allowed".into()),
+            ..MethodRuleConfig::deny(&["admin_*"])
+        },
+        MethodRuleConfig {
+            message: Some("the method {method} does not exist/is not available".into()),
+            ..MethodRuleConfig::deny(&["alchemy_*"])
+        },
+    ]
+}
+
+/// `*` matches any number of characters, including none. everything else must match exactly
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was and the text position it is matching up to
+    let mut star = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // let the last `*` eat one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+/// Configuration for a key that can be used in `/rpc/{key}` or an `Authorization: Bearer {key}` header
+#[serde_inline_default]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct ApiKeyConfig {
+    /// this key is only for the admin api. send it as `Authorization: Bearer {key}`. it does not work for proxy requests
+    #[serde(default = "Default::default")]
+    pub admin: bool,
+    /// methods this key is allowed to call. empty allows any method that isn't denied
+    #[serde_inline_default(vec![])]
+    pub allowed_methods: Vec<String>,
+    /// "best", "fastest", "versus", or "quorum". empty allows all of them
+    #[serde_inline_default(vec![])]
+    pub allowed_proxy_modes: Vec<String>,
+    /// methods this key is not allowed to call
+    #[serde_inline_default(vec![])]
+    pub denied_methods: Vec<String>,
+    /// simple way to disable a key without deleting it
+    #[serde(default = "Default::default")]
+    pub disabled: bool,
+    /// the secret. keys without one are ignored
+    #[serde(default = "Default::default")]
+    pub key: String,
+    /// max number of http requests or websocket messages that can be in progress at once
+    pub max_concurrent_requests: Option<u32>,
+    /// jsonrpc requests per second. a batch counts each request. the burst is the same size, so bigger batches are refused
+    pub requests_per_second: Option<u32>,
+    /// unknown config options get put here
+    #[serde(flatten, default = "HashMap::default")]
+    pub extra: HashMap<String, toml::Value>,
+}
+
+impl Default for ApiKeyConfig {
+    fn default() -> Self {
+        sonic_rs::from_str("{}").unwrap()
+    }
+}
+
+impl fmt::Debug for ApiKeyConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("ApiKeyConfig")
+            .field("admin", &self.admin)
+            .field("allowed_methods", &self.allowed_methods)
+            .field("allowed_proxy_modes", &self.allowed_proxy_modes)
+            .field("denied_methods", &self.denied_methods)
+            .field("disabled", &self.disabled)
+            .field("key", &"[REDACTED]")
+            .field("max_concurrent_requests", &self.max_concurrent_requests)
+            .field("requests_per_second", &self.requests_per_second)
+            .field("extra", &self.extra)
+            .finish()
+    }
+}
+
+/// The groups of rpcs in the top config
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum RpcGroup {
+    #[default]
+    #[serde(rename = "balanced_rpcs")]
+    Balanced,
+    #[serde(rename = "private_rpcs")]
+    Private,
+    #[serde(rename = "bundler_4337_rpcs")]
+    Bundler4337,
+}
+
+impl RpcGroup {
+    /// the name of the group's section in the config
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Balanced => "balanced_rpcs",
+            Self::Private => "private_rpcs",
+            Self::Bundler4337 => "bundler_4337_rpcs",
+        }
+    }
+}
+
+/// Send some methods to a group of rpcs other than balanced_rpcs, or only to rpcs with some tags
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RouteConfig {
+    /// groups to try, in order, if `group` has no rpcs that can serve the request. tags are not checked on these
+    #[serde_inline_default(vec![])]
+    pub fallback: Vec<RpcGroup>,
+    /// "balanced_rpcs", "private_rpcs", or "bundler_4337_rpcs"
+    #[serde(default = "Default::default")]
+    pub group: RpcGroup,
+    /// glob patterns. `*` matches anything. "trace_*" or "debug_trace*"
+    #[serde_inline_default(vec![])]
+    pub methods: Vec<String>,
+    /// only rpcs in `group` that have all of these tags are used
+    #[serde_inline_default(vec![])]
+    pub tags: Vec<String>,
+    /// unknown config options get put here
+    #[serde(flatten, default = "HashMap::default")]
+    pub extra: HashMap<String, toml::Value>,
+}
+
+impl RouteConfig {
+    pub fn matches(&self, method: &str) -> bool {
+        self.methods.iter().any(|x| glob_match(x, method))
+    }
+}
+
+/// settings that are never logged
+const REDACTED_KEYS: &[&str] = &["http_url", "key", "sentry_url", "ws_url"];
+
+/// One setting that is different between two configs
+#[derive(Debug, PartialEq)]
+pub struct ConfigChange {
+    /// dotted path to the setting. "app.min_synced_rpcs"
+    pub path: String,
+    /// json of the old value. "null" if it wasn't set
+    pub old: String,
+    pub new: String,
+}
+
+/// every setting that changed. objects are compared key by key. arrays and everything else are compared whole
+/// TODO: this serializes both configs. thats fine for reloads, but don't call it on the hot path
+pub fn config_diff<T: Serialize>(old: &T, new: &T) -> Vec<ConfigChange> {
+    let old = sonic_rs::to_value(old).unwrap_or_default();
+    let new = sonic_rs::to_value(new).unwrap_or_default();
+
+    let mut changes = vec![];
+
+    diff_values("", &old, &new, &mut changes);
+
+    changes
+}
+
+fn diff_values(
+    path: &str,
+    old: &sonic_rs::Value,
+    new: &sonic_rs::Value,
+    changes: &mut Vec<ConfigChange>,
+) {
+    if old == new {
+        return;
+    }
+
+    let key = path.rsplit('.').next().unwrap_or(path);
+
+    let show = |x: &sonic_rs::Value| {
+        if REDACTED_KEYS.contains(&key) && !x.is_null() {
+            "\"[REDACTED]\"".to_string()
+        } else {
+            x.to_string()
+        }
+    };
+
+    match (old.as_object(), new.as_object()) {
+        (Some(old), Some(new)) => {
+            let keys: BTreeSet<&str> = old.iter().chain(new.iter()).map(|(k, _)| k).collect();
+
+            let null = sonic_rs::Value::default();
+
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                diff_values(
+                    &child,
+                    old.get(&key).unwrap_or(&null),
+                    new.get(&key).unwrap_or(&null),
+                    changes,
+                );
+            }
+        }
+        _ => changes.push(ConfigChange {
+            path: path.to_string(),
+            old: show(old),
+            new: show(new),
+        }),
     }
 }
 
@@ -210,6 +856,16 @@ impl<'de> Deserialize<'de> for BlockDataLimit {
     }
 }
 
+impl Serialize for BlockDataLimit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            BlockDataLimit::Archive => serializer.serialize_str("archive"),
+            BlockDataLimit::Set(x) => serializer.serialize_u64(*x),
+            BlockDataLimit::Unknown => serializer.serialize_str("unknown"),
+        }
+    }
+}
+
 impl From<BlockDataLimit> for AtomicU64 {
     fn from(value: BlockDataLimit) -> Self {
         match value {
@@ -222,7 +878,7 @@ impl From<BlockDataLimit> for AtomicU64 {
 
 /// Configuration for a backend web3 RPC server
 #[serde_inline_default]
-#[derive(Clone, Deserialize, PartialEq)]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 pub struct Web3RpcConfig {
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     #[serde(default = "Default::default")]
@@ -230,15 +886,41 @@ pub struct Web3RpcConfig {
     /// block data limit. If None, will be queried
     #[serde(default = "Default::default")]
     pub block_data_limit: BlockDataLimit,
+    /// health checks that fail in a row before the circuit breaker opens and the rpc gets no requests. 0 disables the breaker
+    #[serde_inline_default(5u32)]
+    pub circuit_breaker_failures: u32,
+    /// how long the circuit breaker stays open before a health check can close it
+    #[serde_inline_default(30u64)]
+    pub circuit_breaker_seconds: u64,
     /// simple way to disable a connection without deleting the row
     #[serde(default = "Default::default")]
     pub disabled: bool,
     /// a name used in /status and other user facing messages
     pub display_name: Option<String>,
+    /// the most requests to send in `hard_limit_period_seconds`. requests past this wait or go to another rpc
+    pub hard_limit: Option<u32>,
+    #[serde_inline_default(1u64)]
+    pub hard_limit_period_seconds: u64,
     /// while not absolutely required, a http:// or https:// connection will allow erigon to stream JSON
     pub http_url: Option<String>,
     /// while not absolutely required, a ipc connection should be fastest
     pub ipc_path: Option<PathBuf>,
+    /// the most requests to have in flight at once
+    pub max_concurrency: Option<u32>,
+    /// the widest eth_getLogs range this rpc allows. A smaller range learned from the rpc's errors is used for a while
+    pub max_get_logs_range: Option<u64>,
+    /// methods to call after connecting. any that return "method not found" are not sent to this rpc
+    #[serde(default = "Default::default")]
+    pub probe_methods: Vec<String>,
+    /// the longest wait for the first reconnect. each failure doubles it. the actual wait is random between 0 and this
+    #[serde_inline_default(1_000u64)]
+    pub reconnect_backoff_ms: u64,
+    /// the longest wait between reconnects
+    #[serde_inline_default(60_000u64)]
+    pub reconnect_backoff_max_ms: u64,
+    /// the backoff resets after being connected for this long
+    #[serde_inline_default(60u64)]
+    pub reconnect_stable_seconds: u64,
     /// the requests per second at which the server starts slowing down
     #[serde_inline_default(1u32)]
     pub soft_limit: u32,
@@ -246,6 +928,13 @@ pub struct Web3RpcConfig {
     /// Don't do this with free rpcs
     #[serde(default = "Default::default")]
     pub subscribe_txs: bool,
+    /// labels like "trace" or "archive". `[[routes]]` can send methods only to rpcs with certain tags
+    #[serde(default = "Default::default")]
+    pub tags: Vec<String>,
+    /// after a "method not found" error, the method is not sent to this rpc for this long. 0 never skips methods
+    /// the "finalized" and "safe" tags are polled this rarely once the rpc has said it doesn't support them
+    #[serde_inline_default(3_600u64)]
+    pub unsupported_method_seconds: u64,
     /// while not absolutely required, a ws:// or wss:// connection will be able to subscribe to head blocks
     pub ws_url: Option<String>,
     /// unknown config options get put here
@@ -265,12 +954,27 @@ impl fmt::Debug for Web3RpcConfig {
             .debug_struct("Web3RpcConfig")
             .field("backup", &self.backup)
             .field("block_data_limit", &self.block_data_limit)
+            .field("circuit_breaker_failures", &self.circuit_breaker_failures)
+            .field("circuit_breaker_seconds", &self.circuit_breaker_seconds)
             .field("disabled", &self.disabled)
             .field("display_name", &self.display_name)
+            .field("hard_limit", &self.hard_limit)
+            .field("hard_limit_period_seconds", &self.hard_limit_period_seconds)
             .field("http_url", &self.http_url.as_ref().map(|_| "[REDACTED]"))
             .field("ipc_path", &self.ipc_path)
+            .field("max_concurrency", &self.max_concurrency)
+            .field("max_get_logs_range", &self.max_get_logs_range)
+            .field("probe_methods", &self.probe_methods)
+            .field("reconnect_backoff_ms", &self.reconnect_backoff_ms)
+            .field("reconnect_backoff_max_ms", &self.reconnect_backoff_max_ms)
+            .field("reconnect_stable_seconds", &self.reconnect_stable_seconds)
             .field("soft_limit", &self.soft_limit)
             .field("subscribe_txs", &self.subscribe_txs)
+            .field("tags", &self.tags)
+            .field(
+                "unsupported_method_seconds",
+                &self.unsupported_method_seconds,
+            )
             .field("ws_url", &self.ws_url.as_ref().map(|_| "[REDACTED]"))
             .field("extra", &self.extra)
             .finish()
@@ -314,7 +1018,11 @@ impl Web3RpcConfig {
 
 #[cfg(test)]
 mod tests {
-    use super::{AppConfig, TopConfig, Web3RpcConfig};
+    use super::{
+        config_diff, glob_match, AppConfig, BlockDataLimit, RpcGroup, TopConfig, Web3RpcConfig,
+    };
+    use crate::errors::Web3ProxyError;
+    use alloy::primitives::U256;
     use sonic_rs::json;
     use std::env;
 
@@ -359,10 +1067,287 @@ mod tests {
 
         assert_eq!(
             format!("{config:?}"),
-            "Web3RpcConfig { backup: false, block_data_limit: Unknown, disabled: false, display_name: None, http_url: Some(\"[REDACTED]\"), ipc_path: None, soft_limit: 1, subscribe_txs: false, ws_url: Some(\"[REDACTED]\"), extra: {} }"
+            "Web3RpcConfig { backup: false, block_data_limit: Unknown, circuit_breaker_failures: 5, circuit_breaker_seconds: 30, disabled: false, display_name: None, hard_limit: None, hard_limit_period_seconds: 1, http_url: Some(\"[REDACTED]\"), ipc_path: None, max_concurrency: None, max_get_logs_range: None, probe_methods: [], reconnect_backoff_ms: 1000, reconnect_backoff_max_ms: 60000, reconnect_stable_seconds: 60, soft_limit: 1, subscribe_txs: false, tags: [], unsupported_method_seconds: 3600, ws_url: Some(\"[REDACTED]\"), extra: {} }"
+        );
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let old = AppConfig::default();
+
+        assert!(config_diff(&old, &old).is_empty());
+
+        let new = AppConfig {
+            min_synced_rpcs: 2,
+            sentry_url: Some("https://public@sentry.example.com/1".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let changes = config_diff(&old, &new);
+
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|x| (x.path.as_str(), x.old.as_str(), x.new.as_str()))
+            .collect();
+
+        assert_eq!(
+            changes,
+            vec![
+                ("min_synced_rpcs", "1", "2"),
+                ("sentry_url", "null", "\"[REDACTED]\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn top_config_diff_redacts_urls() {
+        let old = TopConfig::from_toml_str(
+            r#"
+                [app]
+                chain_id = 1
+
+                [api_keys.alice]
+                key = "old-secret"
+
+                [balanced_rpcs.local]
+                http_url = "http://127.0.0.1:8545"
+                block_data_limit = "archive"
+            "#,
+        )
+        .unwrap();
+
+        assert!(config_diff(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.api_keys.get_mut("alice").unwrap().key = "new-secret".to_string();
+        let local = new.balanced_rpcs.get_mut("local").unwrap();
+        local.http_url = Some("http://127.0.0.1:8546/secret".to_string());
+        local.block_data_limit = BlockDataLimit::Set(64);
+
+        let changes = config_diff(&old, &new);
+
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|x| (x.path.as_str(), x.old.as_str(), x.new.as_str()))
+            .collect();
+
+        assert_eq!(
+            changes,
+            vec![
+                ("api_keys.alice.key", "\"[REDACTED]\"", "\"[REDACTED]\""),
+                ("balanced_rpcs.local.block_data_limit", "\"archive\"", "64"),
+                (
+                    "balanced_rpcs.local.http_url",
+                    "\"[REDACTED]\"",
+                    "\"[REDACTED]\""
+                ),
+            ]
         );
     }
 
+    #[test]
+    fn top_config_validate() {
+        let valid = r#"
+            [app]
+            chain_id = 1
+
+            [balanced_rpcs.local]
+            http_url = "http://127.0.0.1:8545"
+        "#;
+
+        assert!(TopConfig::from_toml_str(valid).unwrap().validate().is_ok());
+
+        let no_url = r#"
+            [app]
+            chain_id = 1
+
+            [balanced_rpcs.local]
+            soft_limit = 100
+        "#;
+
+        assert!(TopConfig::from_toml_str(no_url)
+            .unwrap()
+            .validate()
+            .is_err());
+
+        let all_disabled = r#"
+            [app]
+            chain_id = 1
+
+            [balanced_rpcs.local]
+            disabled = true
+            http_url = "http://127.0.0.1:8545"
+        "#;
+
+        assert!(TopConfig::from_toml_str(all_disabled)
+            .unwrap()
+            .validate()
+            .is_err());
+
+        let bad_app = r#"
+            [app]
+            chain_id = 1
+            min_synced_rpcs = 0
+
+            [balanced_rpcs.local]
+            http_url = "http://127.0.0.1:8545"
+        "#;
+
+        assert!(TopConfig::from_toml_str(bad_app)
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn polygon_gas_increase_defaults() {
+        let mut config = TopConfig::from_toml_str(
+            r#"
+                [app]
+                chain_id = 137
+
+                [balanced_rpcs.local]
+                http_url = "http://127.0.0.1:8545"
+            "#,
+        )
+        .unwrap();
+
+        config.clean();
+
+        assert_eq!(config.app.gas_increase_min, Some(U256::from(40_000)));
+        assert_eq!(config.app.gas_increase_percent, Some(U256::from(40)));
+    }
+
+    #[test]
+    fn app_config_validate() {
+        assert!(AppConfig::default().validate().is_ok());
+
+        let x = AppConfig {
+            min_synced_rpcs: 0,
+            ..Default::default()
+        };
+        assert!(x.validate().is_err());
+
+        let x = AppConfig {
+            get_logs_chunk_size: 0,
+            ..Default::default()
+        };
+        assert!(x.validate().is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("debug_*", "debug_traceCall"));
+        assert!(glob_match("debug_*", "debug_"));
+        assert!(glob_match("*_sign*", "eth_signTransaction"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("eth_call", "eth_call"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+
+        assert!(!glob_match("debug_*", "eth_call"));
+        assert!(!glob_match("eth_call", "eth_callMany"));
+        assert!(!glob_match("a*b*c", "aXbYd"));
+    }
+
+    #[test]
+    fn method_rules() {
+        let config = TopConfig::from_toml_str(
+            r#"
+                [app]
+                chain_id = 1
+
+                [[app.method_rules]]
+                action = "allow"
+                methods = ["debug_*"]
+
+                [[app.method_rules]]
+                action = "deny"
+                methods = ["trace_*"]
+                message = "{method} is for paying customers"
+                code = -32000
+
+                [balanced_rpcs]
+            "#,
+        )
+        .unwrap();
+
+        let app = config.app;
+
+        // allowed before the defaults can deny it
+        assert!(app.check_method("debug_setHead").is_ok());
+        assert!(app.check_method("eth_call").is_ok());
+
+        match app.check_method("trace_block") {
+            Err(Web3ProxyError::JsonRpcErrorData(x)) => {
+                assert_eq!(x.code, -32000);
+                assert_eq!(x.message, "trace_block is for paying customers");
+            }
+            x => panic!("unexpected {x:?}"),
+        }
+
+        // the defaults still apply
+        assert!(matches!(
+            app.check_method("personal_sign"),
+            Err(Web3ProxyError::MethodNotFound(_))
+        ));
+        assert!(matches!(
+            app.check_method("admin_peers"),
+            Err(Web3ProxyError::AccessDenied(_))
+        ));
+        assert!(matches!(
+            app.check_method("alchemy_getTokenBalances"),
+            Err(Web3ProxyError::JsonRpcErrorData(_))
+        ));
+
+        // without the defaults
+        let app = AppConfig {
+            default_method_rules: vec![],
+            ..Default::default()
+        };
+        assert!(app.check_method("personal_sign").is_ok());
+    }
+
+    #[test]
+    fn top_config_routes() {
+        let config = TopConfig::from_toml_str(
+            r#"
+                [app]
+                chain_id = 1
+
+                [balanced_rpcs.local]
+                http_url = "http://127.0.0.1:8545"
+                tags = ["trace"]
+
+                [[routes]]
+                methods = ["trace_*", "debug_trace*"]
+                tags = ["trace"]
+                fallback = ["private_rpcs"]
+
+                [[routes]]
+                methods = ["eth_sendUserOperation"]
+                group = "bundler_4337_rpcs"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.balanced_rpcs["local"].tags, vec!["trace"]);
+
+        let [trace, bundler] = &config.routes[..] else {
+            panic!("expected 2 routes");
+        };
+
+        assert_eq!(trace.group, RpcGroup::Balanced);
+        assert_eq!(trace.fallback, vec![RpcGroup::Private]);
+        assert!(trace.matches("trace_block"));
+        assert!(trace.matches("debug_traceTransaction"));
+        assert!(!trace.matches("debug_getBadBlocks"));
+        assert!(!trace.matches("eth_call"));
+
+        assert_eq!(bundler.group, RpcGroup::Bundler4337);
+        assert!(bundler.matches("eth_sendUserOperation"));
+        assert!(!bundler.matches("eth_sendUserOperationX"));
+    }
+
     #[test]
     fn top_config_expands_environment_variables() {
         const VARIABLE: &str = "WEB3_PROXY_TEST_RPC_URL";
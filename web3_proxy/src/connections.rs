@@ -0,0 +1,248 @@
+//! Counts of open websockets, subscriptions, and in-flight requests. Used for `/status` and to drain them on shutdown.
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{info, warn};
+
+/// sent in the websocket close frame when the server is shutting down. 1001 is "going away"
+pub const GOING_AWAY_CODE: u16 = 1001;
+pub const GOING_AWAY_REASON: &str = "server shutting down. please reconnect";
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Request,
+    Subscription,
+    Websocket,
+}
+
+#[derive(Debug)]
+pub struct Connections {
+    in_flight_requests: AtomicUsize,
+    open_subscriptions: AtomicUsize,
+    open_websockets: AtomicUsize,
+    /// the drain deadline. set when the app starts shutting down. websockets stop taking requests and close
+    draining: watch::Sender<Option<Instant>>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self {
+            in_flight_requests: Default::default(),
+            open_subscriptions: Default::default(),
+            open_websockets: Default::default(),
+            draining: watch::channel(None).0,
+        }
+    }
+}
+
+/// decrements the count when dropped
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    connections: Arc<Connections>,
+    kind: Kind,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections
+            .counter(self.kind)
+            .fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+impl Connections {
+    fn counter(&self, kind: Kind) -> &AtomicUsize {
+        match kind {
+            Kind::Request => &self.in_flight_requests,
+            Kind::Subscription => &self.open_subscriptions,
+            Kind::Websocket => &self.open_websockets,
+        }
+    }
+
+    fn guard(self: &Arc<Self>, kind: Kind) -> ConnectionGuard {
+        self.counter(kind).fetch_add(1, atomic::Ordering::SeqCst);
+
+        ConnectionGuard {
+            connections: self.clone(),
+            kind,
+        }
+    }
+
+    /// hold this until the response is sent
+    pub fn request(self: &Arc<Self>) -> ConnectionGuard {
+        self.guard(Kind::Request)
+    }
+
+    /// hold this until the subscription ends
+    pub fn subscription(self: &Arc<Self>) -> ConnectionGuard {
+        self.guard(Kind::Subscription)
+    }
+
+    /// hold this until the websocket is closed
+    pub fn websocket(self: &Arc<Self>) -> ConnectionGuard {
+        self.guard(Kind::Websocket)
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight_requests.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn open_subscriptions(&self) -> usize {
+        self.open_subscriptions.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn open_websockets(&self) -> usize {
+        self.open_websockets.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.borrow().is_some()
+    }
+
+    /// None until draining starts
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        *self.draining.borrow()
+    }
+
+    /// changes to the deadline when draining starts
+    pub fn draining_receiver(&self) -> watch::Receiver<Option<Instant>> {
+        self.draining.subscribe()
+    }
+
+    /// returns the deadline. calling this again keeps the first deadline
+    pub fn start_draining(&self, drain_time: Duration) -> Instant {
+        let mut deadline = Instant::now() + drain_time;
+
+        let started = self.draining.send_if_modified(|x| match x {
+            Some(old) => {
+                deadline = *old;
+                false
+            }
+            None => {
+                *x = Some(deadline);
+                true
+            }
+        });
+
+        if started {
+            info!(
+                websockets = self.open_websockets(),
+                subscriptions = self.open_subscriptions(),
+                requests = self.in_flight_requests(),
+                ?drain_time,
+                "draining connections"
+            );
+        }
+
+        deadline
+    }
+
+    /// wait until every websocket and request is done. progress is logged every second. false if `deadline` passed first
+    pub async fn wait_drained(&self, deadline: Instant) -> bool {
+        let mut last_log = Instant::now();
+
+        loop {
+            let websockets = self.open_websockets();
+            let requests = self.in_flight_requests();
+
+            if websockets == 0 && requests == 0 {
+                info!("connections drained");
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                warn!(
+                    websockets,
+                    subscriptions = self.open_subscriptions(),
+                    requests,
+                    "drain deadline passed! the rest of the connections will be cut off"
+                );
+                return false;
+            }
+
+            if last_log.elapsed() >= Duration::from_secs(1) {
+                info!(
+                    websockets,
+                    subscriptions = self.open_subscriptions(),
+                    requests,
+                    "waiting for connections to drain"
+                );
+                last_log = Instant::now();
+            }
+
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Serialize for Connections {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Connections", 4)?;
+
+        state.serialize_field("draining", &self.is_draining())?;
+        state.serialize_field("in_flight_requests", &self.in_flight_requests())?;
+        state.serialize_field("open_subscriptions", &self.open_subscriptions())?;
+        state.serialize_field("open_websockets", &self.open_websockets())?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connections;
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connections() {
+        let x = Arc::new(Connections::default());
+
+        let websocket = x.websocket();
+        let subscription = x.subscription();
+        let request = x.request();
+
+        assert_eq!(x.open_websockets(), 1);
+        assert_eq!(x.open_subscriptions(), 1);
+        assert_eq!(x.in_flight_requests(), 1);
+
+        let mut draining = x.draining_receiver();
+        assert!(draining.borrow_and_update().is_none());
+
+        let deadline = x.start_draining(Duration::from_secs(30));
+        assert!(x.is_draining());
+        assert!(draining.has_changed().unwrap());
+        assert_eq!(x.drain_deadline(), Some(deadline));
+
+        // the first deadline is kept
+        assert_eq!(x.start_draining(Duration::from_secs(1)), deadline);
+
+        // the request never finishes
+        assert!(
+            !x.wait_drained(Instant::now() + Duration::from_secs(1))
+                .await
+        );
+
+        drop(request);
+        assert_eq!(x.in_flight_requests(), 0);
+
+        // the websocket is still open
+        assert!(
+            !x.wait_drained(Instant::now() + Duration::from_secs(1))
+                .await
+        );
+
+        drop(subscription);
+        drop(websocket);
+        assert!(
+            x.wait_drained(Instant::now() + Duration::from_secs(1))
+                .await
+        );
+
+        assert_eq!(x.open_websockets(), 0);
+        assert_eq!(x.open_subscriptions(), 0);
+    }
+}
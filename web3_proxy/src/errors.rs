@@ -12,7 +12,7 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use derive_more::{Display, Error, From};
-use http::header::InvalidHeaderValue;
+use http::header::{self, InvalidHeaderValue};
 use http::uri::InvalidUri;
 use reqwest::header::ToStrError;
 use serde::Serialize;
@@ -42,6 +42,24 @@ pub struct RangeTooLargeError {
     pub allowed: U64,
 }
 
+/// What one rpc said during a quorum request
+#[derive(Debug, Serialize)]
+pub struct QuorumVote {
+    pub rpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+}
+
+#[derive(Debug, Display)]
+#[display("{}/{} agreed", max_agreed, min_agree)]
+pub struct QuorumError {
+    pub min_agree: usize,
+    pub max_agreed: usize,
+    pub votes: Vec<QuorumVote>,
+}
+
 #[derive(Debug, Display, Error, From)]
 pub enum Web3ProxyError {
     #[error(ignore)]
@@ -114,6 +132,9 @@ pub enum Web3ProxyError {
     NotFound,
     #[error(ignore)]
     #[from(ignore)]
+    QuorumNotReached(Box<QuorumError>),
+    #[error(ignore)]
+    #[from(ignore)]
     MethodNotFound(Cow<'static, str>),
     #[error(ignore)]
     #[from(ignore)]
@@ -127,6 +148,11 @@ pub enum Web3ProxyError {
     #[error(ignore)]
     #[from(ignore)]
     RangeTooLarge(Box<RangeTooLargeError>),
+    /// the time until the request would be allowed
+    #[display("{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    RateLimited(Option<Duration>),
     Reqwest(reqwest::Error),
     SemaphoreAcquireError(AcquireError),
     Sonic(sonic_rs::Error),
@@ -137,6 +163,7 @@ pub enum Web3ProxyError {
     #[display("{:?}", _0)]
     #[error(ignore)]
     Timeout(Option<Duration>),
+    UnknownApiKey,
     #[error(ignore)]
     UnknownBlockHash(B256),
     #[display("known: {known}, unknown: {unknown}")]
@@ -657,6 +684,27 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::QuorumNotReached(quorum) => {
+                let QuorumError {
+                    min_agree,
+                    max_agreed,
+                    votes,
+                } = quorum.as_ref();
+                warn!(%min_agree, %max_agreed, ?votes, "QuorumNotReached");
+                (
+                    StatusCode::BAD_GATEWAY,
+                    JsonRpcErrorData {
+                        message: "backend rpcs did not agree".into(),
+                        code: StatusCode::BAD_GATEWAY.as_u16().into(),
+                        data: Some(json!({
+                            "min_agree": min_agree,
+                            "max_agreed": max_agreed,
+                            "votes": votes,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::RangeInvalid { from, to } => {
                 trace!(?from, ?to, "RangeInvalid");
                 (
@@ -695,6 +743,19 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::RateLimited(retry_after) => {
+                trace!(?retry_after, "rate limited");
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    JsonRpcErrorData {
+                        message: "too many requests".into(),
+                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: Some(json!({
+                            "retry_after": retry_after.map(|x| x.as_secs_f32()),
+                        })),
+                    },
+                )
+            }
             Self::Reqwest(err) => {
                 warn!(?err, "reqwest");
                 (
@@ -775,6 +836,17 @@ impl Web3ProxyError {
                     method
                 );
             }
+            Self::UnknownApiKey => {
+                // TODO: emit a stat?
+                (
+                    StatusCode::NOT_FOUND,
+                    JsonRpcErrorData {
+                        message: "unknown api key".into(),
+                        code: StatusCode::NOT_FOUND.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::UnknownBlockHash(hash) => {
                 debug!(%hash, "UnknownBlockHash");
                 (
@@ -877,7 +949,18 @@ impl Web3ProxyError {
 
         let response = ParsedResponse::from_response_data(response_data, id);
 
-        jsonrpc::response::json_response(status_code, &response)
+        let mut response = jsonrpc::response::json_response(status_code, &response);
+
+        if let Self::RateLimited(Some(retry_after)) = self {
+            // whole seconds. round up so that clients don't retry too early
+            let retry_after = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+
+        response
     }
 }
 
@@ -938,8 +1021,12 @@ impl Web3ProxyError {
 
 #[cfg(test)]
 mod tests {
-    use super::Web3ProxyError;
+    use super::{QuorumError, QuorumVote, RequestForError, Web3ProxyError};
+    use crate::jsonrpc::ResponseData;
+    use http::StatusCode;
+    use sonic_rs::{json, JsonValueTrait};
     use std::mem::size_of;
+    use std::time::Duration;
 
     #[test]
     fn web3_proxy_error_fits_result_error_budget() {
@@ -951,4 +1038,47 @@ mod tests {
             "Web3ProxyError must be smaller than {CLIPPY_ERROR_SIZE_THRESHOLD} bytes; actual size is {actual_size} bytes"
         );
     }
+
+    #[test]
+    fn quorum_not_reached_lists_votes() {
+        let err = Web3ProxyError::QuorumNotReached(Box::new(QuorumError {
+            min_agree: 2,
+            max_agreed: 1,
+            votes: vec![
+                QuorumVote {
+                    rpc: "a".into(),
+                    result: Some(json!("0x1")),
+                    error: None,
+                },
+                QuorumVote {
+                    rpc: "b".into(),
+                    result: Some(json!("0x2")),
+                    error: None,
+                },
+            ],
+        }));
+
+        let (status, response_data) = err.as_response_parts(None::<RequestForError>);
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        let ResponseData::RpcError { error_data, .. } = response_data else {
+            panic!("expected an error");
+        };
+
+        let data = error_data.data.unwrap();
+        assert_eq!(data["min_agree"].as_u64(), Some(2));
+        assert_eq!(data["votes"][0]["rpc"].as_str(), Some("a"));
+        assert_eq!(data["votes"][1]["result"].as_str(), Some("0x2"));
+        assert!(data["votes"][1].get("error").is_none());
+    }
+
+    #[test]
+    fn rate_limited_sets_retry_after() {
+        let response = Web3ProxyError::RateLimited(Some(Duration::from_millis(1500)))
+            .into_response_with_id(None, None::<RequestForError>);
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "2");
+    }
 }
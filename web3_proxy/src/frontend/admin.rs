@@ -0,0 +1,400 @@
+//! The admin api listens on its own address (`admin_addr`) and needs an api key with `admin = true`.
+//!
+//! Changes are made to the running rpcs and are kept across config reloads. They are NOT saved to the config file.
+//! Every change goes in the audit log.
+
+use super::api_key::AdminAuth;
+use super::client_ip::ClientIp;
+use super::errors;
+use crate::app::App;
+use crate::audit::AuditEntry;
+use crate::config::RpcGroup;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::response::json_response;
+use crate::rpcs::one::{AdminState, Web3Rpc};
+use axum::extract::{Path, State};
+use axum::response::Response;
+use axum::routing::{delete, get, post};
+use axum::Router;
+use chrono::Utc;
+use http::StatusCode;
+use sonic_rs::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+use tower_http::trace::TraceLayer;
+use tracing::info;
+
+/// how many audit entries `/audit` returns
+const AUDIT_LIMIT: usize = 100;
+
+pub fn make_admin_router(app: Arc<App>) -> Router<()> {
+    Router::<Arc<App>>::new()
+        .route("/audit", get(audit))
+        .route("/rpcs", get(list_rpcs))
+        .route("/rpcs/{group}/{name}", get(get_rpc))
+        .route("/rpcs/{group}/{name}/check", post(check))
+        .route(
+            "/rpcs/{group}/{name}/clear_hard_limit",
+            post(clear_hard_limit),
+        )
+        .route("/rpcs/{group}/{name}/disable", post(disable))
+        .route("/rpcs/{group}/{name}/drain", post(drain))
+        .route("/rpcs/{group}/{name}/enable", post(enable))
+        .route("/rpcs/{group}/{name}/tier", delete(clear_tier))
+        .route("/rpcs/{group}/{name}/tier/{tier}", post(set_tier))
+        .layer(TraceLayer::new_for_http())
+        .fallback(errors::handler_404)
+        .with_state(app)
+}
+
+/// Start the admin server. The listener is bound by the caller so that a bad address stops startup
+pub async fn serve(
+    app: Arc<App>,
+    listener: TcpListener,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> Web3ProxyResult<()> {
+    let router = make_admin_router(app);
+
+    info!("admin api listening on {}", listener.local_addr()?);
+
+    // connection addresses are used in the audit log. trusted proxy headers are not checked here
+    let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+
+    axum::serve(listener, make_service)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown_receiver.recv().await;
+        })
+        .await
+        .map_err(Into::into)
+}
+
+fn find_rpc(app: &App, group: RpcGroup, name: &str) -> Web3ProxyResult<Arc<Web3Rpc>> {
+    app.rpcs_for_group(group)
+        .get(name)
+        .ok_or(Web3ProxyError::NotFound)
+}
+
+/// everything needed to write an audit entry
+struct AdminAction {
+    key: AdminAuth,
+    ip: ClientIp,
+    action: &'static str,
+    group: RpcGroup,
+    name: String,
+}
+
+impl AdminAction {
+    async fn record(
+        self,
+        app: &App,
+        old: Option<String>,
+        new: Option<String>,
+        error: Option<String>,
+    ) {
+        let entry = AuditEntry {
+            at: Utc::now(),
+            key_name: self.key.0.name.clone(),
+            client_ip: self.ip.0,
+            action: self.action,
+            target: format!("{}.{}", self.group.as_str(), self.name),
+            old,
+            new,
+            error,
+        };
+
+        let path = app.config.load().admin_audit_path.clone();
+
+        app.admin_audit.record(entry, path.as_deref()).await;
+    }
+}
+
+async fn audit(State(app): State<Arc<App>>, _: AdminAuth) -> Response {
+    json_response(StatusCode::OK, &app.admin_audit.recent(AUDIT_LIMIT))
+}
+
+async fn list_rpcs(State(app): State<Arc<App>>, _: AdminAuth) -> Response {
+    let x = json!({
+        "balanced_rpcs": app.balanced_rpcs,
+        "bundler_4337_rpcs": app.bundler_4337_rpcs,
+        "private_rpcs": app.protected_rpcs,
+    });
+
+    json_response(StatusCode::OK, &x)
+}
+
+async fn get_rpc(
+    State(app): State<Arc<App>>,
+    _: AdminAuth,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let rpc = find_rpc(&app, group, &name)?;
+
+    Ok(json_response(StatusCode::OK, &*rpc))
+}
+
+async fn set_admin_state(
+    app: &App,
+    action: AdminAction,
+    state: AdminState,
+) -> Web3ProxyResult<Response> {
+    let rpc = find_rpc(app, action.group, &action.name)?;
+
+    let old = rpc.set_admin_state(state);
+
+    action
+        .record(
+            app,
+            Some(json!(old).to_string()),
+            Some(json!(state).to_string()),
+            None,
+        )
+        .await;
+
+    Ok(json_response(StatusCode::OK, &*rpc))
+}
+
+/// no new requests and no part in consensus
+async fn disable(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let action = AdminAction {
+        key,
+        ip,
+        action: "disable",
+        group,
+        name,
+    };
+
+    set_admin_state(&app, action, AdminState::Disabled).await
+}
+
+/// no new requests. poll the rpc and watch `active_requests` to know when it is done
+async fn drain(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let action = AdminAction {
+        key,
+        ip,
+        action: "drain",
+        group,
+        name,
+    };
+
+    set_admin_state(&app, action, AdminState::Draining).await
+}
+
+async fn enable(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let action = AdminAction {
+        key,
+        ip,
+        action: "enable",
+        group,
+        name,
+    };
+
+    set_admin_state(&app, action, AdminState::Enabled).await
+}
+
+async fn set_tier(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name, tier)): Path<(RpcGroup, String, u32)>,
+) -> Web3ProxyResult<Response> {
+    if tier == 0 {
+        return Err(Web3ProxyError::BadRequest("tiers start at 1".into()));
+    }
+
+    let rpc = find_rpc(&app, group, &name)?;
+
+    let old = rpc.tier_override();
+
+    rpc.set_tier_override(Some(tier));
+
+    AdminAction {
+        key,
+        ip,
+        action: "tier",
+        group,
+        name,
+    }
+    .record(
+        &app,
+        Some(json!(old).to_string()),
+        Some(tier.to_string()),
+        None,
+    )
+    .await;
+
+    Ok(json_response(StatusCode::OK, &*rpc))
+}
+
+/// go back to tiers based on latency
+async fn clear_tier(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let rpc = find_rpc(&app, group, &name)?;
+
+    let old = rpc.tier_override();
+
+    rpc.set_tier_override(None);
+
+    AdminAction {
+        key,
+        ip,
+        action: "tier",
+        group,
+        name,
+    }
+    .record(
+        &app,
+        Some(json!(old).to_string()),
+        Some("null".to_string()),
+        None,
+    )
+    .await;
+
+    Ok(json_response(StatusCode::OK, &*rpc))
+}
+
+async fn clear_hard_limit(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let rpc = find_rpc(&app, group, &name)?;
+
+    rpc.clear_hard_limit_until();
+
+    AdminAction {
+        key,
+        ip,
+        action: "clear_hard_limit",
+        group,
+        name,
+    }
+    .record(&app, None, None, None)
+    .await;
+
+    Ok(json_response(StatusCode::OK, &*rpc))
+}
+
+/// run the health check now. the rpc's `healthy` and circuit breaker are updated like a normal health check
+async fn check(
+    State(app): State<Arc<App>>,
+    key: AdminAuth,
+    ip: ClientIp,
+    Path((group, name)): Path<(RpcGroup, String)>,
+) -> Web3ProxyResult<Response> {
+    let rpc = find_rpc(&app, group, &name)?;
+
+    let result = timeout(Duration::from_secs(30), rpc.check_provider_now())
+        .await
+        .map_err(Web3ProxyError::from)
+        .and_then(|x| x);
+
+    AdminAction {
+        key,
+        ip,
+        action: "check",
+        group,
+        name,
+    }
+    .record(
+        &app,
+        None,
+        None,
+        result.as_ref().err().map(|x| x.to_string()),
+    )
+    .await;
+
+    result?;
+
+    Ok(json_response(StatusCode::OK, &*rpc))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::TestFrontend;
+    use http::StatusCode;
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+
+    const CONFIG: &str = r#"
+        [app]
+        chain_id = 31337
+
+        [balanced_rpcs]
+
+        [api_keys.a]
+        key = "secret"
+
+        [api_keys.ops]
+        admin = true
+        key = "admin-secret"
+    "#;
+
+    #[test_log::test(tokio::test)]
+    async fn test_admin_auth() {
+        let x = TestFrontend::spawn(CONFIG).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_url = format!("http://{}/rpcs", listener.local_addr().unwrap());
+
+        let (shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        tokio::spawn(super::serve(x.app.clone(), listener, shutdown_receiver));
+
+        let client = reqwest::Client::new();
+
+        let get = |key: Option<&str>| {
+            let mut request = client.get(&admin_url);
+
+            if let Some(key) = key {
+                request = request.bearer_auth(key);
+            }
+
+            async move { request.send().await.unwrap().status() }
+        };
+
+        assert_eq!(get(None).await, StatusCode::FORBIDDEN);
+        assert_eq!(get(Some("secret")).await, StatusCode::FORBIDDEN);
+        assert_eq!(get(Some("unknown")).await, StatusCode::NOT_FOUND);
+        assert_eq!(get(Some("admin-secret")).await, StatusCode::OK);
+
+        // admin keys do not work on the proxy
+        let status = |key: &str| {
+            let request = client
+                .post(x.http_url("/"))
+                .bearer_auth(key)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(r#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId","params":[]}"#);
+
+            async move { request.send().await.unwrap().status() }
+        };
+
+        assert_eq!(status("admin-secret").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("secret").await, StatusCode::OK);
+
+        let _ = shutdown_sender.send(());
+    }
+}
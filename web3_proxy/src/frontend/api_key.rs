@@ -0,0 +1,104 @@
+use crate::api_keys::ApiKey;
+use crate::app::App;
+use crate::errors::Web3ProxyError;
+use axum::extract::{FromRequestParts, RawPathParams};
+use http::header::AUTHORIZATION;
+use http::request::Parts;
+use std::sync::Arc;
+
+/// The key from a `/rpc/{key}` path or an `Authorization: Bearer {key}` header. None for anonymous requests.
+/// Unknown keys are rejected.
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeyAuth(pub Option<Arc<ApiKey>>);
+
+impl FromRequestParts<Arc<App>> for ApiKeyAuth {
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app: &Arc<App>,
+    ) -> Result<Self, Self::Rejection> {
+        let from_path = RawPathParams::from_request_parts(parts, app)
+            .await
+            .ok()
+            .and_then(|params| {
+                params
+                    .iter()
+                    .find(|(k, _)| *k == "key")
+                    .map(|(_, v)| v.to_string())
+            });
+
+        let secret = from_path.or_else(|| bearer(parts));
+
+        match secret {
+            None => Ok(Self(None)),
+            Some(secret) => app
+                .api_keys
+                .get(&secret)
+                .map(|x| Self(Some(x)))
+                .ok_or(Web3ProxyError::UnknownApiKey),
+        }
+    }
+}
+
+/// A key with `admin = true` from an `Authorization: Bearer {key}` header. The admin api requires this
+#[derive(Clone, Debug)]
+pub struct AdminAuth(pub Arc<ApiKey>);
+
+impl FromRequestParts<Arc<App>> for AdminAuth {
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app: &Arc<App>,
+    ) -> Result<Self, Self::Rejection> {
+        let secret = bearer(parts).ok_or(Web3ProxyError::AccessDenied(
+            "the admin api needs an admin key".into(),
+        ))?;
+
+        match app.api_keys.get_admin(&secret) {
+            Some(key) => Ok(Self(key)),
+            None if app.api_keys.get(&secret).is_some() => Err(Web3ProxyError::AccessDenied(
+                "this key cannot use the admin api".into(),
+            )),
+            None => Err(Web3ProxyError::UnknownApiKey),
+        }
+    }
+}
+
+fn bearer(parts: &Parts) -> Option<String> {
+    let x = parts.headers.get(AUTHORIZATION)?.to_str().ok()?;
+
+    let (scheme, secret) = x.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(secret.trim().to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bearer;
+    use http::Request;
+
+    #[test]
+    fn bearer_header() {
+        let (parts, _) = Request::builder()
+            .header("authorization", "bearer  abc ")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        assert_eq!(bearer(&parts).as_deref(), Some("abc"));
+
+        let (parts, _) = Request::builder()
+            .header("authorization", "Basic abc")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        assert_eq!(bearer(&parts), None);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::app::App;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::response::Response;
+use http::request::Parts;
+use http::{HeaderMap, Request};
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+/// The client's ip. ClientIpLayer sets this from the trusted proxy headers. Without the layer, this is the tcp connection's address.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(x) = parts.extensions.get::<ClientIp>() {
+            return Ok(*x);
+        }
+
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|x| x.0.ip());
+
+        Ok(Self(ip))
+    }
+}
+
+/// Middleware layer that sets ClientIp. The per-ip limits are applied by the handlers once the api key is known
+#[derive(Clone)]
+pub struct ClientIpLayer {
+    pub app: Arc<App>,
+}
+
+impl<S> tower_layer::Layer<S> for ClientIpLayer {
+    type Service = ClientIpService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientIpService {
+            app: self.app.clone(),
+            inner,
+        }
+    }
+}
+
+/// Service used by ClientIpLayer
+#[derive(Clone)]
+pub struct ClientIpService<S> {
+    app: Arc<App>,
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for ClientIpService<S>
+where
+    S: Service<Request<Body>, Response = Response>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|x| {
+            let config = self.app.config.load();
+
+            client_ip(
+                x.0.ip(),
+                req.headers(),
+                &config.trusted_proxies,
+                &config.client_ip_headers,
+            )
+        });
+
+        req.extensions_mut().insert(ClientIp(ip));
+
+        self.inner.call(req)
+    }
+}
+
+/// The rightmost address in the trusted headers that is not one of our proxies.
+/// Addresses to the left of it could have been set by anyone.
+/// Headers are ignored unless the connection is from a trusted proxy.
+pub fn client_ip(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+    client_ip_headers: &[String],
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|x| x.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    for name in client_ip_headers {
+        let forwarded = name.eq_ignore_ascii_case("forwarded");
+
+        let mut hops = headers
+            .get_all(name.as_str())
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .collect::<Vec<_>>();
+
+        if forwarded {
+            // only the for= part of each hop is needed
+            hops = hops.into_iter().filter_map(forwarded_for).collect();
+        }
+
+        let mut leftmost = None;
+        for hop in hops.into_iter().rev() {
+            // anything past a bad address could be spoofed
+            let Some(ip) = parse_hop(hop) else {
+                break;
+            };
+
+            if !is_trusted(&ip) {
+                return ip;
+            }
+
+            leftmost = Some(ip);
+        }
+
+        // every hop was one of our proxies
+        if let Some(ip) = leftmost {
+            return ip;
+        }
+    }
+
+    peer
+}
+
+/// `for=192.0.2.60;proto=http` -> `192.0.2.60`
+fn forwarded_for(hop: &str) -> Option<&str> {
+    hop.split(';').find_map(|x| {
+        let (k, v) = x.split_once('=')?;
+
+        k.trim().eq_ignore_ascii_case("for").then_some(v.trim())
+    })
+}
+
+/// `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]:80`, and any of those in quotes
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+
+    if let Ok(x) = hop.parse::<IpAddr>() {
+        return Some(x);
+    }
+
+    if let Ok(x) = hop.parse::<SocketAddr>() {
+        return Some(x.ip());
+    }
+
+    hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, ClientIp};
+    use axum::extract::{ConnectInfo, FromRequestParts};
+    use http::{HeaderMap, Request};
+    use std::net::{IpAddr, SocketAddr};
+
+    #[tokio::test]
+    async fn connect_info_fallback() {
+        let (mut parts, _) = Request::builder()
+            .header("x-forwarded-for", "1.2.3.4")
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+            .extensions
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 8545))));
+
+        // without the layer, headers are never trusted
+        let ClientIp(ip) = ClientIp::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(ip, Some(IpAddr::from([127, 0, 0, 1])));
+
+        parts
+            .extensions
+            .insert(ClientIp(Some(IpAddr::from([5, 6, 7, 8]))));
+
+        let ClientIp(ip) = ClientIp::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(ip, Some(IpAddr::from([5, 6, 7, 8])));
+    }
+
+    #[test]
+    fn trusted_proxy_headers() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let all_headers = [
+            "cf-connecting-ip".to_string(),
+            "forwarded".to_string(),
+            "x-forwarded-for".to_string(),
+        ];
+
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let stranger = IpAddr::from([9, 9, 9, 9]);
+
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "6.6.6.6, 1.2.3.4".parse().unwrap());
+        headers.append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+
+        // the rightmost untrusted hop
+        assert_eq!(
+            client_ip(proxy, &headers, &trusted, &all_headers),
+            IpAddr::from([1, 2, 3, 4])
+        );
+
+        // anyone else can't set headers
+        assert_eq!(
+            client_ip(stranger, &headers, &trusted, &all_headers),
+            stranger
+        );
+
+        // only the configured headers are used
+        assert_eq!(
+            client_ip(proxy, &headers, &trusted, &["forwarded".to_string()]),
+            proxy
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            "for=6.6.6.6, for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.3"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            client_ip(proxy, &headers, &trusted, &all_headers),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        // earlier headers win
+        headers.insert("cf-connecting-ip", "5.6.7.8".parse().unwrap());
+        assert_eq!(
+            client_ip(proxy, &headers, &trusted, &all_headers),
+            IpAddr::from([5, 6, 7, 8])
+        );
+
+        // a bad hop stops the search
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, unknown".parse().unwrap());
+        assert_eq!(client_ip(proxy, &headers, &trusted, &all_headers), proxy);
+    }
+}
@@ -4,6 +4,9 @@
 //!
 //! There are a lot of things in tower/axum that i should have used instead of implementing here.
 // TODO: these are only public so docs are generated. What's a better way to do this?
+pub mod admin;
+pub mod api_key;
+pub mod client_ip;
 pub mod errors;
 pub mod request_id;
 pub mod rpc_proxy_http;
@@ -20,7 +23,9 @@ use axum::{
 use http::Request;
 use request_id::RequestId;
 
+use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{net::SocketAddr, sync::atomic::Ordering};
 use tokio::{net::TcpListener, process::Command, sync::broadcast};
 use tower_http::{cors::CorsLayer, normalize_path::NormalizePathLayer, trace::TraceLayer};
@@ -29,6 +34,18 @@ use tracing::{error, error_span, info, trace_span};
 #[cfg(feature = "listenfd")]
 use listenfd::ListenFd;
 
+/// keys in `/rpc/{key}` paths must not end up in the logs
+fn redacted_path(path: &str) -> Cow<'_, str> {
+    let Some(rest) = path.strip_prefix("/rpc/") else {
+        return Cow::Borrowed(path);
+    };
+
+    match rest.split_once('/') {
+        Some((_, rest)) => format!("/rpc/[REDACTED]/{}", rest).into(),
+        None => "/rpc/[REDACTED]".into(),
+    }
+}
+
 /// build our axum Router
 pub fn make_router(app: Arc<App>) -> Router<()> {
     let router = Router::<Arc<App>>::new()
@@ -64,13 +81,42 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
             "/versus/",
             post(rpc_proxy_http::versus_proxy_web3_rpc).get(rpc_proxy_ws::versus_websocket_handler),
         )
+        // public quorum
+        .route(
+            "/quorum/{min_agree}/{max_tries}",
+            post(rpc_proxy_http::quorum_proxy_web3_rpc).get(rpc_proxy_ws::quorum_websocket_handler),
+        )
+        .route(
+            "/quorum/{min_agree}/{max_tries}/",
+            post(rpc_proxy_http::quorum_proxy_web3_rpc).get(rpc_proxy_ws::quorum_websocket_handler),
+        )
+        // authenticated. the key can also be sent as a bearer token to the public routes
+        .route(
+            "/rpc/{key}",
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
+        )
+        .route(
+            "/rpc/{key}/fastest",
+            post(rpc_proxy_http::fastest_proxy_web3_rpc)
+                .get(rpc_proxy_ws::fastest_websocket_handler),
+        )
+        .route(
+            "/rpc/{key}/versus",
+            post(rpc_proxy_http::versus_proxy_web3_rpc).get(rpc_proxy_ws::versus_websocket_handler),
+        )
+        .route(
+            "/rpc/{key}/quorum/{min_agree}/{max_tries}",
+            post(rpc_proxy_http::quorum_proxy_web3_rpc).get(rpc_proxy_ws::quorum_websocket_handler),
+        )
         //
         // System things
         //
         .route("/health", get(status::health))
+        .route("/metrics", get(status::metrics))
         .route("/status", get(status::status))
         .route("/status/backups_needed", get(status::backups_needed))
-        .route("/status/debug_request", get(status::debug_request));
+        .route("/status/debug_request", get(status::debug_request))
+        .route("/status/reload", get(status::reload));
 
     // Axum layers
     // layers are ordered bottom up
@@ -104,7 +150,7 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
                     "request",
                     id = %request_id,
                     method = %request.method(),
-                    path = %request.uri().path(),
+                    path = %redacted_path(request.uri().path()),
                 );
 
                 if s.is_disabled() {
@@ -117,6 +163,8 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
                 }
             }), // .on_failure(|| todo!("on failure that has the request and response body so we can debug more easily")),
         )
+        // client ip and the per-ip http limit. this runs after the request id is set
+        .layer(client_ip::ClientIpLayer { app: app.clone() })
         .layer(request_id::RequestIdLayer)
         // 404 for any unknown routes
         .fallback(errors::handler_404)
@@ -158,9 +206,8 @@ pub async fn serve(
         TcpListener::bind(addr).await?
     };
 
-    // The frontend runs behind a trusted proxy. Client IP extraction uses the
-    // rightmost address from X-Forwarded-For.
-    let make_service = router.into_make_service();
+    // ClientIpLayer uses the connection's address unless it is one of the trusted proxies
+    let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
 
     let server = axum::serve(listener, make_service);
 
@@ -170,13 +217,20 @@ pub async fn serve(
     app.frontend_port.store(port, Ordering::SeqCst);
 
     let server = server
-        // TODO: option to use with_connect_info. we want it in dev, but not when running behind a proxy, but not
         .with_graceful_shutdown(async move {
             let _ = shutdown_receiver.recv().await;
 
-            if let Some(shutdown_script) = app.config.shutdown_script.as_ref() {
+            let config = app.config.load_full();
+
+            // /health fails from here on. the listener stays open so that load balancers can see that and /status shows the progress
+            // websockets are not part of axum's graceful shutdown. they finish their requests and close on their own
+            let deadline = app
+                .connections
+                .start_draining(Duration::from_secs(config.shutdown_drain_seconds));
+
+            if let Some(shutdown_script) = config.shutdown_script.as_ref() {
                 let shutdown_script = Command::new(shutdown_script)
-                    .args(&app.config.shutdown_script_args)
+                    .args(&config.shutdown_script_args)
                     .spawn()
                     .expect("failed to execute script");
 
@@ -189,6 +243,9 @@ pub async fn serve(
                     }
                 };
             }
+
+            // the listener is closed after this
+            app.connections.wait_drained(deadline).await;
         })
         .await
         .map_err(Into::into);
@@ -197,3 +254,19 @@ pub async fn serve(
 
     server
 }
+
+#[cfg(test)]
+mod tests {
+    use super::redacted_path;
+
+    #[test]
+    fn test_redacted_path() {
+        assert_eq!(redacted_path("/"), "/");
+        assert_eq!(redacted_path("/status"), "/status");
+        assert_eq!(redacted_path("/rpc/secret"), "/rpc/[REDACTED]");
+        assert_eq!(
+            redacted_path("/rpc/secret/quorum/2/3"),
+            "/rpc/[REDACTED]/quorum/2/3"
+        );
+    }
+}
@@ -1,54 +1,102 @@
 //! Public HTTP JSON-RPC entrypoints.
 
+use super::api_key::ApiKeyAuth;
+use super::client_ip::ClientIp;
 use super::request_id::RequestId;
-use super::rpc_proxy_ws::ProxyMode;
+use super::rpc_proxy_ws::{ProxyMode, QuorumParams};
+use crate::api_keys::ApiKey;
 use crate::errors::{RequestForError, Web3ProxyError};
 use crate::{app::App, jsonrpc::JsonRpcRequestEnum};
 use axum::body::Bytes;
 use axum::extract::rejection::BytesRejection;
-use axum::extract::State;
-use axum::http::{header, HeaderMap};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, HeaderValue};
 use axum::response::{IntoResponse, Response};
 use axum::Extension;
 use axum_macros::debug_handler;
 use itertools::Itertools;
+use std::net::IpAddr;
 use std::sync::Arc;
+use tracing::warn;
 
 #[debug_handler]
 pub async fn proxy_web3_rpc(
     State(app): State<Arc<App>>,
     Extension(RequestId(request_id)): Extension<RequestId>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
     headers: HeaderMap,
     payload: Result<Bytes, BytesRejection>,
 ) -> Response {
     let payload = parse_payload(&headers, payload);
-    proxy(app, payload, ProxyMode::Best, request_id)
-        .await
-        .unwrap_or_else(|response| *response)
+    proxy(
+        app,
+        payload,
+        ProxyMode::Best,
+        request_id,
+        client_ip,
+        api_key,
+    )
+    .await
+    .unwrap_or_else(|response| *response)
 }
 
 #[debug_handler]
 pub async fn fastest_proxy_web3_rpc(
     State(app): State<Arc<App>>,
     Extension(RequestId(request_id)): Extension<RequestId>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
     headers: HeaderMap,
     payload: Result<Bytes, BytesRejection>,
 ) -> Response {
     let payload = parse_payload(&headers, payload);
-    proxy(app, payload, ProxyMode::Fastest(0), request_id)
-        .await
-        .unwrap_or_else(|response| *response)
+    proxy(
+        app,
+        payload,
+        ProxyMode::Fastest(0),
+        request_id,
+        client_ip,
+        api_key,
+    )
+    .await
+    .unwrap_or_else(|response| *response)
 }
 
 #[debug_handler]
 pub async fn versus_proxy_web3_rpc(
     State(app): State<Arc<App>>,
     Extension(RequestId(request_id)): Extension<RequestId>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
+    headers: HeaderMap,
+    payload: Result<Bytes, BytesRejection>,
+) -> Response {
+    let payload = parse_payload(&headers, payload);
+    proxy(
+        app,
+        payload,
+        ProxyMode::Versus,
+        request_id,
+        client_ip,
+        api_key,
+    )
+    .await
+    .unwrap_or_else(|response| *response)
+}
+
+#[debug_handler]
+pub async fn quorum_proxy_web3_rpc(
+    State(app): State<Arc<App>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
+    Path(quorum): Path<QuorumParams>,
     headers: HeaderMap,
     payload: Result<Bytes, BytesRejection>,
 ) -> Response {
     let payload = parse_payload(&headers, payload);
-    proxy(app, payload, ProxyMode::Versus, request_id)
+    proxy(app, payload, quorum.into(), request_id, client_ip, api_key)
         .await
         .unwrap_or_else(|response| *response)
 }
@@ -90,11 +138,26 @@ async fn proxy(
     payload: Result<JsonRpcRequestEnum, Web3ProxyError>,
     proxy_mode: ProxyMode,
     request_id: String,
+    client_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
 ) -> Result<Response, Box<Response>> {
+    // counted so that shutdown can report on it
+    let _in_flight = app.connections.request();
+
     let payload = payload
         .map_err(|error| Box::new(error.into_response_with_id(None, None::<RequestForError>)))?;
 
     let first_id = payload.first_id();
+
+    // requests with a valid api key use the key's limits. ApiKeyAuth already rejected unknown keys
+    if let (None, Some(ip)) = (api_key.as_ref(), client_ip) {
+        app.ip_rate_limits
+            .check_request(ip, payload.methods().len())
+            .await
+            .map_err(|error| {
+                Box::new(error.into_response_with_id(first_id.clone(), None::<RequestForError>))
+            })?;
+    }
     if let Some(error_id) = payload.validate() {
         let error = Web3ProxyError::BadRequest("request failed validation".into());
         return Err(Box::new(
@@ -102,8 +165,19 @@ async fn proxy(
         ));
     }
 
-    let (status_code, response, rpcs) = app
-        .proxy_web3_rpc(proxy_mode, payload, Some(request_id))
+    // the permit is held until the response is ready
+    let _permit = match api_key.as_ref() {
+        None => None,
+        Some(api_key) => api_key
+            .check_proxy_mode(proxy_mode)
+            .and_then(|_| api_key.authorize(payload.methods()))
+            .map_err(|error| {
+                Box::new(error.into_response_with_id(first_id.clone(), None::<RequestForError>))
+            })?,
+    };
+
+    let (status_code, response, rpcs, versus) = app
+        .proxy_web3_rpc(proxy_mode, payload, Some(request_id), client_ip, api_key)
         .await
         .map_err(|error| {
             Box::new(error.into_response_with_id(first_id, None::<RequestForError>))
@@ -131,5 +205,20 @@ async fn proxy(
             .expect("the backup flag must form a header"),
     );
 
+    if !versus.is_empty() {
+        // one report for each request in the payload
+        let versus = sonic_rs::to_string(&versus).expect("versus reports must serialize");
+
+        // json escapes control characters, so from_bytes should always work
+        match HeaderValue::from_bytes(versus.as_bytes()) {
+            Ok(versus) => {
+                response.headers_mut().insert("X-W3P-VERSUS", versus);
+            }
+            Err(err) => {
+                warn!(?err, "versus report does not form a header");
+            }
+        }
+    }
+
     Ok(response)
 }
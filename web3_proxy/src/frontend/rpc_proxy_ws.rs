@@ -2,28 +2,43 @@
 //!
 //! WebSockets are the preferred method of receiving requests, but not all clients have good support.
 
+use super::api_key::ApiKeyAuth;
+use super::client_ip::ClientIp;
+use crate::api_keys::ApiKey;
+use crate::app::SubscriptionGate;
+use crate::connections::{ConnectionGuard, GOING_AWAY_CODE, GOING_AWAY_REASON};
 use crate::errors::{RequestForError, Web3ProxyError, Web3ProxyResponse};
-use crate::jsonrpc::{self, ParsedResponse, ValidatedRequest};
+use crate::jsonrpc::{self, JsonRpcRequestEnum, ParsedResponse, ValidatedRequest};
+use crate::rate_limit::SubscriptionPermit;
+use crate::rpcs::blockchain::BlockHeader;
 use crate::{app::App, errors::Web3ProxyResult, jsonrpc::SingleRequest};
 use alloy::primitives::U64;
 use axum::{
-    extract::ws::{rejection::WebSocketUpgradeRejection, Message, WebSocket, WebSocketUpgrade},
-    extract::State,
+    extract::ws::{
+        close_code, rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket,
+        WebSocketUpgrade,
+    },
+    extract::{Path, State},
     response::{IntoResponse, Redirect},
 };
 use axum_macros::debug_handler;
+use futures::future::join_all;
 use futures::SinkExt;
 use futures::{
     future::AbortHandle,
     stream::{SplitSink, SplitStream, StreamExt},
 };
 use hashbrown::HashMap;
+use serde::Deserialize;
 use sonic_rs::{json, JsonValueTrait};
+use std::net::IpAddr;
 use std::str::from_utf8;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 use tokio::select;
 use tokio::sync::{broadcast, mpsc, RwLock as AsyncRwLock};
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Instant};
 use tracing::trace;
 
 /// How to select backend servers for a request
@@ -32,10 +47,41 @@ pub enum ProxyMode {
     /// send to the "best" synced server. on error, try the next
     #[default]
     Best,
-    /// send to all synced servers and return the fastest non-error response (reverts do not count as errors here)
+    /// send to the best N synced servers (0 = all) and return the fastest non-error response (reverts do not count as errors here)
     Fastest(usize),
     /// send to all servers for benchmarking. return the fastest non-error response
+    /// http responses include a comparison of every server in the `X-W3P-VERSUS` header. websocket responses have a `versus` field
     Versus,
+    /// send to synced servers until `min_agree` of them return identical responses. try at most `max_tries` servers
+    Quorum { min_agree: usize, max_tries: usize },
+}
+
+/// `/quorum/{min_agree}/{max_tries}`
+#[derive(Debug, Deserialize)]
+pub struct QuorumParams {
+    pub min_agree: usize,
+    pub max_tries: usize,
+}
+
+impl From<QuorumParams> for ProxyMode {
+    fn from(x: QuorumParams) -> Self {
+        Self::Quorum {
+            min_agree: x.min_agree,
+            max_tries: x.max_tries,
+        }
+    }
+}
+
+impl ProxyMode {
+    /// the name used in the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Best => "best",
+            Self::Fastest(_) => "fastest",
+            Self::Versus => "versus",
+            Self::Quorum { .. } => "quorum",
+        }
+    }
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests.
@@ -43,9 +89,11 @@ pub enum ProxyMode {
 #[debug_handler]
 pub async fn websocket_handler(
     State(app): State<Arc<App>>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
     ws_upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
 ) -> Web3ProxyResponse {
-    _websocket_handler(ProxyMode::Best, app, ws_upgrade).await
+    _websocket_handler(ProxyMode::Best, app, client_ip, api_key, ws_upgrade).await
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
@@ -53,11 +101,13 @@ pub async fn websocket_handler(
 // #[debug_handler]
 pub async fn fastest_websocket_handler(
     State(app): State<Arc<App>>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
     ws_upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
 ) -> Web3ProxyResponse {
     // TODO: get the fastest number from the url params (default to 0/all)
     // TODO: config to disable this
-    _websocket_handler(ProxyMode::Fastest(0), app, ws_upgrade).await
+    _websocket_handler(ProxyMode::Fastest(0), app, client_ip, api_key, ws_upgrade).await
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
@@ -65,23 +115,47 @@ pub async fn fastest_websocket_handler(
 #[debug_handler]
 pub async fn versus_websocket_handler(
     State(app): State<Arc<App>>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
+    ws_upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Web3ProxyResponse {
+    // TODO: config to disable this
+    _websocket_handler(ProxyMode::Versus, app, client_ip, api_key, ws_upgrade).await
+}
+
+/// Public entrypoint for WebSocket JSON-RPC requests that need multiple servers to agree.
+/// Queries at least `min_agree` backends with every request! This might get expensive!
+#[debug_handler]
+pub async fn quorum_websocket_handler(
+    State(app): State<Arc<App>>,
+    ClientIp(client_ip): ClientIp,
+    ApiKeyAuth(api_key): ApiKeyAuth,
+    Path(quorum): Path<QuorumParams>,
     ws_upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
 ) -> Web3ProxyResponse {
     // TODO: config to disable this
-    _websocket_handler(ProxyMode::Versus, app, ws_upgrade).await
+    _websocket_handler(quorum.into(), app, client_ip, api_key, ws_upgrade).await
 }
 
 async fn _websocket_handler(
     proxy_mode: ProxyMode,
     app: Arc<App>,
+    client_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
     ws_upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
 ) -> Web3ProxyResponse {
+    if let Some(api_key) = api_key.as_ref() {
+        api_key.check_proxy_mode(proxy_mode)?;
+    }
+
     match ws_upgrade {
         Ok(ws) => Ok(ws
-            .on_upgrade(move |socket| proxy_web3_socket(app, proxy_mode, socket))
+            .on_upgrade(move |socket| {
+                proxy_web3_socket(app, proxy_mode, client_ip, api_key, socket)
+            })
             .into_response()),
         Err(_) => {
-            if let Some(redirect) = &app.config.redirect_public_url {
+            if let Some(redirect) = &app.config.load().redirect_public_url {
                 // this is not a websocket. redirect to a friendly page
                 Ok(Redirect::permanent(redirect).into_response())
             } else {
@@ -91,7 +165,16 @@ async fn _websocket_handler(
     }
 }
 
-async fn proxy_web3_socket(app: Arc<App>, proxy_mode: ProxyMode, socket: WebSocket) {
+/// the permit counts against the client's open subscriptions until the subscription is removed
+type Subscription = (AbortHandle, Option<SubscriptionPermit>);
+
+async fn proxy_web3_socket(
+    app: Arc<App>,
+    proxy_mode: ProxyMode,
+    client_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
+    socket: WebSocket,
+) {
     // split the websocket so we can read and write concurrently
     let (ws_tx, ws_rx) = socket.split();
 
@@ -101,20 +184,48 @@ async fn proxy_web3_socket(app: Arc<App>, proxy_mode: ProxyMode, socket: WebSock
     // TODO: this should be bounded. async blocking on too many messages would be fine
     let (response_sender, response_receiver) = mpsc::channel::<Message>(buffer);
 
-    tokio::spawn(write_web3_socket(response_receiver, ws_tx));
-    tokio::spawn(read_web3_socket(app, proxy_mode, ws_rx, response_sender));
+    // the socket is counted as open until the writer is done
+    let connection_guard = app.connections.websocket();
+
+    tokio::spawn(write_web3_socket(
+        response_receiver,
+        ws_tx,
+        connection_guard,
+    ));
+    tokio::spawn(read_web3_socket(
+        app,
+        proxy_mode,
+        client_ip,
+        api_key,
+        ws_rx,
+        response_sender,
+    ));
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn websocket_proxy_web3_rpc(
     app: &Arc<App>,
     proxy_mode: ProxyMode,
+    client_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
+    head_block: Option<BlockHeader>,
     json_request: SingleRequest,
     response_sender: &mpsc::Sender<Message>,
+    gate: &SubscriptionGate,
     subscription_count: &AtomicU64,
-    subscriptions: &AsyncRwLock<HashMap<U64, AbortHandle>>,
+    subscriptions: &AsyncRwLock<HashMap<U64, Subscription>>,
 ) -> Web3ProxyResult<jsonrpc::Response> {
     match &json_request.method[..] {
         "eth_subscribe" => {
+            // everything else is checked in proxy_request
+            app.config.load().check_method(&json_request.method)?;
+
+            // api keys have their own limits
+            let permit = match (api_key.as_ref(), client_ip) {
+                (None, Some(ip)) => app.ip_rate_limits.try_subscribe(ip)?,
+                _ => None,
+            };
+
             let web3_request = ValidatedRequest::new_with_app(
                 app,
                 proxy_mode,
@@ -122,12 +233,19 @@ async fn websocket_proxy_web3_rpc(
                 json_request.into(),
                 None,
                 None,
+                client_ip,
+                api_key,
             )
             .await?;
 
             // TODO: how can we subscribe with proxy_mode?
             match app
-                .eth_subscribe(web3_request, subscription_count, response_sender.clone())
+                .eth_subscribe(
+                    web3_request,
+                    subscription_count,
+                    response_sender.clone(),
+                    gate.clone(),
+                )
                 .await
             {
                 Ok((handle, response)) => {
@@ -137,10 +255,12 @@ async fn websocket_proxy_web3_rpc(
                     {
                         let mut x = subscriptions.write().await;
 
-                        let subscription_id = sonic_rs::to_value(subscription_id.as_ref()).unwrap();
-                        let key: U64 = sonic_rs::from_value(&subscription_id).unwrap();
+                        // lazy values don't convert to a Value. go through the json string instead
+                        let subscription_id =
+                            sonic_rs::to_string(subscription_id.as_ref()).unwrap();
+                        let key: U64 = sonic_rs::from_str(&subscription_id).unwrap();
 
-                        x.insert(key, handle);
+                        x.insert(key, (handle, permit));
                     }
 
                     Ok(response.into())
@@ -156,6 +276,8 @@ async fn websocket_proxy_web3_rpc(
                 json_request.into(),
                 None,
                 None,
+                client_ip,
+                api_key,
             )
             .await?;
 
@@ -182,7 +304,7 @@ async fn websocket_proxy_web3_rpc(
                 let mut x = subscriptions.write().await;
                 match x.remove(&subscription_id) {
                     None => false,
-                    Some(handle) => {
+                    Some((handle, _permit)) => {
                         handle.abort();
                         true
                     }
@@ -195,72 +317,195 @@ async fn websocket_proxy_web3_rpc(
             let response = jsonrpc::SingleResponse::Parsed(response);
 
             web3_request.set_response(&response);
+            app.record_access(&web3_request);
             let response = response.parsed().await.expect("Response already parsed");
 
             Ok(response.into())
         }
-        _ => app
-            .proxy_web3_rpc(proxy_mode, json_request.into(), None)
-            .await
-            .map(|(_, response, _)| response),
+        _ => {
+            let (_, response, _, versus) = app
+                .proxy_request(
+                    json_request,
+                    proxy_mode,
+                    head_block,
+                    None,
+                    client_ip,
+                    api_key,
+                )
+                .await;
+
+            // there are no headers on a websocket message. the report goes in the response instead
+            if versus.is_some() {
+                let mut response = response.parsed().await?;
+
+                response.versus = versus;
+
+                return Ok(response.into());
+            }
+
+            Ok(jsonrpc::Response::Single(response))
+        }
     }
 }
 
 /// websockets support a few more methods than http clients
+#[allow(clippy::too_many_arguments)]
 async fn handle_socket_payload(
     app: &Arc<App>,
     proxy_mode: ProxyMode,
+    client_ip: Option<IpAddr>,
+    api_key: Option<&Arc<ApiKey>>,
     payload: &str,
     response_sender: &mpsc::Sender<Message>,
+    gate: &SubscriptionGate,
     subscription_count: &AtomicU64,
-    subscriptions: Arc<AsyncRwLock<HashMap<U64, AbortHandle>>>,
+    subscriptions: Arc<AsyncRwLock<HashMap<U64, Subscription>>>,
 ) -> Web3ProxyResult<Message> {
-    // TODO: handle batched requests
-    let (response_id, response) = match sonic_rs::from_str::<SingleRequest>(payload) {
-        Ok(json_request) => {
-            let request_id = json_request.id.clone();
+    // counted so that shutdown can wait for it
+    let _in_flight = app.connections.request();
+
+    let json_request = match sonic_rs::from_str::<JsonRpcRequestEnum>(payload) {
+        Ok(x) => x,
+        Err(err) => {
+            return Ok(Web3ProxyError::from(err).into_message(None, None::<RequestForError>));
+        }
+    };
+
+    // the permit is held until the response is ready. anonymous clients are limited by ip instead
+    let authorized = match (api_key, client_ip) {
+        (Some(api_key), _) => api_key.authorize(json_request.methods()),
+        (None, Some(ip)) => app
+            .ip_rate_limits
+            .check_ws_message(ip, json_request.methods().len())
+            .await
+            .map(|_| None),
+        (None, None) => Ok(None),
+    };
+    let _permit = match authorized {
+        Ok(x) => x,
+        Err(err) => {
+            return Ok(err.into_message(json_request.first_id(), None::<RequestForError>));
+        }
+    };
 
-            // TODO: move this to a seperate function so we can use the try operator
-            let x = websocket_proxy_web3_rpc(
+    let response_str = match json_request {
+        JsonRpcRequestEnum::Single(json_request) => {
+            handle_socket_request(
                 app,
                 proxy_mode,
+                client_ip,
+                api_key.cloned(),
+                None,
                 json_request,
                 response_sender,
+                gate,
                 subscription_count,
                 &subscriptions,
             )
+            .await
+            .to_json_string()
+            .await?
+        }
+        JsonRpcRequestEnum::Batch(json_requests) => {
+            if json_requests.is_empty() {
+                return Err(Web3ProxyError::BadRequest("empty batch".into()));
+            }
+
+            // get the head block now so that any requests that need it all use the same block (like http batches)
+            let head_block = app.balanced_rpcs.head_block();
+
+            // subscriptions and ordinary calls are all handled concurrently. join_all keeps the order of the responses
+            // TODO: use streams and buffers so we don't overwhelm our server
+            let responses = join_all(json_requests.into_iter().map(|json_request| {
+                handle_socket_request(
+                    app,
+                    proxy_mode,
+                    client_ip,
+                    api_key.cloned(),
+                    head_block.clone(),
+                    json_request,
+                    response_sender,
+                    gate,
+                    subscription_count,
+                    &subscriptions,
+                )
+            }))
             .await;
 
-            (request_id, x)
+            let mut collected = Vec::with_capacity(responses.len());
+            for response in responses {
+                match response {
+                    jsonrpc::Response::Single(x) => collected.push(x.parsed().await?),
+                    jsonrpc::Response::Batch(x) => collected.extend(x),
+                }
+            }
+
+            jsonrpc::Response::Batch(collected).to_json_string().await?
         }
-        Err(err) => (Default::default(), Err(err.into())),
     };
 
-    let response_str = match response {
-        Ok(x) => x.to_json_string().await?,
+    Ok(Message::Text(response_str.into()))
+}
+
+/// handle one request from a single or batched payload. errors are turned into responses with the request's id
+#[allow(clippy::too_many_arguments)]
+async fn handle_socket_request(
+    app: &Arc<App>,
+    proxy_mode: ProxyMode,
+    client_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
+    head_block: Option<BlockHeader>,
+    json_request: SingleRequest,
+    response_sender: &mpsc::Sender<Message>,
+    gate: &SubscriptionGate,
+    subscription_count: &AtomicU64,
+    subscriptions: &AsyncRwLock<HashMap<U64, Subscription>>,
+) -> jsonrpc::Response {
+    let response_id = json_request.id.clone();
+
+    match websocket_proxy_web3_rpc(
+        app,
+        proxy_mode,
+        client_ip,
+        api_key,
+        head_block,
+        json_request,
+        response_sender,
+        gate,
+        subscription_count,
+        subscriptions,
+    )
+    .await
+    {
+        Ok(x) => x,
         Err(err) => {
             let (_, response_data) = err.as_response_parts(None::<RequestForError>);
 
-            let response = ParsedResponse::from_response_data(response_data, response_id);
-
-            sonic_rs::to_string(&response).expect("to_string should always work here")
+            ParsedResponse::from_response_data(response_data, response_id).into()
         }
-    };
-
-    Ok(Message::Text(response_str.into()))
+    }
 }
 
 async fn read_web3_socket(
     app: Arc<App>,
     proxy_mode: ProxyMode,
+    client_ip: Option<IpAddr>,
+    mut api_key: Option<Arc<ApiKey>>,
     mut ws_rx: SplitStream<WebSocket>,
     response_sender: mpsc::Sender<Message>,
 ) {
+    let mut api_keys_changed = app.api_keys.subscribe();
+
     let subscriptions = Arc::new(AsyncRwLock::new(HashMap::new()));
     let subscription_count = Arc::new(AtomicU64::new(1));
 
     let (close_sender, mut close_receiver) = broadcast::channel(1);
 
+    let mut draining = app.connections.draining_receiver();
+
+    // this socket's in-flight requests. draining waits for these and not for the rest of the app
+    let mut in_flight = JoinSet::new();
+
     loop {
         select! {
             msg = ws_rx.next() => {
@@ -271,16 +516,24 @@ async fn read_web3_socket(
                     let response_sender = response_sender.clone();
                     let subscriptions = subscriptions.clone();
                     let subscription_count = subscription_count.clone();
+                    let api_key = api_key.clone();
 
                     let f = async move {
+                        // subscriptions started by this message wait until its response is queued
+                        let gate = SubscriptionGate::default();
+                        let held = gate.hold();
+
                         // new message from our client. forward to a backend and then send it through response_sender
                         let response_msg = match msg {
                             Message::Text(payload) => {
                                 match handle_socket_payload(
                                     &app,
                                     proxy_mode,
+                                    client_ip,
+                                    api_key.as_ref(),
                                     &payload,
                                     &response_sender,
+                                    &gate,
                                     &subscription_count,
                                     subscriptions,
                                 )
@@ -302,7 +555,6 @@ async fn read_web3_socket(
                             }
                             Message::Close(_) => {
                                 trace!("closing websocket connection");
-                                // TODO: do something to close subscriptions?
                                 let _ = close_sender.send(true);
                                 return;
                             }
@@ -312,8 +564,11 @@ async fn read_web3_socket(
                                 let m = match handle_socket_payload(
                                     &app,
                                     proxy_mode,
+                                    client_ip,
+                                    api_key.as_ref(),
                                     payload,
                                     &response_sender,
+                                    &gate,
                                     &subscription_count,
                                     subscriptions,
                                 )
@@ -339,43 +594,219 @@ async fn read_web3_socket(
                         if response_sender.send(response_msg).await.is_err() {
                             let _ = close_sender.send(true);
                         };
+
+                        drop(held);
                     };
 
-                    tokio::spawn(f);
+                    in_flight.spawn(f);
                 } else {
                     break;
                 }
             }
+            Some(_) = in_flight.join_next() => {}
             _ = close_receiver.recv() => {
                 break;
             }
+            Ok(_) = api_keys_changed.changed(), if api_key.is_some() => {
+                // a reload can change or revoke the key that opened this socket
+                match api_key.as_ref().and_then(|x| app.api_keys.current(x)) {
+                    Some(x) => api_key = Some(x),
+                    None => {
+                        let close = CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "api key revoked".into(),
+                        };
+
+                        let _ = response_sender.send(Message::Close(Some(close))).await;
+
+                        break;
+                    }
+                }
+            }
+            deadline = async { draining.wait_for(Option::is_some).await.ok().and_then(|x| *x) } => {
+                let deadline = deadline.unwrap_or_else(Instant::now);
+
+                // no new requests. let the in-flight ones finish, then tell the client to reconnect
+                let _ = timeout_at(deadline, async {
+                    while in_flight.join_next().await.is_some() {}
+                })
+                .await;
+
+                let close = CloseFrame {
+                    code: GOING_AWAY_CODE,
+                    reason: GOING_AWAY_REASON.into(),
+                };
+
+                let _ = response_sender.send(Message::Close(Some(close))).await;
+
+                break;
+            }
         }
     }
+
+    // requests that are still running finish on their own
+    in_flight.detach_all();
+
+    // the client is gone or the server is shutting down. nothing else will be sent to the subscriptions
+    for (_, (handle, _permit)) in subscriptions.write().await.drain() {
+        handle.abort();
+    }
 }
 
 async fn write_web3_socket(
     mut response_rx: mpsc::Receiver<Message>,
     mut ws_tx: SplitSink<WebSocket, Message>,
+    _connection_guard: ConnectionGuard,
 ) {
-    // TODO: increment counter for open websockets
-
     while let Some(msg) = response_rx.recv().await {
         // a response is ready
 
+        let is_close = matches!(msg, Message::Close(_));
+
         // forward the response to through the websocket
         if let Err(err) = ws_tx.send(msg).await {
             // this is common. it happens whenever a client disconnects
             trace!("unable to write to websocket: {:?}", err);
             break;
         };
-    }
 
-    // TODO: decrement counter for open websockets
+        // nothing can be sent after a close frame
+        if is_close {
+            break;
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use sonic_rs::{OwnedLazyValue, Value};
+    use crate::config::TopConfig;
+    use crate::test_utils::TestFrontend;
+    use futures::{SinkExt, StreamExt};
+    use sonic_rs::{json, JsonContainerTrait, JsonValueTrait, OwnedLazyValue, Value};
+    use tokio::net::TcpStream;
+    use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    const CONFIG: &str = r#"
+        [app]
+        chain_id = 31337
+
+        [balanced_rpcs]
+    "#;
+
+    async fn send(socket: &mut Socket, request: Value) {
+        socket
+            .send(Message::Text(request.to_string().into()))
+            .await
+            .unwrap();
+    }
+
+    /// the next text message
+    async fn next_json(socket: &mut Socket) -> Value {
+        loop {
+            let msg = timeout(Duration::from_secs(5), socket.next())
+                .await
+                .expect("no message from the proxy")
+                .unwrap()
+                .unwrap();
+
+            if let Message::Text(x) = msg {
+                return sonic_rs::from_str(&x).unwrap();
+            }
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_mixed_batch() {
+        let x = TestFrontend::spawn(CONFIG).await;
+
+        let (mut socket, _) = connect_async(x.ws_url("/")).await.unwrap();
+
+        send(
+            &mut socket,
+            json!([
+                {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []},
+                {"jsonrpc": "2.0", "id": 2, "method": "eth_subscribe", "params": ["newHeads"]},
+                {"jsonrpc": "2.0", "id": 3, "method": "eth_unsubscribe", "params": ["0x999"]},
+                {"jsonrpc": "2.0", "id": 4, "method": "eth_subscribe", "params": ["notAThing"]},
+                {"jsonrpc": "2.0", "id": 5, "method": "eth_subscribe", "params": ["newHeads"]},
+            ]),
+        )
+        .await;
+
+        // one message for the whole batch. in the same order as the requests
+        let response = next_json(&mut socket).await;
+        let response = response.as_array().expect("a batch response");
+
+        let ids: Vec<_> = response.iter().map(|x| x["id"].as_u64()).collect();
+        assert_eq!(ids, [Some(1), Some(2), Some(3), Some(4), Some(5)]);
+
+        assert_eq!(response[0]["result"].as_str(), Some("0x7a69"));
+        assert_eq!(response[2]["result"].as_bool(), Some(false));
+        assert!(response[3]["error"].is_object());
+
+        // subscriptions inside a batch get their own ids
+        let a = response[1]["result"].as_str().expect("a subscription id");
+        let b = response[4]["result"].as_str().expect("a subscription id");
+        assert_ne!(a, b);
+
+        // and can be unsubscribed like any other subscription
+        send(
+            &mut socket,
+            json!({"jsonrpc": "2.0", "id": 6, "method": "eth_unsubscribe", "params": [a]}),
+        )
+        .await;
+
+        let response = next_json(&mut socket).await;
+        assert_eq!(response["id"].as_u64(), Some(6));
+        assert_eq!(response["result"].as_bool(), Some(true));
+
+        assert_eq!(x.app.connections.open_subscriptions(), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_revoked_key_closes_socket() {
+        let x = TestFrontend::spawn(&format!("{}\n[api_keys.a]\nkey = \"secret\"\n", CONFIG)).await;
+
+        let (mut socket, _) = connect_async(x.ws_url("/rpc/secret")).await.unwrap();
+
+        send(
+            &mut socket,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []}),
+        )
+        .await;
+        assert_eq!(next_json(&mut socket).await["id"].as_u64(), Some(1));
+
+        // the same path as editing the config file
+        x.new_top_config
+            .send_replace(TopConfig::from_toml_str(CONFIG).unwrap());
+
+        let msg = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no message from the proxy")
+            .unwrap()
+            .unwrap();
+
+        let Message::Close(Some(close)) = msg else {
+            panic!("expected a close frame. got {:?}", msg);
+        };
+        assert_eq!(u16::from(close.code), 1008);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_empty_batch() {
+        let x = TestFrontend::spawn(CONFIG).await;
+
+        let (mut socket, _) = connect_async(x.ws_url("/")).await.unwrap();
+
+        send(&mut socket, json!([])).await;
+
+        let response = next_json(&mut socket).await;
+        assert!(response["error"].is_object());
+    }
 
     #[test]
     fn nulls_and_defaults() {
@@ -1,7 +1,7 @@
 //! Used by admins for health checks and inspecting global statistics.
 //!
 //! For ease of development, users can currently access these endponts.
-//! They will eventually move to another port.
+//! Endpoints that change things are on the admin api's port. See `admin.rs`.
 
 use crate::{
     app::{App, APP_USER_AGENT},
@@ -31,6 +31,7 @@ static BACKUPS_NEEDED_FALSE: Lazy<Bytes> = Lazy::new(|| Bytes::from("false\n"));
 
 static CONTENT_TYPE_JSON: &str = "application/json";
 static CONTENT_TYPE_PLAIN: &str = "text/plain";
+static CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4";
 
 #[debug_handler]
 pub async fn debug_request(State(app): State<Arc<App>>, headers: HeaderMap) -> impl IntoResponse {
@@ -81,7 +82,8 @@ pub async fn health(State(app): State<Arc<App>>) -> Result<impl IntoResponse, We
 
 #[inline]
 async fn _health(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
-    if app.balanced_rpcs.synced() {
+    // a draining server should be taken out of the load balancer
+    if app.balanced_rpcs.synced() && !app.connections.is_draining() {
         (StatusCode::OK, CONTENT_TYPE_PLAIN, HEALTH_OK.clone())
     } else {
         (
@@ -156,9 +158,13 @@ async fn _status(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
     // TODO: what else should we include? CPU load and memory use.
     // TODO: the hostname is probably not going to change. only get once at the start?
     let body = json!({
+        "access_log_dropped": app.access_log.as_ref().map(|x| x.dropped()),
         "balanced_rpcs": app.balanced_rpcs,
         "bundler_4337_rpcs": app.bundler_4337_rpcs,
-        "chain_id": app.config.chain_id,
+        "capture_dropped": app.capture.as_ref().map(|x| x.dropped()),
+        "chain_id": app.config.load().chain_id,
+        "connections": app.connections,
+        "filters": app.filters.len(),
         "head_block_hash": head_block.as_ref().map(|x| x.hash()),
         "head_block_num": head_block.as_ref().map(|x| x.number()),
         "hostname": app.hostname,
@@ -181,6 +187,43 @@ async fn _status(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
     (code, CONTENT_TYPE_JSON, body)
 }
 
+/// When the config was last reloaded and why the last reload failed.
+/// a 500 while the last reload is failing makes alerting easy
+#[debug_handler]
+pub async fn reload(State(app): State<Arc<App>>) -> Result<impl IntoResponse, Web3ProxyError> {
+    let status = app.config_reload_status();
+
+    let code = if status.last_error.is_some() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::OK
+    };
+
+    Ok(crate::jsonrpc::response::json_response(code, &status))
+}
+
+/// Prometheus metrics.
+#[debug_handler]
+pub async fn metrics(State(app): State<Arc<App>>) -> Result<impl IntoResponse, Web3ProxyError> {
+    let (code, content_type, body) = timeout(Duration::from_secs(1), _metrics(app)).await?;
+
+    let x = Response::builder()
+        .status(code)
+        .header("content-type", content_type)
+        .body(Body::from(body))
+        .unwrap();
+
+    Ok(x)
+}
+
+#[inline]
+async fn _metrics(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
+    let body = Bytes::from(app.metrics.encode(&app));
+
+    // unlike /status, this is always a 200. prometheus can alert on the values
+    (StatusCode::OK, CONTENT_TYPE_PROMETHEUS, body)
+}
+
 pub struct MokaCacheSerializer<'a, K, V>(pub &'a Cache<K, V>);
 
 impl<'a, K, V> Serialize for MokaCacheSerializer<'a, K, V> {
@@ -23,6 +23,12 @@ impl JsonRpcErrorData {
             .len() as u64
     }
 
+    /// reverts are valid answers from a backend. they should not be retried on other servers
+    #[inline]
+    pub fn is_revert(&self) -> bool {
+        self.message.starts_with("execution reverted")
+    }
+
     // pub fn is_retryable(&self) -> bool {
     //     // TODO: move stuff from request to here
     //     todo!()
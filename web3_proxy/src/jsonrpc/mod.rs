@@ -12,7 +12,7 @@ pub use self::request::{JsonRpcRequestEnum, SingleRequest};
 pub use self::response::{
     ParsedResponse, Response, ResponseData, ResponsePayload, SingleResponse, StreamResponse,
 };
-pub use request_builder::{RequestOrMethod, ValidatedRequest};
+pub use request_builder::{RequestOrMethod, ValidatedRequest, ValidatedResponse};
 
 pub trait JsonRpcParams = fmt::Debug + serde::Serialize + Send + Sync + 'static;
 pub trait JsonRpcResultData =
@@ -38,6 +38,7 @@ mod tests {
             payload: ResponsePayload::Success {
                 result: sonic_rs::from_str::<sonic_rs::OwnedLazyValue>("100").unwrap(),
             },
+            versus: None,
         };
         let json = sonic_rs::to_string(&obj).unwrap();
         assert_eq!(json, r#"{"jsonrpc":"2.0","id":null,"result":100}"#);
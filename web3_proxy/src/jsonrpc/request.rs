@@ -75,6 +75,13 @@ impl JsonRpcRequestEnum {
         }
     }
 
+    pub fn methods(&self) -> Vec<&str> {
+        match self {
+            Self::Batch(x) => x.iter().map(|x| x.method.as_ref()).collect(),
+            Self::Single(x) => vec![x.method.as_ref()],
+        }
+    }
+
     /// returns the id of the first invalid result (if any). None is good
     pub fn validate(&self) -> Option<OwnedLazyValue> {
         match self {
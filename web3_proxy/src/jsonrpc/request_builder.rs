@@ -1,11 +1,13 @@
 use super::{JsonRpcParams, LooseId, SingleRequest};
 use crate::{
+    api_keys::ApiKey,
     app::App,
     block_number::RequestBlocks,
+    config::RouteConfig,
     errors::{Web3ProxyError, Web3ProxyResult},
     frontend::rpc_proxy_ws::ProxyMode,
     globals::APP,
-    rpcs::{blockchain::BlockHeader, one::Web3Rpc},
+    rpcs::{blockchain::BlockHeader, many::VersusReport, one::Web3Rpc},
 };
 use alloy::primitives::U64;
 use chrono::Utc;
@@ -18,7 +20,8 @@ use std::time::Duration;
 use std::{borrow::Cow, sync::Arc};
 use std::{
     fmt::{self, Display},
-    sync::OnceLock,
+    net::IpAddr,
+    sync::{atomic::AtomicBool, OnceLock},
 };
 use tokio::time::Instant;
 
@@ -106,6 +109,9 @@ pub struct ValidatedResponse {
     /// RPC servers used by this request.
     pub backend_rpcs: Vec<Arc<Web3Rpc>>,
 
+    /// The response came from the response cache. No rpcs were used
+    pub cache_hit: bool,
+
     /// The number of times the request got stuck waiting because no servers were synced
     pub no_servers: u64,
 
@@ -126,6 +132,9 @@ pub struct ValidatedResponse {
 
     /// If the request is invalid or received a jsonrpc error response (excluding reverts)
     pub user_error_response: bool,
+
+    /// How each rpc handled the request. Only set for ProxyMode::Versus
+    pub versus: Option<VersusReport>,
 }
 
 /// TODO:
@@ -160,6 +169,18 @@ pub struct ValidatedRequest {
 
     /// RequestId from x-amzn-trace-id or generated
     pub request_id: Option<String>,
+
+    /// None for internal requests
+    pub client_ip: Option<IpAddr>,
+
+    /// None for anonymous and internal requests
+    pub api_key: Option<Arc<ApiKey>>,
+
+    /// the first of the app's `[[routes]]` that matches the method. None sends the request to the default rpcs
+    pub route: Option<Arc<RouteConfig>>,
+
+    /// set once the route's group has failed. the fallback groups do not check the route's tags
+    pub route_fallback: AtomicBool,
 }
 
 impl Display for ValidatedRequest {
@@ -218,6 +239,8 @@ impl ValidatedRequest {
         max_wait: Option<Duration>,
         mut request: RequestOrMethod,
         request_id: Option<String>,
+        client_ip: Option<IpAddr>,
+        api_key: Option<Arc<ApiKey>>,
         proxy_mode: ProxyMode,
     ) -> Web3ProxyResult<Arc<Self>> {
         let start_instant = Instant::now();
@@ -241,6 +264,8 @@ impl ValidatedRequest {
             .unwrap_or_else(|| Duration::from_secs(60))
             .max(connect_timeout);
 
+        let route = app.and_then(|x| x.route_for(request.method()));
+
         let x = Self {
             response: Mutex::new(Default::default()),
             request_blocks,
@@ -252,11 +277,16 @@ impl ValidatedRequest {
             proxy_mode,
             start_instant,
             request_id,
+            client_ip,
+            api_key,
+            route,
+            route_fallback: AtomicBool::new(false),
         };
 
         Ok(Arc::new(x))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn new_with_app(
         app: &App,
         proxy_mode: ProxyMode,
@@ -264,8 +294,10 @@ impl ValidatedRequest {
         request: RequestOrMethod,
         head_block: Option<BlockHeader>,
         request_id: Option<String>,
+        client_ip: Option<IpAddr>,
+        api_key: Option<Arc<ApiKey>>,
     ) -> Web3ProxyResult<Arc<Self>> {
-        let chain_id = app.config.chain_id;
+        let chain_id = app.config.load().chain_id;
 
         Self::new_with_options(
             Some(app),
@@ -274,6 +306,8 @@ impl ValidatedRequest {
             max_wait,
             request,
             request_id,
+            client_ip,
+            api_key,
             proxy_mode,
         )
         .await
@@ -299,6 +333,8 @@ impl ValidatedRequest {
                 request.into(),
                 head_block,
                 None,
+                None,
+                None,
             )
             .await
         } else {
@@ -309,6 +345,8 @@ impl ValidatedRequest {
                 max_wait,
                 request.into(),
                 None,
+                None,
+                None,
                 ProxyMode::Best,
             )
             .await
@@ -322,6 +360,10 @@ impl ValidatedRequest {
         response_lock.backend_rpcs.clone()
     }
 
+    pub fn versus_report(&self) -> Option<VersusReport> {
+        self.response.lock().versus.clone()
+    }
+
     #[inline]
     pub fn id(&self) -> OwnedLazyValue {
         self.inner.id()
@@ -1,6 +1,7 @@
 use super::JsonRpcErrorData;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::jsonrpc::ValidatedRequest;
+use crate::rpcs::many::VersusReport;
 use axum::body::Body;
 use axum::http::{header, StatusCode};
 use axum::response::IntoResponse;
@@ -115,6 +116,9 @@ pub struct ParsedResponse<T = Arc<OwnedLazyValue>> {
     pub id: OwnedLazyValue,
     #[serde(flatten)]
     pub payload: ResponsePayload<T>,
+    /// how each rpc handled a `ProxyMode::Versus` request. only set for websocket clients. http clients get it in the `X-W3P-VERSUS` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub versus: Option<VersusReport>,
 }
 
 impl ParsedResponse {
@@ -144,6 +148,7 @@ impl<T> ParsedResponse<T> {
             jsonrpc: "2.0".into(),
             id,
             payload: ResponsePayload::Success { result },
+            versus: None,
         }
     }
 
@@ -153,6 +158,7 @@ impl<T> ParsedResponse<T> {
             jsonrpc: "2.0".into(),
             id,
             payload: ResponsePayload::Error { error },
+            versus: None,
         }
     }
 
@@ -277,6 +283,7 @@ where
                     jsonrpc,
                     id,
                     payload,
+                    versus: None,
                 })
             }
         }
@@ -357,6 +364,16 @@ where
         }
     }
 
+    pub fn jsonrpc_error(&self) -> Option<&JsonRpcErrorData> {
+        match self {
+            Self::Parsed(resp, ..) => match &resp.payload {
+                ResponsePayload::Error { error } => Some(error),
+                ResponsePayload::Success { .. } => None,
+            },
+            Self::Stream(..) => None,
+        }
+    }
+
     // TODO: threshold from configs
     // TODO: error handling
     // TODO: if a large stream's response's initial chunk "error" then we should buffer it
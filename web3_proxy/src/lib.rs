@@ -1,13 +1,21 @@
 #![feature(trait_alias)]
 #![forbid(unsafe_code)]
 
+pub mod access_log;
+pub mod api_keys;
 pub mod app;
+pub mod audit;
 pub mod block_number;
+pub mod capture;
 pub mod config;
+pub mod connections;
 pub mod errors;
 pub mod frontend;
 pub mod globals;
 pub mod jsonrpc;
+pub mod metrics;
 pub mod prelude;
+pub mod rate_limit;
+pub mod response_cache;
 pub mod rpcs;
 pub mod test_utils;
@@ -0,0 +1,530 @@
+//! Prometheus metrics for the `/metrics` endpoint.
+//!
+//! Request counters and latency histograms are kept for the life of the app.
+//! Everything else is read from the app's current state on every scrape.
+use crate::app::App;
+use crate::config::{glob_match, AppConfig};
+use crate::jsonrpc::ValidatedRequest;
+use crate::rpcs::many::Web3Rpcs;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// methods are user input. only these and `metrics_methods` get their own label. everything else is "other"
+/// keep this sorted
+const KNOWN_METHODS: &[&str] = &[
+    "debug_traceBlockByHash",
+    "debug_traceBlockByNumber",
+    "debug_traceCall",
+    "debug_traceTransaction",
+    "eth_accounts",
+    "eth_blobBaseFee",
+    "eth_blockNumber",
+    "eth_call",
+    "eth_chainId",
+    "eth_createAccessList",
+    "eth_estimateGas",
+    "eth_feeHistory",
+    "eth_gasPrice",
+    "eth_getBalance",
+    "eth_getBlockByHash",
+    "eth_getBlockByNumber",
+    "eth_getBlockReceipts",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getCode",
+    "eth_getFilterChanges",
+    "eth_getFilterLogs",
+    "eth_getLogs",
+    "eth_getProof",
+    "eth_getStorageAt",
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getTransactionByHash",
+    "eth_getTransactionCount",
+    "eth_getTransactionReceipt",
+    "eth_getUncleByBlockHashAndIndex",
+    "eth_getUncleByBlockNumberAndIndex",
+    "eth_getUncleCountByBlockHash",
+    "eth_getUncleCountByBlockNumber",
+    "eth_maxPriorityFeePerGas",
+    "eth_newBlockFilter",
+    "eth_newFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_sendRawTransaction",
+    "eth_sendRawTransactionConditional",
+    "eth_subscribe",
+    "eth_syncing",
+    "eth_uninstallFilter",
+    "eth_unsubscribe",
+    "net_listening",
+    "net_peerCount",
+    "net_version",
+    "trace_block",
+    "trace_call",
+    "trace_callMany",
+    "trace_filter",
+    "trace_get",
+    "trace_replayBlockTransactions",
+    "trace_replayTransaction",
+    "trace_transaction",
+    "web3_clientVersion",
+    "web3_sha3",
+];
+
+fn method_label<'a>(method: &'a str, config: &AppConfig) -> &'a str {
+    if KNOWN_METHODS.binary_search(&method).is_ok()
+        || config.metrics_methods.iter().any(|x| glob_match(x, method))
+    {
+        method
+    } else {
+        "other"
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, collector: T) -> T {
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("metric names should be unique");
+
+    collector
+}
+
+pub struct AppMetrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    api_key_requests: IntCounterVec,
+    errors: IntCounterVec,
+    cache_hits: IntCounterVec,
+    request_duration: HistogramVec,
+    rpc_request_duration: HistogramVec,
+}
+
+impl Default for AppMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new("web3_proxy_requests_total", "jsonrpc requests by method"),
+                &["method"],
+            )
+            .unwrap(),
+        );
+
+        // api key names come from the config, so they are safe to use as labels
+        let api_key_requests = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "web3_proxy_api_key_requests_total",
+                    "jsonrpc requests by api key name",
+                ),
+                &["api_key"],
+            )
+            .unwrap(),
+        );
+
+        let errors = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "web3_proxy_errors_total",
+                    "jsonrpc requests that failed. kind is \"app\" for our errors and \"user\" for jsonrpc error responses",
+                ),
+                &["method", "kind"],
+            )
+            .unwrap(),
+        );
+
+        let cache_hits = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "web3_proxy_response_cache_hits_total",
+                    "jsonrpc requests answered from the response cache",
+                ),
+                &["method"],
+            )
+            .unwrap(),
+        );
+
+        let request_duration = register(
+            &registry,
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "web3_proxy_request_duration_seconds",
+                    "time to respond to jsonrpc requests",
+                ),
+                &["method"],
+            )
+            .unwrap(),
+        );
+
+        // TODO: this is the time for the whole request. track each backend request separately
+        let rpc_request_duration = register(
+            &registry,
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "web3_proxy_rpc_request_duration_seconds",
+                    "time to respond to jsonrpc requests that used this rpc",
+                ),
+                &["rpc"],
+            )
+            .unwrap(),
+        );
+
+        Self {
+            registry,
+            requests,
+            api_key_requests,
+            errors,
+            cache_hits,
+            request_duration,
+            rpc_request_duration,
+        }
+    }
+
+    /// call this after `set_response`
+    pub fn record_request(&self, web3_request: &ValidatedRequest, config: &AppConfig) {
+        let method = method_label(web3_request.inner.method(), config);
+
+        let response_lock = web3_request.response.lock();
+
+        self.requests.with_label_values(&[method]).inc();
+
+        if let Some(api_key) = web3_request.api_key.as_ref() {
+            self.api_key_requests
+                .with_label_values(&[&*api_key.name])
+                .inc();
+        }
+
+        if response_lock.error_response {
+            self.errors.with_label_values(&[method, "app"]).inc();
+        } else if response_lock.user_error_response {
+            self.errors.with_label_values(&[method, "user"]).inc();
+        }
+
+        if response_lock.cache_hit {
+            self.cache_hits.with_label_values(&[method]).inc();
+        }
+
+        let seconds = response_lock.response_millis as f64 / 1000.0;
+
+        self.request_duration
+            .with_label_values(&[method])
+            .observe(seconds);
+
+        for rpc in response_lock.backend_rpcs.iter() {
+            self.rpc_request_duration
+                .with_label_values(&[rpc.name.as_str()])
+                .observe(seconds);
+        }
+    }
+
+    /// the prometheus text format
+    pub fn encode(&self, app: &App) -> String {
+        let scrape = Registry::new();
+
+        let head_block_num = register(
+            &scrape,
+            IntGauge::new(
+                "web3_proxy_head_block_number",
+                "the consensus head block number",
+            )
+            .unwrap(),
+        );
+        let head_block_age = register(
+            &scrape,
+            IntGauge::new(
+                "web3_proxy_head_block_age_seconds",
+                "seconds since the consensus head block was mined",
+            )
+            .unwrap(),
+        );
+        let synced_rpcs = register(
+            &scrape,
+            IntGauge::new(
+                "web3_proxy_synced_rpcs",
+                "balanced rpcs that are on the consensus head block",
+            )
+            .unwrap(),
+        );
+
+        if let Some(head_block) = app.watch_consensus_head_receiver.borrow().as_ref() {
+            head_block_num.set(head_block.number().to::<i64>());
+            head_block_age.set(head_block.age().as_secs() as i64);
+        }
+
+        if let Some(ranked_rpcs) = app.balanced_rpcs.watch_ranked_rpcs.borrow().as_ref() {
+            synced_rpcs.set(ranked_rpcs.num_synced as i64);
+        }
+
+        // these are counters that live on the broadcaster. they are copied into a fresh counter on every scrape
+        let pending_txids = [
+            (
+                "web3_proxy_pending_txids_unfiltered_total",
+                "pending transactions received from all rpcs",
+                app.pending_txid_firehose.total_unfiltered(),
+            ),
+            (
+                "web3_proxy_pending_txids_filtered_total",
+                "pending transactions after removing duplicates",
+                app.pending_txid_firehose.total_filtered(),
+            ),
+            (
+                "web3_proxy_pending_txids_broadcasts_total",
+                "pending transactions sent to subscribers",
+                app.pending_txid_firehose.total_broadcasts(),
+            ),
+        ];
+        for (name, help, value) in pending_txids {
+            register(&scrape, IntCounter::new(name, help).unwrap()).inc_by(value as u64);
+        }
+        register(
+            &scrape,
+            IntGauge::new(
+                "web3_proxy_pending_txids_subscriptions",
+                "subscribers to pending transactions",
+            )
+            .unwrap(),
+        )
+        .set(app.pending_txid_firehose.subscriptions() as i64);
+
+        let cache_entries = register(
+            &scrape,
+            IntGaugeVec::new(
+                Opts::new("web3_proxy_cache_entries", "entries in a moka cache"),
+                &["group", "cache"],
+            )
+            .unwrap(),
+        );
+        let cache_weighted_size = register(
+            &scrape,
+            IntGaugeVec::new(
+                Opts::new(
+                    "web3_proxy_cache_weighted_size",
+                    "weighted size of a moka cache. this is bytes for the response cache",
+                ),
+                &["group", "cache"],
+            )
+            .unwrap(),
+        );
+
+        let rpc_gauges = RpcGauges::new(&scrape);
+
+        for (group, rpcs) in [
+            ("balanced_rpcs", &app.balanced_rpcs),
+            ("bundler_4337_rpcs", &app.bundler_4337_rpcs),
+            ("private_rpcs", &app.protected_rpcs),
+        ] {
+            rpcs_caches(group, rpcs, &cache_entries, &cache_weighted_size);
+
+            for rpc in rpcs.by_name.read().values() {
+                rpc.update_metrics(&[group, rpc.name.as_str()], &rpc_gauges);
+            }
+        }
+
+        let mut families = self.registry.gather();
+        families.extend(scrape.gather());
+
+        encode(&families)
+    }
+}
+
+fn rpcs_caches(
+    group: &str,
+    rpcs: &Web3Rpcs,
+    cache_entries: &IntGaugeVec,
+    cache_weighted_size: &IntGaugeVec,
+) {
+    let mut caches = vec![
+        (
+            "blocks_by_hash",
+            rpcs.blocks_by_hash.entry_count(),
+            rpcs.blocks_by_hash.weighted_size(),
+        ),
+        (
+            "blocks_by_number",
+            rpcs.blocks_by_number.entry_count(),
+            rpcs.blocks_by_number.weighted_size(),
+        ),
+    ];
+
+    if let Some(response_cache) = rpcs.response_cache.as_ref() {
+        caches.push((
+            "jsonrpc_response_cache",
+            response_cache.entry_count(),
+            response_cache.weighted_size(),
+        ));
+    }
+
+    for (name, entries, weighted_size) in caches {
+        cache_entries
+            .with_label_values(&[group, name])
+            .set(entries as i64);
+        cache_weighted_size
+            .with_label_values(&[group, name])
+            .set(weighted_size as i64);
+    }
+}
+
+/// gauges that are set from each Web3Rpc on every scrape. labeled by group and rpc name
+pub struct RpcGauges {
+    pub active_requests: IntGaugeVec,
+    pub block_data_limit: GaugeVec,
+    pub hard_limited: IntGaugeVec,
+    pub head_block_age: IntGaugeVec,
+    pub head_block_num: IntGaugeVec,
+    pub healthy: IntGaugeVec,
+    pub median_latency: GaugeVec,
+    pub peak_latency: GaugeVec,
+    pub tier: IntGaugeVec,
+    pub total_requests: IntCounterVec,
+}
+
+impl RpcGauges {
+    fn new(registry: &Registry) -> Self {
+        let labels = &["group", "rpc"];
+
+        let int_gauge = |name: &str, help: &str| {
+            register(
+                registry,
+                IntGaugeVec::new(Opts::new(name, help), labels).unwrap(),
+            )
+        };
+        let gauge = |name: &str, help: &str| {
+            register(
+                registry,
+                GaugeVec::new(Opts::new(name, help), labels).unwrap(),
+            )
+        };
+
+        Self {
+            active_requests: int_gauge(
+                "web3_proxy_rpc_active_requests",
+                "requests in flight to this rpc",
+            ),
+            block_data_limit: gauge(
+                "web3_proxy_rpc_block_data_limit",
+                "how many blocks of state this rpc has. +Inf for archive nodes",
+            ),
+            hard_limited: int_gauge(
+                "web3_proxy_rpc_hard_limited",
+                "1 if this rpc is currently rate limited",
+            ),
+            head_block_age: int_gauge(
+                "web3_proxy_rpc_head_block_age_seconds",
+                "seconds since this rpc's head block was mined",
+            ),
+            head_block_num: int_gauge(
+                "web3_proxy_rpc_head_block_number",
+                "this rpc's head block number",
+            ),
+            healthy: int_gauge(
+                "web3_proxy_rpc_healthy",
+                "0 if the last health check failed",
+            ),
+            median_latency: gauge(
+                "web3_proxy_rpc_median_latency_seconds",
+                "rolling median request latency",
+            ),
+            peak_latency: gauge(
+                "web3_proxy_rpc_peak_latency_seconds",
+                "peak ewma request latency",
+            ),
+            tier: int_gauge("web3_proxy_rpc_tier", "lower tiers are preferred"),
+            total_requests: register(
+                registry,
+                IntCounterVec::new(
+                    Opts::new("web3_proxy_rpc_requests_total", "requests sent to this rpc"),
+                    labels,
+                )
+                .unwrap(),
+            ),
+        }
+    }
+}
+
+fn encode(families: &[MetricFamily]) -> String {
+    let mut buffer = vec![];
+
+    TextEncoder::new()
+        .encode(families, &mut buffer)
+        .expect("text encoding should always work");
+
+    String::from_utf8(buffer).expect("prometheus text should always be utf8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, method_label, AppMetrics, KNOWN_METHODS};
+    use crate::config::AppConfig;
+    use crate::jsonrpc::ValidatedRequest;
+
+    #[test_log::test(tokio::test)]
+    async fn test_method_counters() {
+        let metrics = AppMetrics::new();
+        let config = AppConfig::default();
+
+        let request = ValidatedRequest::new_internal("eth_chainId".into(), &(), None, None)
+            .await
+            .unwrap();
+
+        request.set_response(10);
+
+        metrics.record_request(&request, &config);
+
+        {
+            let mut response_lock = request.response.lock();
+            response_lock.user_error_response = true;
+            response_lock.cache_hit = true;
+        }
+
+        metrics.record_request(&request, &config);
+
+        let unknown = ValidatedRequest::new_internal("something_new".into(), &(), None, None)
+            .await
+            .unwrap();
+
+        unknown.set_response(10);
+
+        metrics.record_request(&unknown, &config);
+
+        let text = encode(&metrics.registry.gather());
+
+        assert!(text.contains("web3_proxy_requests_total{method=\"eth_chainId\"} 2"));
+        assert!(text.contains("web3_proxy_requests_total{method=\"other\"} 1"));
+        assert!(text.contains("web3_proxy_response_cache_hits_total{method=\"eth_chainId\"} 1"));
+        assert!(!text.contains("something_new"));
+        assert!(text.contains("web3_proxy_errors_total{kind=\"user\",method=\"eth_chainId\"} 1"));
+        assert!(
+            text.contains("web3_proxy_request_duration_seconds_count{method=\"eth_chainId\"} 2")
+        );
+    }
+
+    #[test]
+    fn test_method_label() {
+        assert!(KNOWN_METHODS.is_sorted());
+
+        let mut config = AppConfig::default();
+
+        assert_eq!(method_label("eth_call", &config), "eth_call");
+        assert_eq!(method_label("arbtrace_block", &config), "other");
+
+        config.metrics_methods = vec!["arbtrace_*".into()];
+
+        assert_eq!(method_label("arbtrace_block", &config), "arbtrace_block");
+        assert_eq!(method_label("something_new", &config), "other");
+    }
+}
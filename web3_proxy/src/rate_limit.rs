@@ -0,0 +1,289 @@
+//! In-memory rate limits. These are per-process. Running multiple proxies multiplies the limits.
+use crate::config::AppConfig;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use hashbrown::HashMap;
+use moka::future::{Cache, CacheBuilder};
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Refills `per_second` tokens every second and holds at most `burst` of them.
+#[derive(Debug)]
+pub struct TokenBucket {
+    burst: f64,
+    per_second: f64,
+    /// tokens available at the instant
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// the bucket starts full
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+
+        Self {
+            burst,
+            per_second: per_second.max(1) as f64,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// `limit` tokens every `period`. the burst is the whole limit
+    pub fn per_period(limit: u32, period: Duration) -> Self {
+        let burst = limit.max(1) as f64;
+
+        Self {
+            burst,
+            per_second: burst / period.as_secs_f64().max(0.001),
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// take `n` tokens. on failure, nothing is taken and the time until enough tokens are available is returned.
+    /// more than `burst` tokens are never available, so a larger `n` fails with None
+    pub fn try_acquire(&self, n: u32) -> Result<(), Option<Duration>> {
+        let n = n as f64;
+
+        if n > self.burst {
+            return Err(None);
+        }
+
+        let now = Instant::now();
+
+        let mut state = self.state.lock();
+
+        let (tokens, last) = *state;
+
+        let tokens =
+            (tokens + now.duration_since(last).as_secs_f64() * self.per_second).min(self.burst);
+
+        if tokens >= n {
+            *state = (tokens - n, now);
+            Ok(())
+        } else {
+            *state = (tokens, now);
+            Err(Some(Duration::from_secs_f64(
+                (n - tokens) / self.per_second,
+            )))
+        }
+    }
+}
+
+/// A TokenBucket for each ip. Idle buckets are full, so they are forgotten after a while.
+pub struct IpTokenBuckets {
+    per_second: u32,
+    buckets: Cache<IpAddr, Arc<TokenBucket>>,
+}
+
+impl IpTokenBuckets {
+    pub fn new(name: &'static str, per_second: u32) -> Self {
+        // TODO: config for the burst size. then this can be burst / per_second
+        let buckets = CacheBuilder::new(100_000)
+            .name(name)
+            .time_to_idle(Duration::from_secs(60))
+            .build();
+
+        Self {
+            per_second,
+            buckets,
+        }
+    }
+
+    pub async fn try_acquire(&self, ip: IpAddr, n: u32) -> Result<(), Option<Duration>> {
+        let per_second = self.per_second;
+
+        self.buckets
+            .get_with(ip, async move {
+                Arc::new(TokenBucket::new(per_second, per_second))
+            })
+            .await
+            .try_acquire(n)
+    }
+}
+
+/// Limits for anonymous clients. Requests with an api key use the key's limits instead.
+#[derive(Default)]
+pub struct IpRateLimits {
+    requests: Option<IpTokenBuckets>,
+    ws_messages: Option<IpTokenBuckets>,
+    max_subscriptions: Option<u32>,
+    subscriptions: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl IpRateLimits {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            requests: config
+                .ip_requests_per_second
+                .map(|x| IpTokenBuckets::new("ip_requests", x)),
+            ws_messages: config
+                .ip_ws_messages_per_second
+                .map(|x| IpTokenBuckets::new("ip_ws_messages", x)),
+            max_subscriptions: config.ip_max_subscriptions,
+            subscriptions: Default::default(),
+        }
+    }
+
+    /// one http request. a batch costs one token for each of its calls
+    pub async fn check_request(&self, ip: IpAddr, num_calls: usize) -> Web3ProxyResult<()> {
+        check(self.requests.as_ref(), ip, num_calls).await
+    }
+
+    /// one websocket message. a batch costs one token for each of its calls
+    pub async fn check_ws_message(&self, ip: IpAddr, num_calls: usize) -> Web3ProxyResult<()> {
+        check(self.ws_messages.as_ref(), ip, num_calls).await
+    }
+
+    /// the permit must be kept for as long as the subscription is open
+    pub fn try_subscribe(&self, ip: IpAddr) -> Web3ProxyResult<Option<SubscriptionPermit>> {
+        let Some(max_subscriptions) = self.max_subscriptions else {
+            return Ok(None);
+        };
+
+        let mut subscriptions = self.subscriptions.lock();
+
+        let count = subscriptions.entry(ip).or_default();
+
+        if *count >= max_subscriptions {
+            // we can't know when a subscription will close
+            return Err(Web3ProxyError::RateLimited(None));
+        }
+
+        *count += 1;
+
+        Ok(Some(SubscriptionPermit {
+            ip,
+            subscriptions: self.subscriptions.clone(),
+        }))
+    }
+
+    pub fn num_subscriptions(&self, ip: IpAddr) -> u32 {
+        self.subscriptions
+            .lock()
+            .get(&ip)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// batches bigger than the burst are always refused. there is no point in retrying them
+async fn check(
+    buckets: Option<&IpTokenBuckets>,
+    ip: IpAddr,
+    num_calls: usize,
+) -> Web3ProxyResult<()> {
+    match buckets {
+        None => Ok(()),
+        Some(x) => x
+            .try_acquire(ip, num_calls.try_into().unwrap_or(u32::MAX))
+            .await
+            .map_err(Web3ProxyError::RateLimited),
+    }
+}
+
+/// counts against the ip's open subscriptions until dropped
+#[derive(Debug)]
+pub struct SubscriptionPermit {
+    ip: IpAddr,
+    subscriptions: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl Drop for SubscriptionPermit {
+    fn drop(&mut self) {
+        let mut subscriptions = self.subscriptions.lock();
+
+        if let Some(count) = subscriptions.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+
+            if *count == 0 {
+                subscriptions.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpRateLimits, TokenBucket};
+    use crate::config::AppConfig;
+    use crate::errors::Web3ProxyError;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tokio::time::advance;
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(2, 4);
+
+        bucket.try_acquire(3).unwrap();
+        bucket.try_acquire(1).unwrap();
+
+        let wait = bucket.try_acquire(1).unwrap_err();
+        assert_eq!(wait, Some(Duration::from_millis(500)));
+
+        advance(Duration::from_millis(500)).await;
+        bucket.try_acquire(1).unwrap();
+
+        // never more than burst
+        advance(Duration::from_secs(60)).await;
+        bucket.try_acquire(4).unwrap();
+        assert!(bucket.try_acquire(1).is_err());
+
+        // too big for the bucket. waiting would never help
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(bucket.try_acquire(100), Err(None));
+        bucket.try_acquire(4).unwrap();
+
+        // 10 per minute
+        let bucket = TokenBucket::per_period(10, Duration::from_secs(60));
+        bucket.try_acquire(10).unwrap();
+        assert_eq!(
+            bucket.try_acquire(1).unwrap_err(),
+            Some(Duration::from_secs(6))
+        );
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_ip_rate_limits() {
+        let limits = IpRateLimits::new(&AppConfig {
+            ip_max_subscriptions: Some(1),
+            ip_requests_per_second: Some(1),
+            ..Default::default()
+        });
+
+        let a = IpAddr::from([1, 2, 3, 4]);
+        let b = IpAddr::from([5, 6, 7, 8]);
+
+        limits.check_request(a, 1).await.unwrap();
+        assert!(matches!(
+            limits.check_request(a, 1).await,
+            Err(Web3ProxyError::RateLimited(Some(_)))
+        ));
+
+        // every ip has its own bucket
+        limits.check_request(b, 1).await.unwrap();
+
+        // a batch costs one token per call. one bigger than the bucket never fits
+        advance(Duration::from_secs(1)).await;
+        assert!(matches!(
+            limits.check_request(b, 2).await,
+            Err(Web3ProxyError::RateLimited(None))
+        ));
+        limits.check_request(b, 1).await.unwrap();
+
+        // websocket messages are not limited
+        limits.check_ws_message(a, 1_000).await.unwrap();
+        limits.check_ws_message(a, 1_000).await.unwrap();
+
+        let permit = limits.try_subscribe(a).unwrap();
+        assert!(permit.is_some());
+        assert!(limits.try_subscribe(a).is_err());
+        assert_eq!(limits.num_subscriptions(a), 1);
+
+        drop(permit);
+        assert_eq!(limits.num_subscriptions(a), 0);
+        assert!(limits.try_subscribe(a).unwrap().is_some());
+    }
+}
@@ -0,0 +1,337 @@
+//! Cache jsonrpc responses that can not change for a given block.
+use crate::block_number::{BlockNumOrHash, RequestBlocks};
+use crate::frontend::rpc_proxy_ws::ProxyMode;
+use crate::jsonrpc::{ResponseData, ValidatedRequest};
+use crate::rpcs::blockchain::MAX_REORG_DEPTH;
+use crate::rpcs::many::Web3Rpcs;
+use crate::rpcs::one::Web3Rpc;
+use alloy::primitives::{B256, U64};
+use moka::future::{Cache, CacheBuilder};
+use serde::{ser::SerializeStruct, Serialize};
+use sonic_rs::OwnedLazyValue;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{trace, warn};
+
+/// methods whose responses are fixed once the block they are pinned to is known.
+/// TODO: put this on the config?
+fn cacheable_by_block(method: &str) -> bool {
+    matches!(
+        method,
+        "debug_traceBlockByNumber"
+            | "eth_call"
+            | "eth_feeHistory"
+            | "eth_getBalance"
+            | "eth_getBlockByNumber"
+            | "eth_getBlockReceipts"
+            | "eth_getBlockTransactionCountByNumber"
+            | "eth_getCode"
+            | "eth_getLogs"
+            | "eth_getStorageAt"
+            | "eth_getTransactionByBlockNumberAndIndex"
+            | "eth_getTransactionCount"
+            | "eth_getUncleByBlockNumberAndIndex"
+            | "eth_getUncleCountByBlockNumber"
+            | "trace_block"
+    )
+}
+
+/// methods that take a block hash. these never change, so they don't need a block in the key
+fn cacheable_by_hash(method: &str) -> bool {
+    matches!(
+        method,
+        "debug_traceBlockByHash"
+            | "eth_getBlockByHash"
+            | "eth_getBlockTransactionCountByHash"
+            | "eth_getTransactionByBlockHashAndIndex"
+            | "eth_getUncleByBlockHashAndIndex"
+            | "eth_getUncleCountByBlockHash"
+    )
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct JsonRpcQueryCacheKey {
+    method: Cow<'static, str>,
+    /// params after `RequestBlocks::try_new` replaced "latest" with a number
+    params: String,
+    /// the highest block that the response depends on
+    max_block_num: Option<U64>,
+    /// if set, the response is pinned to this exact chain and reorgs don't need to invalidate it.
+    /// when inserting, this is the hash that the rpcs that answered had. see `for_response`
+    max_block_hash: Option<B256>,
+}
+
+impl JsonRpcQueryCacheKey {
+    pub fn new(
+        method: Cow<'static, str>,
+        params: String,
+        max_block_num: Option<U64>,
+        max_block_hash: Option<B256>,
+    ) -> Self {
+        Self {
+            method,
+            params,
+            max_block_num,
+            max_block_hash,
+        }
+    }
+
+    /// None if the request should not be cached
+    pub fn try_from_request(web3_request: &ValidatedRequest) -> Option<Self> {
+        // versus and quorum exist to compare backends. caching would defeat the point
+        if !matches!(
+            web3_request.proxy_mode,
+            ProxyMode::Best | ProxyMode::Fastest(..)
+        ) {
+            return None;
+        }
+
+        let request = web3_request.inner.jsonrpc_request()?;
+
+        let method = request.method.as_ref();
+
+        let (max_block_num, max_block_hash) = if cacheable_by_hash(method) {
+            (None, None)
+        } else if cacheable_by_block(method) {
+            // the request_blocks are None if we didn't know the head block. don't cache those
+            // TODO: eth_getLogs with a blockHash could be cached too
+            let to_block = match &web3_request.request_blocks {
+                RequestBlocks::None => return None,
+                x => x.to_block()?,
+            };
+
+            match to_block {
+                BlockNumOrHash::Num(num) => (Some(*num), None),
+                BlockNumOrHash::And(x) => (Some(x.num()), Some(*x.hash())),
+            }
+        } else {
+            return None;
+        };
+
+        let params = sonic_rs::to_string(&request.params).ok()?;
+
+        // "pending" is left in the params for the backend. so are "safe" and "finalized" if the rpcs don't support them
+        // TODO: something better than searching the string
+        if params.contains("\"pending\"")
+            || params.contains("\"safe\"")
+            || params.contains("\"finalized\"")
+        {
+            return None;
+        }
+
+        Some(Self::new(
+            request.method.clone(),
+            params,
+            max_block_num,
+            max_block_hash,
+        ))
+    }
+
+    /// The key to insert a response from `backend_rpcs` with.
+    /// The params were rewritten to a block number, so the hash from when the request was received might not be the block that the rpcs used.
+    /// None if the rpcs' hash for the block is unknown or they disagree. Blocks deep enough to not reorg keep the key as is
+    pub async fn for_response(
+        mut self,
+        rpcs: &Web3Rpcs,
+        backend_rpcs: &[Arc<Web3Rpc>],
+    ) -> Option<Self> {
+        let Some(num) = self.max_block_num else {
+            // pinned to a hash in the params
+            return Some(self);
+        };
+
+        if backend_rpcs.is_empty() {
+            return None;
+        }
+
+        let mut used_hash = None;
+
+        for rpc in backend_rpcs {
+            let head_num = rpc.head_block()?.number();
+
+            if head_num.saturating_sub(num) > U64::from(MAX_REORG_DEPTH) {
+                continue;
+            }
+
+            let hash = rpcs.block_hash_on_rpc(rpc, num).await?;
+
+            if used_hash.is_some_and(|x| x != hash) {
+                trace!(%num, "rpcs answered for different blocks. not caching");
+                return None;
+            }
+
+            used_hash = Some(hash);
+        }
+
+        if used_hash.is_some() {
+            self.max_block_hash = used_hash;
+        }
+
+        Some(self)
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.method.len() + self.params.len()
+    }
+}
+
+pub type JsonRpcResponseData = ResponseData<Arc<OwnedLazyValue>>;
+
+/// responses from the balanced rpcs.
+/// TODO: cache errors? some of them are deterministic (like reverts)
+pub struct JsonRpcResponseCache {
+    cache: Cache<JsonRpcQueryCacheKey, JsonRpcResponseData>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl JsonRpcResponseCache {
+    pub fn new(max_bytes: u64) -> Self {
+        // TODO: time_to_idle from config
+        let cache = CacheBuilder::new(max_bytes)
+            .name("jsonrpc_response_cache")
+            .time_to_idle(Duration::from_secs(30 * 60))
+            .weigher(|k: &JsonRpcQueryCacheKey, v: &JsonRpcResponseData| {
+                (k.num_bytes() as u64 + v.num_bytes())
+                    .try_into()
+                    .unwrap_or(u32::MAX)
+            })
+            .support_invalidation_closures()
+            .build();
+
+        Self {
+            cache,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn get(&self, key: &JsonRpcQueryCacheKey) -> Option<JsonRpcResponseData> {
+        let x = self.cache.get(key).await;
+
+        if x.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        x
+    }
+
+    /// errors and nulls are not cached. a null is usually a backend that hasn't seen the block yet
+    pub async fn insert(&self, key: JsonRpcQueryCacheKey, value: JsonRpcResponseData) {
+        if value.is_error() || value.is_null() {
+            return;
+        }
+
+        self.cache.insert(key, value).await;
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    pub fn weighted_size(&self) -> u64 {
+        self.cache.weighted_size()
+    }
+
+    /// the block at `num` changed. anything that depends on it and isn't pinned to a hash is now wrong
+    /// TODO: there is a small race here with requests that started before the reorg and finish after
+    pub fn invalidate_from_block(&self, num: U64) {
+        trace!(%num, "invalidating cached responses");
+
+        if let Err(err) = self.cache.invalidate_entries_if(move |k, _| {
+            k.max_block_hash.is_none() && k.max_block_num.is_some_and(|x| x >= num)
+        }) {
+            warn!(?err, "unable to invalidate cached responses");
+        }
+    }
+}
+
+impl Serialize for JsonRpcResponseCache {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut state = serializer.serialize_struct("JsonRpcResponseCache", 5)?;
+
+        state.serialize_field("entry_count", &self.cache.entry_count())?;
+        state.serialize_field("hits", &self.hits.load(Ordering::Relaxed))?;
+        state.serialize_field("misses", &self.misses.load(Ordering::Relaxed))?;
+        state.serialize_field("name", &self.cache.name())?;
+        state.serialize_field("weighted_size", &self.cache.weighted_size())?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonRpcQueryCacheKey, JsonRpcResponseCache};
+    use crate::rpcs::many::Web3Rpcs;
+    use alloy::primitives::{B256, U64};
+    use sonic_rs::{json, OwnedLazyValue};
+
+    fn key(num: u64, hash: Option<B256>) -> JsonRpcQueryCacheKey {
+        JsonRpcQueryCacheKey::new(
+            "eth_getBalance".into(),
+            format!(
+                "[\"0x0000000000000000000000000000000000000000\",\"{:#x}\"]",
+                num
+            ),
+            Some(U64::from(num)),
+            hash,
+        )
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_reorg_invalidation() {
+        let cache = JsonRpcResponseCache::new(1_000_000);
+
+        let by_num_old = key(1, None);
+        let by_num_new = key(2, None);
+        let by_hash = key(2, Some(B256::with_last_byte(2)));
+
+        for k in [&by_num_old, &by_num_new, &by_hash] {
+            cache.insert(k.clone(), json!("0x1").into()).await;
+        }
+
+        // nulls are not cached
+        let null_key = key(3, None);
+        let null = sonic_rs::from_str::<OwnedLazyValue>("null").unwrap();
+        cache.insert(null_key.clone(), null.into()).await;
+        assert!(cache.get(&null_key).await.is_none());
+
+        cache.invalidate_from_block(U64::from(2));
+        cache.cache.run_pending_tasks().await;
+
+        assert!(cache.get(&by_num_old).await.is_some());
+        assert!(cache.get(&by_num_new).await.is_none());
+        assert!(cache.get(&by_hash).await.is_some());
+
+        let stats = sonic_rs::to_value(&cache).unwrap();
+        assert_eq!(stats["hits"], json!(2));
+        assert_eq!(stats["misses"], json!(2));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_key_for_response() {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), None, None, None, 0)
+                .await
+                .unwrap();
+
+        // no rpcs means no way to know which block the response was for
+        assert!(key(1, None).for_response(&rpcs, &[]).await.is_none());
+
+        // hashes in the params don't depend on the rpcs
+        let by_hash =
+            JsonRpcQueryCacheKey::new("eth_getBlockByHash".into(), "[]".into(), None, None);
+        assert_eq!(
+            by_hash.clone().for_response(&rpcs, &[]).await,
+            Some(by_hash)
+        );
+    }
+}
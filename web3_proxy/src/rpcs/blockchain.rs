@@ -1,14 +1,16 @@
 //! Keep track of the blockchain as seen by a Web3Rpcs.
 use super::consensus::ConsensusFinder;
 use super::many::Web3Rpcs;
+use super::one::Web3Rpc;
 use crate::config::{average_block_interval, BlockAndRpc};
-use crate::errors::Web3ProxyResult;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use alloy::primitives::{TxHash, B256, U64};
 use alloy::rpc::types::Block;
 use moka::future::Cache;
 use serde::ser::SerializeStruct;
 use serde::Serialize;
 use sonic_rs::json;
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::time::Duration;
@@ -141,6 +143,127 @@ impl From<ArcBlock> for BlockHeader {
     }
 }
 
+/// a cursor will fetch this many blocks by number to catch up. past this, the subscription ends
+const MAX_GAP_FILL: u64 = 1_000;
+
+/// blocks further than this below an rpc's head are not expected to reorg
+pub const MAX_REORG_DEPTH: u64 = 128;
+
+/// Follows the consensus head without skipping any blocks.
+/// After a reorg, the new branch is returned again from the fork point (the way geth's newHeads works).
+#[derive(Debug)]
+pub struct BlockCursor {
+    /// recently returned blocks, oldest first
+    recent: VecDeque<BlockHeader>,
+    /// how far back to look for a fork point
+    max_depth: usize,
+}
+
+impl BlockCursor {
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            recent: VecDeque::with_capacity(max_depth),
+            max_depth,
+        }
+    }
+
+    /// Returns the blocks that are no longer canonical (newest first) and the new canonical blocks (oldest first).
+    /// Errors if the gap can't be filled. The caller should end the subscription instead of skipping blocks.
+    pub async fn advance(
+        &mut self,
+        rpcs: &Web3Rpcs,
+        new_head: BlockHeader,
+    ) -> Web3ProxyResult<(Vec<BlockHeader>, Vec<BlockHeader>)> {
+        let Some(last) = self.recent.back().cloned() else {
+            // nothing to fill in yet
+            self.recent.push_back(new_head.clone());
+            return Ok((vec![], vec![new_head]));
+        };
+
+        let (ancestor, branch) = rpcs
+            .new_branch(
+                new_head.clone(),
+                |x| self.recent.contains(x),
+                self.max_depth,
+            )
+            .await;
+
+        let (fork_num, branch) = match ancestor {
+            Some(ancestor) => (ancestor.number(), branch),
+            None => {
+                // the new head is more than max_depth blocks past the last one we returned (or a parent is missing)
+                (last.number(), self.fill_gap(rpcs, &last, new_head).await?)
+            }
+        };
+
+        let split_at = self.recent.partition_point(|x| x.number() <= fork_num);
+
+        let removed = self.recent.drain(split_at..).rev().collect();
+
+        for block in branch.iter() {
+            if self.recent.len() >= self.max_depth {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(block.clone());
+        }
+
+        Ok((removed, branch))
+    }
+
+    /// the canonical blocks after `last` up to and including `new_head`. every parent hash is checked
+    async fn fill_gap(
+        &self,
+        rpcs: &Web3Rpcs,
+        last: &BlockHeader,
+        new_head: BlockHeader,
+    ) -> Web3ProxyResult<Vec<BlockHeader>> {
+        let gap = new_head.number().saturating_sub(last.number());
+
+        if gap.is_zero() || gap > U64::from(MAX_GAP_FILL) {
+            // a reorg deeper than max_depth or a very long outage
+            return Err(Web3ProxyError::BadResponse(
+                format!("unable to fill {} blocks after {}", gap, last).into(),
+            ));
+        }
+
+        let mut branch = Vec::with_capacity(gap.to::<usize>());
+        let mut parent = last.clone();
+
+        let mut num = last.number() + U64::from(1);
+        while num < new_head.number() {
+            let block = rpcs.block_by_number(num).await?;
+
+            if block.parent_hash() != parent.hash() {
+                return Err(Web3ProxyError::BadResponse(
+                    format!(
+                        "block {} does not follow {}. the chain changed while filling a gap",
+                        block, parent
+                    )
+                    .into(),
+                ));
+            }
+
+            branch.push(block.clone());
+            parent = block;
+            num += U64::from(1);
+        }
+
+        if new_head.parent_hash() != parent.hash() {
+            return Err(Web3ProxyError::BadResponse(
+                format!(
+                    "head {} does not follow {}. the chain changed while filling a gap",
+                    new_head, parent
+                )
+                .into(),
+            ));
+        }
+
+        branch.push(new_head);
+
+        Ok(branch)
+    }
+}
+
 impl Web3Rpcs {
     /// add a block to our mappings and track the heaviest chain
     pub async fn try_cache_block_header(
@@ -161,10 +284,18 @@ impl Web3Rpcs {
         if consensus_head {
             let block_num = block.number();
 
-            // TODO: if there is an existing entry with a different block_hash,
             // TODO: use entry api to handle changing existing entries
+            let old_hash = self.blocks_by_number.get(&block_num).await;
+
             self.blocks_by_number.insert(block_num, block_hash).await;
 
+            if old_hash.is_some_and(|x| x != block_hash) {
+                // reorg! anything cached by number alone might be wrong now
+                if let Some(response_cache) = self.response_cache.as_ref() {
+                    response_cache.invalidate_from_block(block_num);
+                }
+            }
+
             for uncle in block.uncles() {
                 self.blocks_by_hash.invalidate(uncle).await;
                 // TODO: save uncles somewhere?
@@ -173,9 +304,17 @@ impl Web3Rpcs {
             // loop to make sure parent hashes match our caches
             // set the first ancestor to the blocks' parent hash. but keep going up the chain
             if let Some(parent_num) = block.number().checked_sub(U64::from(1)) {
-                self.blocks_by_number
-                    .insert(parent_num, *block.parent_hash())
-                    .await;
+                let parent_hash = *block.parent_hash();
+
+                let old_hash = self.blocks_by_number.get(&parent_num).await;
+
+                self.blocks_by_number.insert(parent_num, parent_hash).await;
+
+                if old_hash.is_some_and(|x| x != parent_hash) {
+                    if let Some(response_cache) = self.response_cache.as_ref() {
+                        response_cache.invalidate_from_block(parent_num);
+                    }
+                }
             }
         }
 
@@ -187,6 +326,104 @@ impl Web3Rpcs {
         Ok(block)
     }
 
+    /// get a block header from the cache or from the balanced rpcs
+    pub async fn block_by_hash(&self, hash: &B256) -> Web3ProxyResult<BlockHeader> {
+        if let Some(block) = self.blocks_by_hash.get(hash).await {
+            return Ok(block);
+        }
+
+        // TODO: max_wait from config
+        let block: Option<ArcBlock> = self
+            .internal_request(
+                "eth_getBlockByHash".into(),
+                &(*hash, false),
+                Some(Duration::from_secs(30)),
+            )
+            .await?;
+
+        let block = block.ok_or(Web3ProxyError::UnknownBlockHash(*hash))?;
+
+        self.try_cache_block_header(block.into(), false).await
+    }
+
+    /// get the consensus block at this height from the cache or from the balanced rpcs
+    pub async fn block_by_number(&self, num: U64) -> Web3ProxyResult<BlockHeader> {
+        if let Some(hash) = self.blocks_by_number.get(&num).await {
+            return self.block_by_hash(&hash).await;
+        }
+
+        // TODO: max_wait from config
+        let block: Option<ArcBlock> = self
+            .internal_request(
+                "eth_getBlockByNumber".into(),
+                &(num, false),
+                Some(Duration::from_secs(30)),
+            )
+            .await?;
+
+        let block = block.ok_or(Web3ProxyError::UnknownBlockNumber {
+            known: U64::ZERO,
+            unknown: num,
+        })?;
+
+        self.try_cache_block_header(block.into(), false).await
+    }
+
+    /// The hash of block `num` on the chain that `rpc` is following. Only cached blocks are checked.
+    /// None if the rpc doesn't have the block yet, it is more than MAX_REORG_DEPTH below the rpc's head, or a parent is not cached
+    pub async fn block_hash_on_rpc(&self, rpc: &Web3Rpc, num: U64) -> Option<B256> {
+        let mut block = rpc.head_block()?;
+
+        if block.number() < num || block.number() - num > U64::from(MAX_REORG_DEPTH) {
+            return None;
+        }
+
+        while block.number() > num {
+            block = self.blocks_by_hash.get(block.parent_hash()).await?;
+        }
+
+        Some(*block.hash())
+    }
+
+    /// Follow parent hashes back from `new_head` until reaching a block that `is_known`.
+    /// Returns that common ancestor and the blocks after it, oldest first.
+    /// The ancestor is None if max_depth was reached or a parent could not be found.
+    pub async fn new_branch(
+        &self,
+        new_head: BlockHeader,
+        is_known: impl Fn(&BlockHeader) -> bool,
+        max_depth: usize,
+    ) -> (Option<BlockHeader>, Vec<BlockHeader>) {
+        let mut branch = vec![];
+        let mut block = new_head;
+
+        let ancestor = loop {
+            if is_known(&block) {
+                break Some(block);
+            }
+
+            let parent_hash = *block.parent_hash();
+
+            branch.push(block);
+
+            if branch.len() >= max_depth {
+                break None;
+            }
+
+            match self.block_by_hash(&parent_hash).await {
+                Ok(parent) => block = parent,
+                Err(err) => {
+                    warn!(?err, %parent_hash, "unable to find parent block");
+                    break None;
+                }
+            }
+        };
+
+        branch.reverse();
+
+        (ancestor, branch)
+    }
+
     pub(super) async fn process_incoming_blocks(
         &self,
         mut block_and_rpc_receiver: mpsc::UnboundedReceiver<BlockAndRpc>,
@@ -196,8 +433,7 @@ impl Web3Rpcs {
         }
 
         // TODO: should this be spawned and then we just hold onto the handle here?
-        let mut consensus_finder =
-            ConsensusFinder::new(Some(self.max_head_block_age), self.max_head_block_lag);
+        let mut consensus_finder = ConsensusFinder::new();
 
         // TODO: what timeout on block receiver? we want to keep consensus_finder fresh so that server tiers are correct
         let triple_block_time = average_block_interval(self.chain_id).mul_f32(3.0);
@@ -244,3 +480,161 @@ impl Web3Rpcs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockCursor, BlockHeader, Web3Rpcs};
+    use crate::rpcs::one::Web3Rpc;
+    use alloy::primitives::{B256, U64};
+    use alloy::rpc::types::Block;
+    use std::sync::Arc;
+    use tokio::sync::watch;
+
+    fn block(number: u64, hash: u8, parent_hash: u8) -> BlockHeader {
+        let mut block: Block = Block::default();
+        block.header.hash = B256::with_last_byte(hash);
+        block.header.inner.number = number;
+        block.header.inner.parent_hash = B256::with_last_byte(parent_hash);
+        BlockHeader::new(Arc::new(block))
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_block_hash_on_rpc() {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), None, None, None, 0)
+                .await
+                .unwrap();
+
+        // 1 <- 2 <- 3 <- 4 is the consensus chain. 3 <- 14 is a fork
+        let chain: Vec<_> = (1..=4).map(|x| block(x, x as u8, x as u8 - 1)).collect();
+        let fork = block(4, 14, 3);
+
+        for x in chain.iter().chain([&fork]) {
+            rpcs.try_cache_block_header(x.clone(), false).await.unwrap();
+        }
+
+        let (head_block_sender, _) = watch::channel(Some(fork.clone()));
+
+        let rpc = Web3Rpc {
+            head_block_sender: Some(head_block_sender),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            rpcs.block_hash_on_rpc(&rpc, U64::from(4)).await,
+            Some(B256::with_last_byte(14))
+        );
+        assert_eq!(
+            rpcs.block_hash_on_rpc(&rpc, U64::from(2)).await,
+            Some(B256::with_last_byte(2))
+        );
+
+        // the rpc doesn't have this block yet
+        assert_eq!(rpcs.block_hash_on_rpc(&rpc, U64::from(5)).await, None);
+
+        // block 0 was never cached
+        assert_eq!(rpcs.block_hash_on_rpc(&rpc, U64::ZERO).await, None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_new_branch_after_reorg() {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), None, None, None, 0)
+                .await
+                .unwrap();
+
+        // 1 <- 2 <- 3 <- 4 <- 5 is the old chain. 3 <- 14 <- 15 <- 16 is the new chain
+        let old_chain: Vec<_> = (1..=5).map(|x| block(x, x as u8, x as u8 - 1)).collect();
+        let new_chain = [block(4, 14, 3), block(5, 15, 14), block(6, 16, 15)];
+
+        for x in old_chain.iter().chain(new_chain.iter()) {
+            rpcs.try_cache_block_header(x.clone(), false).await.unwrap();
+        }
+
+        let (ancestor, branch) = rpcs
+            .new_branch(new_chain[2].clone(), |x| old_chain.contains(x), 10)
+            .await;
+
+        assert_eq!(ancestor.as_ref(), Some(&old_chain[2]));
+        assert_eq!(branch, new_chain);
+
+        // stop early if the branch is too long
+        let (ancestor, branch) = rpcs
+            .new_branch(new_chain[2].clone(), |x| old_chain.contains(x), 2)
+            .await;
+
+        assert_eq!(ancestor, None);
+        assert_eq!(branch, new_chain[1..]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_cursor_fills_gaps_and_follows_reorgs() {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), None, None, None, 0)
+                .await
+                .unwrap();
+
+        let old_chain: Vec<_> = (1..=5).map(|x| block(x, x as u8, x as u8 - 1)).collect();
+        let new_chain = [block(4, 14, 3), block(5, 15, 14)];
+
+        for x in old_chain.iter().chain(new_chain.iter()) {
+            rpcs.try_cache_block_header(x.clone(), false).await.unwrap();
+        }
+
+        let mut cursor = BlockCursor::new(10);
+
+        let (removed, added) = cursor.advance(&rpcs, old_chain[1].clone()).await.unwrap();
+        assert!(removed.is_empty());
+        assert_eq!(added, old_chain[1..2]);
+
+        // 3 and 4 were skipped by the watch channel
+        let (removed, added) = cursor.advance(&rpcs, old_chain[4].clone()).await.unwrap();
+        assert!(removed.is_empty());
+        assert_eq!(added, old_chain[2..]);
+
+        // the same head again is a noop
+        let (removed, added) = cursor.advance(&rpcs, old_chain[4].clone()).await.unwrap();
+        assert!(removed.is_empty());
+        assert!(added.is_empty());
+
+        // reorg back to block 3
+        let (removed, added) = cursor.advance(&rpcs, new_chain[1].clone()).await.unwrap();
+        assert_eq!(removed, [old_chain[4].clone(), old_chain[3].clone()]);
+        assert_eq!(added, new_chain);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_cursor_fills_gaps_past_max_depth() {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), None, None, None, 0)
+                .await
+                .unwrap();
+
+        // 1 <- 2 <- 3 <- 4 <- 5 <- 6 is the consensus chain. 3 <- 14 <- 15 <- 16 is a fork
+        let chain: Vec<_> = (1..=6).map(|x| block(x, x as u8, x as u8 - 1)).collect();
+        let fork = [block(4, 14, 3), block(5, 15, 14), block(6, 16, 15)];
+
+        for x in chain.iter() {
+            rpcs.try_cache_block_header(x.clone(), true).await.unwrap();
+        }
+        for x in fork.iter() {
+            rpcs.try_cache_block_header(x.clone(), false).await.unwrap();
+        }
+
+        let mut cursor = BlockCursor::new(2);
+
+        cursor.advance(&rpcs, chain[0].clone()).await.unwrap();
+
+        // too far for the parent hashes. the gap is filled by number
+        let (removed, added) = cursor.advance(&rpcs, chain[5].clone()).await.unwrap();
+        assert!(removed.is_empty());
+        assert_eq!(added, chain[1..]);
+
+        // a fork deeper than max_depth can't be followed
+        let mut cursor = BlockCursor::new(2);
+
+        cursor.advance(&rpcs, chain[0].clone()).await.unwrap();
+
+        assert!(cursor.advance(&rpcs, fork[2].clone()).await.is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Backing off from rpcs that keep failing.
+use nanorand::Rng;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Exponential backoff with full jitter.
+/// The jitter keeps all of our proxies from reconnecting to a recovered rpc at the same moment.
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// the most the next delay could be
+    fn cap(&self) -> Duration {
+        self.base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max)
+    }
+
+    /// a random delay between 0 and the cap. the cap doubles every time
+    pub fn next_delay(&mut self) -> Duration {
+        let cap = self.cap().as_millis() as u64;
+
+        self.attempt = self.attempt.saturating_add(1);
+
+        Duration::from_millis(nanorand::tls_rng().generate_range(0..=cap))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// requests are allowed
+    Closed,
+    /// too many failures. no requests until the open duration passes
+    Open,
+    /// the open duration passed. only health checks are sent until one works
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+    /// consecutive failures
+    failures: u32,
+    opened_at: Option<Instant>,
+    /// how many times the breaker has opened
+    trips: u64,
+}
+
+/// Opens after `max_failures` health checks fail in a row. An open rpc is left out of the consensus and gets no requests.
+/// After `open_duration`, the breaker is half-open and the next health check decides if it closes or opens again.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    /// 0 disables the breaker
+    max_failures: u32,
+    open_duration: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_failures: u32, open_duration: Duration) -> Self {
+        Self {
+            max_failures,
+            open_duration,
+            inner: Default::default(),
+        }
+    }
+
+    fn state_at(&self, inner: &BreakerInner, now: Instant) -> BreakerState {
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(x) if now < x + self.open_duration => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state_at(&self.inner.lock(), Instant::now())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state() == BreakerState::Closed
+    }
+
+    /// returns true if this failure opened the breaker
+    pub fn failure(&self) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+
+        inner.failures = inner.failures.saturating_add(1);
+
+        match self.state_at(&inner, now) {
+            BreakerState::Closed if inner.failures >= self.max_failures => {}
+            // the probe failed
+            BreakerState::HalfOpen => {}
+            _ => return false,
+        }
+
+        inner.opened_at = Some(now);
+        inner.trips += 1;
+
+        true
+    }
+
+    /// returns true if this success closed the breaker
+    pub fn success(&self) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+
+        match self.state_at(&inner, now) {
+            BreakerState::Closed => {
+                inner.failures = 0;
+                false
+            }
+            // successes while open don't count. the rpc needs to stay out for the whole duration
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => {
+                inner.failures = 0;
+                inner.opened_at = None;
+                true
+            }
+        }
+    }
+}
+
+impl Serialize for CircuitBreaker {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let inner = self.inner.lock();
+
+        let mut state = serializer.serialize_struct("CircuitBreaker", 3)?;
+
+        state.serialize_field("state", &self.state_at(&inner, Instant::now()))?;
+        state.serialize_field("failures", &inner.failures)?;
+        state.serialize_field("trips", &inner.trips)?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, BreakerState, CircuitBreaker};
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        for cap in [1, 2, 4, 8, 10, 10] {
+            assert_eq!(backoff.cap(), Duration::from_secs(cap));
+            assert!(backoff.next_delay() <= Duration::from_secs(cap));
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.cap(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+
+        assert!(!breaker.failure());
+        assert!(!breaker.failure());
+        // a success resets the count
+        assert!(!breaker.success());
+        assert!(!breaker.failure());
+        assert!(!breaker.failure());
+        assert!(breaker.failure());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // it stays open even if the rpc looks fine
+        assert!(!breaker.success());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        // a failed probe opens it again
+        assert!(breaker.failure());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(breaker.success());
+        assert!(breaker.is_closed());
+
+        // 0 disables it
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(30));
+        for _ in 0..10 {
+            assert!(!breaker.failure());
+        }
+        assert!(breaker.is_closed());
+    }
+}
@@ -1,6 +1,6 @@
 use super::blockchain::BlockHeader;
 use super::many::Web3Rpcs;
-use super::one::Web3Rpc;
+use super::one::{AdminState, Web3Rpc};
 use super::request::OpenRequestHandle;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::jsonrpc::ValidatedRequest;
@@ -86,6 +86,10 @@ enum SortMethod {
 #[derive(Clone, Debug, Serialize)]
 pub struct RankedRpcs {
     pub head_block: Option<BlockHeader>,
+    /// the highest "finalized" block that at least min_synced_rpcs of the synced rpcs have reached
+    pub finalized_block: Option<BlockHeader>,
+    /// the highest "safe" block that at least min_synced_rpcs of the synced rpcs have reached
+    pub safe_block: Option<BlockHeader>,
     pub num_synced: usize,
     pub backups_needed: bool,
     pub check_block_data: bool,
@@ -103,6 +107,19 @@ pub struct RpcsForRequest {
     request: Arc<ValidatedRequest>,
 }
 
+/// the highest block that at least `min_rpcs` of the rpcs have reached. rpcs that don't know the block are ignored
+fn consensus_block_tag(
+    rpcs: &HashSet<Arc<Web3Rpc>>,
+    min_rpcs: usize,
+    f: impl Fn(&Web3Rpc) -> Option<BlockHeader>,
+) -> Option<BlockHeader> {
+    let mut blocks: Vec<_> = rpcs.iter().filter_map(|x| f(x)).collect();
+
+    blocks.sort_by_key(|x| Reverse(x.number()));
+
+    blocks.into_iter().nth(min_rpcs.saturating_sub(1))
+}
+
 impl RankedRpcs {
     pub fn from_rpcs(
         rpcs: Vec<Arc<Web3Rpc>>,
@@ -120,12 +137,15 @@ impl RankedRpcs {
 
         let sort_mode = SortMethod::Shuffle;
 
+        // these rpcs aren't watched, so they don't poll the block tags
         Self {
             backups_needed,
             check_block_data,
+            finalized_block: None,
             head_block,
             inner: rpcs,
             num_synced,
+            safe_block: None,
             sort_mode,
         }
     }
@@ -169,6 +189,11 @@ impl RankedRpcs {
             let backups_needed = best_rpcs.iter().any(|x| x.backup);
             let num_synced = best_rpcs.len();
 
+            // only the rpcs on the head block get a say in finality
+            let finalized_block =
+                consensus_block_tag(&best_rpcs, min_synced_rpcs, Web3Rpc::finalized_block);
+            let safe_block = consensus_block_tag(&best_rpcs, min_synced_rpcs, Web3Rpc::safe_block);
+
             // add all the rpcs that are behind the ranked rpcs. these might be needed for serving archive requests
             for (x, x_head) in heads.iter() {
                 // TODO: do we care about this "contains" when a set won't add more than once anyways?
@@ -200,7 +225,9 @@ impl RankedRpcs {
             let consensus = RankedRpcs {
                 backups_needed,
                 check_block_data: true,
+                finalized_block,
                 head_block: Some(best_block),
+                safe_block,
                 sort_mode,
                 inner: best_rpcs,
                 num_synced,
@@ -230,6 +257,15 @@ impl RankedRpcs {
         let min_block_needed = web3_request.min_block_needed();
         let max_block_needed = web3_request.max_block_needed();
 
+        // rpcs that haven't finalized the block yet could still be on a different fork
+        let finalized_needed = max_block_needed.filter(|x| {
+            self.finalized_block
+                .as_ref()
+                .is_some_and(|finalized| *x <= finalized.number())
+        });
+
+        let method = web3_request.inner.method();
+
         // max lag was already handled
         for rpc in self.inner.iter().cloned() {
             if rpc.backup && !self.backups_needed {
@@ -238,6 +274,11 @@ impl RankedRpcs {
                 continue;
             }
 
+            if !rpc.supports_method(method) {
+                trace!(%rpc, %method, "skipping rpc without the method");
+                continue;
+            }
+
             if self.check_block_data {
                 if let Some(block_needed) = min_block_needed {
                     if !rpc.has_block_data(block_needed) {
@@ -251,6 +292,15 @@ impl RankedRpcs {
                         continue;
                     }
                 }
+                if let Some(block_needed) = finalized_needed {
+                    if rpc
+                        .finalized_block()
+                        .is_none_or(|x| x.number() < block_needed)
+                    {
+                        outer_for_request.push(rpc);
+                        continue;
+                    }
+                }
             }
 
             inner_for_request.push(rpc);
@@ -331,6 +381,18 @@ impl Web3Rpcs {
             .and_then(|x| x.borrow().as_ref().map(|x| x.number()))
     }
 
+    /// the consensus "finalized" block. None if the rpcs don't support the tag
+    #[inline]
+    pub fn finalized_block(&self) -> Option<BlockHeader> {
+        self.watch_finalized_block.borrow().clone()
+    }
+
+    /// the consensus "safe" block. None if the rpcs don't support the tag
+    #[inline]
+    pub fn safe_block(&self) -> Option<BlockHeader> {
+        self.watch_safe_block.borrow().clone()
+    }
+
     pub fn synced(&self) -> bool {
         let consensus = self.watch_ranked_rpcs.borrow();
 
@@ -357,16 +419,18 @@ type FirstSeenCache = Cache<B256, Instant>;
 /// A ConsensusConnections builder that tracks all connection heads across multiple groups of servers
 pub struct ConsensusFinder {
     rpc_heads: HashMap<Arc<Web3Rpc>, BlockHeader>,
-    /// no consensus if the best known block is too old
-    max_head_block_age: Option<Duration>,
-    /// no consensus if the best consensus block is too far behind the best known
-    max_head_block_lag: U64,
     /// Block Hash -> First Seen Instant. used to track rpc.head_delay. The same cache should be shared between all ConnectionsGroups
     first_seen: FirstSeenCache,
 }
 
+impl Default for ConsensusFinder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConsensusFinder {
-    pub fn new(max_head_block_age: Option<Duration>, max_head_block_lag: U64) -> Self {
+    pub fn new() -> Self {
         // TODO: what's a good capacity for this? it shouldn't need to be very large
         let first_seen = Cache::new(16);
 
@@ -374,8 +438,6 @@ impl ConsensusFinder {
 
         Self {
             rpc_heads,
-            max_head_block_age,
-            max_head_block_lag,
             first_seen,
         }
     }
@@ -439,6 +501,24 @@ impl ConsensusFinder {
             .watch_ranked_rpcs
             .send_replace(Some(new_ranked_rpcs.clone()));
 
+        // finality never goes backwards. a new rpc that is still catching up shouldn't move it
+        for (sender, new_block) in [
+            (
+                &web3_rpcs.watch_finalized_block,
+                &new_ranked_rpcs.finalized_block,
+            ),
+            (&web3_rpcs.watch_safe_block, &new_ranked_rpcs.safe_block),
+        ] {
+            sender.send_if_modified(|old_block| match (old_block.as_ref(), new_block) {
+                (_, None) => false,
+                (Some(old), Some(new)) if old.number() >= new.number() => false,
+                (_, Some(new)) => {
+                    *old_block = Some(new.clone());
+                    true
+                }
+            });
+        }
+
         let backups_voted_str = if backups_needed { "B " } else { "" };
 
         let rpc_head_str = if let Some(rpc) = rpc.as_ref() {
@@ -715,7 +795,8 @@ impl ConsensusFinder {
             0 => {}
             1 => {
                 for rpc in self.rpc_heads.keys() {
-                    rpc.tier.store(1, atomic::Ordering::SeqCst)
+                    rpc.tier
+                        .store(rpc.tier_override().unwrap_or(1), atomic::Ordering::SeqCst)
                 }
             }
             _ => {
@@ -795,6 +876,9 @@ impl ConsensusFinder {
 
                     trace!("{} - p50_sec: {}, tier {}", rpc, median_latency_sec, tier);
 
+                    // an admin can pin the tier
+                    let tier = rpc.tier_override().unwrap_or(tier);
+
                     rpc.tier.store(tier, atomic::Ordering::SeqCst);
                 }
             }
@@ -807,18 +891,16 @@ impl ConsensusFinder {
     pub async fn rank_rpcs(&mut self, web3_rpcs: &Web3Rpcs) -> Web3ProxyResult<Option<RankedRpcs>> {
         self.update_tiers().await?;
 
+        // no consensus if the best known block is too old
+        // these are read every time because config reloads can change them
+        let max_head_block_age = web3_rpcs.max_head_block_age();
+        // no consensus if the best consensus block is too far behind the best known
+        let max_head_block_lag = web3_rpcs.max_head_block_lag();
+
         let minmax_block = self
             .rpc_heads
             .values()
-            .filter(|x| {
-                if let Some(max_block_age) = self.max_head_block_age {
-                    if x.age() > max_block_age {
-                        return false;
-                    }
-                }
-
-                true
-            })
+            .filter(|x| x.age() <= max_head_block_age)
             .minmax_by_key(|x| x.number());
 
         let (lowest_block, highest_block) = match minmax_block {
@@ -835,7 +917,7 @@ impl ConsensusFinder {
 
         // TODO: move this default. should be in config, not here
         // TODO: arbitrum needs more slack
-        let max_lag_block_number = highest_block_number.saturating_sub(self.max_head_block_lag);
+        let max_lag_block_number = highest_block_number.saturating_sub(max_head_block_lag);
 
         trace!("max_lag_block_number: {}", max_lag_block_number);
 
@@ -849,18 +931,20 @@ impl ConsensusFinder {
             HashMap::with_capacity(num_known);
 
         for (rpc, rpc_head) in self.rpc_heads.iter() {
-            if !rpc.healthy.load(atomic::Ordering::SeqCst) {
+            if !rpc.healthy.load(atomic::Ordering::SeqCst) || !rpc.circuit_breaker.is_closed() {
                 // TODO: should unhealthy servers get a vote? they were included in minmax_block. i think that is enough
                 continue;
             }
 
+            if rpc.admin_state() == AdminState::Disabled {
+                continue;
+            }
+
             let mut block_to_check = rpc_head.clone();
 
             while block_to_check.number() >= max_lag_block_number {
-                if let Some(max_age) = self.max_head_block_age {
-                    if block_to_check.age() > max_age {
-                        break;
-                    }
+                if block_to_check.age() > max_head_block_age {
+                    break;
                 }
 
                 if !rpc.backup {
@@ -895,8 +979,8 @@ impl ConsensusFinder {
 
         // we finished processing all tiers. check for primary results (if anything but the last tier found consensus, we already returned above)
         if let Some(consensus) = RankedRpcs::from_votes(
-            web3_rpcs.min_synced_rpcs,
-            web3_rpcs.min_sum_soft_limit,
+            web3_rpcs.min_head_rpcs(),
+            web3_rpcs.min_sum_soft_limit(),
             max_lag_block_number,
             primary_votes,
             self.rpc_heads.clone(),
@@ -906,8 +990,8 @@ impl ConsensusFinder {
 
         // primary votes didn't work. hopefully backup tiers are synced
         Ok(RankedRpcs::from_votes(
-            web3_rpcs.min_synced_rpcs,
-            web3_rpcs.min_sum_soft_limit,
+            web3_rpcs.min_head_rpcs(),
+            web3_rpcs.min_sum_soft_limit(),
             max_lag_block_number,
             backup_votes,
             self.rpc_heads.clone(),
@@ -944,6 +1028,62 @@ fn best_rpc<'a>(rpc_a: &'a Arc<Web3Rpc>, rpc_b: &'a Arc<Web3Rpc>) -> &'a Arc<Web
 */
 
 impl RpcsForRequest {
+    /// the synced rpcs. best first
+    pub fn inner(&self) -> &[Arc<Web3Rpc>] {
+        &self.inner
+    }
+
+    /// rpcs to try once the inner rpcs have failed
+    pub fn outer(&self) -> &[Arc<Web3Rpc>] {
+        &self.outer
+    }
+
+    /// keep only the rpcs that pass the check
+    pub fn retain(&mut self, f: impl Fn(&Web3Rpc) -> bool) {
+        self.inner.retain(|x| f(x));
+        self.outer.retain(|x| f(x));
+    }
+
+    /// Open handles on up to `max_rpcs` of the synced rpcs without waiting. 0 means all of them.
+    /// Rpcs that are rate limited or lagged are skipped so the next best rpc gets a chance.
+    /// TODO: if none of the inner rpcs are ready, should we wait like `to_stream` does?
+    pub async fn try_handles(&self, max_rpcs: usize) -> Vec<OpenRequestHandle> {
+        let max_rpcs = if max_rpcs == 0 {
+            self.inner.len()
+        } else {
+            max_rpcs.min(self.inner.len())
+        };
+
+        let mut handles = Vec::with_capacity(max_rpcs);
+
+        for rpc in self.inner.iter() {
+            if handles.len() >= max_rpcs {
+                break;
+            }
+
+            match rpc.try_request_handle(&self.request, None, false).await {
+                Ok(OpenRequestResult::Handle(handle)) => {
+                    trace!("opened handle: {}", rpc);
+                    handles.push(handle);
+                }
+                Ok(OpenRequestResult::RetryAt(_)) => {
+                    trace!("{} is rate limited. skipping", rpc);
+                }
+                Ok(OpenRequestResult::Lagged(_)) => {
+                    trace!("{} is lagged. skipping", rpc);
+                }
+                Ok(OpenRequestResult::Failed) => {
+                    trace!("{} not ready. skipping", rpc);
+                }
+                Err(err) => {
+                    trace!("No request handle for {}. err={:?}", rpc, err);
+                }
+            }
+        }
+
+        handles
+    }
+
     pub fn to_stream(self) -> impl Stream<Item = OpenRequestHandle> {
         stream! {
             trace!("entered stream");
@@ -1068,3 +1208,54 @@ impl std::fmt::Display for MaybeBlockNum<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::consensus_block_tag;
+    use crate::rpcs::blockchain::BlockHeader;
+    use crate::rpcs::one::Web3Rpc;
+    use alloy::primitives::{B256, U64};
+    use alloy::rpc::types::Block;
+    use hashbrown::HashSet;
+    use std::sync::Arc;
+
+    fn rpc(name: &str, finalized: Option<u64>) -> Arc<Web3Rpc> {
+        let finalized = finalized.map(|number| {
+            let mut block: Block = Block::default();
+            block.header.hash = B256::with_last_byte(number as u8);
+            block.header.inner.number = number;
+            BlockHeader::new(Arc::new(block))
+        });
+
+        let rpc = Web3Rpc {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        *rpc.finalized_block.write() = finalized;
+
+        Arc::new(rpc)
+    }
+
+    #[test]
+    fn test_consensus_block_tag() {
+        let rpcs: HashSet<_> = [
+            rpc("a", Some(100)),
+            rpc("b", Some(90)),
+            rpc("c", Some(80)),
+            rpc("d", None),
+        ]
+        .into_iter()
+        .collect();
+
+        let number = |min_rpcs| {
+            consensus_block_tag(&rpcs, min_rpcs, Web3Rpc::finalized_block).map(|x| x.number())
+        };
+
+        assert_eq!(number(1), Some(U64::from(100)));
+        assert_eq!(number(2), Some(U64::from(90)));
+        assert_eq!(number(3), Some(U64::from(80)));
+        // rpcs without the tag don't count
+        assert_eq!(number(4), None);
+    }
+}
@@ -0,0 +1,601 @@
+//! Persistent connections to a node's ipc socket.
+//!
+//! Requests get our own ids so that many of them can share one connection. The node's responses are matched back up by id.
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{ParsedResponse, ResponsePayload};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sonic_rs::{json, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::AbortHandle;
+use tokio::time::{timeout, Duration};
+use tracing::{trace, warn};
+
+/// how many connections to open to each node
+/// TODO: config for this?
+const IPC_POOL_SIZE: usize = 4;
+
+/// a node that doesn't respond within this long is given up on. the same as the http client's timeout
+const IPC_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60 - 2);
+
+/// a bigger response closes the connection. the stream can't be resynced after skipping part of a frame
+const MAX_IPC_FRAME_BYTES: usize = 256 * 1024 * 1024;
+
+/// A few persistent connections to one ipc socket. Closed connections are replaced on the next request.
+pub struct IpcPool {
+    path: PathBuf,
+    slots: Vec<tokio::sync::Mutex<Option<Arc<IpcConnection>>>>,
+    next_slot: AtomicUsize,
+}
+
+impl IpcPool {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            slots: (0..IPC_POOL_SIZE).map(|_| Default::default()).collect(),
+            next_slot: Default::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// an open connection. connecting if necessary
+    async fn connection(&self) -> Web3ProxyResult<Arc<IpcConnection>> {
+        let i = self.next_slot.fetch_add(1, atomic::Ordering::Relaxed) % self.slots.len();
+
+        let mut slot = self.slots[i].lock().await;
+
+        if let Some(x) = slot.as_ref().filter(|x| !x.is_closed()) {
+            return Ok(x.clone());
+        }
+
+        trace!(path=?self.path, slot=i, "connecting to ipc");
+
+        let x = Arc::new(IpcConnection::connect(&self.path).await?);
+
+        *slot = Some(x.clone());
+
+        Ok(x)
+    }
+
+    /// send a request and return the full response. the response's id is ours, not the caller's
+    pub async fn request(&self, method: &str, params: &Value) -> Web3ProxyResult<Vec<u8>> {
+        self.connection().await?.request(method, params).await
+    }
+
+    /// eth_subscribe. the subscription ends when the connection closes
+    pub async fn subscribe(&self, params: &Value) -> Web3ProxyResult<IpcSubscription> {
+        let connection = self.connection().await?;
+
+        let (sender, notifications) = mpsc::unbounded_channel();
+
+        let response = connection
+            .send("eth_subscribe", params, Some(sender))
+            .await?;
+
+        let response: ParsedResponse<String> = sonic_rs::from_slice(&response)?;
+
+        match response.payload {
+            ResponsePayload::Success { result } => Ok(IpcSubscription {
+                connection,
+                id: result,
+                notifications,
+            }),
+            ResponsePayload::Error { error } => Err(error.into()),
+        }
+    }
+}
+
+impl fmt::Debug for IpcPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpcPool")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// a request waiting for its response
+struct Pending {
+    response: oneshot::Sender<Vec<u8>>,
+    /// set for eth_subscribe. the subscription is registered before the response is sent so no notifications are missed
+    subscription: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+/// state shared with the connection's reader task
+#[derive(Default)]
+struct Shared {
+    closed: AtomicBool,
+    pending: Mutex<HashMap<u64, Pending>>,
+    subscriptions: Mutex<HashMap<String, mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+impl Shared {
+    fn close(&self) {
+        self.closed.store(true, atomic::Ordering::SeqCst);
+
+        // dropping the senders wakes everyone waiting on this connection
+        self.pending.lock().clear();
+        self.subscriptions.lock().clear();
+    }
+
+    /// send a frame from the node to whoever is waiting for it
+    fn route(&self, frame: Vec<u8>) {
+        #[derive(Deserialize)]
+        struct Envelope {
+            id: Option<u64>,
+            params: Option<EnvelopeParams>,
+        }
+
+        #[derive(Deserialize)]
+        struct EnvelopeParams {
+            subscription: String,
+        }
+
+        let envelope: Envelope = match sonic_rs::from_slice(&frame) {
+            Ok(x) => x,
+            Err(err) => {
+                // batches are never sent, so arrays end up here too
+                warn!(?err, "unexpected message from ipc");
+                return;
+            }
+        };
+
+        if let Some(id) = envelope.id {
+            let Some(pending) = self.pending.lock().remove(&id) else {
+                trace!(%id, "response for a request that is gone");
+                return;
+            };
+
+            self.respond(pending, frame);
+        } else if let Some(params) = envelope.params {
+            let mut subscriptions = self.subscriptions.lock();
+
+            if let Some(sender) = subscriptions.get(&params.subscription) {
+                if sender.send(frame).is_err() {
+                    subscriptions.remove(&params.subscription);
+                }
+            }
+        } else {
+            // an error with a null id. the node couldn't read one of our requests, but we don't know which
+            let mut pending = self.pending.lock();
+
+            if pending.len() == 1 {
+                let (_, x) = pending.drain().next().expect("len was just checked");
+                drop(pending);
+
+                self.respond(x, frame);
+            } else {
+                drop(pending);
+
+                warn!("ipc response without an id. closing the connection");
+                self.close();
+            }
+        }
+    }
+
+    fn respond(&self, pending: Pending, frame: Vec<u8>) {
+        #[derive(Deserialize)]
+        struct SubscribeResult {
+            result: String,
+        }
+
+        if let Some(sender) = pending.subscription {
+            if let Ok(x) = sonic_rs::from_slice::<SubscribeResult>(&frame) {
+                self.subscriptions.lock().insert(x.result, sender);
+            }
+        }
+
+        let _ = pending.response.send(frame);
+    }
+}
+
+/// removes a request's pending entry if it times out or the caller goes away
+struct PendingGuard<'a> {
+    id: u64,
+    shared: &'a Shared,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.shared.pending.lock().remove(&self.id);
+    }
+}
+
+/// One persistent connection to an ipc socket
+pub struct IpcConnection {
+    next_id: AtomicU64,
+    reader: AbortHandle,
+    shared: Arc<Shared>,
+    /// whole requests for the writer task. a caller going away can't leave half of a request on the stream
+    writer: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl IpcConnection {
+    pub async fn connect(path: &Path) -> Web3ProxyResult<Self> {
+        let stream = UnixStream::connect(path).await?;
+
+        let (reader, writer) = stream.into_split();
+
+        let shared = Arc::new(Shared::default());
+
+        let reader = tokio::spawn(read_frames(reader, shared.clone())).abort_handle();
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        // this exits once the connection is dropped
+        tokio::spawn(write_frames(writer, receiver, shared.clone()));
+
+        Ok(Self {
+            next_id: AtomicU64::new(1),
+            reader,
+            shared,
+            writer: sender,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(atomic::Ordering::SeqCst)
+    }
+
+    pub async fn request(&self, method: &str, params: &Value) -> Web3ProxyResult<Vec<u8>> {
+        self.send(method, params, None).await
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        params: &Value,
+        subscription: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    ) -> Web3ProxyResult<Vec<u8>> {
+        if self.is_closed() {
+            return Err(closed());
+        }
+
+        let id = self.next_id.fetch_add(1, atomic::Ordering::Relaxed);
+
+        let mut request = sonic_rs::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+        request.push(b'\n');
+
+        let (sender, receiver) = oneshot::channel();
+
+        self.shared.pending.lock().insert(
+            id,
+            Pending {
+                response: sender,
+                subscription,
+            },
+        );
+
+        let _guard = PendingGuard {
+            id,
+            shared: &self.shared,
+        };
+
+        self.writer.send(request).map_err(|_| closed())?;
+
+        match timeout(IPC_REQUEST_TIMEOUT, receiver).await {
+            Ok(x) => x.map_err(|_| closed()),
+            Err(_) => Err(Web3ProxyError::Timeout(Some(IPC_REQUEST_TIMEOUT))),
+        }
+    }
+}
+
+impl Drop for IpcConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Notifications from an eth_subscribe over ipc
+pub struct IpcSubscription {
+    connection: Arc<IpcConnection>,
+    id: String,
+    notifications: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl IpcSubscription {
+    /// the next notification's result. None once the connection is closed
+    pub async fn next<T: DeserializeOwned>(&mut self) -> Option<Web3ProxyResult<T>> {
+        #[derive(Deserialize)]
+        struct Notification<T> {
+            params: NotificationParams<T>,
+        }
+
+        #[derive(Deserialize)]
+        struct NotificationParams<T> {
+            result: T,
+        }
+
+        let frame = self.notifications.recv().await?;
+
+        let x = sonic_rs::from_slice::<Notification<T>>(&frame)
+            .map(|x| x.params.result)
+            .map_err(Into::into);
+
+        Some(x)
+    }
+}
+
+impl Drop for IpcSubscription {
+    fn drop(&mut self) {
+        self.connection.shared.subscriptions.lock().remove(&self.id);
+
+        if self.connection.is_closed() {
+            return;
+        }
+
+        // tell the node to stop sending. the connection might be shared with other requests
+        let connection = self.connection.clone();
+        let id = std::mem::take(&mut self.id);
+
+        tokio::spawn(async move {
+            if let Err(err) = connection.request("eth_unsubscribe", &json!([id])).await {
+                trace!(?err, "failed unsubscribing on ipc");
+            }
+        });
+    }
+}
+
+fn closed() -> Web3ProxyError {
+    anyhow::anyhow!("ipc connection closed").into()
+}
+
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut requests: mpsc::UnboundedReceiver<Vec<u8>>,
+    shared: Arc<Shared>,
+) {
+    while let Some(request) = requests.recv().await {
+        if let Err(err) = writer.write_all(&request).await {
+            warn!(?err, "ipc write failed");
+            shared.close();
+            break;
+        }
+    }
+}
+
+async fn read_frames(mut reader: OwnedReadHalf, shared: Arc<Shared>) {
+    let mut framer = JsonFramer::default();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => {
+                trace!("ipc connection closed by the node");
+                break;
+            }
+            Ok(n) => {
+                framer.push(&buf[..n]);
+
+                while let Some(frame) = framer.next_frame() {
+                    shared.route(frame);
+                }
+
+                if framer.buffered() > MAX_IPC_FRAME_BYTES {
+                    warn!(
+                        bytes = framer.buffered(),
+                        "ipc response is too large. closing the connection"
+                    );
+                    break;
+                }
+            }
+            Err(err) => {
+                warn!(?err, "ipc read failed");
+                break;
+            }
+        }
+    }
+
+    shared.close();
+}
+
+/// Splits a stream of bytes into json values.
+/// Nodes usually put a newline after each value, but that isn't required.
+#[derive(Default)]
+struct JsonFramer {
+    buf: Vec<u8>,
+    /// how much of buf has been checked
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonFramer {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// the bytes of an unfinished frame
+    fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        while self.scanned < self.buf.len() {
+            let b = self.buf[self.scanned];
+            self.scanned += 1;
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+
+                    if self.depth == 0 {
+                        let frame = self.buf.drain(..self.scanned).collect();
+                        self.scanned = 0;
+                        return Some(frame);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpcPool, JsonFramer};
+    use alloy::primitives::U64;
+    use serde::Deserialize;
+    use sonic_rs::{json, JsonValueTrait};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+    use tokio::time::Duration;
+
+    fn frames(framer: &mut JsonFramer) -> Vec<String> {
+        std::iter::from_fn(|| framer.next_frame())
+            .map(|x| String::from_utf8(x).unwrap().trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_json_framer() {
+        let mut framer = JsonFramer::default();
+
+        // newlines are optional
+        framer.push(b"{\"id\":1}\n{\"id\":2}{\"id\"");
+        assert_eq!(frames(&mut framer), ["{\"id\":1}", "{\"id\":2}"]);
+
+        // the rest shows up in a later read
+        framer.push(b":3,\"result\":[1,{\"a\":2}]}");
+        assert_eq!(frames(&mut framer), ["{\"id\":3,\"result\":[1,{\"a\":2}]}"]);
+
+        // brackets and escaped quotes inside strings don't count
+        framer.push(br#"{"result":"}]\"{"}"#);
+        assert_eq!(frames(&mut framer), [r#"{"result":"}]\"{"}"#]);
+
+        assert!(framer.next_frame().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ipc_pool() {
+        let path = std::env::temp_dir().join(format!("web3_proxy_test_{}.ipc", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // a fake node. responses are sent in reverse order and the subscription sends a notification right away
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    let mut held = vec![];
+
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        let request: sonic_rs::Value = sonic_rs::from_str(&line).unwrap();
+                        let id = request["id"].as_u64().unwrap();
+
+                        match request["method"].as_str().unwrap() {
+                            "eth_subscribe" => {
+                                let response =
+                                    json!({"jsonrpc": "2.0", "id": id, "result": "0xabc"});
+                                let notification = json!({"jsonrpc": "2.0", "method": "eth_subscription", "params": {"subscription": "0xabc", "result": "0x5"}});
+                                writer
+                                    .write_all(format!("{response}{notification}").as_bytes())
+                                    .await
+                                    .unwrap();
+                            }
+                            "eth_blockNumber" => {
+                                held.push(json!({"jsonrpc": "2.0", "id": id, "result": format!("0x{id:x}")}));
+
+                                if held.len() == 2 {
+                                    for x in held.drain(..).rev() {
+                                        writer
+                                            .write_all(format!("{x}\n").as_bytes())
+                                            .await
+                                            .unwrap();
+                                    }
+                                }
+                            }
+                            "bad" => {
+                                // like a node that couldn't parse the request
+                                let response = json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32700, "message": "parse error"}});
+                                writer
+                                    .write_all(format!("{response}\n").as_bytes())
+                                    .await
+                                    .unwrap();
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+
+        #[derive(Deserialize)]
+        struct Response {
+            id: u64,
+            result: U64,
+        }
+
+        let pool = IpcPool::new(path.clone());
+
+        // round robin means these would be on different connections. use one connection to test multiplexing
+        let connection = pool.connection().await.unwrap();
+        let params = json!([]);
+        let (a, b) = tokio::join!(
+            connection.request("eth_blockNumber", &params),
+            connection.request("eth_blockNumber", &params),
+        );
+
+        for x in [a, b] {
+            let x: Response = sonic_rs::from_slice(&x.unwrap()).unwrap();
+            assert_eq!(x.result, U64::from(x.id));
+        }
+
+        let mut subscription = pool.subscribe(&json!(["newHeads"])).await.unwrap();
+        let x: U64 = subscription.next().await.unwrap().unwrap();
+        assert_eq!(x, U64::from(5));
+
+        // a request that the node never answers doesn't leave anything behind
+        let x = tokio::time::timeout(
+            Duration::from_millis(100),
+            connection.request("eth_ignored", &params),
+        )
+        .await;
+        assert!(x.is_err());
+        assert!(connection.shared.pending.lock().is_empty());
+
+        // an error without an id goes to the only request that is waiting
+        let x = connection.request("bad", &params).await.unwrap();
+        let x: sonic_rs::Value = sonic_rs::from_slice(&x).unwrap();
+        assert_eq!(x["error"]["code"].as_i64(), Some(-32700));
+        assert!(!connection.is_closed());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -2,42 +2,70 @@
 use super::blockchain::{BlockHeader, BlocksByHashCache, BlocksByNumberCache};
 use super::consensus::{RankedRpcs, RpcsForRequest};
 use super::one::Web3Rpc;
+use super::request::{OpenRequestHandle, OpenRequestResult};
 use crate::app::{App, Web3ProxyJoinHandle};
-use crate::config::{average_block_interval, BlockAndRpc, Web3RpcConfig};
-use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::config::{average_block_interval, BlockAndRpc, RpcGroup, Web3RpcConfig};
+use crate::errors::{QuorumError, QuorumVote, Web3ProxyError, Web3ProxyResult};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::frontend::status::MokaCacheSerializer;
 use crate::jsonrpc::ValidatedRequest;
 use crate::jsonrpc::{self, JsonRpcErrorData, JsonRpcParams, JsonRpcResultData};
-use alloy::primitives::{TxHash, U64};
+use crate::response_cache::JsonRpcResponseCache;
+use alloy::primitives::{keccak256, TxHash, B256, U64};
 use deduped_broadcast::DedupedBroadcaster;
 use derive_more::From;
-use futures::stream::StreamExt;
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures_util::future::join_all;
 use hashbrown::HashMap;
+use itertools::Itertools;
 use moka::future::CacheBuilder;
 use parking_lot::RwLock;
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use sonic_rs::json;
 use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fmt::{self, Display};
+use std::sync::atomic::{self, AtomicU32, AtomicU64, AtomicUsize};
 use std::sync::Arc;
-use tokio::pin;
 use tokio::sync::{mpsc, watch};
-use tokio::time::{Duration, Instant};
+use tokio::time::{sleep_until, timeout_at, Duration, Instant};
+use tokio::{pin, select};
 use tracing::{debug, error, info, trace, warn};
 
+/// connections spawned for a new config. they are not used until `Web3Rpcs::commit_server_configs`
+pub struct PendingRpcs {
+    /// the new connections and the configs they were spawned with
+    new_rpcs: Vec<(Arc<Web3Rpc>, Web3RpcConfig)>,
+    names_to_keep: Vec<String>,
+}
+
+impl PendingRpcs {
+    /// disconnect the new connections. the current rpcs are not touched
+    pub fn abandon(self) {
+        for (rpc, _) in self.new_rpcs {
+            if let Some(ref disconnect_sender) = rpc.disconnect_watch {
+                debug!("telling new {} to disconnect. the config was rejected", rpc);
+                disconnect_sender.send_replace(true);
+            }
+        }
+    }
+}
+
 /// A collection of web3 connections. Sends requests either the current best server or all servers.
 #[derive(From)]
 pub struct Web3Rpcs {
     pub(crate) name: Cow<'static, str>,
+    /// which section of the top config these rpcs are from. `[[routes]]` for this group can require tags
+    pub(crate) group: Option<RpcGroup>,
     pub(crate) chain_id: u64,
     /// if watch_head_block is some, Web3Rpc inside self will send blocks here when they get them
     pub(crate) block_and_rpc_sender: mpsc::UnboundedSender<(Option<BlockHeader>, Arc<Web3Rpc>)>,
     /// any requests will be forwarded to one (or more) of these connections
     /// TODO: hopefully this not being an async lock will be okay. if you need it across awaits, clone the arc
     pub(crate) by_name: RwLock<HashMap<String, Arc<Web3Rpc>>>,
+    /// the config that each rpc in `by_name` was spawned with. rpcs with unchanged configs are kept on reload
+    pub(super) configs: RwLock<HashMap<String, Web3RpcConfig>>,
     /// all providers with the same consensus head block. won't update if there is no `self.watch_head_block`
     /// TODO: why is watch_head_block in an Option, but this one isn't?
     /// TODO: document that this is a watch sender and not a broadcast! if things get busy, blocks might get missed
@@ -46,6 +74,10 @@ pub struct Web3Rpcs {
     /// this head receiver makes it easy to wait until there is a new block
     /// this is None if none of the child Rpcs are subscribed to newHeads
     pub(super) watch_head_block: Option<watch::Sender<Option<BlockHeader>>>,
+    /// the consensus "finalized" block. this only moves forward
+    pub(crate) watch_finalized_block: watch::Sender<Option<BlockHeader>>,
+    /// the consensus "safe" block. this only moves forward
+    pub(crate) watch_safe_block: watch::Sender<Option<BlockHeader>>,
     /// TODO: this map is going to grow forever unless we add pruning.
     /// all blocks, including uncles
     /// TODO: i think uncles should be excluded
@@ -53,16 +85,19 @@ pub struct Web3Rpcs {
     /// blocks on the heaviest chain
     pub(crate) blocks_by_number: BlocksByNumberCache,
     /// the number of rpcs required to agree on consensus for the head block (thundering herd protection)
-    pub(super) min_synced_rpcs: usize,
+    /// these limits are atomics so that config reloads can change them
+    pub(super) min_synced_rpcs: AtomicUsize,
     /// the soft limit required to agree on consensus for the head block. (thundering herd protection)
-    pub(super) min_sum_soft_limit: u32,
+    pub(super) min_sum_soft_limit: AtomicU32,
     /// how far behind the highest known block height we can be before we stop serving requests
-    pub(super) max_head_block_lag: U64,
-    /// how old our consensus head block we can be before we stop serving requests
+    pub(super) max_head_block_lag: AtomicU64,
+    /// how old our consensus head block we can be before we stop serving requests (in milliseconds)
     /// calculated based on max_head_block_lag and averge block times
-    pub(super) max_head_block_age: Duration,
+    pub(super) max_head_block_age_ms: AtomicU64,
     /// all of the pending txids for all of the rpcs. this still has duplicates
     pub(super) pending_txid_firehose: Option<Arc<DedupedBroadcaster<TxHash>>>,
+    /// responses that are fixed for a given block. entries not pinned to a hash are invalidated when blocks_by_number changes
+    pub(crate) response_cache: Option<JsonRpcResponseCache>,
 }
 
 /// this is a RankedRpcs that should be ready to use
@@ -93,16 +128,117 @@ impl From<Option<RpcsForRequest>> for TryRpcsForRequest {
     }
 }
 
+/// How one rpc handled a request sent with `ProxyMode::Versus`
+#[derive(Clone, Debug, Serialize)]
+pub struct VersusResult {
+    pub rpc: String,
+    pub latency_ms: f32,
+    /// keccak256 of the jsonrpc result (or jsonrpc error). Hashed so the report stays small enough for a header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_hash: Option<B256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Cow<'static, str>>,
+    /// true if this rpc gave the same response as more rpcs than any other response
+    pub agrees_with_majority: bool,
+    /// true if this rpc hadn't responded when the report was made. the finished comparison is logged at debug
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+}
+
+/// One `VersusResult` for each rpc that was sent the request
+pub type VersusReport = Arc<[VersusResult]>;
+
+/// after the client has its response, slow rpcs get this long to finish the versus comparison
+/// TODO: config for this
+const VERSUS_MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// One rpc's response to a versus request. Hashed so that the response itself can go to the client
+struct VersusSample {
+    rpc: Arc<Web3Rpc>,
+    latency: Duration,
+    response_hash: Option<B256>,
+    error: Option<Cow<'static, str>>,
+}
+
+impl VersusSample {
+    /// the bool is true if this response can be sent to the client. reverts are successful responses
+    fn new<R: JsonRpcResultData>(
+        rpc: Arc<Web3Rpc>,
+        latency: Duration,
+        response: &Web3ProxyResult<jsonrpc::ParsedResponse<R>>,
+    ) -> (Self, bool) {
+        let (response_hash, error, good) = match response {
+            Ok(x) => {
+                let payload =
+                    sonic_rs::to_vec(&x.payload).expect("responses should always serialize");
+
+                let (error, good) = match &x.payload {
+                    jsonrpc::ResponsePayload::Success { .. } => (None, true),
+                    jsonrpc::ResponsePayload::Error { error } => {
+                        (Some(error.message.clone()), error.is_revert())
+                    }
+                };
+
+                (Some(keccak256(payload)), error, good)
+            }
+            Err(err) => (None, Some(err.to_string().into()), false),
+        };
+
+        let x = Self {
+            rpc,
+            latency,
+            response_hash,
+            error,
+        };
+
+        (x, good)
+    }
+}
+
+/// `pending` rpcs haven't responded yet. they have been waited on for `waited`
+fn versus_report(
+    samples: &[VersusSample],
+    pending: &[Arc<Web3Rpc>],
+    waited: Duration,
+) -> VersusReport {
+    let hashes: Vec<_> = samples.iter().map(|x| x.response_hash).collect();
+
+    let majority = majority_hash(&hashes);
+
+    samples
+        .iter()
+        .map(|x| VersusResult {
+            rpc: x.rpc.name.clone(),
+            latency_ms: x.latency.as_secs_f32() * 1000.0,
+            response_hash: x.response_hash,
+            error: x.error.clone(),
+            agrees_with_majority: x.response_hash.is_some() && x.response_hash == majority,
+            pending: false,
+        })
+        .chain(pending.iter().map(|rpc| VersusResult {
+            rpc: rpc.name.clone(),
+            latency_ms: waited.as_secs_f32() * 1000.0,
+            response_hash: None,
+            error: None,
+            agrees_with_majority: false,
+            pending: true,
+        }))
+        .collect()
+}
+
 impl Web3Rpcs {
     /// Spawn durable connections to multiple Web3 providers.
+    #[allow(clippy::too_many_arguments)]
     pub async fn spawn(
         chain_id: u64,
         max_head_block_lag: Option<U64>,
         min_head_rpcs: usize,
         min_sum_soft_limit: u32,
         name: Cow<'static, str>,
+        group: Option<RpcGroup>,
         watch_consensus_head_sender: Option<watch::Sender<Option<BlockHeader>>>,
         pending_txid_firehose: Option<Arc<DedupedBroadcaster<TxHash>>>,
+        response_cache_max_bytes: u64,
     ) -> anyhow::Result<(
         Arc<Self>,
         Web3ProxyJoinHandle<()>,
@@ -127,22 +263,17 @@ impl Web3Rpcs {
             .time_to_idle(Duration::from_secs(30 * 60))
             .build();
 
+        let response_cache = (response_cache_max_bytes > 0)
+            .then(|| JsonRpcResponseCache::new(response_cache_max_bytes));
+
         let (watch_consensus_rpcs_sender, consensus_connections_watcher) =
             watch::channel(Default::default());
 
-        // by_name starts empty. self.apply_server_configs will add to it
+        // by_name starts empty. self.commit_server_configs will add to it
         let by_name = RwLock::new(HashMap::new());
 
-        let block_interval = average_block_interval(chain_id);
-
-        // TODO: think about the max more for long block interval chains
-        let max_head_block_lag = max_head_block_lag.unwrap_or_else(|| {
-            U64::from(5.max((60f32 / block_interval.as_secs_f32()).round() as u64))
-        });
-
-        // TODO: think about the max more for long block interval chains
-        let max_head_block_age =
-            block_interval.mul_f32((max_head_block_lag.to::<u64>() * 10) as f32);
+        let (max_head_block_lag, max_head_block_age) =
+            head_block_limits(chain_id, max_head_block_lag);
 
         let connections = Arc::new(Self {
             block_and_rpc_sender,
@@ -150,14 +281,19 @@ impl Web3Rpcs {
             blocks_by_number,
             by_name,
             chain_id,
-            max_head_block_age,
-            max_head_block_lag,
-            min_synced_rpcs: min_head_rpcs,
-            min_sum_soft_limit,
+            configs: Default::default(),
+            group,
+            max_head_block_age_ms: (max_head_block_age.as_millis() as u64).into(),
+            max_head_block_lag: max_head_block_lag.to::<u64>().into(),
+            min_synced_rpcs: min_head_rpcs.into(),
+            min_sum_soft_limit: min_sum_soft_limit.into(),
             name,
             pending_txid_firehose,
+            response_cache,
+            watch_finalized_block: watch::Sender::new(None),
             watch_head_block: watch_consensus_head_sender,
             watch_ranked_rpcs: watch_consensus_rpcs_sender,
+            watch_safe_block: watch::Sender::new(None),
         });
 
         let handle = {
@@ -175,22 +311,64 @@ impl Web3Rpcs {
         Ok((connections, handle, consensus_connections_watcher))
     }
 
-    /// update the rpcs in this group
-    pub async fn apply_server_configs(
+    /// change the consensus limits. the rpcs get the new max head block age too
+    pub fn apply_limits(
+        &self,
+        max_head_block_lag: Option<U64>,
+        min_synced_rpcs: usize,
+        min_sum_soft_limit: u32,
+    ) {
+        let (max_head_block_lag, max_head_block_age) =
+            head_block_limits(self.chain_id, max_head_block_lag);
+
+        self.max_head_block_lag
+            .store(max_head_block_lag.to(), atomic::Ordering::Relaxed);
+        self.max_head_block_age_ms.store(
+            max_head_block_age.as_millis() as u64,
+            atomic::Ordering::Relaxed,
+        );
+        self.min_synced_rpcs
+            .store(min_synced_rpcs, atomic::Ordering::Relaxed);
+        self.min_sum_soft_limit
+            .store(min_sum_soft_limit, atomic::Ordering::Relaxed);
+
+        for rpc in self.by_name.read().values() {
+            rpc.set_max_head_block_age(max_head_block_age);
+        }
+    }
+
+    pub fn max_head_block_age(&self) -> Duration {
+        Duration::from_millis(self.max_head_block_age_ms.load(atomic::Ordering::Relaxed))
+    }
+
+    pub fn max_head_block_lag(&self) -> U64 {
+        U64::from(self.max_head_block_lag.load(atomic::Ordering::Relaxed))
+    }
+
+    pub fn min_sum_soft_limit(&self) -> u32 {
+        self.min_sum_soft_limit.load(atomic::Ordering::Relaxed)
+    }
+
+    /// spawn connections for a new config without using them yet. nothing changes if this errors
+    /// rpcs with unchanged configs are not respawned. None means the current rpcs should be kept as they are
+    pub async fn prepare_server_configs(
         &self,
         app: &App,
         rpc_configs: &HashMap<String, Web3RpcConfig>,
-    ) -> Web3ProxyResult<()> {
+        min_synced_rpcs: usize,
+        min_head_rpcs: usize,
+        min_sum_soft_limit: u32,
+    ) -> Web3ProxyResult<Option<PendingRpcs>> {
         // safety checks
-        if rpc_configs.len() < app.config.min_synced_rpcs {
+        if rpc_configs.len() < min_synced_rpcs {
             // TODO: don't count disabled servers!
             // TODO: include if this is balanced, private, or 4337
             warn!(
                 "Only {}/{} rpcs! Add more rpcs or reduce min_synced_rpcs.",
                 rpc_configs.len(),
-                app.config.min_synced_rpcs
+                min_synced_rpcs
             );
-            return Ok(());
+            return Ok(None);
         }
 
         // safety check on sum soft limit
@@ -198,14 +376,14 @@ impl Web3Rpcs {
         let sum_soft_limit = rpc_configs.values().fold(0, |acc, x| acc + x.soft_limit);
 
         // TODO: require a buffer?
-        if sum_soft_limit < self.min_sum_soft_limit {
+        if sum_soft_limit < min_sum_soft_limit {
             return Err(Web3ProxyError::NotEnoughSoftLimit {
                 available: sum_soft_limit,
-                needed: self.min_sum_soft_limit,
+                needed: min_sum_soft_limit,
             });
         }
 
-        let chain_id = app.config.chain_id;
+        let chain_id = app.config.load().chain_id;
 
         let block_interval = average_block_interval(chain_id);
 
@@ -220,6 +398,16 @@ impl Web3Rpcs {
                     return None;
                 }
 
+                names_to_keep.push(server_name.clone());
+
+                if self.configs.read().get(server_name) == Some(server_config)
+                    && self.by_name.read().contains_key(server_name)
+                {
+                    // respawning would lose everything the rpc has learned (and wait for it to sync again)
+                    trace!("{} is unchanged", server_name);
+                    return None;
+                }
+
                 let http_client = app.http_client.clone();
                 let block_and_rpc_sender = if self.watch_head_block.is_some() {
                     Some(self.block_and_rpc_sender.clone())
@@ -231,8 +419,6 @@ impl Web3Rpcs {
 
                 debug!("spawning tasks for {}", server_name);
 
-                names_to_keep.push(server_name.clone());
-
                 let handle = server_config.clone().spawn(
                     server_name.clone(),
                     chain_id,
@@ -241,59 +427,20 @@ impl Web3Rpcs {
                     blocks_by_hash_cache,
                     block_and_rpc_sender,
                     self.pending_txid_firehose.clone(),
-                    self.max_head_block_age,
+                    self.max_head_block_age(),
                 );
 
-                Some(handle)
+                Some(async move { (handle.await, server_config.clone()) })
             })
             .collect();
 
-        for x in join_all(spawn_handles).await {
+        let mut new_rpcs = vec![];
+
+        for (x, server_config) in join_all(spawn_handles).await {
             match x {
-                Ok((new_rpc, _handle)) => {
-                    // web3 connection worked
-
-                    let old_rpc = self.by_name.read().get(&new_rpc.name).map(Arc::clone);
-
-                    // clean up the old rpc if it exists
-                    if let Some(old_rpc) = old_rpc {
-                        trace!("old_rpc: {}", old_rpc);
-
-                        // if the old rpc was synced, wait for the new one to sync
-                        if old_rpc
-                            .head_block_sender
-                            .as_ref()
-                            .unwrap()
-                            .borrow()
-                            .is_some()
-                        {
-                            let mut new_head_receiver =
-                                new_rpc.head_block_sender.as_ref().unwrap().subscribe();
-                            trace!("waiting for new {} connection to sync", new_rpc);
-
-                            // TODO: maximum wait time
-                            while new_head_receiver.borrow_and_update().is_none() {
-                                if new_head_receiver.changed().await.is_err() {
-                                    break;
-                                };
-                            }
-                        }
-
-                        // new rpc is synced (or old one was not synced). update the local map
-                        // make sure that any new requests use the new connection
-                        self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
-
-                        // tell the old rpc to disconnect
-                        if let Some(ref disconnect_sender) = old_rpc.disconnect_watch {
-                            debug!("telling old {} to disconnect", old_rpc);
-                            disconnect_sender.send_replace(true);
-                        }
-                    } else {
-                        self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
-                    }
-                }
+                Ok((new_rpc, _handle)) => new_rpcs.push((new_rpc, server_config)),
                 Err(err) => {
-                    // if we got an error here, the app can continue on
+                    // if we got an error here, the app can continue on. an old rpc with the same name is kept
                     // TODO: include context about which connection failed
                     // TODO: retry automatically
                     error!("Unable to create connection. err={:?}", err);
@@ -301,12 +448,110 @@ impl Web3Rpcs {
             }
         }
 
-        // TODO: remove any RPCs that were part of the config, but are now removed
+        if new_rpcs.is_empty() && {
+            let by_name = self.by_name.read();
+
+            by_name.len() == names_to_keep.len()
+                && names_to_keep.iter().all(|x| by_name.contains_key(x))
+        } {
+            // nothing to spawn or disconnect
+            return Ok(None);
+        }
+
+        let pending = PendingRpcs {
+            new_rpcs,
+            names_to_keep,
+        };
+
+        // the rpcs that will be left after commit_server_configs
+        let num_rpcs = {
+            let by_name = self.by_name.read();
+
+            pending
+                .names_to_keep
+                .iter()
+                .filter(|name| {
+                    by_name.contains_key(*name)
+                        || pending.new_rpcs.iter().any(|(x, _)| &x.name == *name)
+                })
+                .count()
+        };
+
+        if num_rpcs < min_head_rpcs {
+            pending.abandon();
+
+            return Err(Web3ProxyError::NotEnoughRpcs {
+                num_known: num_rpcs,
+                min_head_rpcs,
+            });
+        }
+
+        Ok(Some(pending))
+    }
+
+    /// start using the connections from `prepare_server_configs`. rpcs that are no longer configured are disconnected
+    pub async fn commit_server_configs(&self, pending: PendingRpcs) {
+        for (new_rpc, new_config) in pending.new_rpcs {
+            self.configs
+                .write()
+                .insert(new_rpc.name.clone(), new_config);
+
+            let old_rpc = self.by_name.read().get(&new_rpc.name).map(Arc::clone);
+
+            // clean up the old rpc if it exists
+            if let Some(old_rpc) = old_rpc {
+                trace!("old_rpc: {}", old_rpc);
+
+                // if the old rpc was synced, wait for the new one to sync
+                if old_rpc
+                    .head_block_sender
+                    .as_ref()
+                    .unwrap()
+                    .borrow()
+                    .is_some()
+                {
+                    let mut new_head_receiver =
+                        new_rpc.head_block_sender.as_ref().unwrap().subscribe();
+                    trace!("waiting for new {} connection to sync", new_rpc);
+
+                    // TODO: maximum wait time
+                    while new_head_receiver.borrow_and_update().is_none() {
+                        if new_head_receiver.changed().await.is_err() {
+                            break;
+                        };
+                    }
+                }
+
+                // new rpc is synced (or old one was not synced). update the local map
+                // make sure that any new requests use the new connection
+                {
+                    let mut by_name = self.by_name.write();
+
+                    // an rpc that was disabled by an admin stays disabled
+                    // this is after waiting for the sync so that admin changes made while waiting are not lost
+                    new_rpc.copy_admin_state(&old_rpc);
+
+                    by_name.insert(new_rpc.name.clone(), new_rpc);
+                }
+
+                // tell the old rpc to disconnect
+                if let Some(ref disconnect_sender) = old_rpc.disconnect_watch {
+                    debug!("telling old {} to disconnect", old_rpc);
+                    disconnect_sender.send_replace(true);
+                }
+            } else {
+                self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
+            }
+        }
+
+        // remove any RPCs that were part of the config, but are now removed
         let active_names: Vec<_> = self.by_name.read().keys().cloned().collect();
         for name in active_names {
-            if names_to_keep.contains(&name) {
+            if pending.names_to_keep.contains(&name) {
                 continue;
             }
+            self.configs.write().remove(&name);
+
             if let Some(old_rpc) = self.by_name.write().remove(&name) {
                 if let Some(ref disconnect_sender) = old_rpc.disconnect_watch {
                     debug!("telling {} to disconnect. no longer needed", old_rpc);
@@ -314,17 +559,6 @@ impl Web3Rpcs {
                 }
             }
         }
-
-        let num_rpcs = self.len();
-
-        if num_rpcs < self.min_synced_rpcs {
-            return Err(Web3ProxyError::NotEnoughRpcs {
-                num_known: num_rpcs,
-                min_head_rpcs: self.min_synced_rpcs,
-            });
-        }
-
-        Ok(())
     }
 
     pub fn get(&self, conn_name: &str) -> Option<Arc<Web3Rpc>> {
@@ -341,7 +575,7 @@ impl Web3Rpcs {
 
     /// TODO: rename to be consistent between "head" and "synced"
     pub fn min_head_rpcs(&self) -> usize {
-        self.min_synced_rpcs
+        self.min_synced_rpcs.load(atomic::Ordering::Relaxed)
     }
 
     /// get all rpc servers that are not rate limited
@@ -375,10 +609,32 @@ impl Web3Rpcs {
                 Arc::new(x)
             };
 
-        match ranked_rpcs.for_request(web3_request) {
-            None => Err(Web3ProxyError::NoServersSynced),
-            Some(x) => Ok(x),
+        let Some(mut rpcs) = ranked_rpcs.for_request(web3_request) else {
+            let method = web3_request.inner.method();
+
+            // no need to wait for servers to sync if none of them have the method
+            if !ranked_rpcs.is_empty() && ranked_rpcs.all().all(|x| !x.supports_method(method)) {
+                return Err(Web3ProxyError::MethodNotFound(method.to_string().into()));
+            }
+
+            return Err(Web3ProxyError::NoServersSynced);
+        };
+
+        // fallback groups don't check tags. even if the fallback is the route's own group
+        if let Some(route) = web3_request.route.as_ref().filter(|x| {
+            !x.tags.is_empty()
+                && self.group == Some(x.group)
+                && !web3_request.route_fallback.load(atomic::Ordering::Relaxed)
+        }) {
+            rpcs.retain(|x| x.has_tags(&route.tags));
+
+            if rpcs.inner().is_empty() && rpcs.outer().is_empty() {
+                trace!(tags=?route.tags, "no rpcs with the route's tags");
+                return Err(Web3ProxyError::NoServersSynced);
+            }
         }
+
+        Ok(rpcs)
     }
 
     pub async fn internal_request<P: JsonRpcParams, R: JsonRpcResultData>(
@@ -416,7 +672,6 @@ impl Web3Rpcs {
     /// Make a request with stat tracking.
     /// The first jsonrpc response will be returned.
     /// TODO? move this to RankedRpcsForRequest along with a bunch of other similar functions? but it needs watch_ranked_rpcs and other things on Web3Rpcs...
-    /// TODO: have a similar function for quorum(size, max_tries)
     /// TODO: should max_tries be on web3_request. maybe as tries_left?
     pub async fn request_with_metadata<R: JsonRpcResultData>(
         &self,
@@ -524,6 +779,337 @@ impl Web3Rpcs {
         .into())
     }
 
+    /// Race the request across the best `max_rpcs` synced rpcs. 0 means all of them.
+    /// The first response that isn't an error is returned. Reverts count as a successful response.
+    /// The other requests are cancelled when their futures are dropped.
+    pub async fn request_with_fastest<R: JsonRpcResultData>(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+        max_rpcs: usize,
+    ) -> Web3ProxyResult<jsonrpc::SingleResponse<R>> {
+        let rpcs = self.try_rpcs_for_request(web3_request).await?;
+
+        let handles = rpcs.try_handles(max_rpcs).await;
+
+        if handles.is_empty() {
+            // none of the rpcs were ready right now. fall back to the normal path which knows how to wait
+            // TODO: wait and race once some are ready?
+            trace!("no handles for racing. falling back to best");
+            return self.request_with_metadata(web3_request).await;
+        }
+
+        {
+            let mut response_lock = web3_request.response.lock();
+
+            response_lock
+                .backend_rpcs
+                .extend(handles.iter().map(|x| x.clone_connection()));
+        }
+
+        let mut requests: FuturesUnordered<_> =
+            handles.into_iter().map(|x| x.request::<R>()).collect();
+
+        // jsonrpc errors are saved in case nothing better comes back
+        let mut jsonrpc_errors = vec![];
+        let mut errors = vec![];
+
+        while let Some(response) = requests.next().await {
+            match response {
+                Ok(response) => {
+                    if response.jsonrpc_error().is_some_and(|x| !x.is_revert()) {
+                        // TODO: some jsonrpc errors should probably be returned immediately
+                        jsonrpc_errors.push(response);
+                    } else {
+                        // dropping `requests` cancels the slower rpcs
+                        return Ok(response);
+                    }
+                }
+                Err(error) => {
+                    errors.push(error);
+                }
+            }
+        }
+
+        // TODO: find the most common error
+        if let Some(response) = jsonrpc_errors.into_iter().next() {
+            return Ok(response);
+        }
+
+        if let Some(err) = errors.into_iter().next() {
+            return Err(err);
+        }
+
+        Err(Web3ProxyError::NoServersSynced)
+    }
+
+    /// Send the request to every rpc that can serve it and compare their responses.
+    /// The first response that isn't an error is returned right away. Reverts count as a successful response.
+    /// The comparison finishes in the background. Rpcs that haven't responded `VERSUS_MAX_WAIT` after that are counted as timeouts.
+    /// A `VersusResult` for each rpc is saved on the request's response. Rpcs that were still working when it was returned are marked pending.
+    pub async fn request_with_versus<R: JsonRpcResultData>(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+    ) -> Web3ProxyResult<jsonrpc::SingleResponse<R>> {
+        let rpcs = self.try_rpcs_for_request(web3_request).await?;
+
+        let handles = rpcs.try_handles(0).await;
+
+        if handles.is_empty() {
+            // none of the rpcs were ready right now. fall back to the normal path which knows how to wait
+            trace!("no handles for versus. falling back to best");
+            return self.request_with_metadata(web3_request).await;
+        }
+
+        let sent: Vec<_> = handles.iter().map(|x| x.clone_connection()).collect();
+
+        web3_request
+            .response
+            .lock()
+            .backend_rpcs
+            .extend(sent.iter().cloned());
+
+        let start = Instant::now();
+        let deadline = web3_request.expire_at();
+
+        let mut requests: FuturesUnordered<_> = handles
+            .into_iter()
+            .map(|handle| async move {
+                let rpc = handle.clone_connection();
+
+                // streaming responses are read completely so that they can be compared
+                let response = timeout_at(deadline, async move {
+                    handle.request::<R>().await?.parsed().await
+                })
+                .await
+                .unwrap_or_else(|_| Err(Web3ProxyError::Timeout(Some(start.elapsed()))));
+
+                (rpc, start.elapsed(), response)
+            })
+            .collect();
+
+        // these are in the order that the rpcs responded
+        let mut samples = Vec::with_capacity(requests.len());
+
+        // the fastest successful response. reverts are successful responses
+        let mut best = None;
+        // then the fastest jsonrpc error. then the fastest error
+        let mut fallback = None;
+
+        while let Some((rpc, latency, response)) = requests.next().await {
+            let (sample, good) = VersusSample::new(rpc, latency, &response);
+
+            samples.push(sample);
+
+            if good {
+                best = Some(response);
+                break;
+            }
+
+            if fallback
+                .as_ref()
+                .is_none_or(|x: &Web3ProxyResult<_>| x.is_err() && response.is_ok())
+            {
+                fallback = Some(response);
+            }
+        }
+
+        let response = best.or(fallback).expect("at least one request was sent");
+
+        let pending: Vec<_> = sent
+            .iter()
+            .filter(|x| !samples.iter().any(|y| Arc::ptr_eq(&y.rpc, x)))
+            .cloned()
+            .collect();
+
+        web3_request.response.lock().versus =
+            Some(versus_report(&samples, &pending, start.elapsed()));
+
+        if pending.is_empty() {
+            debug!(report=?web3_request.versus_report(), %web3_request, "versus");
+        } else {
+            // the client doesn't wait on the slow rpcs
+            let web3_request = web3_request.clone();
+
+            tokio::spawn(async move {
+                let _ = timeout_at(deadline.min(Instant::now() + VERSUS_MAX_WAIT), async {
+                    while let Some((rpc, latency, response)) = requests.next().await {
+                        samples.push(VersusSample::new(rpc, latency, &response).0);
+                    }
+                })
+                .await;
+
+                // dropping the requests that are left cancels them
+                let latency = start.elapsed();
+                for rpc in sent {
+                    if !samples.iter().any(|x| Arc::ptr_eq(&x.rpc, &rpc)) {
+                        samples.push(VersusSample {
+                            rpc,
+                            latency,
+                            response_hash: None,
+                            error: Some(Web3ProxyError::Timeout(Some(latency)).to_string().into()),
+                        });
+                    }
+                }
+
+                let report = versus_report(&samples, &[], latency);
+
+                debug!(?report, %web3_request, "versus");
+
+                web3_request.response.lock().versus = Some(report);
+            });
+        }
+
+        response.map(Into::into)
+    }
+
+    /// Send the request to rpcs until `min_agree` of them give byte-identical responses.
+    /// At most `max_tries` rpcs are used. `min_agree` is at least 1 and `max_tries` is at least `min_agree`.
+    /// Jsonrpc errors (like reverts) can be agreed on too. Other errors never count towards the quorum.
+    /// Handles are only opened when the requests in flight can't reach the quorum. Rate limited rpcs are waited on.
+    pub async fn request_with_quorum<R: JsonRpcResultData>(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+        min_agree: usize,
+        max_tries: usize,
+    ) -> Web3ProxyResult<jsonrpc::SingleResponse<R>> {
+        let min_agree = min_agree.max(1);
+        let max_tries = max_tries.max(min_agree);
+
+        let rpcs = self.try_rpcs_for_request(web3_request).await?;
+
+        // best first
+        let mut untried: VecDeque<_> = rpcs.inner().iter().cloned().collect();
+        // rpcs that were rate limited and when the first of them can be tried again
+        let mut waiting = vec![];
+        let mut retry_at: Option<Instant> = None;
+
+        let mut opened = 0;
+        let mut requests = FuturesUnordered::new();
+
+        // the rpcs that have responded and what they said
+        let mut votes = vec![];
+        let mut counts: HashMap<B256, usize> = HashMap::new();
+        let mut max_agreed = 0;
+
+        loop {
+            // start more requests if the responses we are waiting on are not enough to reach the quorum
+            while requests.len() + max_agreed < min_agree && opened < max_tries {
+                let Some(rpc) = untried.pop_front() else {
+                    break;
+                };
+
+                match rpc.try_request_handle(web3_request, None, false).await {
+                    Ok(OpenRequestResult::Handle(handle)) => {
+                        web3_request
+                            .response
+                            .lock()
+                            .backend_rpcs
+                            .push(handle.clone_connection());
+
+                        opened += 1;
+                        requests.push(quorum_request::<R>(handle));
+                    }
+                    Ok(OpenRequestResult::RetryAt(x)) => {
+                        trace!("{} is rate limited. trying it again later", rpc);
+                        retry_at = Some(retry_at.map_or(x, |y| y.min(x)));
+                        waiting.push(rpc);
+                    }
+                    Ok(OpenRequestResult::Lagged(_)) | Ok(OpenRequestResult::Failed) => {
+                        trace!("{} not ready. skipping", rpc);
+                    }
+                    Err(err) => {
+                        trace!(?err, "no request handle for {}", rpc);
+                    }
+                }
+            }
+
+            let need_more = requests.len() + max_agreed < min_agree && opened < max_tries;
+
+            // don't wait past the request's connect timeout
+            let retry = retry_at.filter(|x| need_more && *x <= web3_request.connect_timeout_at());
+
+            let next = match (requests.is_empty(), retry) {
+                (true, None) => break,
+                (true, Some(retry_at)) => {
+                    sleep_until(retry_at).await;
+                    None
+                }
+                (false, None) => requests.next().await,
+                (false, Some(retry_at)) => {
+                    select! {
+                        x = requests.next() => x,
+                        _ = sleep_until(retry_at) => None,
+                    }
+                }
+            };
+
+            let Some((rpc, response)) = next else {
+                // time to try the rate limited rpcs again
+                untried.extend(waiting.drain(..));
+                retry_at = None;
+                continue;
+            };
+
+            if let Ok(parsed) = &response {
+                let payload =
+                    sonic_rs::to_vec(&parsed.payload).expect("responses should always serialize");
+
+                let count = counts.entry(keccak256(payload)).or_default();
+                *count += 1;
+
+                if *count >= min_agree {
+                    // dropping `requests` cancels any other requests
+                    return response.map(Into::into);
+                }
+
+                max_agreed = max_agreed.max(*count);
+            }
+
+            votes.push((rpc, response));
+        }
+
+        if opened == 0 && min_agree == 1 {
+            // none of the rpcs were ready. the normal path knows how to wait for lagged rpcs
+            trace!("no handles for quorum. falling back to best");
+            return self.request_with_metadata(web3_request).await;
+        }
+
+        if opened == 0 {
+            return Err(Web3ProxyError::NoServersSynced);
+        }
+
+        let votes = votes
+            .into_iter()
+            .map(|(rpc, response)| {
+                let (result, error) = match response {
+                    Ok(parsed) => match parsed.payload {
+                        jsonrpc::ResponsePayload::Success { result } => (
+                            Some(sonic_rs::to_value(&result).expect("results should serialize")),
+                            None,
+                        ),
+                        jsonrpc::ResponsePayload::Error { error } => (
+                            None,
+                            Some(sonic_rs::to_value(&error).expect("errors should serialize")),
+                        ),
+                    },
+                    Err(err) => (None, Some(json!(err.to_string()))),
+                };
+
+                QuorumVote {
+                    rpc: rpc.name.clone(),
+                    result,
+                    error,
+                }
+            })
+            .collect();
+
+        Err(Web3ProxyError::QuorumNotReached(Box::new(QuorumError {
+            min_agree,
+            max_agreed,
+            votes,
+        })))
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub async fn try_proxy_connection<R: JsonRpcResultData>(
         &self,
@@ -533,12 +1119,64 @@ impl Web3Rpcs {
 
         match proxy_mode {
             ProxyMode::Best => self.request_with_metadata(web3_request).await,
-            ProxyMode::Fastest(_x) => todo!("Fastest"),
-            ProxyMode::Versus => todo!("Versus"),
+            ProxyMode::Fastest(max_rpcs) => self.request_with_fastest(web3_request, max_rpcs).await,
+            ProxyMode::Versus => self.request_with_versus(web3_request).await,
+            ProxyMode::Quorum {
+                min_agree,
+                max_tries,
+            } => {
+                self.request_with_quorum(web3_request, min_agree, max_tries)
+                    .await
+            }
         }
     }
 }
 
+/// A request for `Web3Rpcs::request_with_quorum`. Streaming responses are read so that they can be compared
+async fn quorum_request<R: JsonRpcResultData>(
+    handle: OpenRequestHandle,
+) -> (Arc<Web3Rpc>, Web3ProxyResult<jsonrpc::ParsedResponse<R>>) {
+    let rpc = handle.clone_connection();
+
+    let response = match handle.request::<R>().await {
+        Ok(x) => x.parsed().await,
+        Err(err) => Err(err),
+    };
+
+    (rpc, response)
+}
+
+/// how far behind and how old the head block can be. the age is based on the lag and the chain's block time
+fn head_block_limits(chain_id: u64, max_head_block_lag: Option<U64>) -> (U64, Duration) {
+    let block_interval = average_block_interval(chain_id);
+
+    // TODO: think about the max more for long block interval chains
+    let max_head_block_lag = max_head_block_lag
+        .unwrap_or_else(|| U64::from(5.max((60f32 / block_interval.as_secs_f32()).round() as u64)));
+
+    // TODO: think about the max more for long block interval chains
+    let max_head_block_age = block_interval.mul_f32((max_head_block_lag.to::<u64>() * 10) as f32);
+
+    (max_head_block_lag, max_head_block_age)
+}
+
+/// The most common hash. None if there is a tie or there are no hashes
+fn majority_hash(hashes: &[Option<B256>]) -> Option<B256> {
+    let mut counts: HashMap<B256, usize> = HashMap::new();
+    for hash in hashes.iter().flatten() {
+        *counts.entry(*hash).or_default() += 1;
+    }
+
+    let max_count = counts.values().max().copied()?;
+
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count == max_count)
+        .map(|(hash, _)| hash)
+        .exactly_one()
+        .ok()
+}
+
 impl Display for Web3Rpcs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(&self.name)
@@ -569,7 +1207,7 @@ impl Serialize for Web3Rpcs {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Web3Rpcs", 7)?;
+        let mut state = serializer.serialize_struct("Web3Rpcs", 9)?;
 
         {
             let by_name = self.by_name.read();
@@ -580,10 +1218,13 @@ impl Serialize for Web3Rpcs {
 
         state.serialize_field(
             "max_head_block_age_ms",
-            &self.max_head_block_age.as_millis(),
+            &self.max_head_block_age().as_millis(),
         )?;
 
-        state.serialize_field("max_head_block_lag", &self.max_head_block_lag)?;
+        state.serialize_field("max_head_block_lag", &self.max_head_block_lag())?;
+
+        state.serialize_field("safe_block", &*self.watch_safe_block.borrow())?;
+        state.serialize_field("finalized_block", &*self.watch_finalized_block.borrow())?;
 
         {
             let consensus_rpcs = self.watch_ranked_rpcs.borrow().clone();
@@ -613,6 +1254,7 @@ impl Serialize for Web3Rpcs {
             &(
                 MokaCacheSerializer(&self.blocks_by_hash),
                 MokaCacheSerializer(&self.blocks_by_number),
+                &self.response_cache,
             ),
         )?;
 
@@ -637,8 +1279,10 @@ mod tests {
 
     use super::*;
     use crate::block_number::{BlockNumAndHash, RequestBlocks};
+    use crate::config::RouteConfig;
     use crate::rpcs::blockchain::BlockHeader;
     use crate::rpcs::consensus::ConsensusFinder;
+    use crate::test_utils::stub_rpc::{stub_error, StubRpc};
     use alloy::primitives::{B256, U256};
     use alloy::rpc::types::Block;
     use arc_swap::ArcSwap;
@@ -646,6 +1290,7 @@ mod tests {
     use moka::future::{Cache, CacheBuilder};
     use std::cmp::Reverse;
     use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::time::sleep;
     use tracing::trace;
 
     #[cfg(test)]
@@ -661,6 +1306,133 @@ mod tests {
         block
     }
 
+    /// rpcs without a head block subscription that send their requests to the stubs
+    async fn stub_rpcs(stubs: &[(&str, &StubRpc)]) -> Arc<Web3Rpcs> {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), None, None, None, 0)
+                .await
+                .unwrap();
+
+        for (name, stub) in stubs {
+            let rpc = Web3Rpc::for_tests(name, &stub.url).await;
+
+            rpcs.by_name.write().insert(name.to_string(), rpc);
+        }
+
+        rpcs
+    }
+
+    async fn stub_request(method: &'static str) -> Arc<ValidatedRequest> {
+        ValidatedRequest::new_internal(method.into(), &[(); 0], None, Some(Duration::from_secs(10)))
+            .await
+            .unwrap()
+    }
+
+    /// balanced rpcs without a head block subscription. "a" has the "trace" tag and "b" doesn't
+    async fn tagged_rpcs(group: RpcGroup) -> Arc<Web3Rpcs> {
+        let (rpcs, _handle, _ranked) =
+            Web3Rpcs::spawn(1, None, 1, 1, "test".into(), Some(group), None, None, 0)
+                .await
+                .unwrap();
+
+        for (name, tags) in [("a", vec!["trace".to_string()]), ("b", vec![])] {
+            let rpc = Web3Rpc {
+                name: name.to_string(),
+                tags,
+                ..Default::default()
+            };
+
+            rpcs.by_name.write().insert(name.to_string(), Arc::new(rpc));
+        }
+
+        rpcs
+    }
+
+    async fn routed_request(route: &str) -> Arc<ValidatedRequest> {
+        let mut x = ValidatedRequest::new_internal("trace_block".into(), &["0x1"], None, None)
+            .await
+            .unwrap();
+
+        Arc::get_mut(&mut x).unwrap().route = Some(Arc::new(toml::from_str(route).unwrap()));
+
+        x
+    }
+
+    async fn rpc_names(rpcs: &Web3Rpcs, web3_request: &Arc<ValidatedRequest>) -> Vec<String> {
+        let x = rpcs.try_rpcs_for_request(web3_request).await.unwrap();
+
+        let mut names: Vec<_> = x
+            .inner()
+            .iter()
+            .chain(x.outer())
+            .map(|x| x.name.clone())
+            .collect();
+
+        names.sort();
+
+        names
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_route_tags() {
+        let balanced = tagged_rpcs(RpcGroup::Balanced).await;
+
+        let web3_request = routed_request(
+            r#"
+            methods = ["trace_*"]
+            tags = ["trace"]
+            "#,
+        )
+        .await;
+
+        assert_eq!(rpc_names(&balanced, &web3_request).await, ["a"]);
+
+        // tags only apply to the route's group
+        let private = tagged_rpcs(RpcGroup::Private).await;
+
+        assert_eq!(rpc_names(&private, &web3_request).await, ["a", "b"]);
+
+        // no rpcs have the tag
+        let web3_request = routed_request(
+            r#"
+            methods = ["trace_*"]
+            tags = ["archive"]
+            "#,
+        )
+        .await;
+
+        assert!(matches!(
+            balanced.try_rpcs_for_request(&web3_request).await,
+            Err(Web3ProxyError::NoServersSynced)
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_route_fallback_to_same_group() {
+        let balanced = tagged_rpcs(RpcGroup::Balanced).await;
+
+        let web3_request = routed_request(
+            r#"
+            fallback = ["balanced_rpcs"]
+            methods = ["trace_*"]
+            tags = ["archive"]
+            "#,
+        )
+        .await;
+
+        let route: &RouteConfig = web3_request.route.as_ref().unwrap();
+        assert_eq!(route.fallback, [RpcGroup::Balanced]);
+
+        assert!(balanced.try_rpcs_for_request(&web3_request).await.is_err());
+
+        // proxy_routed_request sets this before it tries the fallback groups
+        web3_request
+            .route_fallback
+            .store(true, atomic::Ordering::Relaxed);
+
+        assert_eq!(rpc_names(&balanced, &web3_request).await, ["a", "b"]);
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_sort_connections_by_sync_status() {
         let block_0 = block(0, B256::ZERO);
@@ -1435,4 +2207,207 @@ mod tests {
 
         assert_eq!(test_vec, sorted_vec);
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_fastest_handles_limit() {
+        let rpcs: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    healthy: true.into(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let ranked_rpcs = RankedRpcs::from_rpcs(rpcs, None, false);
+
+        let web3_request = ValidatedRequest::new_internal("eth_chainId".into(), &(), None, None)
+            .await
+            .unwrap();
+
+        let rpcs_for_request = ranked_rpcs.for_request(&web3_request).unwrap();
+
+        assert_eq!(rpcs_for_request.try_handles(2).await.len(), 2);
+        assert_eq!(rpcs_for_request.try_handles(0).await.len(), 3);
+        assert_eq!(rpcs_for_request.try_handles(10).await.len(), 3);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_fastest_races() {
+        let revert = StubRpc::constant(
+            Duration::from_millis(10),
+            Err(stub_error("execution reverted: nope")),
+        )
+        .await;
+        let broken = StubRpc::constant(Duration::ZERO, Err(stub_error("broken"))).await;
+        let slow = StubRpc::constant(Duration::from_millis(1_500), Ok(json!("0x1"))).await;
+
+        let rpcs = stub_rpcs(&[("revert", &revert), ("broken", &broken), ("slow", &slow)]).await;
+
+        let web3_request = stub_request("eth_call").await;
+
+        let start = Instant::now();
+
+        let response = rpcs
+            .request_with_fastest::<sonic_rs::Value>(&web3_request, 0)
+            .await
+            .unwrap();
+
+        // a revert is a good response. the earlier error is not
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(response.jsonrpc_error().unwrap().is_revert());
+
+        // every rpc got the request
+        assert_eq!(revert.requests(), 1);
+        assert_eq!(broken.requests(), 1);
+        assert_eq!(slow.requests(), 1);
+
+        // the slow rpc's request was cancelled instead of left running
+        assert_eq!(rpcs.get("slow").unwrap().active_requests(), 0);
+
+        // without a good response, the error is returned
+        let rpcs = stub_rpcs(&[("broken", &broken)]).await;
+
+        let response = rpcs
+            .request_with_fastest::<sonic_rs::Value>(&web3_request, 0)
+            .await
+            .unwrap();
+
+        assert!(!response.jsonrpc_error().unwrap().is_revert());
+    }
+
+    #[test]
+    fn test_versus_majority() {
+        let a = B256::with_last_byte(1);
+        let b = B256::with_last_byte(2);
+
+        assert_eq!(majority_hash(&[]), None);
+        assert_eq!(majority_hash(&[None, None]), None);
+        assert_eq!(majority_hash(&[Some(a)]), Some(a));
+        assert_eq!(majority_hash(&[Some(a), Some(b)]), None);
+        assert_eq!(majority_hash(&[Some(a), None, Some(b), Some(a)]), Some(a));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_versus_returns_early() {
+        let fast = StubRpc::constant(Duration::from_millis(10), Ok(json!("0x1"))).await;
+        let broken = StubRpc::constant(Duration::ZERO, Err(stub_error("broken"))).await;
+        let slow = StubRpc::constant(Duration::from_millis(1_500), Ok(json!("0x1"))).await;
+
+        let rpcs = stub_rpcs(&[("fast", &fast), ("broken", &broken), ("slow", &slow)]).await;
+
+        let web3_request = stub_request("eth_chainId").await;
+
+        let start = Instant::now();
+
+        let response = rpcs
+            .request_with_versus::<sonic_rs::Value>(&web3_request)
+            .await
+            .unwrap()
+            .parsed()
+            .await
+            .unwrap();
+
+        // the slow rpc is not waited on
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(response.result(), Some(&json!("0x1")));
+
+        let find = |report: &VersusReport, name: &str| {
+            report.iter().find(|x| x.rpc == name).unwrap().clone()
+        };
+
+        let report = web3_request.versus_report().unwrap();
+        assert_eq!(report.len(), 3);
+        assert!(!find(&report, "fast").pending);
+        assert!(find(&report, "broken").error.is_some());
+        assert!(find(&report, "slow").pending);
+
+        // the comparison finishes in the background
+        sleep(Duration::from_secs(2)).await;
+
+        let report = web3_request.versus_report().unwrap();
+        assert_eq!(report.len(), 3);
+        assert!(report.iter().all(|x| !x.pending));
+        assert!(find(&report, "fast").agrees_with_majority);
+        assert!(find(&report, "slow").agrees_with_majority);
+        assert!(!find(&report, "broken").agrees_with_majority);
+
+        assert_eq!(slow.requests(), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_quorum_opens_handles_lazily() {
+        let a = StubRpc::constant(Duration::from_millis(10), Ok(json!("0x1"))).await;
+        let b = StubRpc::constant(Duration::from_millis(10), Ok(json!("0x1"))).await;
+        let c = StubRpc::constant(Duration::from_millis(10), Ok(json!("0x1"))).await;
+
+        let rpcs = stub_rpcs(&[("a", &a), ("b", &b), ("c", &c)]).await;
+
+        let web3_request = stub_request("eth_chainId").await;
+
+        let response = rpcs
+            .request_with_quorum::<sonic_rs::Value>(&web3_request, 2, 3)
+            .await
+            .unwrap()
+            .parsed()
+            .await
+            .unwrap();
+        assert_eq!(response.result(), Some(&json!("0x1")));
+
+        // two agreeing rpcs are enough. the third is never opened
+        assert_eq!(a.requests() + b.requests() + c.requests(), 2);
+        assert_eq!(web3_request.response.lock().backend_rpcs.len(), 2);
+
+        // disagreements open more handles until max_tries
+        let a = StubRpc::constant(Duration::ZERO, Ok(json!("0x1"))).await;
+        let b = StubRpc::constant(Duration::ZERO, Ok(json!("0x2"))).await;
+        let c = StubRpc::constant(Duration::ZERO, Ok(json!("0x3"))).await;
+
+        let rpcs = stub_rpcs(&[("a", &a), ("b", &b), ("c", &c)]).await;
+
+        let web3_request = stub_request("eth_chainId").await;
+
+        let err = rpcs
+            .request_with_quorum::<sonic_rs::Value>(&web3_request, 2, 3)
+            .await
+            .unwrap_err();
+
+        let Web3ProxyError::QuorumNotReached(err) = err else {
+            panic!("expected no quorum. got {:?}", err);
+        };
+        assert_eq!(err.max_agreed, 1);
+        assert_eq!(err.votes.len(), 3);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_quorum_waits_for_rate_limits() {
+        let stub = StubRpc::constant(Duration::ZERO, Ok(json!("0x1"))).await;
+
+        let rpcs = stub_rpcs(&[]).await;
+
+        let mut rpc = Web3Rpc::for_tests("a", &stub.url).await;
+
+        let (hard_limit_until, _) = watch::channel(Instant::now() + Duration::from_millis(300));
+        Arc::get_mut(&mut rpc).unwrap().hard_limit_until = Some(hard_limit_until);
+
+        rpcs.by_name.write().insert("a".to_string(), rpc);
+
+        let web3_request = stub_request("eth_chainId").await;
+
+        let start = Instant::now();
+
+        let response = rpcs
+            .request_with_quorum::<sonic_rs::Value>(&web3_request, 1, 1)
+            .await
+            .unwrap()
+            .parsed()
+            .await
+            .unwrap();
+
+        assert_eq!(response.result(), Some(&json!("0x1")));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(stub.requests(), 1);
+    }
 }
@@ -1,6 +1,8 @@
 // TODO: all pub, or export useful things here instead?
 pub mod blockchain;
+pub mod breaker;
 pub mod consensus;
+pub mod ipc;
 pub mod many;
 pub mod one;
 pub mod provider;
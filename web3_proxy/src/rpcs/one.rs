@@ -1,5 +1,7 @@
 //! Rate-limited communication with a web3 provider.
 use super::blockchain::{ArcBlock, BlockHeader, BlocksByHashCache};
+use super::breaker::{Backoff, CircuitBreaker};
+use super::ipc::IpcPool;
 use super::provider::{connect_ws, AlloyWsProvider};
 use super::request::{OpenRequestHandle, OpenRequestResult};
 use crate::app::Web3ProxyJoinHandle;
@@ -8,9 +10,11 @@ use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::globals;
 use crate::jsonrpc::ValidatedRequest;
 use crate::jsonrpc::{self, JsonRpcParams, JsonRpcResultData};
+use crate::metrics::RpcGauges;
+use crate::rate_limit::TokenBucket;
 use crate::rpcs::request::RequestErrorHandler;
 use alloy::consensus::Transaction as _;
-use alloy::primitives::{Address, Bytes, TxHash, U256, U64};
+use alloy::primitives::{Address, Bytes, TxHash, B256, U256, U64};
 use alloy::providers::Provider;
 use alloy::rpc::types::Transaction;
 use anyhow::{anyhow, Context};
@@ -18,26 +22,64 @@ use arc_swap::ArcSwapOption;
 use deduped_broadcast::DedupedBroadcaster;
 use futures::future::select_all;
 use futures::StreamExt;
+use hashbrown::HashMap;
 use latency::{EwmaLatency, PeakEwmaLatency, RollingQuantileLatency};
 use nanorand::tls::TlsWyRand;
 use nanorand::Rng;
 use parking_lot::RwLock;
 use serde::ser::{SerializeStruct, Serializer};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sonic_rs::json;
 use std::borrow::Cow;
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize};
 use std::{cmp::Ordering, sync::Arc};
 use tokio::select;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, watch, Semaphore};
 use tokio::time::{interval, sleep, sleep_until, Duration, Instant, MissedTickBehavior};
 use tracing::{debug, error, info, trace, warn, Level};
 use url::Url;
 
+/// Set at runtime by the admin api. This is kept when a config reload replaces the rpc
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminState {
+    #[default]
+    Enabled,
+    /// no new requests. in-flight requests finish. the rpc still helps pick the consensus head block
+    Draining,
+    /// no new requests and no part in consensus. health checks keep running
+    Disabled,
+}
+
+impl AdminState {
+    fn from_u8(x: u8) -> Self {
+        match x {
+            1 => Self::Draining,
+            2 => Self::Disabled,
+            _ => Self::Enabled,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Enabled => 0,
+            Self::Draining => 1,
+            Self::Disabled => 2,
+        }
+    }
+}
+
+/// unsupported methods remembered per rpc. the method names come from clients
+const MAX_UNSUPPORTED_METHODS: usize = 100;
+
+/// how long an eth_getLogs range learned from errors is used. then the configured range is tried again
+/// TODO: config for this?
+const LEARNED_GET_LOGS_RANGE_TTL: Duration = Duration::from_secs(10 * 60);
+
 /// An active connection to a Web3 RPC server like geth or erigon.
 /// TODO: smarter Default derive or move the channels around so they aren't part of this at all
 #[derive(Default)]
@@ -50,6 +92,8 @@ pub struct Web3Rpc {
 
     /// Track in-flight requests
     pub(super) active_requests: AtomicUsize,
+    /// an AdminState. set by the admin api
+    pub(super) admin_state: AtomicU8,
     /// mapping of block numbers and hashes
     pub(super) block_map: Option<BlocksByHashCache>,
     /// created_at is only inside an Option so that the "Default" derive works. it will always be set.
@@ -61,22 +105,41 @@ pub struct Web3Rpc {
     pub(super) ws_url: Option<Url>,
     /// the websocket provider is only used for subscriptions
     pub(super) ws_provider: ArcSwapOption<AlloyWsProvider>,
-    /// most all requests prefer the ipc provider.
-    /// TODO: ArcSwapOption?
-    pub(super) ipc_path: Option<PathBuf>,
+    /// most all requests prefer the ipc connections. subscriptions too
+    pub(super) ipc: Option<IpcPool>,
     /// keep track of hard limits
     /// hard_limit_until is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) hard_limit_until: Option<watch::Sender<Instant>>,
+    /// the configured hard limit. checked before every request so that we never hit the rpc's limit
+    pub(super) hard_limit: Option<TokenBucket>,
+    /// the configured max requests in flight
+    pub(super) max_concurrency: Option<Arc<Semaphore>>,
     /// used for ensuring enough requests are available before advancing the head block
     pub(super) soft_limit: u32,
     /// use web3 queries to find the block data limit for archive/pruned nodes
     pub(super) automatic_block_limit: bool,
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     pub backup: bool,
+    /// labels from the config. `[[routes]]` use these to pick rpcs
+    pub tags: Vec<String>,
+    /// methods that got "method not found" and when they can be sent to this rpc again
+    pub(super) unsupported_methods: RwLock<HashMap<String, Instant>>,
+    /// how long to skip a method after "method not found". zero never skips
+    pub(super) unsupported_method_ttl: Duration,
+    /// methods to try after connecting
+    pub(super) probe_methods: Vec<String>,
     /// if subscribed to new heads, blocks are sent through this channel to update a parent Web3Rpcs
     pub(super) block_and_rpc_sender: Option<mpsc::UnboundedSender<BlockAndRpc>>,
     /// TODO: have an enum for this so that "no limit" prints pretty?
     pub(super) block_data_limit: AtomicU64,
+    /// the widest eth_getLogs range to send this rpc from the config. 0 if not configured
+    pub(super) max_get_logs_range: AtomicU64,
+    /// a smaller range learned from errors and when it expires
+    pub(super) learned_get_logs_range: RwLock<Option<(u64, Instant)>>,
+    /// the rpc's "finalized" block. None if it hasn't been polled yet or the rpc doesn't support the tag
+    pub(super) finalized_block: RwLock<Option<BlockHeader>>,
+    /// the rpc's "safe" block. None if it hasn't been polled yet or the rpc doesn't support the tag
+    pub(super) safe_block: RwLock<Option<BlockHeader>>,
     /// head_block is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) head_block_sender: Option<watch::Sender<Option<BlockHeader>>>,
     /// Track head block latency.
@@ -84,15 +147,24 @@ pub struct Web3Rpc {
     pub(super) head_delay: RwLock<EwmaLatency>,
     /// false if a health check has failed
     pub(super) healthy: AtomicBool,
+    /// opens after repeated health check failures. the rpc gets no requests while it is open
+    pub(super) circuit_breaker: CircuitBreaker,
+    /// reconnects wait a random time up to this. it doubles after every failure
+    pub(super) reconnect_backoff: Duration,
+    pub(super) reconnect_backoff_max: Duration,
+    /// the backoff resets after being connected this long
+    pub(super) reconnect_stable: Duration,
     /// Track peak request latency
     /// peak_latency is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) peak_latency: Option<PeakEwmaLatency>,
     /// Automatically set priority based on request latency and active requests
     pub(super) tier: AtomicU32,
+    /// set by the admin api. 0 lets `tier` be set automatically
+    pub(super) tier_override: AtomicU32,
     /// Track total requests served.
     pub(super) total_requests: AtomicUsize,
-    /// If the head block is too old, it is ignored.
-    pub(super) max_head_block_age: Duration,
+    /// If the head block is too old, it is ignored. milliseconds so that config reloads can change it
+    pub(super) max_head_block_age_ms: AtomicU64,
     /// Track request latency.
     /// request_ms_histogram is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) median_latency: Option<RollingQuantileLatency>,
@@ -131,9 +203,9 @@ impl Web3Rpc {
         // and track on servers that have a configured hard limit
         let (hard_limit_until, _) = watch::channel(Instant::now());
 
-        if config.ws_url.is_none() && config.http_url.is_none() {
+        if config.ws_url.is_none() && config.http_url.is_none() && config.ipc_path.is_none() {
             return Err(anyhow!(
-                "either ws_url or http_url are required. it is best to set both. they must both point to the same server!"
+                "one of ipc_path, ws_url, or http_url is required. it is best to set more than one. they must all point to the same server!"
             ));
         }
 
@@ -189,18 +261,36 @@ impl Web3Rpc {
             block_interval,
             block_map: Some(block_map),
             chain_id,
+            circuit_breaker: CircuitBreaker::new(
+                config.circuit_breaker_failures,
+                Duration::from_secs(config.circuit_breaker_seconds),
+            ),
             created_at: Some(created_at),
             display_name: config.display_name,
+            hard_limit: config.hard_limit.map(|x| {
+                TokenBucket::per_period(x, Duration::from_secs(config.hard_limit_period_seconds))
+            }),
             hard_limit_until: Some(hard_limit_until),
             head_block_sender: Some(head_block),
             http_url,
             http_client,
-            ipc_path: config.ipc_path,
-            max_head_block_age,
+            ipc: config.ipc_path.map(IpcPool::new),
+            max_concurrency: config
+                .max_concurrency
+                .map(|x| Arc::new(Semaphore::new(x as usize))),
+            max_get_logs_range: config.max_get_logs_range.unwrap_or_default().into(),
+            max_head_block_age_ms: (max_head_block_age.as_millis() as u64).into(),
             name,
             peak_latency: Some(peak_latency),
+            reconnect_backoff: Duration::from_millis(config.reconnect_backoff_ms),
+            reconnect_backoff_max: Duration::from_millis(config.reconnect_backoff_max_ms),
+            reconnect_stable: Duration::from_secs(config.reconnect_stable_seconds),
             median_latency: Some(median_request_latency),
             soft_limit: config.soft_limit,
+            tags: config.tags,
+            unsupported_methods: Default::default(),
+            unsupported_method_ttl: Duration::from_secs(config.unsupported_method_seconds),
+            probe_methods: config.probe_methods,
             pending_txid_firehose,
             block_and_rpc_sender,
             ws_url,
@@ -257,7 +347,7 @@ impl Web3Rpc {
             head_block = head_block.min(max_block);
         }
 
-        let tier = self.tier.load(atomic::Ordering::SeqCst);
+        let tier = self.tier();
 
         let backup = self.backup;
 
@@ -424,6 +514,223 @@ impl Web3Rpc {
         U64::from_limbs([self.block_data_limit.load(atomic::Ordering::SeqCst)])
     }
 
+    pub fn finalized_block(&self) -> Option<BlockHeader> {
+        self.finalized_block.read().clone()
+    }
+
+    pub fn head_block(&self) -> Option<BlockHeader> {
+        self.head_block_sender.as_ref()?.borrow().clone()
+    }
+
+    pub fn max_head_block_age(&self) -> Duration {
+        Duration::from_millis(self.max_head_block_age_ms.load(atomic::Ordering::Relaxed))
+    }
+
+    pub(super) fn set_max_head_block_age(&self, max_head_block_age: Duration) {
+        self.max_head_block_age_ms.store(
+            max_head_block_age.as_millis() as u64,
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// true if this rpc has every one of the tags
+    pub fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|x| self.tags.contains(x))
+    }
+
+    /// false if the method recently got "method not found" from this rpc
+    pub fn supports_method(&self, method: &str) -> bool {
+        self.unsupported_methods
+            .read()
+            .get(method)
+            .is_none_or(|until| *until <= Instant::now())
+    }
+
+    /// skip this method on this rpc for a while
+    pub fn mark_unsupported_method(&self, method: &str) {
+        if self.unsupported_method_ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+
+        let mut unsupported = self.unsupported_methods.write();
+
+        // expired methods might be supported now. they get checked again
+        unsupported.retain(|_, until| *until > now);
+
+        // method names come from clients. don't let them fill up memory
+        if unsupported.len() >= MAX_UNSUPPORTED_METHODS && !unsupported.contains_key(method) {
+            if let Some(oldest) = unsupported
+                .iter()
+                .min_by_key(|(_, until)| **until)
+                .map(|(x, _)| x.clone())
+            {
+                unsupported.remove(&oldest);
+            }
+        }
+
+        if unsupported
+            .insert(method.to_string(), now + self.unsupported_method_ttl)
+            .is_none()
+        {
+            debug!(rpc=%self, %method, "method is not supported");
+        }
+    }
+
+    /// unsupported probe methods and how many seconds until they are tried again.
+    /// other methods came from clients and are only counted. their names are not safe to show
+    fn unsupported_methods_for_status(&self) -> (BTreeMap<String, u64>, usize) {
+        let now = Instant::now();
+
+        let mut probed = BTreeMap::new();
+        let mut others = 0;
+
+        for (method, until) in self.unsupported_methods.read().iter() {
+            if *until <= now {
+                continue;
+            }
+
+            if self.probe_methods.contains(method) {
+                probed.insert(method.clone(), (*until - now).as_secs());
+            } else {
+                others += 1;
+            }
+        }
+
+        (probed, others)
+    }
+
+    /// call each of the probe methods. the ones that return "method not found" are skipped until they expire
+    async fn probe_methods(self: &Arc<Self>) {
+        for method in self.probe_methods.iter() {
+            let response = self
+                .internal_request::<_, Arc<sonic_rs::OwnedLazyValue>>(
+                    method.clone().into(),
+                    &[(); 0],
+                    Some(Level::TRACE.into()),
+                    Some(Duration::from_secs(5)),
+                )
+                .await;
+
+            // bad params or any other error still means the method exists
+            match response {
+                Err(Web3ProxyError::MethodNotFound(_)) => self.mark_unsupported_method(method),
+                Err(Web3ProxyError::JsonRpcErrorData(err)) if err.code == -32601 => {
+                    self.mark_unsupported_method(method)
+                }
+                _ => {
+                    self.unsupported_methods.write().remove(method);
+                }
+            }
+        }
+    }
+
+    pub fn admin_state(&self) -> AdminState {
+        AdminState::from_u8(self.admin_state.load(atomic::Ordering::SeqCst))
+    }
+
+    /// returns the old state
+    pub fn set_admin_state(&self, state: AdminState) -> AdminState {
+        AdminState::from_u8(
+            self.admin_state
+                .swap(state.as_u8(), atomic::Ordering::SeqCst),
+        )
+    }
+
+    /// requests that are in progress. a draining rpc is done when this is 0
+    pub fn active_requests(&self) -> usize {
+        self.active_requests.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn tier(&self) -> u32 {
+        self.tier.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn tier_override(&self) -> Option<u32> {
+        match self.tier_override.load(atomic::Ordering::SeqCst) {
+            0 => None,
+            x => Some(x),
+        }
+    }
+
+    /// pin the tier instead of setting it from latency. None goes back to automatic tiers on the next head block
+    pub fn set_tier_override(&self, tier: Option<u32>) {
+        let tier = tier.unwrap_or_default();
+
+        self.tier_override.store(tier, atomic::Ordering::SeqCst);
+
+        if tier > 0 {
+            self.tier.store(tier, atomic::Ordering::SeqCst);
+        }
+    }
+
+    /// forget any rate limit that the rpc told us about (or that we guessed)
+    pub fn clear_hard_limit_until(&self) {
+        if let Some(x) = self.hard_limit_until.as_ref() {
+            x.send_replace(Instant::now());
+        }
+    }
+
+    /// run the health check now instead of waiting for the next one
+    pub async fn check_provider_now(self: &Arc<Self>) -> Web3ProxyResult<()> {
+        let result = self.check_provider().await;
+
+        self.record_health_check(result.is_ok());
+
+        result
+    }
+
+    /// keep the admin api's settings when a config reload replaces this rpc
+    pub(super) fn copy_admin_state(&self, old: &Self) {
+        self.set_admin_state(old.admin_state());
+        self.set_tier_override(old.tier_override());
+    }
+
+    /// the widest eth_getLogs range to send this rpc. None if it hasn't been configured or learned
+    pub fn max_get_logs_range(&self) -> Option<u64> {
+        self.get_logs_range(self.learned_get_logs_range.read().as_ref())
+    }
+
+    fn get_logs_range(&self, learned: Option<&(u64, Instant)>) -> Option<u64> {
+        let configured = match self.max_get_logs_range.load(atomic::Ordering::Relaxed) {
+            0 => None,
+            x => Some(x),
+        };
+
+        let learned = learned
+            .filter(|(_, until)| *until > Instant::now())
+            .map(|(x, _)| *x);
+
+        match (configured, learned) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// the rpc rejected a range. it is only raised again once the learned range expires
+    pub fn lower_max_get_logs_range(&self, range: u64) {
+        let range = range.max(1);
+
+        let mut learned = self.learned_get_logs_range.write();
+
+        let old = self.get_logs_range(learned.as_ref());
+
+        if old.is_some_and(|x| range >= x) {
+            return;
+        }
+
+        *learned = Some((range, Instant::now() + LEARNED_GET_LOGS_RANGE_TTL));
+
+        drop(learned);
+
+        info!(rpc=%self, ?old, new=range, ttl=?LEARNED_GET_LOGS_RANGE_TTL, "lowered max eth_getLogs range");
+    }
+
+    pub fn safe_block(&self) -> Option<BlockHeader> {
+        self.safe_block.read().clone()
+    }
+
     /// TODO: get rid of this now that consensus rpcs does it
     pub fn has_block_data(&self, needed_block_num: U64) -> bool {
         if let Some(head_block_sender) = self.head_block_sender.as_ref() {
@@ -527,6 +834,8 @@ impl Web3Rpc {
             .await
             .context(format!("unable to check_block_data_limit of {}", self))?;
 
+        self.probe_methods().await;
+
         info!("successfully connected to {}", self);
 
         Ok(())
@@ -616,7 +925,7 @@ impl Web3Rpc {
         let head_block = self.head_block_sender.as_ref().unwrap().borrow().clone();
 
         if let Some(head_block) = head_block {
-            if head_block.age() > self.max_head_block_age {
+            if head_block.age() > self.max_head_block_age() {
                 // TODO: if the server is expected to be syncing, make a way to quiet this error
                 return Err(Web3ProxyError::OldHead(self.clone(), head_block));
             }
@@ -663,9 +972,13 @@ impl Web3Rpc {
         Ok(())
     }
 
-    /// TODO: this needs to be a subscribe_with_reconnect that does a retry with jitter and exponential backoff
+    /// reconnect with exponential backoff and full jitter
     async fn subscribe_with_reconnect(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let mut backoff = Backoff::new(self.reconnect_backoff, self.reconnect_backoff_max);
+
         loop {
+            let connected_at = Instant::now();
+
             if let Err(err) = self.clone().subscribe().await {
                 if self.should_disconnect() {
                     break;
@@ -676,18 +989,36 @@ impl Web3Rpc {
                 break;
             }
 
-            // TODO: exponential backoff with jitter
+            if connected_at.elapsed() >= self.reconnect_stable {
+                backoff.reset();
+            }
+
+            let delay = backoff.next_delay();
+
             if self.backup {
-                debug!("reconnecting to {} in 10 seconds", self);
+                debug!(?delay, "reconnecting to {}", self);
             } else {
-                info!("reconnecting to {} in 10 seconds", self);
+                info!(?delay, "reconnecting to {}", self);
             }
-            sleep(Duration::from_secs(10)).await;
+            sleep(delay).await;
         }
 
         Ok(())
     }
 
+    /// count a health check towards the circuit breaker
+    fn record_health_check(&self, healthy: bool) {
+        self.healthy.store(healthy, atomic::Ordering::SeqCst);
+
+        if healthy {
+            if self.circuit_breaker.success() {
+                info!("circuit breaker on {} closed", self);
+            }
+        } else if self.circuit_breaker.failure() {
+            warn!("circuit breaker on {} opened", self);
+        }
+    }
+
     /// subscribe to blocks and transactions
     /// This should only exit when the program is exiting.
     /// TODO: should more of these args be on self? chain_id for sure
@@ -724,7 +1055,7 @@ impl Web3Rpc {
             .await
             .web3_context("failed check_provider")
         {
-            self.healthy.store(false, atomic::Ordering::SeqCst);
+            self.record_health_check(false);
             return Err(err);
         }
 
@@ -761,7 +1092,7 @@ impl Web3Rpc {
 
                     // TODO: if this fails too many times, reset the connection
                     if let Err(err) = rpc.check_health(detailed_healthcheck, error_handler).await {
-                        rpc.healthy.store(false, atomic::Ordering::SeqCst);
+                        rpc.record_health_check(false);
 
                         // TODO: different level depending on the error handler
                         // TODO: if rate limit error, set "retry_at"
@@ -771,7 +1102,7 @@ impl Web3Rpc {
                             error!(?err, "health check on {} failed", rpc);
                         }
                     } else {
-                        rpc.healthy.store(true, atomic::Ordering::SeqCst);
+                        rpc.record_health_check(true);
                     }
 
                     // TODO: should we count the requests done inside this health check
@@ -796,7 +1127,7 @@ impl Web3Rpc {
                 true
             };
 
-            self.healthy.store(initial_check, atomic::Ordering::SeqCst);
+            self.record_health_check(initial_check);
 
             tokio::spawn(f)
         } else {
@@ -812,7 +1143,7 @@ impl Web3Rpc {
 
                     // TODO: if this fails too many times, reset the connection
                     if let Err(err) = rpc.check_provider().await {
-                        rpc.healthy.store(false, atomic::Ordering::SeqCst);
+                        rpc.record_health_check(false);
 
                         // TODO: if rate limit error, set "retry_at"
                         if rpc.backup {
@@ -821,7 +1152,7 @@ impl Web3Rpc {
                             error!(?err, "provider check on {} failed", rpc);
                         }
                     } else {
-                        rpc.healthy.store(true, atomic::Ordering::SeqCst);
+                        rpc.record_health_check(true);
                     }
 
                     sleep(Duration::from_secs(health_sleep_seconds)).await;
@@ -849,8 +1180,23 @@ impl Web3Rpc {
             abort_handles.push(a);
         }
 
+        // poll the finalized and safe blocks
+        if self.block_and_rpc_sender.is_some() {
+            let clone = self.clone();
+
+            let f = async move { clone.poll_finality().await };
+
+            let h = tokio::spawn(f);
+            let a = h.abort_handle();
+
+            futures.push(h);
+            abort_handles.push(a);
+        }
+
         // subscribe to new transactions
-        if self.pending_txid_firehose.is_some() && self.ws_provider.load().is_some() {
+        if self.pending_txid_firehose.is_some()
+            && (self.ipc.is_some() || self.ws_provider.load().is_some())
+        {
             let clone = self.clone();
 
             let f = async move {
@@ -882,6 +1228,8 @@ impl Web3Rpc {
 
         // clear the head block
         self.send_head_block_result(Ok(None)).await?;
+        *self.finalized_block.write() = None;
+        *self.safe_block.write() = None;
 
         // stop the other futures
         for a in abort_handles {
@@ -898,7 +1246,17 @@ impl Web3Rpc {
 
         let pending_txid_firehose = self.pending_txid_firehose.as_ref().unwrap();
 
-        if let Some(ws_provider) = self.ws_provider.load().as_ref() {
+        if let Some(ipc) = self.ipc.as_ref() {
+            self.wait_for_throttle(Instant::now() + Duration::from_secs(5))
+                .await?;
+
+            // TODO: only subscribe if a user has subscribed
+            let mut subscription = ipc.subscribe(&json!(["newPendingTransactions"])).await?;
+
+            while let Some(x) = subscription.next::<TxHash>().await {
+                pending_txid_firehose.send(x?).await;
+            }
+        } else if let Some(ws_provider) = self.ws_provider.load().as_ref() {
             // todo: move subscribe_blocks onto the request handle instead of having a seperate wait_for_throttle
             self.wait_for_throttle(Instant::now() + Duration::from_secs(5))
                 .await?;
@@ -911,7 +1269,7 @@ impl Web3Rpc {
                 pending_txid_firehose.send(x).await;
             }
         } else {
-            // only websockets subscribe to pending transactions
+            // only ipc and websockets subscribe to pending transactions
             // its possible to do with http, but not recommended
             // TODO: what should we do here?
             unimplemented!()
@@ -920,6 +1278,78 @@ impl Web3Rpc {
         Ok(())
     }
 
+    /// Poll the "finalized" and "safe" tags. There is no subscription for these.
+    /// Rpcs that don't support the tags (like pre-merge chains) keep None and are asked again after `unsupported_method_ttl`.
+    /// Timeouts and other errors keep the last block.
+    async fn poll_finality(self: &Arc<Self>) -> Web3ProxyResult<()> {
+        // finality moves much slower than the head. once per block is plenty
+        let mut i = interval(self.block_interval);
+        i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // an unsupported tag is an error every time. don't spam the logs
+        let error_handler = Some(Level::TRACE.into());
+
+        // when to ask again about a tag that the rpc does not support
+        let mut unsupported_until: [Option<Instant>; 2] = [None, None];
+
+        loop {
+            i.tick().await;
+
+            if self.should_disconnect() {
+                break;
+            }
+
+            for ((tag, lock), unsupported_until) in [
+                ("finalized", &self.finalized_block),
+                ("safe", &self.safe_block),
+            ]
+            .into_iter()
+            .zip(unsupported_until.iter_mut())
+            {
+                if unsupported_until.is_some_and(|x| x > Instant::now()) {
+                    continue;
+                }
+
+                let block = match self
+                    .internal_request::<_, Option<ArcBlock>>(
+                        "eth_getBlockByNumber".into(),
+                        &(tag, false),
+                        error_handler,
+                        Some(Duration::from_secs(5)),
+                    )
+                    .await
+                {
+                    // null if the rpc doesn't have a block for the tag
+                    Ok(x) => x.map(BlockHeader::new),
+                    Err(Web3ProxyError::JsonRpcErrorData(err)) => {
+                        trace!(?err, %tag, "{} does not support the tag", self);
+
+                        if !self.unsupported_method_ttl.is_zero() {
+                            *unsupported_until = Some(Instant::now() + self.unsupported_method_ttl);
+                        }
+
+                        None
+                    }
+                    Err(err) => {
+                        // a timeout says nothing about the tag. keep the last block
+                        trace!(?err, %tag, "unable to get block from {}", self);
+                        continue;
+                    }
+                };
+
+                let mut lock = lock.write();
+
+                if lock.as_ref().map(|x| x.number()) != block.as_ref().map(|x| x.number()) {
+                    trace!(%tag, block=?block, "new block tag on {}", self);
+                }
+
+                *lock = block;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Subscribe to new block headers.
     async fn subscribe_new_heads(self: &Arc<Self>) -> Web3ProxyResult<()> {
         info!("subscribing to new heads on {}", self);
@@ -930,7 +1360,45 @@ impl Web3Rpc {
             Some(Level::ERROR.into())
         };
 
-        if let Some(ws_provider) = self.ws_provider.load().as_ref() {
+        if let Some(ipc) = self.ipc.as_ref() {
+            self.wait_for_throttle(Instant::now() + Duration::from_secs(5))
+                .await?;
+
+            #[derive(Deserialize)]
+            struct NewHead {
+                hash: B256,
+            }
+
+            let mut heads = ipc.subscribe(&json!(["newHeads"])).await?;
+
+            // the subscription doesn't send the current block
+            let latest_block: Result<Option<ArcBlock>, _> = self
+                .internal_request(
+                    "eth_getBlockByNumber".into(),
+                    &("latest", false),
+                    error_handler,
+                    Some(Duration::from_secs(5)),
+                )
+                .await;
+
+            self.send_head_block_result(latest_block).await?;
+
+            while let Some(head) = heads.next::<NewHead>().await {
+                let head = head?;
+
+                // the notification only has the header. the full block is needed
+                let block: Result<Option<ArcBlock>, _> = self
+                    .internal_request(
+                        "eth_getBlockByHash".into(),
+                        &(head.hash, false),
+                        error_handler,
+                        Some(Duration::from_secs(5)),
+                    )
+                    .await;
+
+                self.send_head_block_result(block).await?;
+            }
+        } else if let Some(ws_provider) = self.ws_provider.load().as_ref() {
             self.wait_for_throttle(Instant::now() + Duration::from_secs(5))
                 .await?;
 
@@ -982,7 +1450,7 @@ impl Web3Rpc {
                 i.tick().await;
             }
         } else {
-            return Err(anyhow!("no ws or http provider!").into());
+            return Err(anyhow!("no ipc, ws, or http provider!").into());
         }
 
         // clear the head block. this might not be needed, but it won't hurt
@@ -1077,7 +1545,12 @@ impl Web3Rpc {
         // TODO: if websocket is reconnecting, return an error?
 
         if !allow_unhealthy {
-            if !(self.healthy.load(atomic::Ordering::SeqCst)) {
+            if !(self.healthy.load(atomic::Ordering::SeqCst)) || !self.circuit_breaker.is_closed() {
+                return Ok(OpenRequestResult::Failed);
+            }
+
+            if self.admin_state() != AdminState::Enabled {
+                trace!("{} is {:?} by an admin", self, self.admin_state());
                 return Ok(OpenRequestResult::Failed);
             }
 
@@ -1171,8 +1644,28 @@ impl Web3Rpc {
             return Ok(OpenRequestResult::RetryAt(retry_at));
         }
 
+        // the permit is held by the handle
+        let permit = match self.max_concurrency.as_ref() {
+            None => None,
+            Some(x) => match x.clone().try_acquire_owned() {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    // TODO: wake when a permit is released instead of polling
+                    trace!("{} is at its max concurrency", self);
+                    return Ok(OpenRequestResult::RetryAt(now + Duration::from_millis(10)));
+                }
+            },
+        };
+
+        if let Some(hard_limit) = self.hard_limit.as_ref() {
+            if let Err(Some(wait)) = hard_limit.try_acquire(1) {
+                trace!(?wait, "{} is at its hard limit", self);
+                return Ok(OpenRequestResult::RetryAt(now + wait));
+            }
+        }
+
         let handle =
-            OpenRequestHandle::new(web3_request.clone(), self.clone(), error_handler).await;
+            OpenRequestHandle::new(web3_request.clone(), self.clone(), error_handler, permit).await;
 
         Ok(handle.into())
     }
@@ -1238,6 +1731,7 @@ impl Hash for Web3Rpc {
         self.name.hash(state);
 
         self.http_url.hash(state);
+        self.ipc.as_ref().map(|x| x.path()).hash(state);
         self.ws_url.hash(state);
 
         // TODO: don't include soft_limit if we change them to be dynamic
@@ -1259,6 +1753,70 @@ impl PartialOrd for Web3Rpc {
     }
 }
 
+impl Web3Rpc {
+    /// copy the current state into the prometheus gauges. labels are the group and the rpc name
+    pub(crate) fn update_metrics(&self, labels: &[&str], gauges: &RpcGauges) {
+        gauges
+            .active_requests
+            .with_label_values(labels)
+            .set(self.active_requests.load(atomic::Ordering::SeqCst) as i64);
+
+        let block_data_limit = match self.block_data_limit.load(atomic::Ordering::SeqCst) {
+            u64::MAX => f64::INFINITY,
+            x => x as f64,
+        };
+        gauges
+            .block_data_limit
+            .with_label_values(labels)
+            .set(block_data_limit);
+
+        let now = Instant::now();
+        gauges
+            .hard_limited
+            .with_label_values(labels)
+            .set((self.next_available(now) > now) as i64);
+
+        if let Some(head_block) = self.head_block_sender.as_ref().unwrap().borrow().as_ref() {
+            gauges
+                .head_block_num
+                .with_label_values(labels)
+                .set(head_block.number().to::<i64>());
+            gauges
+                .head_block_age
+                .with_label_values(labels)
+                .set(head_block.age().as_secs() as i64);
+        }
+
+        gauges
+            .healthy
+            .with_label_values(labels)
+            .set(self.healthy.load(atomic::Ordering::SeqCst) as i64);
+
+        gauges.median_latency.with_label_values(labels).set(
+            self.median_latency
+                .as_ref()
+                .unwrap()
+                .latency()
+                .as_secs_f64(),
+        );
+
+        gauges
+            .peak_latency
+            .with_label_values(labels)
+            .set(self.peak_latency.as_ref().unwrap().latency().as_secs_f64());
+
+        gauges
+            .tier
+            .with_label_values(labels)
+            .set(self.tier.load(atomic::Ordering::SeqCst) as i64);
+
+        gauges
+            .total_requests
+            .with_label_values(labels)
+            .inc_by(self.total_requests.load(atomic::Ordering::Relaxed) as u64);
+    }
+}
+
 impl PartialEq for Web3Rpc {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
@@ -1270,7 +1828,7 @@ impl Serialize for Web3Rpc {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Web3Rpc", 15)?;
+        let mut state = serializer.serialize_struct("Web3Rpc", 22)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1279,6 +1837,8 @@ impl Serialize for Web3Rpc {
 
         state.serialize_field("backup", &self.backup)?;
 
+        state.serialize_field("tags", &self.tags)?;
+
         state.serialize_field("web3_clientVersion", &self.client_version.read().as_ref())?;
 
         match self.block_data_limit.load(atomic::Ordering::SeqCst) {
@@ -1290,8 +1850,21 @@ impl Serialize for Web3Rpc {
             }
         }
 
+        state.serialize_field("max_get_logs_range", &self.max_get_logs_range())?;
+
+        {
+            let (probed, others) = self.unsupported_methods_for_status();
+
+            state.serialize_field("unsupported_methods", &probed)?;
+            state.serialize_field("unsupported_methods_other", &others)?;
+        }
+
         state.serialize_field("tier", &self.tier)?;
 
+        state.serialize_field("tier_override", &self.tier_override())?;
+
+        state.serialize_field("admin_state", &self.admin_state())?;
+
         state.serialize_field("soft_limit", &self.soft_limit)?;
 
         // TODO: maybe this is too much data. serialize less?
@@ -1302,6 +1875,9 @@ impl Serialize for Web3Rpc {
             state.serialize_field("head_block", &head_block)?;
         }
 
+        state.serialize_field("safe_block", &*self.safe_block.read())?;
+        state.serialize_field("finalized_block", &*self.finalized_block.read())?;
+
         state.serialize_field(
             "total_requests",
             &self.total_requests.load(atomic::Ordering::Relaxed),
@@ -1340,6 +1916,8 @@ impl Serialize for Web3Rpc {
         {
             let healthy = self.healthy.load(atomic::Ordering::SeqCst);
             state.serialize_field("healthy", &healthy)?;
+
+            state.serialize_field("circuit_breaker", &self.circuit_breaker)?;
         }
 
         state.end()
@@ -1385,6 +1963,29 @@ impl fmt::Display for Web3Rpc {
     }
 }
 
+#[cfg(test)]
+impl Web3Rpc {
+    /// a healthy rpc that sends requests to `http_url` (like a `StubRpc`). nothing is spawned to watch it
+    pub(crate) async fn for_tests(name: &str, http_url: &str) -> Arc<Self> {
+        let x = Self {
+            name: name.to_string(),
+            disconnect_watch: Some(watch::channel(false).0),
+            healthy: true.into(),
+            http_client: Some(reqwest::Client::new()),
+            http_url: Some(http_url.parse().unwrap()),
+            peak_latency: Some(PeakEwmaLatency::spawn(
+                Duration::from_secs(15),
+                1_000,
+                Duration::from_secs(1),
+            )),
+            median_latency: Some(RollingQuantileLatency::spawn_median(1_000).await),
+            ..Default::default()
+        };
+
+        Arc::new(x)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(unused_imports)]
@@ -1461,6 +2062,86 @@ mod tests {
         assert!(!x.has_block_data(head_block.number() + U64::from(1000)));
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_unsupported_methods() {
+        let x = Web3Rpc {
+            name: "name".to_string(),
+            probe_methods: vec!["trace_block".to_string()],
+            unsupported_method_ttl: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        assert!(x.supports_method("trace_block"));
+
+        x.mark_unsupported_method("trace_block");
+        assert!(!x.supports_method("trace_block"));
+        assert!(x.supports_method("eth_call"));
+        assert_eq!(
+            x.unsupported_methods_for_status().0.get("trace_block"),
+            Some(&60)
+        );
+
+        // names from clients are counted but not shown
+        x.mark_unsupported_method("junk_1");
+        assert!(!x.supports_method("junk_1"));
+        let (probed, others) = x.unsupported_methods_for_status();
+        assert_eq!(probed.len(), 1);
+        assert_eq!(others, 1);
+
+        // it gets tried again after it expires
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(x.supports_method("trace_block"));
+        assert_eq!(x.unsupported_methods_for_status(), (BTreeMap::new(), 0));
+
+        // random method names can't grow the map forever. the oldest entries go first
+        for i in 0..MAX_UNSUPPORTED_METHODS + 10 {
+            x.mark_unsupported_method(&format!("junk_{}", i));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(x.unsupported_methods.read().len(), MAX_UNSUPPORTED_METHODS);
+        assert!(x.supports_method("junk_0"));
+        assert!(!x.supports_method(&format!("junk_{}", MAX_UNSUPPORTED_METHODS + 9)));
+
+        // a ttl of 0 never skips methods
+        let x = Web3Rpc::default();
+        x.mark_unsupported_method("trace_block");
+        assert!(x.supports_method("trace_block"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admin_state() {
+        let (hard_limit_until, _) = watch::channel(Instant::now() + Duration::from_secs(60));
+
+        let x = Web3Rpc {
+            name: "name".to_string(),
+            hard_limit_until: Some(hard_limit_until),
+            tier: 3.into(),
+            ..Default::default()
+        };
+
+        assert_eq!(x.admin_state(), AdminState::Enabled);
+        assert_eq!(x.set_admin_state(AdminState::Draining), AdminState::Enabled);
+        assert_eq!(x.admin_state(), AdminState::Draining);
+
+        x.set_tier_override(Some(1));
+        assert_eq!(x.tier(), 1);
+        assert_eq!(x.tier_override(), Some(1));
+
+        // a config reload makes a new rpc. the admin's changes are kept
+        let y = Web3Rpc::default();
+        y.copy_admin_state(&x);
+        assert_eq!(y.admin_state(), AdminState::Draining);
+        assert_eq!(y.tier_override(), Some(1));
+
+        x.set_tier_override(None);
+        assert_eq!(x.tier_override(), None);
+
+        let now = Instant::now();
+        assert!(x.next_available(now) > now);
+        x.clear_hard_limit_until();
+        assert_eq!(x.next_available(now), now);
+    }
+
     /*
     // TODO: think about how to bring the concept of a "lagged" node back
     #[test]
@@ -1507,4 +2188,73 @@ mod tests {
         assert!(!x.has_block_data(head_block.number() + 1000));
     }
     */
+
+    #[test_log::test(tokio::test)]
+    async fn test_poll_finality_keeps_blocks() {
+        use crate::test_utils::stub_rpc::{stub_error, StubRpc};
+        use sonic_rs::JsonValueTrait;
+        use std::sync::atomic::AtomicBool;
+
+        let finalized = sonic_rs::to_value(&block(16, 0)).unwrap();
+        let broken = Arc::new(AtomicBool::new(false));
+        let safe_requests = Arc::new(AtomicUsize::new(0));
+
+        let stub = {
+            let broken = broken.clone();
+            let safe_requests = safe_requests.clone();
+
+            StubRpc::spawn(move |_, params| {
+                if params[0].as_str() == Some("safe") {
+                    safe_requests.fetch_add(1, atomic::Ordering::SeqCst);
+
+                    return (Duration::ZERO, Err(stub_error("unknown block")));
+                }
+
+                if broken.load(atomic::Ordering::SeqCst) {
+                    // not a block. like a timeout, this says nothing about the tag
+                    (Duration::ZERO, Ok(sonic_rs::json!("garbage")))
+                } else {
+                    (Duration::ZERO, Ok(finalized.clone()))
+                }
+            })
+            .await
+        };
+
+        let mut rpc = Web3Rpc::for_tests("a", &stub.url).await;
+
+        {
+            let x = Arc::get_mut(&mut rpc).unwrap();
+            x.block_interval = Duration::from_millis(20);
+            x.unsupported_method_ttl = Duration::from_secs(60);
+        }
+
+        let handle = tokio::spawn({
+            let rpc = rpc.clone();
+            async move { rpc.poll_finality().await }
+        });
+
+        sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(
+            rpc.finalized_block().map(|x| x.number()),
+            Some(U64::from(16))
+        );
+        assert!(rpc.safe_block().is_none());
+
+        // the unsupported tag is not asked about again until the ttl passes
+        assert_eq!(safe_requests.load(atomic::Ordering::SeqCst), 1);
+
+        broken.store(true, atomic::Ordering::SeqCst);
+        let requests = stub.requests();
+
+        sleep(Duration::from_millis(200)).await;
+
+        assert!(stub.requests() > requests);
+        assert_eq!(
+            rpc.finalized_block().map(|x| x.number()),
+            Some(U64::from(16))
+        );
+
+        handle.abort();
+    }
 }
@@ -5,14 +5,15 @@ use crate::jsonrpc::{
 };
 use alloy::providers::Provider;
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use derive_more::From;
 use futures::Future;
+use reqwest::header::HeaderMap;
 use reqwest::StatusCode;
 use std::pin::Pin;
 use std::sync::atomic;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::UnixStream;
+use tokio::sync::OwnedSemaphorePermit;
 use tokio::time::{Duration, Instant};
 use tracing::{debug, error, info, trace, warn, Level};
 
@@ -35,6 +36,8 @@ pub struct OpenRequestHandle {
     web3_request: Arc<ValidatedRequest>,
     error_handler: RequestErrorHandler,
     rpc: Arc<Web3Rpc>,
+    /// for rpcs with a max_concurrency
+    _permit: Option<OwnedSemaphorePermit>,
 }
 
 /// Depending on the context, RPC errors require different handling.
@@ -87,6 +90,7 @@ impl OpenRequestHandle {
         web3_request: Arc<ValidatedRequest>,
         rpc: Arc<Web3Rpc>,
         error_handler: Option<RequestErrorHandler>,
+        permit: Option<OwnedSemaphorePermit>,
     ) -> Self {
         // TODO: take request_id as an argument?
         // TODO: attach a unique id to this? customer requests have one, but not internal queries
@@ -100,6 +104,7 @@ impl OpenRequestHandle {
             web3_request,
             error_handler,
             rpc,
+            _permit: permit,
         }
     }
 
@@ -119,12 +124,21 @@ impl OpenRequestHandle {
             warn!(?duration, "rate limited on {}!", self.rpc);
         }
 
-        // TODO: use send_if_modified to be sure we only send if our value is greater
+        let until = Instant::now() + duration;
+
+        // never shorten a longer limit
         self.rpc
             .hard_limit_until
             .as_ref()
             .unwrap()
-            .send_replace(Instant::now() + duration);
+            .send_if_modified(|x| {
+                if until > *x {
+                    *x = until;
+                    true
+                } else {
+                    false
+                }
+            });
     }
 
     /// Just get the response from the provider without any extra handling.
@@ -132,30 +146,19 @@ impl OpenRequestHandle {
     async fn _request<R: JsonRpcResultData + serde::Serialize>(
         &self,
     ) -> Web3ProxyResult<jsonrpc::SingleResponse<R>> {
-        if let Some(ipc_path) = self.rpc.ipc_path.as_ref() {
-            // first, prefer the unix stream
+        if let Some(ipc) = self.rpc.ipc.as_ref() {
+            // first, prefer the unix socket
             let request = self
                 .web3_request
                 .inner
                 .jsonrpc_request()
                 .context("there should always be a request here")?;
 
-            // TODO: instead of connecting every time, use a connection pool
-            let mut ipc_stream = UnixStream::connect(ipc_path).await?;
-
-            ipc_stream.writable().await?;
-
-            let x = sonic_rs::to_vec(request)?;
-
-            let _ = ipc_stream.write(&x).await?;
+            let response = ipc.request(&request.method, &request.params).await?;
 
-            ipc_stream.readable().await?;
-
-            let mut buf = Vec::new();
-
-            let n = ipc_stream.try_read(&mut buf)?;
-
-            let x: ParsedResponse<R> = sonic_rs::from_slice(&buf[..n])?;
+            // the connection uses its own ids
+            let mut x: ParsedResponse<R> = sonic_rs::from_slice(&response)?;
+            x.id = self.web3_request.id();
 
             Ok(x.into())
         } else if let (Some(url), Some(ref client)) =
@@ -187,7 +190,11 @@ impl OpenRequestHandle {
             }
             let response = request_builder.send().await?;
 
-            if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let too_many_requests = response.status() == StatusCode::TOO_MANY_REQUESTS;
+
+            if let Some(duration) = retry_after(response.headers(), too_many_requests, Utc::now()) {
+                self.rate_limit_for(duration);
+            } else if too_many_requests {
                 // TODO: how much should we actually rate limit?
                 self.rate_limit_for(Duration::from_secs(1));
             }
@@ -304,7 +311,7 @@ impl OpenRequestHandle {
                     ResponsePayload::Error { error } => {
                         trace!(?error, "jsonrpc error data");
 
-                        if error.message.starts_with("execution reverted") {
+                        if error.is_revert() {
                             ResponseType::Revert
                         } else if error.code == StatusCode::TOO_MANY_REQUESTS.as_u16() as i64 {
                             ResponseType::RateLimited
@@ -371,6 +378,9 @@ impl OpenRequestHandle {
                                     {
                                         let method = self.web3_request.inner.method().to_string();
 
+                                        // other rpcs get this method until this expires
+                                        self.rpc.mark_unsupported_method(&method);
+
                                         response =
                                             Err(Web3ProxyError::MethodNotFound(method.into()))
                                     }
@@ -450,3 +460,134 @@ impl OpenRequestHandle {
         response
     }
 }
+
+/// never wait longer than this because of a backend's headers
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// the headers are from the backend. NaN is ignored and anything too big for a Duration is the max
+fn seconds(x: f64) -> Option<Duration> {
+    if x.is_nan() {
+        return None;
+    }
+
+    Some(Duration::try_from_secs_f64(x.max(0.0)).unwrap_or(MAX_RETRY_AFTER))
+}
+
+/// How long the backend wants us to wait. `Retry-After` is used first.
+/// `x-ratelimit-reset` is used once `x-ratelimit-remaining` hits 0 or we were told to slow down.
+fn retry_after(
+    headers: &HeaderMap,
+    too_many_requests: bool,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .map(str::trim)
+    };
+
+    // seconds or an http date
+    let retry_after = header("retry-after").and_then(|x| {
+        x.parse::<f64>().ok().and_then(seconds).or_else(|| {
+            let x = DateTime::parse_from_rfc2822(x).ok()?;
+            (x.with_timezone(&Utc) - now).to_std().ok()
+        })
+    });
+
+    let duration = retry_after.or_else(|| {
+        let remaining = header("x-ratelimit-remaining").and_then(|x| x.parse::<u64>().ok());
+
+        if !too_many_requests && remaining != Some(0) {
+            return None;
+        }
+
+        let reset = header("x-ratelimit-reset")?.parse::<f64>().ok()?;
+
+        // some providers send seconds to wait and some send a unix timestamp
+        if reset > 1_000_000_000.0 {
+            seconds(reset - now.timestamp_millis() as f64 / 1000.0)
+        } else {
+            seconds(reset)
+        }
+    })?;
+
+    Some(duration.min(MAX_RETRY_AFTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::retry_after;
+    use chrono::{TimeZone, Utc};
+    use reqwest::header::HeaderMap;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+
+        let headers = |x: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (k, v) in x {
+                headers.insert(*k, v.parse().unwrap());
+            }
+            headers
+        };
+
+        assert_eq!(retry_after(&headers(&[]), true, now), None);
+
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "3")]), true, now),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:05 GMT")]),
+                true,
+                now
+            ),
+            Some(Duration::from_secs(5))
+        );
+
+        // reset only matters once the limit is used up
+        let reset = [("x-ratelimit-remaining", "10"), ("x-ratelimit-reset", "2")];
+        assert_eq!(retry_after(&headers(&reset), false, now), None);
+        assert_eq!(
+            retry_after(&headers(&reset), true, now),
+            Some(Duration::from_secs(2))
+        );
+
+        let timestamp = (now.timestamp() + 7).to_string();
+        assert_eq!(
+            retry_after(
+                &headers(&[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", &timestamp)
+                ]),
+                false,
+                now
+            ),
+            Some(Duration::from_secs(7))
+        );
+
+        // a bad header can't take the rpc out for long
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "86400")]), true, now),
+            Some(Duration::from_secs(60))
+        );
+
+        // values that don't fit in a Duration must not panic
+        for x in ["inf", "1e30", "-inf"] {
+            assert!(retry_after(&headers(&[("retry-after", x)]), true, now).is_some());
+            assert!(retry_after(&headers(&[("x-ratelimit-reset", x)]), true, now).is_some());
+        }
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "1e30")]), true, now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "NaN")]), true, now),
+            None
+        );
+    }
+}
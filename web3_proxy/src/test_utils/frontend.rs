@@ -0,0 +1,80 @@
+use crate::app::App;
+use crate::config::TopConfig;
+use crate::frontend;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{sleep, Duration, Instant};
+
+/// an app and its frontend on a random port. there aren't any rpcs, so only requests that the app answers itself work
+/// on drop, the app is shut down
+pub struct TestFrontend {
+    pub app: Arc<App>,
+    pub port: u16,
+    /// send a new config here like the config file watcher would. it also keeps the app's config loop from spinning
+    pub new_top_config: Arc<watch::Sender<TopConfig>>,
+    shutdown_sender: broadcast::Sender<()>,
+}
+
+impl TestFrontend {
+    /// `config` is toml like the config file. it needs at least `[app]` with a chain_id and an empty `[balanced_rpcs]`
+    pub async fn spawn(config: &str) -> Self {
+        let top_config = TopConfig::from_toml_str(config).unwrap();
+
+        let (shutdown_sender, _) = broadcast::channel(1);
+
+        let spawned = App::spawn(
+            Arc::new(AtomicU16::new(0)),
+            top_config,
+            shutdown_sender.clone(),
+        )
+        .await
+        .unwrap();
+
+        let app = spawned.app;
+        let new_top_config = spawned.new_top_config;
+
+        let (shutdown_complete_sender, _) = broadcast::channel(1);
+
+        tokio::spawn(frontend::serve(
+            app.clone(),
+            shutdown_sender.subscribe(),
+            shutdown_complete_sender,
+        ));
+
+        let start = Instant::now();
+        let port = loop {
+            match app.frontend_port.load(Ordering::SeqCst) {
+                0 => {
+                    assert!(
+                        start.elapsed() <= Duration::from_secs(10),
+                        "frontend took too long to start"
+                    );
+                    sleep(Duration::from_millis(10)).await;
+                }
+                x => break x,
+            }
+        };
+
+        Self {
+            app,
+            port,
+            new_top_config,
+            shutdown_sender,
+        }
+    }
+
+    pub fn http_url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub fn ws_url(&self, path: &str) -> String {
+        format!("ws://127.0.0.1:{}{}", self.port, path)
+    }
+}
+
+impl Drop for TestFrontend {
+    fn drop(&mut self) {
+        let _ = self.shutdown_sender.send(());
+    }
+}
@@ -1,3 +1,7 @@
 pub mod anvil;
+pub mod frontend;
+pub mod stub_rpc;
 
 pub use self::anvil::TestAnvil;
+pub use self::frontend::TestFrontend;
+pub use self::stub_rpc::StubRpc;
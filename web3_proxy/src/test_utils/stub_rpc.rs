@@ -0,0 +1,98 @@
+use axum::body::Bytes;
+use axum::routing::post;
+use axum::Router;
+use sonic_rs::{json, JsonValueMutTrait, JsonValueTrait, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::AbortHandle;
+use tokio::time::{sleep, Duration};
+
+/// what a `StubRpc` does with one request: wait this long, then respond with the `result` or `error`
+pub type StubResponse = (Duration, Result<Value, Value>);
+
+/// a jsonrpc server on a random port. every request is answered by a function of the method and params.
+/// batches are not supported. on drop, the server is shut down
+pub struct StubRpc {
+    pub url: String,
+    /// how many requests have been received
+    pub requests: Arc<AtomicUsize>,
+    server: AbortHandle,
+}
+
+impl StubRpc {
+    pub async fn spawn(
+        respond: impl Fn(&str, &Value) -> StubResponse + Send + Sync + 'static,
+    ) -> Self {
+        let respond = Arc::new(respond);
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let router = {
+            let requests = requests.clone();
+
+            Router::new().route(
+                "/",
+                post(move |body: Bytes| async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+
+                    let request: Value = sonic_rs::from_slice(&body).unwrap();
+
+                    let method = request["method"].as_str().unwrap_or_default();
+
+                    let (delay, response) = respond(method, &request["params"]);
+
+                    sleep(delay).await;
+
+                    let mut x = json!({"jsonrpc": "2.0"});
+                    let x_obj = x.as_object_mut().unwrap();
+
+                    x_obj.insert("id", request["id"].clone());
+
+                    match response {
+                        Ok(result) => x_obj.insert("result", result),
+                        Err(error) => x_obj.insert("error", error),
+                    };
+
+                    (
+                        [(http::header::CONTENT_TYPE, "application/json")],
+                        x.to_string(),
+                    )
+                }),
+            )
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        })
+        .abort_handle();
+
+        Self {
+            url,
+            requests,
+            server,
+        }
+    }
+
+    /// every request gets the same response after the same delay
+    pub async fn constant(delay: Duration, response: Result<Value, Value>) -> Self {
+        Self::spawn(move |_, _| (delay, response.clone())).await
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for StubRpc {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// `{"code": -32000, "message": ..}`
+pub fn stub_error(message: &str) -> Value {
+    json!({"code": -32000, "message": message})
+}
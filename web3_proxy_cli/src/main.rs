@@ -12,7 +12,6 @@ use std::{
 use tokio::runtime;
 use tracing::{info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
-use web3_proxy::prelude::alloy::primitives::U256;
 use web3_proxy::{app::APP_USER_AGENT, config::TopConfig};
 use web3_proxy_cli::{sub_commands, DEFAULT_CONFIG_PATH};
 
@@ -52,6 +51,7 @@ enum SubCommand {
     CheckConfig(sub_commands::CheckConfigSubCommand),
     PopularityContest(sub_commands::PopularityContestSubCommand),
     Proxyd(sub_commands::ProxydSubCommand),
+    Replay(sub_commands::ReplaySubCommand),
     Sentryd(sub_commands::SentrydSubCommand),
 }
 
@@ -140,17 +140,6 @@ fn main() -> anyhow::Result<()> {
             cli_config.sentry_url = Some(sentry_url);
         }
 
-        if top_config.app.chain_id == 137 {
-            // TODO: these numbers are arbitrary. i think the maticnetwork/erigon fork has a bug
-            if top_config.app.gas_increase_min.is_none() {
-                top_config.app.gas_increase_min = Some(U256::from(40_000));
-            }
-
-            if top_config.app.gas_increase_percent.is_none() {
-                top_config.app.gas_increase_percent = Some(U256::from(40));
-            }
-        }
-
         top_config.clean();
 
         (Some(top_config), Some(top_config_path))
@@ -251,6 +240,7 @@ fn main() -> anyhow::Result<()> {
                     top_config_path.expect("path must be set when the config exists");
                 command.main(top_config, top_config_path).await
             }
+            SubCommand::Replay(command) => command.main().await,
             SubCommand::Sentryd(command) => {
                 if cli_config.sentry_url.is_none() {
                     warn!("sentry_url is not set; logs will only appear in this console");
@@ -1,5 +1,5 @@
 use crate::DEFAULT_CONFIG_PATH;
-use std::fs;
+use std::path::Path;
 use web3_proxy::config::TopConfig;
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::argh::{self, FromArgs};
@@ -17,10 +17,8 @@ pub struct CheckConfigSubCommand {
 impl CheckConfigSubCommand {
     pub async fn main(self) -> anyhow::Result<()> {
         info!("Loading config @ {}", self.path);
-        let top_config: String = fs::read_to_string(self.path)?;
-        let mut top_config = TopConfig::from_toml_str(&top_config)?;
-
-        top_config.clean();
+        // the same checks that run when proxyd reloads its config
+        let top_config = TopConfig::load(Path::new(&self.path))?;
 
         info!("config: {:#?}", top_config);
 
@@ -1,9 +1,11 @@
 mod check_config;
 mod popularity_contest;
 mod proxyd;
+mod replay;
 mod sentryd;
 
 pub use self::check_config::CheckConfigSubCommand;
 pub use self::popularity_contest::PopularityContestSubCommand;
 pub use self::proxyd::ProxydSubCommand;
+pub use self::replay::ReplaySubCommand;
 pub use self::sentryd::SentrydSubCommand;
@@ -1,23 +1,30 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU16;
 use std::sync::Arc;
-use std::time::Duration;
-use std::{fs, thread};
+use std::time::{Duration, SystemTime};
 use tracing::{error, info, trace, warn};
-use web3_proxy::app::App;
-use web3_proxy::config::TopConfig;
+use web3_proxy::app::{App, ReloadTrigger};
+use web3_proxy::config::{config_diff, TopConfig};
 use web3_proxy::frontend;
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::futures::StreamExt;
 use web3_proxy::prelude::num::Zero;
 use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::tokio::net::TcpListener;
 use web3_proxy::prelude::tokio::process::Command;
 use web3_proxy::prelude::tokio::signal::unix::SignalKind;
-use web3_proxy::prelude::tokio::sync::broadcast;
-use web3_proxy::prelude::tokio::time::{sleep_until, Instant};
+use web3_proxy::prelude::tokio::sync::{broadcast, watch};
+use web3_proxy::prelude::tokio::time::{interval, sleep_until, Instant, MissedTickBehavior};
 use web3_proxy::prelude::tokio::{select, signal};
 
+/// how often to check the config file's modified time
+const CONFIG_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// how often to re-read the config file even if it doesn't look like it changed
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
 /// start the main proxy daemon
 #[derive(FromArgs, PartialEq, Debug, Eq)]
 #[argh(subcommand, name = "proxyd")]
@@ -73,55 +80,16 @@ impl ProxydSubCommand {
 
         let mut head_block_receiver = spawned_app.app.head_block_receiver();
 
-        // start thread for watching config
+        // start a task for watching config
         if let Some(top_config_path) = top_config_path {
-            let config_sender = spawned_app.new_top_config;
-            {
-                let mut current_config = config_sender.borrow().clone();
-
-                // TODO: move this to a helper function
-                thread::spawn(move || loop {
-                    // give the app some time to start before changing configs for the first time
-                    thread::sleep(Duration::from_secs(60));
-
-                    match fs::read_to_string(&top_config_path) {
-                        Ok(new_top_config) => {
-                            match TopConfig::from_toml_str(&new_top_config) {
-                                Ok(mut new_top_config) => {
-                                    new_top_config.clean();
-
-                                    if new_top_config != current_config {
-                                        trace!("current_config: {:#?}", current_config);
-                                        trace!("new_top_config: {:#?}", new_top_config);
-
-                                        // TODO: print the differences
-                                        // TODO: first run seems to always see differences. why?
-                                        info!("config @ {:?} changed", top_config_path);
-                                        match config_sender.send(new_top_config.clone()) {
-                                            Ok(()) => current_config = new_top_config,
-                                            Err(err) => {
-                                                error!(?err, "unable to apply new config")
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    // TODO: panic?
-                                    error!("Unable to parse config! {:#?}", err);
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            // TODO: panic?
-                            error!("Unable to read config! {:#?}", err);
-                        }
-                    }
+            spawned_app.app.set_config_path(top_config_path.clone());
 
-                    // TODO: wait for SIGHUP instead?
-                    // TODO: wait for file to change instead of polling. file notifications are really fragile depending on the system and setup though
-                    thread::sleep(Duration::from_secs(30));
-                });
-            }
+            tokio::spawn(watch_config(
+                spawned_app.app.clone(),
+                top_config_path,
+                spawned_app.new_top_config.clone(),
+                app_shutdown_sender.subscribe(),
+            ));
         }
 
         info!("waiting up to 60 seconds for a head block");
@@ -155,9 +123,27 @@ impl ProxydSubCommand {
             frontend_shutdown_complete_sender,
         ));
 
-        if let Some(start_script) = spawned_app.app.config.start_script.as_ref() {
+        let config = spawned_app.app.config.load_full();
+
+        // the admin api is on its own port so that it can stay off the public internet
+        if let Some(admin_addr) = config.admin_addr {
+            let admin_listener = TcpListener::bind(admin_addr).await?;
+
+            let app = spawned_app.app.clone();
+            let shutdown_receiver = frontend_shutdown_sender.subscribe();
+
+            tokio::spawn(async move {
+                if let Err(err) =
+                    frontend::admin::serve(app, admin_listener, shutdown_receiver).await
+                {
+                    error!(?err, "admin api exited");
+                }
+            });
+        }
+
+        if let Some(start_script) = config.start_script.as_ref() {
             let start_script = Command::new(start_script)
-                .args(&spawned_app.app.config.start_script_args)
+                .args(&config.start_script_args)
                 .spawn()
                 .expect("failed to execute script");
 
@@ -310,3 +296,100 @@ impl ProxydSubCommand {
         }
     }
 }
+
+/// reload the config on SIGHUP or when the file's modified time changes.
+/// file events are fragile depending on the system and setup, so the file is also re-read on an interval.
+/// SIGHUP sends the file even if it matches the last config sent. that retries a config that failed to apply
+async fn watch_config(
+    app: Arc<App>,
+    path: PathBuf,
+    config_sender: Arc<watch::Sender<TopConfig>>,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let mut sighup_stream = signal::unix::signal(SignalKind::hangup())?;
+
+    let mut file_interval = interval(CONFIG_FILE_CHECK_INTERVAL);
+    file_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut poll_interval = interval(CONFIG_POLL_INTERVAL);
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // the config was loaded at startup. the first ticks are immediate
+    file_interval.tick().await;
+    poll_interval.tick().await;
+
+    let mut last_modified = config_modified(&path);
+    let mut load_failed = false;
+
+    loop {
+        let trigger = select! {
+            _ = shutdown_receiver.recv() => break,
+            x = sighup_stream.recv() => {
+                if x.is_none() {
+                    break;
+                }
+                info!(?path, "reloading config from SIGHUP");
+                ReloadTrigger::Sighup
+            }
+            _ = file_interval.tick() => {
+                let modified = config_modified(&path);
+
+                if modified == last_modified {
+                    continue;
+                }
+
+                last_modified = modified;
+
+                ReloadTrigger::FileChanged
+            }
+            _ = poll_interval.tick() => ReloadTrigger::Poll,
+        };
+
+        // the same checks as check_config
+        let new_top_config = match TopConfig::load(&path) {
+            Ok(x) => x,
+            Err(err) => {
+                error!(?err, %trigger, "unable to reload config! keeping the current config");
+                app.config_reload_failed(Some(trigger), format!("{:#}", err));
+                load_failed = true;
+                continue;
+            }
+        };
+
+        // compare the serialized configs so that the differences can be logged. urls and keys are redacted
+        // this is the last config sent. it might not have been applied, so SIGHUP always sends the file again
+        let changes = config_diff(&*config_sender.borrow(), &new_top_config);
+
+        if changes.is_empty() && !matches!(trigger, ReloadTrigger::Sighup) {
+            trace!(%trigger, "config unchanged");
+
+            if load_failed {
+                app.config_reload_unchanged(trigger);
+                load_failed = false;
+            }
+
+            continue;
+        }
+
+        load_failed = false;
+
+        if changes.is_empty() {
+            info!(%trigger, "config unchanged. applying it again");
+        }
+
+        for change in changes.iter() {
+            info!(%trigger, path=%change.path, old=%change.old, new=%change.new, "config changed");
+        }
+
+        app.config_reload_started(trigger, changes.into_iter().map(|x| x.path).collect());
+
+        // the app validates and applies it. the result is shown at /status/reload
+        config_sender.send_replace(new_top_config);
+    }
+
+    Ok(())
+}
+
+fn config_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
@@ -0,0 +1,456 @@
+use prettytable::{row, Table};
+use std::path::PathBuf;
+use std::time::Duration;
+use web3_proxy::capture::CaptureRecord;
+use web3_proxy::prelude::anyhow::{self, Context};
+use web3_proxy::prelude::argh::{self, FromArgs};
+use web3_proxy::prelude::futures::future::join_all;
+use web3_proxy::prelude::futures::stream::{FuturesUnordered, StreamExt};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::sonic_rs::{self, json, JsonValueTrait, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{interval, Instant, Interval, MissedTickBehavior},
+};
+use web3_proxy::prelude::tracing::{info, warn};
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// send the requests from a traffic capture to one or more servers. compare their responses and show latency percentiles
+#[argh(subcommand, name = "replay")]
+pub struct ReplaySubCommand {
+    #[argh(positional)]
+    /// the capture file. json lines written by the `capture_path` config
+    capture: PathBuf,
+
+    #[argh(positional)]
+    /// the servers to send requests to. with more than one, responses are compared to the first
+    endpoints: Vec<String>,
+
+    #[argh(option, default = "100")]
+    /// the most requests to have in flight at once
+    concurrency: usize,
+
+    #[argh(option)]
+    /// stop after this many requests
+    limit: Option<usize>,
+
+    #[argh(option, default = "10.0")]
+    /// requests per second. 0 sends as fast as concurrency allows
+    rate: f64,
+
+    #[argh(option, default = "10")]
+    /// how many mismatches to print
+    show_mismatches: usize,
+
+    #[argh(option, default = "30")]
+    /// seconds to wait for each response
+    timeout: u64,
+}
+
+/// what happened when one request was sent to one endpoint
+enum Outcome {
+    Response(Value),
+    /// the request failed or the body was not json
+    Failed(String),
+}
+
+struct Sent {
+    millis: f64,
+    outcome: Outcome,
+}
+
+#[derive(Default)]
+struct EndpointStats {
+    latencies_ms: Vec<f64>,
+    failures: u64,
+    jsonrpc_errors: u64,
+}
+
+struct Mismatch {
+    method: String,
+    params: Value,
+    /// one per endpoint
+    responses: Vec<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    endpoints: Vec<EndpointStats>,
+    compared: u64,
+    mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    fn add(&mut self, record: CaptureRecord, sent: Vec<Sent>) {
+        for (stats, x) in self.endpoints.iter_mut().zip(sent.iter()) {
+            stats.latencies_ms.push(x.millis);
+
+            match &x.outcome {
+                Outcome::Response(body) if body.get("error").is_some() => stats.jsonrpc_errors += 1,
+                Outcome::Response(_) => {}
+                Outcome::Failed(_) => stats.failures += 1,
+            }
+        }
+
+        // everything is compared to the first endpoint
+        let Some(Outcome::Response(first)) = sent.first().map(|x| &x.outcome) else {
+            return;
+        };
+
+        let first = comparable(first);
+
+        let mut matches = true;
+
+        for x in sent.iter().skip(1) {
+            if let Outcome::Response(body) = &x.outcome {
+                self.compared += 1;
+
+                if comparable(body) != first {
+                    matches = false;
+                }
+            }
+        }
+
+        if !matches {
+            let responses = sent
+                .iter()
+                .map(|x| match &x.outcome {
+                    Outcome::Response(body) => comparable(body).to_string(),
+                    Outcome::Failed(err) => format!("failed: {}", err),
+                })
+                .collect();
+
+            self.mismatches.push(Mismatch {
+                method: record.method,
+                params: record.params,
+                responses,
+            });
+        }
+    }
+}
+
+/// what is compared between servers. ids, jsonrpc versions, and error messages are ignored
+fn comparable(body: &Value) -> Value {
+    if let Some(result) = body.get("result") {
+        json!({ "result": result })
+    } else if let Some(code) = body.get("error").and_then(|x| x.get("code")) {
+        json!({ "error": code })
+    } else {
+        body.clone()
+    }
+}
+
+/// nearest rank. `sorted` must be sorted
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+async fn post(client: &reqwest::Client, endpoint: &str, body: Vec<u8>) -> anyhow::Result<Value> {
+    let response = client
+        .post(endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?;
+
+    let status = response.status();
+
+    let body = response.bytes().await?;
+
+    // errors like rate limits still have jsonrpc bodies. only fail if there is nothing to compare
+    sonic_rs::from_slice(&body).with_context(|| format!("status {} without a json body", status))
+}
+
+async fn send(client: &reqwest::Client, endpoint: &str, body: Vec<u8>) -> Sent {
+    let start = Instant::now();
+
+    let outcome = match post(client, endpoint, body).await {
+        Ok(x) => Outcome::Response(x),
+        Err(err) => Outcome::Failed(format!("{:#}", err)),
+    };
+
+    Sent {
+        millis: start.elapsed().as_secs_f64() * 1000.0,
+        outcome,
+    }
+}
+
+/// send one captured request to every endpoint at the same time
+async fn replay_one(
+    client: &reqwest::Client,
+    endpoints: &[String],
+    id: usize,
+    record: CaptureRecord,
+) -> (CaptureRecord, Vec<Sent>) {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": record.method,
+        "params": record.params,
+    });
+
+    let body = sonic_rs::to_vec(&body).expect("json values always serialize");
+
+    let sent = join_all(endpoints.iter().map(|x| send(client, x, body.clone()))).await;
+
+    (record, sent)
+}
+
+/// the time between requests. None for a rate of 0, which sends as fast as possible
+fn tick_period(rate: f64) -> anyhow::Result<Option<Duration>> {
+    if rate == 0.0 {
+        return Ok(None);
+    }
+
+    if !rate.is_finite() || rate < 0.0 {
+        return Err(anyhow::anyhow!(
+            "rate must be a positive number. got {}",
+            rate
+        ));
+    }
+
+    match Duration::try_from_secs_f64(1.0 / rate) {
+        Ok(x) if !x.is_zero() => Ok(Some(x)),
+        _ => Err(anyhow::anyhow!(
+            "rate {} is too high. the most is 1 request per nanosecond",
+            rate
+        )),
+    }
+}
+
+/// waits for the next tick. None never waits
+async fn tick(x: &mut Option<Interval>) {
+    if let Some(x) = x {
+        x.tick().await;
+    }
+}
+
+impl ReplaySubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        if self.endpoints.is_empty() {
+            return Err(anyhow::anyhow!("at least one endpoint is required"));
+        }
+
+        let capture = tokio::fs::read_to_string(&self.capture)
+            .await
+            .with_context(|| format!("reading {}", self.capture.display()))?;
+
+        let mut records = vec![];
+
+        for (i, line) in capture.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match sonic_rs::from_str::<CaptureRecord>(line) {
+                Ok(x) => records.push(x),
+                Err(err) => warn!(line = i + 1, %err, "skipping a bad capture line"),
+            }
+        }
+
+        if let Some(limit) = self.limit {
+            records.truncate(limit);
+        }
+
+        info!(
+            requests = records.len(),
+            endpoints = self.endpoints.len(),
+            rate = self.rate,
+            "replaying"
+        );
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .build()?;
+
+        let mut ticker = match tick_period(self.rate)? {
+            None => None,
+            Some(period) => {
+                let mut x = interval(period);
+                x.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                Some(x)
+            }
+        };
+
+        let concurrency = self.concurrency.max(1);
+
+        let mut report = Report {
+            endpoints: self.endpoints.iter().map(|_| Default::default()).collect(),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+
+        let mut records = records.into_iter().enumerate().peekable();
+        let mut pending = FuturesUnordered::new();
+
+        loop {
+            let can_send = records.peek().is_some() && pending.len() < concurrency;
+
+            tokio::select! {
+                _ = tick(&mut ticker), if can_send => {
+                    let (id, record) = records.next().expect("checked by can_send");
+
+                    pending.push(replay_one(&client, &self.endpoints, id, record));
+                }
+                Some((record, sent)) = pending.next() => {
+                    report.add(record, sent);
+                }
+                else => break,
+            }
+        }
+
+        let elapsed = start.elapsed();
+
+        let mut table = Table::new();
+
+        table.add_row(row![
+            "endpoint",
+            "requests",
+            "failed",
+            "jsonrpc errors",
+            "p50_ms",
+            "p90_ms",
+            "p99_ms",
+            "max_ms",
+        ]);
+
+        for (endpoint, stats) in self.endpoints.iter().zip(report.endpoints.iter_mut()) {
+            stats.latencies_ms.sort_by(f64::total_cmp);
+
+            let x = &stats.latencies_ms;
+
+            table.add_row(row![
+                endpoint,
+                x.len(),
+                stats.failures,
+                stats.jsonrpc_errors,
+                format!("{:.1}", percentile(x, 50.0)),
+                format!("{:.1}", percentile(x, 90.0)),
+                format!("{:.1}", percentile(x, 99.0)),
+                format!("{:.1}", x.last().copied().unwrap_or_default()),
+            ]);
+        }
+
+        table.printstd();
+
+        println!(
+            "{} requests in {:.1}s. {} comparisons. {} mismatches",
+            report.endpoints[0].latencies_ms.len(),
+            elapsed.as_secs_f64(),
+            report.compared,
+            report.mismatches.len(),
+        );
+
+        for x in report.mismatches.iter().take(self.show_mismatches) {
+            println!("\n{}({})", x.method, x.params);
+
+            for (endpoint, response) in self.endpoints.iter().zip(x.responses.iter()) {
+                println!("  {}: {}", endpoint, response);
+            }
+        }
+
+        if report.mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} responses did not match",
+                report.mismatches.len()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{comparable, percentile, tick_period, Outcome, Report, Sent};
+    use std::time::Duration;
+    use web3_proxy::capture::CaptureRecord;
+    use web3_proxy::prelude::chrono::Utc;
+    use web3_proxy::prelude::sonic_rs::json;
+
+    #[test]
+    fn test_percentile() {
+        let x: Vec<f64> = (1..=100).map(f64::from).collect();
+
+        assert_eq!(percentile(&x, 50.0), 50.0);
+        assert_eq!(percentile(&x, 99.0), 99.0);
+        assert_eq!(percentile(&x, 100.0), 100.0);
+        assert_eq!(percentile(&x, 0.0), 1.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn test_tick_period() {
+        assert_eq!(tick_period(0.0).unwrap(), None);
+        assert_eq!(tick_period(4.0).unwrap(), Some(Duration::from_millis(250)));
+        assert_eq!(tick_period(1e9).unwrap(), Some(Duration::from_nanos(1)));
+
+        assert!(tick_period(f64::INFINITY).is_err());
+        assert!(tick_period(f64::NAN).is_err());
+        assert!(tick_period(-1.0).is_err());
+        assert!(tick_period(1e10).is_err());
+    }
+
+    #[test]
+    fn test_comparable_ignores_ids() {
+        let a = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"});
+        let b = json!({"jsonrpc": "2.0", "id": 2, "result": "0x1"});
+        let c = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "a"}});
+        let d = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "b"}});
+
+        assert_eq!(comparable(&a), comparable(&b));
+        assert_eq!(comparable(&c), comparable(&d));
+        assert_ne!(comparable(&a), comparable(&c));
+    }
+
+    #[test]
+    fn test_report() {
+        let record = || CaptureRecord {
+            at: Utc::now(),
+            method: "eth_blockNumber".into(),
+            params: json!([]),
+            head_block: None,
+            head_block_hash: None,
+            from_block: None,
+            to_block: None,
+            response_millis: 1,
+            error: None,
+        };
+
+        let sent = |x: Option<&str>| Sent {
+            millis: 1.0,
+            outcome: match x {
+                Some(x) => Outcome::Response(json!({"id": 1, "result": x})),
+                None => Outcome::Failed("timeout".into()),
+            },
+        };
+
+        let mut report = Report {
+            endpoints: vec![Default::default(), Default::default(), Default::default()],
+            ..Default::default()
+        };
+
+        report.add(
+            record(),
+            vec![sent(Some("0x1")), sent(Some("0x1")), sent(None)],
+        );
+        assert_eq!(report.compared, 1);
+        assert!(report.mismatches.is_empty());
+        assert_eq!(report.endpoints[2].failures, 1);
+
+        report.add(
+            record(),
+            vec![sent(Some("0x1")), sent(Some("0x2")), sent(Some("0x1"))],
+        );
+        assert_eq!(report.compared, 3);
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].responses[1], "{\"result\":\"0x2\"}");
+    }
+}
@@ -2,7 +2,7 @@ use crate::sub_commands::ProxydSubCommand;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use std::{thread, time::Duration};
-use web3_proxy::config::{AppConfig, TopConfig, Web3RpcConfig};
+use web3_proxy::config::{ApiKeyConfig, AppConfig, TopConfig, Web3RpcConfig};
 use web3_proxy::prelude::alloy::providers::ProviderBuilder;
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::hashbrown::HashMap;
@@ -35,6 +35,16 @@ impl TestApp {
 
         let top_config = TopConfig {
             app: app_config,
+            api_keys: HashMap::from([(
+                "test".to_string(),
+                ApiKeyConfig {
+                    allowed_proxy_modes: vec!["best".to_string()],
+                    denied_methods: vec!["eth_sendRawTransaction".to_string()],
+                    key: "test-key".to_string(),
+                    requests_per_second: Some(10),
+                    ..Default::default()
+                },
+            )]),
             balanced_rpcs: HashMap::from([(
                 "anvil".to_string(),
                 Web3RpcConfig {
@@ -52,6 +62,7 @@ impl TestApp {
                 },
             )]),
             bundler_4337_rpcs: Default::default(),
+            routes: Default::default(),
             extra: Default::default(),
         };
 
@@ -56,7 +56,7 @@ async fn it_starts_and_stops() {
     assert_eq!(status_response.unwrap().status(), StatusCode::OK);
 
     let client = reqwest::Client::new();
-    for path in ["", "fastest", "versus"] {
+    for path in ["", "fastest", "versus", "quorum/1/2"] {
         let response = client
             .post(format!("{}{}", proxy_url, path))
             .header(header::CONTENT_TYPE, "application/json")
@@ -73,6 +73,48 @@ async fn it_starts_and_stops() {
         assert_eq!(body["result"], json!("0x7a69"), "route /{path}");
     }
 
+    // eth_chainId is answered without a backend. versus needs a request that goes to the rpcs
+    let versus_response = client
+        .post(format!("{}versus", proxy_url))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(
+            sonic_rs::to_vec(&json!({"jsonrpc": "2.0", "method": "eth_gasPrice", "id": 1}))
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(versus_response.status(), StatusCode::OK);
+
+    let versus: Value =
+        serde_json::from_str(versus_response.headers()["X-W3P-VERSUS"].to_str().unwrap()).unwrap();
+    assert_eq!(versus[0][0]["rpc"], json!("anvil"));
+    assert_eq!(versus[0][0]["agrees_with_majority"], json!(true));
+
+    // there is only one backend, so it can never agree with a second one
+    for (path, reached) in [("quorum/1/1", true), ("quorum/2/2", false)] {
+        let quorum_response = client
+            .post(format!("{}{}", proxy_url, path))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(
+                sonic_rs::to_vec(&json!({"jsonrpc": "2.0", "method": "eth_gasPrice", "id": 1}))
+                    .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap();
+
+        let body: Value = serde_json::from_slice(&quorum_response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body.get("result").is_some(), reached, "route /{path}");
+        if !reached {
+            assert_eq!(
+                body["error"]["data"]["votes"][0]["rpc"],
+                json!("anvil"),
+                "route /{path}"
+            );
+        }
+    }
+
     let removed_key_route = client
         .post(format!("{}rpc/removed-key", proxy_url))
         .header(header::CONTENT_TYPE, "application/json")
@@ -84,6 +126,40 @@ async fn it_starts_and_stops() {
         .unwrap();
     assert_eq!(removed_key_route.status(), StatusCode::NOT_FOUND);
 
+    // the test app has one api key. it is allowed "best" and is denied eth_sendRawTransaction
+    for (path, bearer, method, expected) in [
+        ("rpc/test-key", None, "eth_chainId", StatusCode::OK),
+        ("", Some("test-key"), "eth_chainId", StatusCode::OK),
+        ("", Some("wrong-key"), "eth_chainId", StatusCode::NOT_FOUND),
+        (
+            "rpc/test-key/versus",
+            None,
+            "eth_chainId",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "rpc/test-key",
+            None,
+            "eth_sendRawTransaction",
+            StatusCode::FORBIDDEN,
+        ),
+    ] {
+        let mut request = client
+            .post(format!("{}{}", proxy_url, path))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(sonic_rs::to_vec(&json!({"jsonrpc": "2.0", "method": method, "id": 1})).unwrap());
+        if let Some(bearer) = bearer {
+            request = request.bearer_auth(bearer);
+        }
+
+        let response = request.send().await.unwrap();
+        assert_eq!(
+            response.status(),
+            expected,
+            "route /{path} {bearer:?} {method}"
+        );
+    }
+
     let anvil_result = anvil_provider
         .raw_request::<_, Option<ArcBlock>>("eth_getBlockByNumber".into(), ("latest", false))
         .await
@@ -104,8 +180,20 @@ async fn it_starts_and_stops() {
     assert!(backend.get("internal_requests").is_none());
     assert!(backend.get("external_requests").is_none());
 
+    let metrics_response = reqwest::get(format!("{}metrics", proxy_url)).await.unwrap();
+    assert_eq!(metrics_response.status(), StatusCode::OK);
+    let metrics = metrics_response.text().await.unwrap();
+    assert!(metrics.contains("web3_proxy_requests_total{method=\"eth_getBlockByNumber\"}"));
+    assert!(metrics.contains("web3_proxy_rpc_healthy{group=\"balanced_rpcs\",rpc=\"anvil\"} 1"));
+
     let first_block_num = anvil_result.number();
 
+    // the proxy keeps its own filters. polling clients don't need a sticky backend
+    let block_filter: Value = proxy_provider
+        .raw_request("eth_newBlockFilter".into(), ())
+        .await
+        .unwrap();
+
     // mine a block
     let _: Value = anvil_provider
         .raw_request("evm_mine".into(), ())
@@ -150,7 +238,30 @@ async fn it_starts_and_stops() {
         }
     }
 
-    assert_eq!(Some(anvil_result), proxy_result);
+    assert_eq!(Some(anvil_result.clone()), proxy_result);
+
+    let mut filter_changes = vec![];
+    for _ in 0..10 {
+        let changes: Vec<B256> = proxy_provider
+            .raw_request("eth_getFilterChanges".into(), (&block_filter,))
+            .await
+            .unwrap();
+
+        filter_changes.extend(changes);
+
+        if !filter_changes.is_empty() {
+            break;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(filter_changes, vec![anvil_result.header.hash]);
+
+    let uninstalled: bool = proxy_provider
+        .raw_request("eth_uninstallFilter".into(), (&block_filter,))
+        .await
+        .unwrap();
+    assert!(uninstalled);
 
     // most tests won't need to wait, but we should wait here to be sure all the shutdown logic works properly
     x.wait_for_stop();